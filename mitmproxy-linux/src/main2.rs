@@ -75,7 +75,7 @@ async fn main() -> anyhow::Result<()> {
     bump_memlock_rlimit();
 
     debug!("Creating tun device...");
-    let (mut device, name) = create_tun_device(None)?;
+    let (mut device, name) = create_tun_device(None, None)?;
     let device_index = device.tun_index().context("failed to get tun device index")? as u32;
     debug!("Tun device created: {name} (id={device_index})");
 
@@ -1,6 +1,7 @@
 from __future__ import annotations
 
-from typing import Any, Literal
+from collections.abc import Callable
+from typing import Any, Literal, TypedDict
 from typing import final, overload, TypeVar
 from . import certs, dns, local, process_info, tun, udp, wireguard
 
@@ -15,6 +16,20 @@ class Stream:
     async def drain(self) -> None: ...
     def write_eof(self): ...
     def close(self): ...
+    def abort(self): ...
+    def accept(self): ...
+    def reject(
+        self,
+        code: Literal[
+            "reset",
+            "net-unreachable",
+            "host-unreachable",
+            "port-unreachable",
+            "admin-prohibited",
+            "ttl-exceeded",
+        ] = "reset",
+    ): ...
+    async def splice_to(self, host: str, port: int) -> tuple[int, int]: ...
     def is_closing(self) -> bool: ...
     async def wait_closed(self) -> None: ...
     @overload
@@ -52,9 +67,96 @@ class Stream:
     @overload
     def get_extra_info(self, name: Literal["process_name"], default: T) -> str | T: ...
     @overload
+    def get_extra_info(
+        self, name: Literal["original_hostname"], default: None = None
+    ) -> str: ...
+    @overload
+    def get_extra_info(
+        self, name: Literal["original_hostname"], default: T
+    ) -> str | T: ...
+    @overload
+    def get_extra_info(self, name: Literal["sni"], default: None = None) -> str: ...
+    @overload
+    def get_extra_info(self, name: Literal["sni"], default: T) -> str | T: ...
+    @overload
+    def get_extra_info(
+        self, name: Literal["alpn"], default: None = None
+    ) -> list[bytes]: ...
+    @overload
+    def get_extra_info(self, name: Literal["alpn"], default: T) -> list[bytes] | T: ...
+    @overload
+    def get_extra_info(
+        self, name: Literal["tls_versions"], default: None = None
+    ) -> list[int]: ...
+    @overload
+    def get_extra_info(
+        self, name: Literal["tls_versions"], default: T
+    ) -> list[int] | T: ...
+    @overload
+    def get_extra_info(
+        self, name: Literal["wireguard_peer"], default: None = None
+    ) -> tuple[str, int]: ...
+    @overload
+    def get_extra_info(
+        self, name: Literal["wireguard_peer"], default: T
+    ) -> tuple[str, int] | T: ...
+    @overload
     def get_extra_info(self, name: str, default: Any) -> Any: ...
     def __repr__(self) -> str: ...
 
+@final
+class ConnectionClosed:
+    @property
+    def transport_protocol(self) -> Literal["tcp", "udp"]: ...
+    @property
+    def peername(self) -> tuple[str, int]: ...
+    @property
+    def sockname(self) -> tuple[str, int]: ...
+    @property
+    def reason(
+        self,
+    ) -> Literal["peer_closed", "reset", "timeout", "local_close", "shutdown"]: ...
+    @property
+    def bytes_received(self) -> int: ...
+    @property
+    def bytes_sent(self) -> int: ...
+    @property
+    def duration(self) -> float: ...
+    def __repr__(self) -> str: ...
+
+# Network stack
+
+@final
+class NetworkOptions:
+    def __init__(
+        self,
+        *,
+        tcp_accept_timeout: float | None = None,
+        mtu: int | None = None,
+        forward_icmp: bool = False,
+        handle_raw_packet: Callable[[bytes], None] | None = None,
+        protocol_actions: dict[int, Literal["drop", "forward", "unreachable"]] | None = None,
+        fake_dns_servers: list[str] | None = None,
+        tcp_peek_timeout: float | None = None,
+    ) -> None: ...
+
+# Traffic shaping
+
+class LinkConditions(TypedDict, total=False):
+    bandwidth: int
+    latency: float
+    jitter: float
+    loss: float
+    reorder: float
+    duplicate: float
+
+class ShapingRule(TypedDict, total=False):
+    server_ip: str
+    server_port: int
+    client_addr: tuple[str, int]
+    uplink: LinkConditions
+    downlink: LinkConditions
+
 __all__ = [
     "certs",
     "dns",
@@ -64,4 +166,6 @@ __all__ = [
     "udp",
     "wireguard",
     "Stream",
+    "ConnectionClosed",
+    "NetworkOptions",
 ]
@@ -2,11 +2,14 @@ from __future__ import annotations
 
 from collections.abc import Awaitable, Callable
 from typing import final
-from . import Stream
+from . import ConnectionClosed, Stream
 
 async def start_local_redirector(
     handle_tcp_stream: Callable[[Stream], Awaitable[None]],
     handle_udp_stream: Callable[[Stream], Awaitable[None]],
+    *,
+    on_close: Callable[[ConnectionClosed], None] | None = None,
+    tcp_accept_timeout: float | None = None,
 ) -> LocalRedirector: ...
 @final
 class LocalRedirector:
@@ -1,17 +1,48 @@
 from __future__ import annotations
 
 from collections.abc import Awaitable, Callable
+from pathlib import Path
 from typing import final
-from . import Stream
+from . import ConnectionClosed, LinkConditions, NetworkOptions, ShapingRule, Stream
 
 async def create_tun_interface(
     handle_tcp_stream: Callable[[Stream], Awaitable[None]],
     handle_udp_stream: Callable[[Stream], Awaitable[None]],
     tun_name: str | None = None,
+    *,
+    on_close: Callable[[ConnectionClosed], None] | None = None,
+    network: NetworkOptions | None = None,
 ) -> TunInterface: ...
 @final
 class TunInterface:
     def tun_name(self) -> str: ...
+    async def open_connection(
+        self,
+        host: str,
+        port: int,
+        *,
+        udp: bool = False,
+        local_addr: tuple[str, int] | None = None,
+    ) -> Stream: ...
+    def send_raw_packet(self, data: bytes) -> None: ...
+    def set_shaping(
+        self,
+        uplink: LinkConditions | None = None,
+        downlink: LinkConditions | None = None,
+        *,
+        rules: list[ShapingRule] = [],
+        seed: int | None = None,
+    ) -> None: ...
+    def start_capture(
+        self,
+        path: Path | str | None = None,
+        *,
+        max_file_size: int | None = None,
+        max_files: int | None = None,
+        ring_size: int = 16 * 1024 * 1024,
+    ) -> None: ...
+    def stop_capture(self) -> bytes | None: ...
+    def capture_buffer(self) -> bytes | None: ...
     def close(self) -> None: ...
     async def wait_closed(self) -> None: ...
     def __repr__(self) -> str: ...
@@ -1,17 +1,22 @@
 from __future__ import annotations
 
 from collections.abc import Awaitable, Callable
-from typing import final
-from . import Stream
+from typing import Literal, final, overload
+from . import ConnectionClosed, Stream
 
 async def start_udp_server(
     host: str,
     port: int,
     handle_udp_stream: Callable[[Stream], Awaitable[None]],
+    *,
+    on_close: Callable[[ConnectionClosed], None] | None = None,
 ) -> UdpServer: ...
 @final
 class UdpServer:
-    def getsockname(self) -> tuple[str, int]: ...
+    @overload
+    def getsockname(self, *, all: Literal[False] = False) -> tuple[str, int]: ...
+    @overload
+    def getsockname(self, *, all: Literal[True]) -> list[tuple[str, int]]: ...
     def close(self) -> None: ...
     async def wait_closed(self) -> None: ...
     def __repr__(self) -> str: ...
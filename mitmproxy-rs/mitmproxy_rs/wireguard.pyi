@@ -1,8 +1,9 @@
 from __future__ import annotations
 
 from collections.abc import Awaitable, Callable
-from typing import final
-from . import Stream
+from pathlib import Path
+from typing import Literal, final, overload
+from . import ConnectionClosed, LinkConditions, NetworkOptions, ShapingRule, Stream
 
 def genkey() -> str: ...
 def pubkey(private_key: str) -> str: ...
@@ -13,17 +14,123 @@ async def start_wireguard_server(
     peer_public_keys: list[str],
     handle_tcp_stream: Callable[[Stream], Awaitable[None]],
     handle_udp_stream: Callable[[Stream], Awaitable[None]],
+    *,
+    allowed_ips: dict[str, list[str]] | None = None,
+    preshared_keys: dict[str, str] | None = None,
+    enrollment_handler: Callable[[str, tuple[str, int]], Awaitable[bool]] | None = None,
+    on_close: Callable[[ConnectionClosed], None] | None = None,
+    network: NetworkOptions | None = None,
 ) -> WireGuardServer: ...
+async def start_wireguard_server_from_config(
+    host: str,
+    config: str,
+    handle_tcp_stream: Callable[[Stream], Awaitable[None]],
+    handle_udp_stream: Callable[[Stream], Awaitable[None]],
+    *,
+    enrollment_handler: Callable[[str, tuple[str, int]], Awaitable[bool]] | None = None,
+    on_close: Callable[[ConnectionClosed], None] | None = None,
+    network: NetworkOptions | None = None,
+) -> WireGuardServer: ...
+def client_config(
+    private_key: str,
+    server_public_key: str,
+    endpoint: str,
+    client_index: int,
+    *,
+    preshared_key: str | None = None,
+    dns: list[str] | None = None,
+) -> str: ...
+def client_address(client_index: int) -> list[str]: ...
 @final
 class WireGuardServer:
-    def getsockname(self) -> tuple[str, int]: ...
+    @overload
+    def getsockname(self, *, all: Literal[False] = False) -> tuple[str, int]: ...
+    @overload
+    def getsockname(self, *, all: Literal[True]) -> list[tuple[str, int]]: ...
+    async def add_peer(
+        self,
+        public_key: str,
+        allowed_ips: list[str] | None = None,
+        preshared_key: str | None = None,
+    ) -> None: ...
+    async def remove_peer(self, public_key: str) -> None: ...
+    async def peers(self) -> list[str]: ...
+    async def stats(self) -> list[PeerStats]: ...
+    async def handshake_stats(self) -> HandshakeStats: ...
+    async def open_connection(
+        self,
+        host: str,
+        port: int,
+        *,
+        udp: bool = False,
+        local_addr: tuple[str, int] | None = None,
+    ) -> Stream: ...
+    def send_raw_packet(self, data: bytes) -> None: ...
+    def set_shaping(
+        self,
+        uplink: LinkConditions | None = None,
+        downlink: LinkConditions | None = None,
+        *,
+        rules: list[ShapingRule] = [],
+        seed: int | None = None,
+    ) -> None: ...
+    def start_capture(
+        self,
+        path: Path | str | None = None,
+        *,
+        max_file_size: int | None = None,
+        max_files: int | None = None,
+        ring_size: int = 16 * 1024 * 1024,
+    ) -> None: ...
+    def stop_capture(self) -> bytes | None: ...
+    def capture_buffer(self) -> bytes | None: ...
     def close(self) -> None: ...
     async def wait_closed(self) -> None: ...
     def __repr__(self) -> str: ...
+@final
+class PeerStats:
+    @property
+    def public_key(self) -> str: ...
+    @property
+    def endpoint(self) -> tuple[str, int] | None: ...
+    @property
+    def last_handshake(self) -> float | None: ...
+    @property
+    def rx_bytes(self) -> int: ...
+    @property
+    def rx_packets(self) -> int: ...
+    @property
+    def tx_bytes(self) -> int: ...
+    @property
+    def tx_packets(self) -> int: ...
+    @property
+    def decapsulation_errors(self) -> int: ...
+    @property
+    def dropped_packets(self) -> int: ...
+    def __repr__(self) -> str: ...
+
+@final
+class HandshakeStats:
+    @property
+    def handshakes(self) -> int: ...
+    @property
+    def handshakes_per_second(self) -> int: ...
+    @property
+    def cookie_replies(self) -> int: ...
+    @property
+    def invalid_handshakes(self) -> int: ...
+    @property
+    def unroutable_packets(self) -> int: ...
+    def __repr__(self) -> str: ...
 
 __all__ = [
     "genkey",
     "pubkey",
     "start_wireguard_server",
+    "start_wireguard_server_from_config",
+    "client_config",
+    "client_address",
     "WireGuardServer",
+    "PeerStats",
+    "HandshakeStats",
 ]
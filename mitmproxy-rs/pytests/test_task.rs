@@ -80,6 +80,8 @@ mod tests {
                 src_addr: "127.0.0.1:51232".parse()?,
                 dst_addr: "127.0.0.1:53".parse()?,
                 tunnel_info: TunnelInfo::None,
+                original_hostname: None,
+                client_hello: None,
                 command_tx: None,
             })
             .await
@@ -6,6 +6,7 @@ use once_cell::sync::Lazy;
 use pyo3::{exceptions::PyException, prelude::*};
 
 mod dns_resolver;
+mod network_options;
 mod process_info;
 mod server;
 mod stream;
@@ -76,13 +77,18 @@ mod mitmproxy_rs {
     #[pymodule]
     mod wireguard {
         #[pymodule_export]
-        use crate::server::{start_wireguard_server, WireGuardServer};
+        use crate::server::{
+            client_address, client_config, start_wireguard_server,
+            start_wireguard_server_from_config, HandshakeStats, PeerStats, WireGuardServer,
+        };
         #[pymodule_export]
         use crate::util::{genkey, pubkey};
     }
 
     #[pymodule_export]
-    use crate::stream::Stream;
+    use crate::network_options::NetworkOptions;
+    #[pymodule_export]
+    use crate::stream::{ConnectionClosed, Stream};
 
     #[pymodule_init]
     #[allow(unused_variables)]
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use mitmproxy::network::{NetworkConf, ProtocolAction};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+/// Options for the network stack of WireGuard servers and TUN interfaces:
+///
+/// - `tcp_accept_timeout`: If set, the TCP handshake is held back until `Stream.accept()` or
+///   `Stream.reject()` is called. Connections that are still pending after this many seconds are rejected.
+/// - `mtu`: The MTU of the tunnel. Defaults to 1420, which matches the default of WireGuard clients.
+///   TCP segments are sized accordingly, and larger UDP and ICMP replies are fragmented.
+///   The minimum is 576, the smallest datagram that IPv4 hosts must accept. IPv6 replies are
+///   never fragmented below 1280 bytes, the minimum MTU of IPv6.
+/// - `forward_icmp`: If set, ICMP echo requests are sent to their real destination and the real
+///   reply is relayed back. By default, every echo request is answered immediately.
+///   On Linux, this requires unprivileged ICMP sockets (`net.ipv4.ping_group_range`).
+/// - `handle_raw_packet`: An optional function that will be called with the raw bytes of IP packets
+///   whose protocol is set to `"forward"` in `protocol_actions`.
+/// - `protocol_actions`: An optional mapping from IP protocol numbers to `"drop"`, `"forward"`, or
+///   `"unreachable"`, which determines how packets of protocols other than TCP, UDP and ICMP are handled.
+///   `"unreachable"` answers with an ICMP protocol unreachable message. By default, packets are dropped.
+/// - `fake_dns_servers`: An optional list of IP addresses for which DNS queries are answered by
+///   mitmproxy_rs itself. A and AAAA queries get synthetic addresses, which lets
+///   `Stream.get_extra_info("original_hostname")` report the hostname a connection was made for.
+///   Other queries are forwarded to the system resolver. Pass `["10.0.0.53"]` to use this with the
+///   default `dns` setting of `client_config`.
+/// - `tcp_peek_timeout`: If set, new TCP connections are only passed to `handle_tcp_stream` once
+///   the client has sent a complete TLS ClientHello or something else, or after this many seconds.
+///   This makes `Stream.get_extra_info("sni")`, `"alpn"` and `"tls_versions"` available without
+///   reading from the stream. Has no effect if `tcp_accept_timeout` is set.
+///   For UDP streams, these are always taken from the QUIC Initial in the first datagram.
+#[pyclass(module = "mitmproxy_rs", frozen)]
+pub struct NetworkOptions {
+    conf: NetworkConf,
+    handle_raw_packet: Option<PyObject>,
+}
+
+#[pymethods]
+impl NetworkOptions {
+    #[new]
+    #[pyo3(signature = (*, tcp_accept_timeout=None, mtu=None, forward_icmp=false, handle_raw_packet=None, protocol_actions=None, fake_dns_servers=None, tcp_peek_timeout=None))]
+    fn new(
+        tcp_accept_timeout: Option<f64>,
+        mtu: Option<usize>,
+        forward_icmp: bool,
+        handle_raw_packet: Option<PyObject>,
+        protocol_actions: Option<HashMap<u8, String>>,
+        fake_dns_servers: Option<Vec<String>>,
+        tcp_peek_timeout: Option<f64>,
+    ) -> PyResult<Self> {
+        if let Some(mtu) = mtu {
+            if !(576..=65535).contains(&mtu) {
+                return Err(PyValueError::new_err(format!(
+                    "Invalid mtu: {mtu} (must be between 576 and 65535)"
+                )));
+            }
+        }
+        let protocol_actions = protocol_actions
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(protocol, action)| {
+                if matches!(protocol, 1 | 6 | 17 | 58) {
+                    return Err(PyValueError::new_err(format!(
+                        "IP protocol {protocol} is handled by the network stack"
+                    )));
+                }
+                let action = match action.as_str() {
+                    "drop" => ProtocolAction::Drop,
+                    "forward" => ProtocolAction::Forward,
+                    "unreachable" => ProtocolAction::Unreachable,
+                    _ => {
+                        return Err(PyValueError::new_err(format!(
+                            "Invalid protocol action: {action}"
+                        )))
+                    }
+                };
+                Ok((protocol, action))
+            })
+            .collect::<PyResult<_>>()?;
+        let fake_dns_servers = fake_dns_servers
+            .unwrap_or_default()
+            .into_iter()
+            .map(|addr| {
+                addr.parse::<IpAddr>()
+                    .map_err(|_| PyValueError::new_err(format!("Not an IP address: {}", addr)))
+            })
+            .collect::<PyResult<_>>()?;
+        Ok(Self {
+            conf: NetworkConf {
+                tcp_accept_timeout: timeout("tcp_accept_timeout", tcp_accept_timeout)?,
+                tcp_peek_timeout: timeout("tcp_peek_timeout", tcp_peek_timeout)?,
+                mtu,
+                forward_icmp,
+                protocol_actions,
+                fake_dns_servers,
+                ..NetworkConf::default()
+            },
+            handle_raw_packet,
+        })
+    }
+}
+
+impl NetworkOptions {
+    /// The network stack configuration and the `handle_raw_packet` callback, with defaults if no
+    /// options were given.
+    pub fn unpack(py: Python<'_>, options: Option<&Self>) -> (NetworkConf, Option<PyObject>) {
+        match options {
+            Some(options) => (
+                options.conf.clone(),
+                options.handle_raw_packet.as_ref().map(|f| f.clone_ref(py)),
+            ),
+            None => (NetworkConf::default(), None),
+        }
+    }
+}
+
+/// Convert a timeout in seconds as passed from Python.
+pub fn timeout(name: &str, secs: Option<f64>) -> PyResult<Option<Duration>> {
+    secs.map(Duration::try_from_secs_f64)
+        .transpose()
+        .map_err(|e| PyValueError::new_err(format!("Invalid {}: {}", name, e)))
+}
@@ -1,13 +1,24 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use crate::stream::{Stream, StreamState};
 use crate::task::PyInteropTask;
+use crate::util::event_queue_unavailable;
 
 use anyhow::Result;
 
+use mitmproxy::network::capture::{Capture, CaptureTap};
+use mitmproxy::network::shaping::ShapingConf;
+use mitmproxy::network::NetworkConf;
 use mitmproxy::packet_sources::{PacketSourceConf, PacketSourceTask};
 use mitmproxy::shutdown::shutdown_task;
+use pyo3::exceptions::{PyOSError, PyValueError};
 use pyo3::prelude::*;
 
+use mitmproxy::messages::{SmolPacket, TransportCommand, TunnelInfo};
 use mitmproxy::shutdown;
 use tokio::sync::mpsc;
+use tokio::sync::oneshot;
 use tokio::sync::watch;
 use tokio::task::JoinSet;
 
@@ -15,6 +26,9 @@ use tokio::task::JoinSet;
 pub struct Server {
     shutdown_done: shutdown::Receiver,
     start_shutdown: Option<watch::Sender<()>>,
+    transport_commands: mpsc::UnboundedSender<TransportCommand>,
+    shaping: watch::Sender<ShapingConf>,
+    capture: CaptureTap,
 }
 
 impl Server {
@@ -32,14 +46,101 @@ impl Server {
             Ok(())
         })
     }
+
+    /// Open a TCP connection or UDP flow from `src_addr` to `dst_addr` in the tunnel.
+    /// If the port of `src_addr` is zero, an ephemeral port is used.
+    pub fn open_connection<'py>(
+        &self,
+        py: Python<'py>,
+        udp: bool,
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let (tx, rx) = oneshot::channel();
+        let (closed_tx, closed_rx) = shutdown::channel();
+        self.transport_commands
+            .send(TransportCommand::OpenConnection {
+                udp,
+                src_addr,
+                dst_addr,
+                tx,
+                closed: closed_tx,
+            })
+            .map_err(event_queue_unavailable)?;
+        let command_tx = self.transport_commands.clone();
+
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let (connection_id, sockname) = rx
+                .await
+                .map_err(|_| PyOSError::new_err("Server has been shut down."))??;
+            Ok(Stream {
+                connection_id,
+                state: StreamState::Open,
+                command_tx,
+                peername: dst_addr,
+                sockname,
+                tunnel_info: TunnelInfo::None,
+                original_hostname: None,
+                client_hello: None,
+                closed: Some(closed_rx),
+            })
+        })
+    }
+
+    /// Send an IP packet into the tunnel as-is.
+    pub fn send_raw_packet(&self, data: Vec<u8>) -> PyResult<()> {
+        let packet = SmolPacket::try_from(data)
+            .map_err(|e| PyValueError::new_err(format!("Invalid IP packet: {e}")))?;
+        self.transport_commands
+            .send(TransportCommand::SendRawPacket(packet))
+            .map_err(event_queue_unavailable)
+    }
+
+    /// Replace the network conditions that are simulated for all packets.
+    pub fn set_shaping(&self, conf: ShapingConf) {
+        self.shaping.send_replace(conf);
+    }
+
+    /// Start capturing packets into a pcapng file, or into memory if no `path` is given.
+    pub fn start_capture(
+        &self,
+        path: Option<PathBuf>,
+        max_file_size: Option<u64>,
+        max_files: Option<usize>,
+        ring_size: usize,
+    ) -> PyResult<()> {
+        let capture = match path {
+            Some(path) => Capture::file(path, max_file_size, max_files)
+                .map_err(|e| PyOSError::new_err(format!("{:#}", e)))?,
+            None => Capture::ring(ring_size),
+        };
+        self.capture.start(capture);
+        Ok(())
+    }
+
+    /// Stop capturing packets. For in-memory captures, return the captured packets.
+    pub fn stop_capture(&self) -> Option<Vec<u8>> {
+        match self.capture.stop()? {
+            Capture::Ring(ring) => Some(ring.to_pcapng()),
+            Capture::File(_) => None,
+        }
+    }
+
+    /// The packets of the in-memory capture that is currently running.
+    pub fn capture_buffer(&self) -> Option<Vec<u8>> {
+        self.capture.ring_contents()
+    }
 }
 
 impl Server {
     /// Set up and initialize a new WireGuard server.
     pub async fn init<T>(
         packet_source_conf: T,
+        mut network_conf: NetworkConf,
         py_tcp_handler: PyObject,
         py_udp_handler: PyObject,
+        py_close_handler: Option<PyObject>,
+        py_raw_packet_handler: Option<PyObject>,
     ) -> Result<(Self, T::Data)>
     where
         T: PacketSourceConf,
@@ -54,21 +155,30 @@ impl Server {
         let (transport_commands_tx, transport_commands_rx) = mpsc::unbounded_channel();
         // Channel used to trigger graceful shutdown
         let (shutdown_start_tx, shutdown_start_rx) = shutdown::channel();
+        // Channel used to change the traffic shaping configuration at runtime
+        let (shaping_tx, shaping_rx) = watch::channel(ShapingConf::default());
+        network_conf.shaping = Some(shaping_rx);
+        // Packet capture that can be started and stopped at runtime
+        let capture = CaptureTap::default();
+        network_conf.capture = Some(capture.clone());
 
         let (packet_source_task, data) = packet_source_conf
             .build(
                 transport_events_tx,
                 transport_commands_rx,
                 shutdown_start_rx.clone(),
+                network_conf,
             )
             .await?;
 
         // initialize Python interop task
         let py_task = PyInteropTask::new(
-            transport_commands_tx,
+            transport_commands_tx.clone(),
             transport_events_rx,
             py_tcp_handler,
             py_udp_handler,
+            py_close_handler,
+            py_raw_packet_handler,
             shutdown_start_rx,
         )?;
 
@@ -86,6 +196,9 @@ impl Server {
             Server {
                 shutdown_done: shutdown_done_rx,
                 start_shutdown: Some(shutdown_start_tx),
+                transport_commands: transport_commands_tx,
+                shaping: shaping_tx,
+                capture,
             },
             data,
         ))
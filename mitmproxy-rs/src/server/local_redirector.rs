@@ -1,4 +1,5 @@
 use mitmproxy::intercept_conf::InterceptConf;
+use mitmproxy::network::NetworkConf;
 
 #[cfg(target_os = "linux")]
 use mitmproxy::packet_sources::linux::LinuxConf;
@@ -91,15 +92,30 @@ impl LocalRedirector {
 ///
 /// - `handle_tcp_stream`: An async function that will be called for each new TCP `Stream`.
 /// - `handle_udp_stream`: An async function that will be called for each new UDP `Stream`.
+/// - `on_close`: An optional function that will be called with a `ConnectionClosed` object
+///   whenever a stream is closed. This is not supported on macOS, where it is never called.
+/// - `tcp_accept_timeout`: If set, the TCP handshake is held back until `Stream.accept()` or
+///   `Stream.reject()` is called. Connections that are still pending after this many seconds are rejected.
+///   This is not supported on macOS, where connections are always accepted immediately.
 ///
 /// *Availability: Windows, Linux, and macOS*
 #[pyfunction]
 #[allow(unused_variables)]
+#[pyo3(signature = (handle_tcp_stream, handle_udp_stream, *, on_close=None, tcp_accept_timeout=None))]
 pub fn start_local_redirector(
     py: Python<'_>,
     handle_tcp_stream: PyObject,
     handle_udp_stream: PyObject,
+    on_close: Option<PyObject>,
+    tcp_accept_timeout: Option<f64>,
 ) -> PyResult<Bound<PyAny>> {
+    let network_conf = NetworkConf {
+        tcp_accept_timeout: crate::network_options::timeout(
+            "tcp_accept_timeout",
+            tcp_accept_timeout,
+        )?,
+        ..NetworkConf::default()
+    };
     #[cfg(windows)]
     {
         let executable_path: std::path::PathBuf = py
@@ -111,8 +127,15 @@ pub fn start_local_redirector(
         }
         let conf = WindowsConf { executable_path };
         pyo3_async_runtimes::tokio::future_into_py(py, async move {
-            let (server, conf_tx) =
-                Server::init(conf, handle_tcp_stream, handle_udp_stream).await?;
+            let (server, conf_tx) = Server::init(
+                conf,
+                network_conf,
+                handle_tcp_stream,
+                handle_udp_stream,
+                on_close,
+                None,
+            )
+            .await?;
 
             Ok(LocalRedirector::new(server, conf_tx))
         })
@@ -128,8 +151,15 @@ pub fn start_local_redirector(
         }
         let conf = LinuxConf { executable_path };
         pyo3_async_runtimes::tokio::future_into_py(py, async move {
-            let (server, conf_tx) =
-                Server::init(conf, handle_tcp_stream, handle_udp_stream).await?;
+            let (server, conf_tx) = Server::init(
+                conf,
+                network_conf,
+                handle_tcp_stream,
+                handle_udp_stream,
+                on_close,
+                None,
+            )
+            .await?;
 
             Ok(LocalRedirector::new(server, conf_tx))
         })
@@ -144,8 +174,15 @@ pub fn start_local_redirector(
                     .await
                     .map_err(|e| anyhow::anyhow!("failed to copy: {}", e))??;
             }
-            let (server, conf_tx) =
-                Server::init(conf, handle_tcp_stream, handle_udp_stream).await?;
+            let (server, conf_tx) = Server::init(
+                conf,
+                network_conf,
+                handle_tcp_stream,
+                handle_udp_stream,
+                on_close,
+                None,
+            )
+            .await?;
             Ok(LocalRedirector::new(server, conf_tx))
         })
     }
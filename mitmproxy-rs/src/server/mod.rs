@@ -7,4 +7,7 @@ mod wireguard;
 pub use local_redirector::{start_local_redirector, LocalRedirector};
 pub use tun::{create_tun_interface, TunInterface};
 pub use udp::{start_udp_server, UdpServer};
-pub use wireguard::{start_wireguard_server, WireGuardServer};
+pub use wireguard::{
+    client_address, client_config, start_wireguard_server, start_wireguard_server_from_config,
+    HandshakeStats, PeerStats, WireGuardServer,
+};
@@ -1,5 +1,11 @@
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+
+use crate::network_options::NetworkOptions;
 use crate::server::base::Server;
+use crate::util::{open_connection_addrs, shaping_conf};
 use pyo3::prelude::*;
+use pyo3::types::PyDict;
 
 #[cfg(target_os = "linux")]
 use nix::unistd;
@@ -31,6 +37,103 @@ impl TunInterface {
         self.server.wait_closed(py)
     }
 
+    /// Open a TCP connection (or a UDP flow if `udp` is set) to `host` and `port` through the
+    /// interface, returning a `Stream` once the connection has been established.
+    ///
+    /// `host` must be an IP address. By default, IPv4 connections originate from `169.254.0.2`
+    /// with an ephemeral port. For IPv6, `local_addr` needs to be specified.
+    ///
+    /// Raises:
+    ///     ConnectionRefusedError if the connection is reset,
+    ///     TimeoutError if there is no response.
+    #[pyo3(signature = (host, port, *, udp=false, local_addr=None))]
+    pub fn open_connection<'p>(
+        &self,
+        py: Python<'p>,
+        host: &str,
+        port: u16,
+        udp: bool,
+        local_addr: Option<(String, u16)>,
+    ) -> PyResult<Bound<'p, PyAny>> {
+        let (remote, local) =
+            open_connection_addrs(host, port, local_addr, Ipv4Addr::new(169, 254, 0, 2), None)?;
+        self.server.open_connection(py, udp, local, remote)
+    }
+
+    /// Send an IP packet into the tunnel as-is, e.g. one that was passed to `handle_raw_packet`.
+    ///
+    /// Raises:
+    ///     ValueError if the data is not a valid IP packet.
+    ///     OSError if the interface has been shut down.
+    pub fn send_raw_packet(&self, data: Vec<u8>) -> PyResult<()> {
+        self.server.send_raw_packet(data)
+    }
+
+    /// Simulate network conditions for the traffic of this interface, replacing any previous settings.
+    /// Call without arguments to turn shaping off.
+    ///
+    /// `uplink` applies to packets from the operating system, `downlink` to packets towards them.
+    /// Both are dicts with any of the following keys:
+    ///   - `bandwidth`: bytes per second
+    ///   - `latency`: seconds
+    ///   - `jitter`: seconds, the latency varies randomly by up to this much
+    ///   - `loss`, `reorder`, `duplicate`: probabilities between 0 and 1
+    ///
+    /// `rules` override these defaults for specific destinations or connections. Each rule is a
+    /// dict with optional `server_ip`, `server_port` and `client_addr` (a `(host, port)` tuple)
+    /// keys to match on and its own `uplink` and `downlink` dicts. The first matching rule applies.
+    /// `seed` makes the random decisions reproducible.
+    ///
+    /// Raises:
+    ///     ValueError if the settings are invalid.
+    #[pyo3(signature = (uplink=None, downlink=None, *, rules=Vec::new(), seed=None))]
+    pub fn set_shaping(
+        &self,
+        uplink: Option<Bound<PyDict>>,
+        downlink: Option<Bound<PyDict>>,
+        rules: Vec<Bound<PyDict>>,
+        seed: Option<u64>,
+    ) -> PyResult<()> {
+        self.server
+            .set_shaping(shaping_conf(uplink, downlink, rules, seed)?);
+        Ok(())
+    }
+
+    /// Record all packets that the network stack receives and sends in pcapng format,
+    /// replacing any capture that is currently running. Each packet has a comment with its
+    /// direction and, for inbound packets, where it came from.
+    ///
+    /// If `path` is given, packets are written to that file. With `max_file_size`, a new file
+    /// (`capture.1.pcapng`, `capture.2.pcapng`, ...) is started once a file would exceed that many
+    /// bytes, and with `max_files`, only the most recent files are kept. Without `path`, the most
+    /// recent packets are kept in memory up to `ring_size` bytes, see `capture_buffer`.
+    ///
+    /// Raises:
+    ///     OSError if the capture file cannot be created.
+    #[pyo3(signature = (path=None, *, max_file_size=None, max_files=None, ring_size=16 * 1024 * 1024))]
+    pub fn start_capture(
+        &self,
+        path: Option<PathBuf>,
+        max_file_size: Option<u64>,
+        max_files: Option<usize>,
+        ring_size: usize,
+    ) -> PyResult<()> {
+        self.server
+            .start_capture(path, max_file_size, max_files, ring_size)
+    }
+
+    /// Stop the running capture. For in-memory captures, the captured packets are returned
+    /// as the contents of a pcapng file.
+    pub fn stop_capture(&self) -> Option<Vec<u8>> {
+        self.server.stop_capture()
+    }
+
+    /// The packets of the running in-memory capture as the contents of a pcapng file,
+    /// or `None` if no in-memory capture is running.
+    pub fn capture_buffer(&self) -> Option<Vec<u8>> {
+        self.server.capture_buffer()
+    }
+
     /// Returns a `str` describing why tun mode is unavailable, or `None` if TUN mode is available.
     ///
     /// Reasons for unavailability may be an unsupported platform, or missing privileges.
@@ -57,23 +160,37 @@ impl TunInterface {
 /// - `handle_tcp_stream`: An async function that will be called for each new TCP `Stream`.
 /// - `handle_udp_stream`: An async function that will be called for each new UDP `Stream`.
 /// - `tun_name`: An optional string to specify the tunnel name. By default, tun0, ... will be used.
+/// - `on_close`: An optional function that will be called with a `ConnectionClosed` object
+///   whenever a stream is closed.
+/// - `network`: Optional `NetworkOptions` for the network stack.
 ///
 /// *Availability: Linux*
 #[pyfunction]
 #[allow(unused_variables)]
-#[pyo3(signature = (handle_tcp_stream, handle_udp_stream, tun_name=None))]
+#[pyo3(signature = (handle_tcp_stream, handle_udp_stream, tun_name=None, *, on_close=None, network=None))]
 pub fn create_tun_interface(
     py: Python<'_>,
     handle_tcp_stream: PyObject,
     handle_udp_stream: PyObject,
     tun_name: Option<String>,
+    on_close: Option<PyObject>,
+    network: Option<Py<NetworkOptions>>,
 ) -> PyResult<Bound<PyAny>> {
     #[cfg(target_os = "linux")]
     {
+        let (network_conf, handle_raw_packet) =
+            NetworkOptions::unpack(py, network.as_ref().map(Py::get));
         let conf = mitmproxy::packet_sources::tun::TunConf { tun_name };
         pyo3_async_runtimes::tokio::future_into_py(py, async move {
-            let (server, tun_name) =
-                Server::init(conf, handle_tcp_stream, handle_udp_stream).await?;
+            let (server, tun_name) = Server::init(
+                conf,
+                network_conf,
+                handle_tcp_stream,
+                handle_udp_stream,
+                on_close,
+                handle_raw_packet,
+            )
+            .await?;
             Ok(TunInterface { server, tun_name })
         })
     }
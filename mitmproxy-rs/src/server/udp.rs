@@ -1,10 +1,12 @@
 use std::net::SocketAddr;
 
+use mitmproxy::network::NetworkConf;
 use mitmproxy::packet_sources::udp::UdpConf;
 
 use pyo3::prelude::*;
 
 use crate::server::base::Server;
+use crate::util::socknames;
 
 /// A running UDP server.
 ///
@@ -15,8 +17,8 @@ use crate::server::base::Server;
 #[pyclass(module = "mitmproxy_rs.udp")]
 #[derive(Debug)]
 pub struct UdpServer {
-    /// local address of the UDP socket
-    local_addr: SocketAddr,
+    /// local addresses of the UDP socket(s)
+    local_addrs: Vec<SocketAddr>,
     server: Server,
 }
 
@@ -39,31 +41,50 @@ impl UdpServer {
     }
 
     /// Get the local socket address that the UDP server is listening on.
-    pub fn getsockname(&self) -> (String, u16) {
-        (self.local_addr.ip().to_string(), self.local_addr.port())
+    ///
+    /// If no host has been specified, the server listens on both IPv4 and IPv6.
+    /// With `all=True`, a list of all listen addresses is returned instead of the first one.
+    #[pyo3(signature = (*, all=false))]
+    pub fn getsockname(&self, py: Python<'_>, all: bool) -> PyResult<PyObject> {
+        socknames(py, &self.local_addrs, all)
     }
 
     pub fn __repr__(&self) -> String {
-        format!("UdpServer({})", self.local_addr)
+        format!("UdpServer({})", self.local_addrs[0])
     }
 }
 
 /// Start a UDP server that is configured with the given parameters:
 ///
-/// - `host`: The host address.
+/// - `host`: The host address. If empty, the server listens on both IPv4 and IPv6.
 /// - `port`: The listen port.
 /// - `handle_udp_stream`: An async function that will be called for each new UDP `Stream`.
+/// - `on_close`: An optional function that will be called with a `ConnectionClosed` object
+///   whenever a stream is closed.
 #[pyfunction]
+#[pyo3(signature = (host, port, handle_udp_stream, *, on_close=None))]
 pub fn start_udp_server(
     py: Python<'_>,
     host: String,
     port: u16,
     handle_udp_stream: PyObject,
+    on_close: Option<PyObject>,
 ) -> PyResult<Bound<PyAny>> {
     let conf = UdpConf { host, port };
     let handle_tcp_stream = py.None();
     pyo3_async_runtimes::tokio::future_into_py(py, async move {
-        let (server, local_addr) = Server::init(conf, handle_tcp_stream, handle_udp_stream).await?;
-        Ok(UdpServer { server, local_addr })
+        let (server, local_addrs) = Server::init(
+            conf,
+            NetworkConf::default(),
+            handle_tcp_stream,
+            handle_udp_stream,
+            on_close,
+            None,
+        )
+        .await?;
+        Ok(UdpServer {
+            server,
+            local_addrs,
+        })
     })
 }
@@ -1,12 +1,28 @@
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::SystemTime;
 
-use crate::util::string_to_key;
+use crate::network_options::NetworkOptions;
+use crate::util::{
+    event_queue_unavailable, open_connection_addrs, shaping_conf, socknames, string_to_key,
+};
 
-use mitmproxy::packet_sources::wireguard::WireGuardConf;
+use mitmproxy::network::NetworkConf;
+use mitmproxy::packet_sources::wireguard::{
+    EnrollmentRequest, WireGuardCommand, WireGuardConf, WireGuardHandshakeStats, WireGuardPeerConf,
+    WireGuardPeerStats,
+};
+use mitmproxy::packet_sources::wireguard_config::{self, ClientConf};
 
+use pyo3::exceptions::{PyKeyError, PyOSError, PyValueError};
 use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use pyo3_async_runtimes::TaskLocals;
 
 use boringtun::x25519::PublicKey;
+use data_encoding::BASE64;
+use tokio::sync::{mpsc, oneshot};
 
 use crate::server::base::Server;
 
@@ -19,9 +35,10 @@ use crate::server::base::Server;
 #[pyclass(module = "mitmproxy_rs.wireguard")]
 #[derive(Debug)]
 pub struct WireGuardServer {
-    /// local address of the WireGuard UDP socket
-    local_addr: SocketAddr,
+    /// local addresses of the WireGuard UDP socket(s)
+    local_addrs: Vec<SocketAddr>,
     server: Server,
+    command_tx: mpsc::UnboundedSender<WireGuardCommand>,
 }
 
 #[pymethods]
@@ -43,24 +60,355 @@ impl WireGuardServer {
     }
 
     /// Get the local socket address that the WireGuard server is listening on.
-    pub fn getsockname(&self) -> (String, u16) {
-        (self.local_addr.ip().to_string(), self.local_addr.port())
+    ///
+    /// If no host has been specified, the server listens on both IPv4 and IPv6.
+    /// With `all=True`, a list of all listen addresses is returned instead of the first one.
+    #[pyo3(signature = (*, all=false))]
+    pub fn getsockname(&self, py: Python<'_>, all: bool) -> PyResult<PyObject> {
+        socknames(py, &self.local_addrs, all)
+    }
+
+    /// Add a peer with the given public X25519 key (base64-encoded) to the running server.
+    ///
+    /// `allowed_ips` optionally restricts the tunnel addresses the peer may use (e.g. `["10.0.0.2/32"]`).
+    /// By default, the peer may use any address. `preshared_key` is an optional base64-encoded
+    /// symmetric key that the peer must also be configured with.
+    ///
+    /// Raises:
+    ///     ValueError if a key or address range is invalid, or if the peer already exists.
+    #[pyo3(signature = (public_key, allowed_ips=None, preshared_key=None))]
+    pub fn add_peer<'p>(
+        &self,
+        py: Python<'p>,
+        public_key: String,
+        allowed_ips: Option<Vec<String>>,
+        preshared_key: Option<String>,
+    ) -> PyResult<Bound<'p, PyAny>> {
+        let peer = peer_conf(public_key, allowed_ips, preshared_key)?;
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(WireGuardCommand::AddPeer(peer, tx))
+            .map_err(event_queue_unavailable)?;
+
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            rx.await
+                .map_err(|_| PyOSError::new_err("Server has been shut down."))?
+                .map_err(|e| PyValueError::new_err(e.to_string()))
+        })
+    }
+
+    /// Remove the peer with the given public X25519 key (base64-encoded) from the running server.
+    ///
+    /// All WireGuard sessions with this peer are discarded.
+    ///
+    /// Raises:
+    ///     ValueError if the key is invalid.
+    ///     KeyError if there is no such peer.
+    pub fn remove_peer<'p>(
+        &self,
+        py: Python<'p>,
+        public_key: String,
+    ) -> PyResult<Bound<'p, PyAny>> {
+        let public_key: PublicKey = string_to_key(public_key)?;
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(WireGuardCommand::RemovePeer(public_key, tx))
+            .map_err(event_queue_unavailable)?;
+
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            rx.await
+                .map_err(|_| PyOSError::new_err("Server has been shut down."))?
+                .map_err(|e| PyKeyError::new_err(e.to_string()))
+        })
+    }
+
+    /// Get the public keys (base64-encoded) of all peers that are currently configured.
+    pub fn peers<'p>(&self, py: Python<'p>) -> PyResult<Bound<'p, PyAny>> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(WireGuardCommand::ListPeers(tx))
+            .map_err(event_queue_unavailable)?;
+
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let peers = rx
+                .await
+                .map_err(|_| PyOSError::new_err("Server has been shut down."))?;
+            Ok(peers
+                .iter()
+                .map(|key| BASE64.encode(key.as_bytes()))
+                .collect::<Vec<String>>())
+        })
+    }
+
+    /// Get statistics for all peers that are currently configured, similar to `wg show`.
+    pub fn stats<'p>(&self, py: Python<'p>) -> PyResult<Bound<'p, PyAny>> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(WireGuardCommand::GetStats(tx))
+            .map_err(event_queue_unavailable)?;
+
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let stats = rx
+                .await
+                .map_err(|_| PyOSError::new_err("Server has been shut down."))?;
+            Ok(stats.into_iter().map(PeerStats).collect::<Vec<PeerStats>>())
+        })
+    }
+
+    /// Get server-wide statistics, including handshakes from unknown peers and packets that could
+    /// not be routed to any peer.
+    pub fn handshake_stats<'p>(&self, py: Python<'p>) -> PyResult<Bound<'p, PyAny>> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(WireGuardCommand::GetHandshakeStats(tx))
+            .map_err(event_queue_unavailable)?;
+
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let stats = rx
+                .await
+                .map_err(|_| PyOSError::new_err("Server has been shut down."))?;
+            Ok(HandshakeStats(stats))
+        })
+    }
+
+    /// Open a TCP connection (or a UDP flow if `udp` is set) to `host` and `port` in the tunnel,
+    /// returning a `Stream` once the connection has been established.
+    ///
+    /// `host` must be an IP address of a peer or a network routed through one of the peers.
+    /// By default, the connection originates from `10.0.0.53` or `fd00::53` with an ephemeral port,
+    /// `local_addr` can be used to pick a different source address.
+    ///
+    /// Raises:
+    ///     ConnectionRefusedError if the peer resets the connection,
+    ///     TimeoutError if the peer does not respond.
+    #[pyo3(signature = (host, port, *, udp=false, local_addr=None))]
+    pub fn open_connection<'p>(
+        &self,
+        py: Python<'p>,
+        host: &str,
+        port: u16,
+        udp: bool,
+        local_addr: Option<(String, u16)>,
+    ) -> PyResult<Bound<'p, PyAny>> {
+        let (remote, local) = open_connection_addrs(
+            host,
+            port,
+            local_addr,
+            Ipv4Addr::new(10, 0, 0, 53),
+            Some(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0x53)),
+        )?;
+        self.server.open_connection(py, udp, local, remote)
+    }
+
+    /// Send an IP packet into the tunnel as-is, e.g. one that was passed to `handle_raw_packet`.
+    /// The packet is routed to the peer whose allowed IPs match its destination.
+    ///
+    /// Raises:
+    ///     ValueError if the data is not a valid IP packet.
+    ///     OSError if the server has been shut down.
+    pub fn send_raw_packet(&self, data: Vec<u8>) -> PyResult<()> {
+        self.server.send_raw_packet(data)
+    }
+
+    /// Simulate network conditions for the traffic of this server, replacing any previous settings.
+    /// Call without arguments to turn shaping off.
+    ///
+    /// `uplink` applies to packets from the WireGuard peers, `downlink` to packets towards them.
+    /// Both are dicts with any of the following keys:
+    ///   - `bandwidth`: bytes per second
+    ///   - `latency`: seconds
+    ///   - `jitter`: seconds, the latency varies randomly by up to this much
+    ///   - `loss`, `reorder`, `duplicate`: probabilities between 0 and 1
+    ///
+    /// `rules` override these defaults for specific destinations or connections. Each rule is a
+    /// dict with optional `server_ip`, `server_port` and `client_addr` (a `(host, port)` tuple)
+    /// keys to match on and its own `uplink` and `downlink` dicts. The first matching rule applies.
+    /// `seed` makes the random decisions reproducible.
+    ///
+    /// Raises:
+    ///     ValueError if the settings are invalid.
+    #[pyo3(signature = (uplink=None, downlink=None, *, rules=Vec::new(), seed=None))]
+    pub fn set_shaping(
+        &self,
+        uplink: Option<Bound<PyDict>>,
+        downlink: Option<Bound<PyDict>>,
+        rules: Vec<Bound<PyDict>>,
+        seed: Option<u64>,
+    ) -> PyResult<()> {
+        self.server
+            .set_shaping(shaping_conf(uplink, downlink, rules, seed)?);
+        Ok(())
+    }
+
+    /// Record all packets that the network stack receives and sends in pcapng format,
+    /// replacing any capture that is currently running. Each packet has a comment with its
+    /// direction and, for inbound packets, where it came from.
+    ///
+    /// If `path` is given, packets are written to that file. With `max_file_size`, a new file
+    /// (`capture.1.pcapng`, `capture.2.pcapng`, ...) is started once a file would exceed that many
+    /// bytes, and with `max_files`, only the most recent files are kept. Without `path`, the most
+    /// recent packets are kept in memory up to `ring_size` bytes, see `capture_buffer`.
+    ///
+    /// Raises:
+    ///     OSError if the capture file cannot be created.
+    #[pyo3(signature = (path=None, *, max_file_size=None, max_files=None, ring_size=16 * 1024 * 1024))]
+    pub fn start_capture(
+        &self,
+        path: Option<PathBuf>,
+        max_file_size: Option<u64>,
+        max_files: Option<usize>,
+        ring_size: usize,
+    ) -> PyResult<()> {
+        self.server
+            .start_capture(path, max_file_size, max_files, ring_size)
+    }
+
+    /// Stop the running capture. For in-memory captures, the captured packets are returned
+    /// as the contents of a pcapng file.
+    pub fn stop_capture(&self) -> Option<Vec<u8>> {
+        self.server.stop_capture()
+    }
+
+    /// The packets of the running in-memory capture as the contents of a pcapng file,
+    /// or `None` if no in-memory capture is running.
+    pub fn capture_buffer(&self) -> Option<Vec<u8>> {
+        self.server.capture_buffer()
     }
 
     pub fn __repr__(&self) -> String {
-        format!("WireGuardServer({})", self.local_addr)
+        format!("WireGuardServer({})", self.local_addrs[0])
+    }
+}
+
+/// Statistics for an individual WireGuard peer.
+/// Byte and packet counts refer to WireGuard datagrams, including handshakes and keepalives.
+#[pyclass(module = "mitmproxy_rs.wireguard", frozen)]
+pub struct PeerStats(WireGuardPeerStats);
+
+#[pymethods]
+impl PeerStats {
+    /// The peer's public X25519 key (base64-encoded).
+    #[getter]
+    fn public_key(&self) -> String {
+        BASE64.encode(self.0.public_key.as_bytes())
+    }
+    /// The address the peer has last sent packets from, if any.
+    #[getter]
+    fn endpoint(&self) -> Option<(String, u16)> {
+        self.0
+            .endpoint
+            .map(|addr| (addr.ip().to_string(), addr.port()))
+    }
+    /// Time of the last successful handshake as a UNIX timestamp, `None` if there is no active session.
+    #[getter]
+    fn last_handshake(&self) -> Option<f64> {
+        self.0
+            .last_handshake
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs_f64())
+    }
+    #[getter]
+    fn rx_bytes(&self) -> u64 {
+        self.0.rx_bytes
+    }
+    #[getter]
+    fn rx_packets(&self) -> u64 {
+        self.0.rx_packets
+    }
+    #[getter]
+    fn tx_bytes(&self) -> u64 {
+        self.0.tx_bytes
+    }
+    #[getter]
+    fn tx_packets(&self) -> u64 {
+        self.0.tx_packets
+    }
+    /// Number of incoming datagrams that could not be decapsulated, e.g. because there was no current session.
+    #[getter]
+    fn decapsulation_errors(&self) -> u64 {
+        self.0.decapsulation_errors
+    }
+    /// Number of decrypted packets that were dropped because the network stack was not keeping up.
+    #[getter]
+    fn dropped_packets(&self) -> u64 {
+        self.0.dropped_packets
+    }
+    fn __repr__(&self) -> String {
+        format!(
+            "PeerStats(public_key={:?}, endpoint={:?}, rx_bytes={}, tx_bytes={})",
+            self.public_key(),
+            self.0.endpoint,
+            self.0.rx_bytes,
+            self.0.tx_bytes,
+        )
+    }
+}
+
+/// Handshake and routing statistics for a WireGuard server.
+/// When more than a few hundred handshakes per second arrive, the server is considered under load
+/// and replies with cookies instead of processing handshakes.
+#[pyclass(module = "mitmproxy_rs.wireguard", frozen)]
+pub struct HandshakeStats(WireGuardHandshakeStats);
+
+#[pymethods]
+impl HandshakeStats {
+    /// Number of handshake initiations that passed the rate limiter.
+    #[getter]
+    fn handshakes(&self) -> u64 {
+        self.0.handshakes
+    }
+    /// Number of handshake initiations that passed the rate limiter in the last full second.
+    #[getter]
+    fn handshakes_per_second(&self) -> u64 {
+        self.0.handshakes_per_second
+    }
+    /// Number of cookie replies that were sent because the server was under load.
+    #[getter]
+    fn cookie_replies(&self) -> u64 {
+        self.0.cookie_replies
+    }
+    /// Number of handshake messages that were dropped because of an invalid MAC.
+    #[getter]
+    fn invalid_handshakes(&self) -> u64 {
+        self.0.invalid_handshakes
+    }
+    /// Number of packets that were dropped because no peer's `allowed_ips` cover their destination.
+    #[getter]
+    fn unroutable_packets(&self) -> u64 {
+        self.0.unroutable_packets
+    }
+    fn __repr__(&self) -> String {
+        format!(
+            "HandshakeStats(handshakes={}, handshakes_per_second={}, cookie_replies={}, invalid_handshakes={}, unroutable_packets={})",
+            self.0.handshakes,
+            self.0.handshakes_per_second,
+            self.0.cookie_replies,
+            self.0.invalid_handshakes,
+            self.0.unroutable_packets,
+        )
     }
 }
 
 /// Start a WireGuard server that is configured with the given parameters:
 ///
-/// - `host`: The host address for the WireGuard UDP socket.
+/// - `host`: The host address for the WireGuard UDP socket. If empty, the server listens on both IPv4 and IPv6.
 /// - `port`: The listen port for the WireGuard server. The default port for WireGuard is `51820`.
 /// - `private_key`: The private X25519 key for the WireGuard server as a base64-encoded string.
 /// - `peer_public_keys`: List of public X25519 keys for WireGuard peers as base64-encoded strings.
 /// - `handle_tcp_stream`: An async function that will be called for each new TCP `Stream`.
 /// - `handle_udp_stream`: An async function that will be called for each new UDP `Stream`.
+/// - `allowed_ips`: Optional mapping from peer public keys to the address ranges they may use.
+///   Peers without an entry may use any address.
+/// - `preshared_keys`: Optional mapping from peer public keys to base64-encoded preshared keys.
+/// - `enrollment_handler`: Optional async function that is called with the public key and source
+///   address of unknown peers that attempt a handshake. If it returns `True`, the peer is added
+///   with unrestricted allowed IPs. Otherwise, the peer is rejected.
+/// - `on_close`: An optional function that will be called with a `ConnectionClosed` object
+///   whenever a stream is closed.
+/// - `network`: Optional `NetworkOptions` for the network stack.
 #[pyfunction]
+#[pyo3(signature = (host, port, private_key, peer_public_keys, handle_tcp_stream, handle_udp_stream, *, allowed_ips=None, preshared_keys=None, enrollment_handler=None, on_close=None, network=None))]
+#[allow(clippy::too_many_arguments)]
 pub fn start_wireguard_server(
     py: Python<'_>,
     host: String,
@@ -69,20 +417,234 @@ pub fn start_wireguard_server(
     peer_public_keys: Vec<String>,
     handle_tcp_stream: PyObject,
     handle_udp_stream: PyObject,
+    allowed_ips: Option<HashMap<String, Vec<String>>>,
+    preshared_keys: Option<HashMap<String, String>>,
+    enrollment_handler: Option<PyObject>,
+    on_close: Option<PyObject>,
+    network: Option<Py<NetworkOptions>>,
 ) -> PyResult<Bound<PyAny>> {
+    let (network_conf, handle_raw_packet) =
+        NetworkOptions::unpack(py, network.as_ref().map(Py::get));
     let private_key = string_to_key(private_key)?;
-    let peer_public_keys = peer_public_keys
+    let mut allowed_ips = allowed_ips.unwrap_or_default();
+    let mut preshared_keys = preshared_keys.unwrap_or_default();
+    let peers = peer_public_keys
         .into_iter()
-        .map(string_to_key)
-        .collect::<PyResult<Vec<PublicKey>>>()?;
+        .map(|key| {
+            let ips = allowed_ips.remove(&key);
+            let psk = preshared_keys.remove(&key);
+            peer_conf(key, ips, psk)
+        })
+        .collect::<PyResult<Vec<WireGuardPeerConf>>>()?;
+    if let Some(key) = allowed_ips.keys().chain(preshared_keys.keys()).next() {
+        return Err(PyValueError::new_err(format!(
+            "Peer configuration specified for unknown peer: {}",
+            key
+        )));
+    }
     let conf = WireGuardConf {
         host,
         port,
         private_key,
-        peer_public_keys,
+        peers,
+        persistent_keepalive: Some(25),
+        enrollment: None,
     };
+    start(
+        py,
+        conf,
+        network_conf,
+        handle_tcp_stream,
+        handle_udp_stream,
+        enrollment_handler,
+        on_close,
+        handle_raw_packet,
+    )
+}
+
+/// Start a WireGuard server that is configured with a `wg setconf`/wg-quick style configuration file:
+///
+/// - `host`: The host address for the WireGuard UDP socket. If empty, the server listens on both IPv4 and IPv6.
+/// - `config`: The configuration file contents. The listen port, peers, preshared keys, allowed IPs,
+///   and keepalive interval are taken from here. wg-quick specific keys such as `Address` are ignored.
+/// - `handle_tcp_stream`: An async function that will be called for each new TCP `Stream`.
+/// - `handle_udp_stream`: An async function that will be called for each new UDP `Stream`.
+/// - `enrollment_handler`: Optional async function to approve unknown peers, see `start_wireguard_server`.
+/// - `on_close`: Optional function that is called when a stream is closed, see `start_wireguard_server`.
+/// - `network`: Optional `NetworkOptions` for the network stack.
+#[pyfunction]
+#[pyo3(signature = (host, config, handle_tcp_stream, handle_udp_stream, *, enrollment_handler=None, on_close=None, network=None))]
+#[allow(clippy::too_many_arguments)]
+pub fn start_wireguard_server_from_config(
+    py: Python<'_>,
+    host: String,
+    config: String,
+    handle_tcp_stream: PyObject,
+    handle_udp_stream: PyObject,
+    enrollment_handler: Option<PyObject>,
+    on_close: Option<PyObject>,
+    network: Option<Py<NetworkOptions>>,
+) -> PyResult<Bound<PyAny>> {
+    let (network_conf, handle_raw_packet) =
+        NetworkOptions::unpack(py, network.as_ref().map(Py::get));
+    let conf = WireGuardConf::from_config(host, &config)
+        .map_err(|e| PyValueError::new_err(format!("{:#}", e)))?;
+    start(
+        py,
+        conf,
+        network_conf,
+        handle_tcp_stream,
+        handle_udp_stream,
+        enrollment_handler,
+        on_close,
+        handle_raw_packet,
+    )
+}
+
+/// Generate a wg-quick configuration for a client that routes all traffic through our server:
+///
+/// - `private_key`: The private X25519 key for the client as a base64-encoded string.
+/// - `server_public_key`: The public X25519 key of the server as a base64-encoded string.
+/// - `endpoint`: The server address as `host:port`.
+/// - `client_index`: Determines the tunnel addresses of the client, see `client_address`.
+/// - `preshared_key`: Optional base64-encoded preshared key.
+/// - `dns`: DNS servers for the client, `10.0.0.53` by default.
+#[pyfunction]
+#[pyo3(signature = (private_key, server_public_key, endpoint, client_index, *, preshared_key=None, dns=None))]
+pub fn client_config(
+    private_key: String,
+    server_public_key: String,
+    endpoint: String,
+    client_index: u32,
+    preshared_key: Option<String>,
+    dns: Option<Vec<String>>,
+) -> PyResult<String> {
+    let mut conf = ClientConf::new(
+        string_to_key(private_key)?,
+        string_to_key(server_public_key)?,
+        endpoint,
+        client_index,
+    )
+    .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    conf.preshared_key = preshared_key.map(string_to_key).transpose()?;
+    if let Some(dns) = dns {
+        conf.dns = dns
+            .iter()
+            .map(|ip| {
+                ip.parse()
+                    .map_err(|_| PyValueError::new_err(format!("Invalid DNS server: {}", ip)))
+            })
+            .collect::<PyResult<_>>()?;
+    }
+    Ok(conf.to_config())
+}
+
+/// Get the tunnel addresses that `client_config` assigns to the client with the given index,
+/// e.g. to pass them as `allowed_ips` to the server.
+#[pyfunction]
+pub fn client_address(client_index: u32) -> PyResult<Vec<String>> {
+    Ok(wireguard_config::client_address(client_index)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?
+        .iter()
+        .map(|cidr| cidr.to_string())
+        .collect())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn start(
+    py: Python<'_>,
+    mut conf: WireGuardConf,
+    network_conf: NetworkConf,
+    handle_tcp_stream: PyObject,
+    handle_udp_stream: PyObject,
+    enrollment_handler: Option<PyObject>,
+    on_close: Option<PyObject>,
+    handle_raw_packet: Option<PyObject>,
+) -> PyResult<Bound<PyAny>> {
+    let enrollment_rx = enrollment_handler.as_ref().map(|_| {
+        let (tx, rx) = mpsc::channel(16);
+        conf.enrollment = Some(tx);
+        rx
+    });
     pyo3_async_runtimes::tokio::future_into_py(py, async move {
-        let (server, local_addr) = Server::init(conf, handle_tcp_stream, handle_udp_stream).await?;
-        Ok(WireGuardServer { server, local_addr })
+        let (server, (local_addrs, command_tx)) = Server::init(
+            conf,
+            network_conf,
+            handle_tcp_stream,
+            handle_udp_stream,
+            on_close,
+            handle_raw_packet,
+        )
+        .await?;
+        if let (Some(handler), Some(rx)) = (enrollment_handler, enrollment_rx) {
+            // Note: The current asyncio event loop needs to be determined here on the main thread.
+            let locals = Python::with_gil(|py| -> PyResult<TaskLocals> {
+                let py_loop = pyo3_async_runtimes::tokio::get_current_loop(py)?;
+                TaskLocals::new(py_loop).copy_context(py)
+            })?;
+            tokio::spawn(handle_enrollments(handler, locals, rx, command_tx.clone()));
+        }
+        Ok(WireGuardServer {
+            server,
+            local_addrs,
+            command_tx,
+        })
     })
 }
+
+/// Pass enrollment requests to the Python handler until the WireGuard task shuts down.
+async fn handle_enrollments(
+    handler: PyObject,
+    locals: TaskLocals,
+    mut enrollment_rx: mpsc::Receiver<EnrollmentRequest>,
+    command_tx: mpsc::UnboundedSender<WireGuardCommand>,
+) {
+    while let Some(request) = enrollment_rx.recv().await {
+        let public_key = BASE64.encode(request.public_key.as_bytes());
+        let src_addr = (request.src_addr.ip().to_string(), request.src_addr.port());
+        let result = async {
+            let future = Python::with_gil(|py| {
+                let coro = handler.call1(py, (public_key.clone(), src_addr))?;
+                pyo3_async_runtimes::into_future_with_locals(&locals, coro.into_bound(py))
+            })?;
+            let accepted = future.await?;
+            Python::with_gil(|py| accepted.extract::<bool>(py))
+        }
+        .await;
+        let (tx, rx) = oneshot::channel();
+        let command = match result {
+            Ok(true) => WireGuardCommand::AddPeer(request.public_key.into(), tx),
+            Ok(false) => WireGuardCommand::RejectEnrollment(request.public_key),
+            Err(err) => {
+                log::error!(
+                    "WireGuard enrollment handler raised an exception for peer {}:\n{}",
+                    public_key,
+                    err
+                );
+                WireGuardCommand::RejectEnrollment(request.public_key)
+            }
+        };
+        if command_tx.send(command).is_err() {
+            break;
+        }
+        // rejections do not get a reply.
+        if let Ok(Err(e)) = rx.await {
+            log::error!("Failed to add WireGuard peer {}: {}", public_key, e);
+        }
+    }
+}
+
+fn peer_conf(
+    public_key: String,
+    allowed_ips: Option<Vec<String>>,
+    preshared_key: Option<String>,
+) -> PyResult<WireGuardPeerConf> {
+    let public_key: PublicKey = string_to_key(public_key)?;
+    let mut conf = match allowed_ips {
+        Some(ips) => WireGuardPeerConf::with_allowed_ips(public_key, ips)
+            .map_err(|e| PyValueError::new_err(e.to_string()))?,
+        None => WireGuardPeerConf::from(public_key),
+    };
+    conf.preshared_key = preshared_key.map(string_to_key).transpose()?;
+    Ok(conf)
+}
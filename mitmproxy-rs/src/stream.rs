@@ -1,6 +1,9 @@
 use std::net::SocketAddr;
+use std::time::Duration;
 
-use pyo3::exceptions::PyKeyError;
+use data_encoding::BASE64;
+use pyo3::exceptions::{PyKeyError, PyValueError};
+use pyo3::types::PyBytes;
 use pyo3::{exceptions::PyOSError, intern, prelude::*, IntoPyObjectExt};
 
 use tokio::sync::{
@@ -8,7 +11,11 @@ use tokio::sync::{
     oneshot::{self},
 };
 
-use mitmproxy::messages::{ConnectionId, TransportCommand, TunnelInfo};
+use mitmproxy::messages::{
+    CloseReason, ConnectionId, Rejection, TransportCommand, TunnelInfo, UnreachableCode,
+};
+use mitmproxy::network::ClientHello;
+use mitmproxy::shutdown;
 
 use crate::util::{event_queue_unavailable, socketaddr_to_py};
 
@@ -31,6 +38,13 @@ pub struct Stream {
     pub peername: SocketAddr,
     pub sockname: SocketAddr,
     pub tunnel_info: TunnelInfo,
+    /// The hostname the client resolved via our fake DNS responder to reach `sockname`, if any.
+    pub original_hostname: Option<String>,
+    /// The TLS ClientHello or QUIC Initial that the client started the connection with, if any.
+    pub client_hello: Option<ClientHello>,
+    /// Fires once the connection has been closed. If this is `None`, the connection is closed
+    /// once the receiving end of `command_tx` has been dropped.
+    pub closed: Option<shutdown::Receiver>,
 }
 
 #[pymethods]
@@ -127,6 +141,102 @@ impl Stream {
         }
     }
 
+    /// Close the stream immediately, discarding any buffered data.
+    /// For TCP streams, this sends a RST to the peer. For UDP streams, this is the same as `Stream.close`.
+    ///
+    /// Raises:
+    ///     OSError if the server has been shut down.
+    fn abort(&mut self) -> PyResult<()> {
+        self.state = StreamState::Closed;
+        self.command_tx
+            .send(TransportCommand::AbortConnection(self.connection_id))
+            .map_err(event_queue_unavailable)
+    }
+
+    /// Complete the TCP handshake for a connection whose accept has been deferred
+    /// (see the `tcp_accept_timeout` server argument). Reading from or writing to the stream
+    /// accepts the connection implicitly. This method is a no-op otherwise.
+    ///
+    /// Raises:
+    ///     OSError if the server has been shut down.
+    fn accept(&self) -> PyResult<()> {
+        self.command_tx
+            .send(TransportCommand::AcceptConnection(self.connection_id))
+            .map_err(event_queue_unavailable)
+    }
+
+    /// Refuse a TCP connection whose accept has been deferred (see the `tcp_accept_timeout`
+    /// server argument), or close a UDP stream and tell the client why.
+    ///
+    /// `code` is one of `"reset"`, `"net-unreachable"`, `"host-unreachable"`, `"port-unreachable"`,
+    /// `"admin-prohibited"` or `"ttl-exceeded"`. TCP clients receive a RST for `"reset"`,
+    /// UDP streams are closed silently. For all other codes, the client receives the respective
+    /// ICMP(v6) error. TCP connections that have already been accepted are aborted instead.
+    ///
+    /// Raises:
+    ///     ValueError if the code is unknown.
+    ///     OSError if the server has been shut down.
+    #[pyo3(signature = (code="reset"))]
+    fn reject(&mut self, code: &str) -> PyResult<()> {
+        let rejection = match code {
+            "reset" => Rejection::Reset,
+            "net-unreachable" => Rejection::Unreachable(UnreachableCode::Network),
+            "host-unreachable" => Rejection::Unreachable(UnreachableCode::Host),
+            "port-unreachable" => Rejection::Unreachable(UnreachableCode::Port),
+            "admin-prohibited" => Rejection::Unreachable(UnreachableCode::Prohibited),
+            "ttl-exceeded" => Rejection::TimeExceeded,
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "Unknown rejection code: {code}"
+                )))
+            }
+        };
+        self.state = StreamState::Closed;
+        self.command_tx
+            .send(TransportCommand::RejectConnection(
+                self.connection_id,
+                rejection,
+            ))
+            .map_err(event_queue_unavailable)
+    }
+
+    /// Hand the stream over to Rust, which connects to `host:port` and relays data in both
+    /// directions until both sides have closed their end. Python cannot use the stream afterwards.
+    /// UDP streams are relayed until the flow ends.
+    ///
+    /// Returns a `(bytes_from_client, bytes_from_server)` tuple once the relay has finished.
+    ///
+    /// Raises:
+    ///     OSError if the stream is closing, the upstream connection fails (the stream is aborted
+    ///     in that case), or the server has been shut down.
+    fn splice_to<'py>(
+        &mut self,
+        py: Python<'py>,
+        host: String,
+        port: u16,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        if !matches!(self.state, StreamState::Open) {
+            return Err(PyOSError::new_err("connection closed"));
+        }
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(TransportCommand::SpliceConnection {
+                connection_id: self.connection_id,
+                host,
+                port,
+                tx,
+            })
+            .map_err(event_queue_unavailable)?;
+        self.state = StreamState::Closed;
+
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let stats = rx
+                .await
+                .map_err(|_| PyOSError::new_err("Server has been shut down."))??;
+            Ok((stats.bytes_from_client, stats.bytes_from_server))
+        })
+    }
+
     /// Check whether this stream is being closed.
     fn is_closing(&self) -> bool {
         match self.state {
@@ -135,16 +245,27 @@ impl Stream {
         }
     }
 
-    /// Wait until the stream is closed (currently a no-op).
+    /// Wait until the underlying connection is closed, either by us or by the peer,
+    /// or until the server has been shut down.
     fn wait_closed<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
-        pyo3_async_runtimes::tokio::future_into_py(py, std::future::ready(Ok(())))
+        let closed = self.closed.clone();
+        let command_tx = self.command_tx.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            match closed {
+                Some(mut closed) => closed.recv().await,
+                None => command_tx.closed().await,
+            }
+            Ok(())
+        })
     }
 
     /// Query the stream for details of the underlying network connection.
     ///
     /// Supported values:
     ///   - Always available: `transport_protocol`, `peername`, `sockname`
-    ///   - WireGuard mode: `original_dst`, `original_src`
+    ///   - With `fake_dns_servers`: `original_hostname`
+    ///   - Connections that start with a TLS ClientHello or QUIC Initial: `sni`, `alpn`, `tls_versions`
+    ///   - WireGuard mode: `original_dst`, `original_src`, `wireguard_peer` (public key and peer index)
     ///   - Local redirector mode: `pid`, `process_name`, `remote_endpoint`
     #[pyo3(signature = (name, default=None))]
     fn get_extra_info(
@@ -164,12 +285,45 @@ impl Stream {
             }
             "peername" => return socketaddr_to_py(py, self.peername),
             "sockname" => return socketaddr_to_py(py, self.sockname),
+            "original_hostname" => {
+                if let Some(hostname) = &self.original_hostname {
+                    return hostname.into_py_any(py);
+                }
+            }
+            "sni" => {
+                if let Some(sni) = self.client_hello.as_ref().and_then(|c| c.sni.as_ref()) {
+                    return sni.into_py_any(py);
+                }
+            }
+            "alpn" => {
+                if let Some(client_hello) = &self.client_hello {
+                    return client_hello
+                        .alpn_protocols
+                        .iter()
+                        .map(|protocol| PyBytes::new(py, protocol))
+                        .collect::<Vec<_>>()
+                        .into_py_any(py);
+                }
+            }
+            "tls_versions" => {
+                if let Some(client_hello) = &self.client_hello {
+                    return client_hello.tls_versions.clone().into_py_any(py);
+                }
+            }
             _ => (),
         }
         match &self.tunnel_info {
-            TunnelInfo::WireGuard { src_addr, dst_addr } => match name.as_str() {
+            TunnelInfo::WireGuard {
+                src_addr,
+                dst_addr,
+                peer_public_key,
+                peer_index,
+            } => match name.as_str() {
                 "original_src" => return socketaddr_to_py(py, *src_addr),
                 "original_dst" => return socketaddr_to_py(py, *dst_addr),
+                "wireguard_peer" => {
+                    return (BASE64.encode(peer_public_key.as_bytes()), *peer_index).into_py_any(py)
+                }
                 _ => (),
             },
             TunnelInfo::LocalRedirector {
@@ -204,6 +358,72 @@ impl Stream {
 
 impl Drop for Stream {
     fn drop(&mut self) {
+        // a full close: written data is still flushed, but the client has to close its end soon.
         self.close().ok();
     }
 }
+
+/// Details about a connection that has been closed, see the `on_close` argument of the server functions.
+#[pyclass(module = "mitmproxy_rs", frozen)]
+#[derive(Debug)]
+pub struct ConnectionClosed {
+    pub connection_id: ConnectionId,
+    pub peername: SocketAddr,
+    pub sockname: SocketAddr,
+    pub reason: CloseReason,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub duration: Duration,
+}
+
+#[pymethods]
+impl ConnectionClosed {
+    /// `"tcp"` or `"udp"`.
+    #[getter]
+    fn transport_protocol(&self) -> &'static str {
+        if self.connection_id.is_tcp() {
+            "tcp"
+        } else {
+            "udp"
+        }
+    }
+    #[getter]
+    fn peername(&self, py: Python) -> PyResult<PyObject> {
+        socketaddr_to_py(py, self.peername)
+    }
+    #[getter]
+    fn sockname(&self, py: Python) -> PyResult<PyObject> {
+        socketaddr_to_py(py, self.sockname)
+    }
+    /// One of `"peer_closed"`, `"reset"`, `"timeout"`, `"local_close"`, or `"shutdown"`.
+    #[getter]
+    fn reason(&self) -> &'static str {
+        self.reason.as_str()
+    }
+    /// Payload bytes received from the peer.
+    #[getter]
+    fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+    /// Payload bytes sent to the peer.
+    #[getter]
+    fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+    /// How long the connection was open, in seconds.
+    #[getter]
+    fn duration(&self) -> f64 {
+        self.duration.as_secs_f64()
+    }
+    fn __repr__(&self) -> String {
+        format!(
+            "ConnectionClosed({}, peer={}, sock={}, reason={}, bytes_received={}, bytes_sent={})",
+            self.connection_id,
+            self.peername,
+            self.sockname,
+            self.reason.as_str(),
+            self.bytes_received,
+            self.bytes_sent,
+        )
+    }
+}
@@ -1,15 +1,17 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Context, Result};
 use pyo3::exceptions::asyncio::CancelledError;
 use pyo3::prelude::*;
+use pyo3::types::PyBytes;
 use pyo3_async_runtimes::TaskLocals;
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{mpsc, watch, Mutex};
 
-use crate::stream::Stream;
 use crate::stream::StreamState;
-use mitmproxy::messages::{TransportCommand, TransportEvent};
+use crate::stream::{ConnectionClosed, Stream};
+use mitmproxy::messages::{ConnectionId, SmolPacket, TransportCommand, TransportEvent};
 use mitmproxy::shutdown;
 
 pub struct PyInteropTask {
@@ -18,9 +20,17 @@ pub struct PyInteropTask {
     transport_events: mpsc::Receiver<TransportEvent>,
     py_tcp_handler: PyObject,
     py_udp_handler: PyObject,
+    py_close_handler: Option<PyObject>,
+    py_raw_packet_handler: Option<PyObject>,
     shutdown: shutdown::Receiver,
+    /// Streams whose closure is reported by the network task.
+    /// Outbound streams are notified by the network task directly and are not tracked here.
+    open_streams: HashMap<ConnectionId, watch::Sender<()>>,
 }
 
+/// How long we wait for the final `ConnectionClosed` events during shutdown.
+const SHUTDOWN_EVENT_TIMEOUT: Duration = Duration::from_millis(100);
+
 impl PyInteropTask {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
@@ -28,6 +38,8 @@ impl PyInteropTask {
         transport_events: mpsc::Receiver<TransportEvent>,
         py_tcp_handler: PyObject,
         py_udp_handler: PyObject,
+        py_close_handler: Option<PyObject>,
+        py_raw_packet_handler: Option<PyObject>,
         shutdown: shutdown::Receiver,
     ) -> Result<Self> {
         // Note: The current asyncio event loop needs to be determined here on the main thread.
@@ -43,7 +55,10 @@ impl PyInteropTask {
             transport_events,
             py_tcp_handler,
             py_udp_handler,
+            py_close_handler,
+            py_raw_packet_handler,
             shutdown,
+            open_streams: HashMap::new(),
         })
     }
 
@@ -66,8 +81,19 @@ impl PyInteropTask {
                             src_addr,
                             dst_addr,
                             tunnel_info,
+                            original_hostname,
+                            client_hello,
                             command_tx,
                         } => {
+                            // Streams with their own command channel are not managed by our network task,
+                            // so we won't receive a ConnectionClosed event for them.
+                            let closed = if command_tx.is_none() {
+                                let (closed_tx, closed_rx) = shutdown::channel();
+                                self.open_streams.insert(connection_id, closed_tx);
+                                Some(closed_rx)
+                            } else {
+                                None
+                            };
                             let command_tx = command_tx.unwrap_or_else(|| self.transport_commands.clone());
                             // initialize new stream
                             let stream = Stream {
@@ -77,6 +103,9 @@ impl PyInteropTask {
                                 peername: src_addr,
                                 sockname: dst_addr,
                                 tunnel_info,
+                                original_hostname,
+                                client_hello,
+                                closed,
                             };
 
                             let mut conns = active_streams.lock().await;
@@ -115,6 +144,12 @@ impl PyInteropTask {
                                 log::error!("Failed to spawn connection handler:\n{}", err);
                             };
                         },
+                        closed @ TransportEvent::ConnectionClosed { .. } => {
+                            self.handle_closed(closed);
+                        },
+                        TransportEvent::RawPacket { packet, .. } => {
+                            self.handle_raw_packet(packet);
+                        },
                     }
                 }
             };
@@ -122,6 +157,19 @@ impl PyInteropTask {
 
         log::debug!("Python interoperability task shutting down.");
 
+        // The network task reports all remaining connections as closed when shutting down.
+        while !self.open_streams.is_empty() {
+            match tokio::time::timeout(SHUTDOWN_EVENT_TIMEOUT, self.transport_events.recv()).await {
+                Ok(Some(event @ TransportEvent::ConnectionClosed { .. })) => {
+                    self.handle_closed(event)
+                }
+                Ok(Some(_)) => {}
+                Ok(None) | Err(_) => break,
+            }
+        }
+        // Dropping the remaining senders resolves all pending `Stream.wait_closed()` calls.
+        self.open_streams.clear();
+
         while let Some((_, handle)) = active_streams.lock().await.drain().next() {
             if handle.is_finished() {
                 // Future is already finished: just await;
@@ -147,4 +195,50 @@ impl PyInteropTask {
 
         Ok(())
     }
+
+    fn handle_closed(&mut self, event: TransportEvent) {
+        let TransportEvent::ConnectionClosed {
+            connection_id,
+            src_addr,
+            dst_addr,
+            reason,
+            bytes_received,
+            bytes_sent,
+            duration,
+        } = event
+        else {
+            return;
+        };
+        if let Some(closed) = self.open_streams.remove(&connection_id) {
+            closed.send(()).ok();
+        }
+
+        let Some(py_close_handler) = &self.py_close_handler else {
+            return;
+        };
+        let closed = ConnectionClosed {
+            connection_id,
+            peername: src_addr,
+            sockname: dst_addr,
+            reason,
+            bytes_received,
+            bytes_sent,
+            duration,
+        };
+        if let Err(err) = Python::with_gil(|py| py_close_handler.call1(py, (closed,))) {
+            log::error!("Connection close handler raised an exception:\n{}", err);
+        }
+    }
+
+    fn handle_raw_packet(&self, packet: SmolPacket) {
+        let Some(py_raw_packet_handler) = &self.py_raw_packet_handler else {
+            return;
+        };
+        let data = packet.into_inner();
+        if let Err(err) =
+            Python::with_gil(|py| py_raw_packet_handler.call1(py, (PyBytes::new(py, &data),)))
+        {
+            log::error!("Raw packet handler raised an exception:\n{}", err);
+        }
+    }
 }
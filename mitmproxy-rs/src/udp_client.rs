@@ -51,6 +51,9 @@ pub fn open_udp_connection(
             peername,
             sockname,
             tunnel_info: TunnelInfo::None,
+            original_hostname: None,
+            client_hello: None,
+            closed: None,
         };
 
         Ok(stream)
@@ -150,6 +153,17 @@ impl UdpClientTask {
                                 break;
                             }
                         },
+                        TransportCommand::AbortConnection(_) | TransportCommand::RejectConnection(_, _) => {
+                            break;
+                        },
+                        TransportCommand::AcceptConnection(_) => (),
+                        TransportCommand::OpenConnection { tx, .. } => {
+                            tx.send(Err(std::io::ErrorKind::Unsupported.into())).ok();
+                        },
+                        TransportCommand::SendRawPacket(_) => (),
+                        TransportCommand::SpliceConnection { tx, .. } => {
+                            tx.send(Err(std::io::ErrorKind::Unsupported.into())).ok();
+                        },
                     }
                 }
             }
@@ -3,12 +3,15 @@ use anyhow::{anyhow, Result};
 use data_encoding::BASE64;
 #[cfg(target_os = "macos")]
 use mitmproxy::certificates;
+use mitmproxy::network::shaping::{LinkConditions, ShapingConf, ShapingProfile, ShapingRule};
 
 use pyo3::exceptions::PyOSError;
+use pyo3::types::PyDict;
 use pyo3::{exceptions::PyValueError, prelude::*, IntoPyObjectExt};
 use rand_core::OsRng;
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
 
 use boringtun::x25519::{PublicKey, StaticSecret};
 use tokio::sync::mpsc;
@@ -29,6 +32,150 @@ pub fn socketaddr_to_py(py: Python, s: SocketAddr) -> PyResult<PyObject> {
     (s.ip().to_string(), s.port()).into_py_any(py)
 }
 
+/// The first of a server's listen addresses, or a list of all of them if `all` is set.
+pub fn socknames(py: Python, addrs: &[SocketAddr], all: bool) -> PyResult<PyObject> {
+    if all {
+        addrs
+            .iter()
+            .map(|addr| (addr.ip().to_string(), addr.port()))
+            .collect::<Vec<_>>()
+            .into_py_any(py)
+    } else {
+        socketaddr_to_py(py, addrs[0])
+    }
+}
+
+/// Build the traffic shaping configuration from the arguments of `set_shaping`.
+pub fn shaping_conf(
+    uplink: Option<Bound<PyDict>>,
+    downlink: Option<Bound<PyDict>>,
+    rules: Vec<Bound<PyDict>>,
+    seed: Option<u64>,
+) -> PyResult<ShapingConf> {
+    let rules = rules
+        .iter()
+        .map(|rule| {
+            let mut uplink = None;
+            let mut downlink = None;
+            let mut rule_conf = ShapingRule::default();
+            for (key, value) in rule.iter() {
+                match key.extract::<String>()?.as_str() {
+                    "server_ip" => {
+                        let ip = value.extract::<String>()?;
+                        rule_conf.server_ip = Some(ip.parse().map_err(|_| {
+                            PyValueError::new_err(format!("Not an IP address: {}", ip))
+                        })?);
+                    }
+                    "server_port" => rule_conf.server_port = Some(value.extract()?),
+                    "client_addr" => {
+                        let (host, port) = value.extract::<(String, u16)>()?;
+                        let ip = host.parse::<IpAddr>().map_err(|_| {
+                            PyValueError::new_err(format!("Not an IP address: {}", host))
+                        })?;
+                        rule_conf.client_addr = Some(SocketAddr::new(ip, port));
+                    }
+                    "uplink" => uplink = Some(value.downcast_into::<PyDict>()?),
+                    "downlink" => downlink = Some(value.downcast_into::<PyDict>()?),
+                    key => {
+                        return Err(PyValueError::new_err(format!(
+                            "Unknown shaping rule key: {key}"
+                        )))
+                    }
+                }
+            }
+            rule_conf.profile = ShapingProfile {
+                uplink: link_conditions(uplink)?,
+                downlink: link_conditions(downlink)?,
+            };
+            Ok(rule_conf)
+        })
+        .collect::<PyResult<_>>()?;
+    Ok(ShapingConf {
+        default: ShapingProfile {
+            uplink: link_conditions(uplink)?,
+            downlink: link_conditions(downlink)?,
+        },
+        rules,
+        seed,
+    })
+}
+
+fn link_conditions(conditions: Option<Bound<PyDict>>) -> PyResult<LinkConditions> {
+    let mut link = LinkConditions::default();
+    let Some(conditions) = conditions else {
+        return Ok(link);
+    };
+    let duration = |key: &str, value: Bound<PyAny>| {
+        Duration::try_from_secs_f64(value.extract()?)
+            .map_err(|e| PyValueError::new_err(format!("Invalid {key}: {e}")))
+    };
+    let probability = |key: &str, value: Bound<PyAny>| {
+        let p: f64 = value.extract()?;
+        if !(0.0..=1.0).contains(&p) {
+            return Err(PyValueError::new_err(format!(
+                "Invalid {key}: {p} (must be between 0 and 1)"
+            )));
+        }
+        Ok(p)
+    };
+    for (key, value) in conditions.iter() {
+        let key = key.extract::<String>()?;
+        match key.as_str() {
+            "bandwidth" => link.bandwidth = Some(value.extract()?),
+            "latency" => link.latency = duration(&key, value)?,
+            "jitter" => link.jitter = duration(&key, value)?,
+            "loss" => link.loss = probability(&key, value)?,
+            "reorder" => link.reorder = probability(&key, value)?,
+            "duplicate" => link.duplicate = probability(&key, value)?,
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "Unknown network condition: {key}"
+                )))
+            }
+        }
+    }
+    Ok(link)
+}
+
+/// Determine the remote and local address for `open_connection`.
+///
+/// `host` must be an IP address. If no `local_addr` is given, the default address
+/// for the respective IP version is used with an ephemeral port.
+pub fn open_connection_addrs(
+    host: &str,
+    port: u16,
+    local_addr: Option<(String, u16)>,
+    default_v4: Ipv4Addr,
+    default_v6: Option<Ipv6Addr>,
+) -> PyResult<(SocketAddr, SocketAddr)> {
+    let remote_ip: IpAddr = host
+        .parse()
+        .map_err(|_| PyValueError::new_err(format!("Not an IP address: {}", host)))?;
+    let local = match local_addr {
+        Some((local_host, local_port)) => {
+            let local_ip: IpAddr = local_host
+                .parse()
+                .map_err(|_| PyValueError::new_err(format!("Not an IP address: {}", local_host)))?;
+            SocketAddr::new(local_ip, local_port)
+        }
+        None => {
+            let local_ip = match remote_ip {
+                IpAddr::V4(_) => IpAddr::V4(default_v4),
+                IpAddr::V6(_) => IpAddr::V6(default_v6.ok_or_else(|| {
+                    PyValueError::new_err("local_addr is required for IPv6 connections.")
+                })?),
+            };
+            SocketAddr::new(local_ip, 0)
+        }
+    };
+    if local.is_ipv4() != remote_ip.is_ipv4() {
+        return Err(PyValueError::new_err(
+            "local_addr and host must have the same IP version.",
+        ));
+    }
+    Ok((SocketAddr::new(remote_ip, port), local))
+}
+
 pub fn event_queue_unavailable<T>(_: mpsc::error::SendError<T>) -> PyErr {
     PyOSError::new_err("Server has been shut down.")
 }
@@ -14,6 +14,7 @@ pub use hickory_resolver::error::ResolveErrorKind;
 pub use hickory_resolver::error::ResolveResult;
 pub use hickory_resolver::proto::op::Query;
 pub use hickory_resolver::proto::op::ResponseCode;
+pub use hickory_resolver::proto::rr::{Name, Record, RecordType};
 
 pub static DNS_SERVERS: Lazy<ResolveResult<Vec<String>>> = Lazy::new(|| {
     let (config, _opts) = read_system_conf()?;
@@ -51,6 +52,14 @@ impl DnsResolver {
         self.0.lookup_ip(host).await.map(_interleave_addrinfos)
     }
 
+    /// Look up records of an arbitrary type, e.g. to forward DNS queries we cannot answer ourselves.
+    pub async fn lookup(&self, name: Name, record_type: RecordType) -> ResolveResult<Vec<Record>> {
+        self.0
+            .lookup(name, record_type)
+            .await
+            .map(|lookup| lookup.records().to_vec())
+    }
+
     // hickory_resolver's ipv4/v6_lookup() doesn't use the hosts file for lookups but lookup_ip does,
     // so we instead filter addresses returned from lookup_ip for now
     //
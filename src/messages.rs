@@ -1,17 +1,25 @@
 use std::fmt;
 use std::fmt::Formatter;
 use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
+use boringtun::x25519::PublicKey;
 use internet_packet::{InternetPacket, TransportProtocol};
-use smoltcp::wire::{IpProtocol, Ipv4Packet, Ipv6Packet};
-use tokio::sync::{mpsc, oneshot};
+use smoltcp::wire::{IpProtocol, Ipv4Packet, Ipv6ExtHeader, Ipv6Packet, IPV6_HEADER_LEN};
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::network::ClientHello;
 
 #[derive(Debug, Clone)]
 pub enum TunnelInfo {
     WireGuard {
         src_addr: SocketAddr,
         dst_addr: SocketAddr,
+        peer_public_key: PublicKey,
+        /// Index of the peer on this server. Unlike `src_addr`, this does not change when
+        /// the peer roams to a different endpoint.
+        peer_index: u32,
     },
     LocalRedirector {
         pid: Option<u32>,
@@ -86,10 +94,59 @@ pub enum TransportEvent {
         src_addr: SocketAddr,
         dst_addr: SocketAddr,
         tunnel_info: TunnelInfo,
+        /// The hostname the client looked up to obtain `dst_addr`,
+        /// see [crate::network::NetworkConf::fake_dns_servers].
+        original_hostname: Option<String>,
+        /// Details of the TLS ClientHello or QUIC Initial that the client started the connection with.
+        client_hello: Option<ClientHello>,
         // Channel over which the stream should emit commands.
         // If command_tx is None, the main channel is used.
         command_tx: Option<mpsc::UnboundedSender<TransportCommand>>,
     },
+    ConnectionClosed {
+        connection_id: ConnectionId,
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        reason: CloseReason,
+        /// Payload bytes received from the peer.
+        bytes_received: u64,
+        /// Payload bytes sent to the peer.
+        bytes_sent: u64,
+        duration: Duration,
+    },
+    /// An IP packet of a protocol that is forwarded as-is,
+    /// see [crate::network::NetworkConf::protocol_actions].
+    RawPacket {
+        packet: SmolPacket,
+        tunnel_info: TunnelInfo,
+    },
+}
+
+/// Why a connection was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The peer closed the connection first (TCP FIN).
+    PeerClosed,
+    /// The peer reset the connection (TCP RST).
+    Reset,
+    /// The connection timed out, e.g. an idle UDP flow or a TCP peer that stopped responding.
+    Timeout,
+    /// We closed the connection first, e.g. with `Stream.close()` or `Stream.abort()`.
+    LocalClose,
+    /// The server is shutting down.
+    Shutdown,
+}
+
+impl CloseReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::PeerClosed => "peer_closed",
+            CloseReason::Reset => "reset",
+            CloseReason::Timeout => "timeout",
+            CloseReason::LocalClose => "local_close",
+            CloseReason::Shutdown => "shutdown",
+        }
+    }
 }
 
 /// Commands that are sent by the Python side to the TCP stack.
@@ -98,16 +155,92 @@ pub enum TransportCommand {
     ReadData(ConnectionId, u32, oneshot::Sender<Vec<u8>>),
     WriteData(ConnectionId, Vec<u8>),
     DrainWriter(ConnectionId, oneshot::Sender<()>),
+    /// Close a connection. If the flag is set, only the write side is closed ("half close").
     CloseConnection(ConnectionId, bool),
+    /// Close a connection immediately, discarding any unsent data. For TCP, this sends a RST.
+    AbortConnection(ConnectionId),
+    /// Complete the handshake of a TCP connection that has been deferred,
+    /// see [crate::network::NetworkConf::tcp_accept_timeout].
+    AcceptConnection(ConnectionId),
+    /// Refuse a TCP connection that has been deferred. Established connections are aborted instead.
+    /// For UDP flows, the ICMP message quotes the most recent datagram and the flow is closed.
+    RejectConnection(ConnectionId, Rejection),
+    /// Open a connection from the network stack into the tunnel.
+    OpenConnection {
+        /// Open a UDP flow instead of a TCP connection.
+        udp: bool,
+        /// Our end of the connection. If the port is zero, an ephemeral port is picked.
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        /// Receives the id and local address of the new connection once it has been established.
+        tx: oneshot::Sender<std::io::Result<(ConnectionId, SocketAddr)>>,
+        /// Notified once the connection has been closed.
+        closed: watch::Sender<()>,
+    },
+    /// Send an IP packet into the tunnel as-is.
+    SendRawPacket(SmolPacket),
+    /// Hand a connection over to a task that relays it to `host:port` without involving Python.
+    /// No other commands must be sent for the connection afterwards.
+    SpliceConnection {
+        connection_id: ConnectionId,
+        host: String,
+        port: u16,
+        /// Receives the number of bytes relayed once both sides have been closed.
+        tx: oneshot::Sender<std::io::Result<SpliceStats>>,
+    },
+}
+
+/// The number of bytes relayed for a spliced connection,
+/// see [TransportCommand::SpliceConnection].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpliceStats {
+    /// Bytes received from the client and sent upstream.
+    pub bytes_from_client: u64,
+    /// Bytes received from upstream and sent to the client.
+    pub bytes_from_server: u64,
+}
+
+/// How a connection is refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// Answer with a TCP RST ("connection refused"). UDP flows are closed silently.
+    Reset,
+    /// Answer with an ICMP(v6) destination unreachable message.
+    Unreachable(UnreachableCode),
+    /// Answer with an ICMP(v6) time exceeded message, as if the TTL had expired in transit.
+    TimeExceeded,
+}
+
+/// The reason given in an ICMP(v6) destination unreachable message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnreachableCode {
+    /// Network unreachable (ICMPv6: no route to destination).
+    Network,
+    /// Host unreachable (ICMPv6: address unreachable).
+    Host,
+    /// Protocol unreachable (ICMPv6: parameter problem, unrecognized next header).
+    Protocol,
+    /// Port unreachable.
+    Port,
+    /// Communication administratively prohibited.
+    Prohibited,
 }
 
 impl TransportCommand {
-    pub fn connection_id(&self) -> &ConnectionId {
+    /// The connection this command refers to, `None` for commands that are not tied to an
+    /// existing connection.
+    pub fn connection_id(&self) -> Option<&ConnectionId> {
         match self {
-            TransportCommand::ReadData(id, _, _) => id,
-            TransportCommand::WriteData(id, _) => id,
-            TransportCommand::DrainWriter(id, _) => id,
-            TransportCommand::CloseConnection(id, _) => id,
+            TransportCommand::ReadData(id, _, _) => Some(id),
+            TransportCommand::WriteData(id, _) => Some(id),
+            TransportCommand::DrainWriter(id, _) => Some(id),
+            TransportCommand::CloseConnection(id, _) => Some(id),
+            TransportCommand::AbortConnection(id) => Some(id),
+            TransportCommand::AcceptConnection(id) => Some(id),
+            TransportCommand::RejectConnection(id, _) => Some(id),
+            TransportCommand::OpenConnection { .. } => None,
+            TransportCommand::SendRawPacket(_) => None,
+            TransportCommand::SpliceConnection { connection_id, .. } => Some(connection_id),
         }
     }
 }
@@ -181,6 +314,33 @@ impl TryFrom<Vec<u8>> for SmolPacket {
     }
 }
 
+/// Walk the IPv6 extension header chain (RFC 8200, Section 4).
+/// Returns the upper-layer protocol and the offset of its header, or `None` if the chain is
+/// truncated.
+fn ipv6_upper_layer(packet: &Ipv6Packet<Vec<u8>>) -> Option<(IpProtocol, usize)> {
+    let data = &packet.as_ref()[..IPV6_HEADER_LEN + packet.payload_len() as usize];
+    let mut next_header = packet.next_header();
+    let mut offset = IPV6_HEADER_LEN;
+    while matches!(
+        next_header,
+        IpProtocol::HopByHop | IpProtocol::Ipv6Route | IpProtocol::Ipv6Opts
+    ) {
+        let ext = Ipv6ExtHeader::new_checked(data.get(offset..)?).ok()?;
+        next_header = ext.next_header();
+        offset += 8 + ext.header_len() as usize * 8;
+    }
+    Some((next_header, offset))
+}
+
+/// Like [ipv6_upper_layer], but a truncated chain is treated as if there was no upper layer,
+/// with all of the packet taken up by headers.
+fn ipv6_upper_layer_or_end(packet: &Ipv6Packet<Vec<u8>>) -> (IpProtocol, usize) {
+    ipv6_upper_layer(packet).unwrap_or((
+        IpProtocol::Ipv6NoNxt,
+        IPV6_HEADER_LEN + packet.payload_len() as usize,
+    ))
+}
+
 impl SmolPacket {
     pub fn src_ip(&self) -> IpAddr {
         match self {
@@ -196,29 +356,67 @@ impl SmolPacket {
         }
     }
 
+    /// The upper-layer protocol. For IPv6, this skips over all extension headers.
+    /// If the extension header chain is truncated, this is [IpProtocol::Ipv6NoNxt].
     pub fn transport_protocol(&self) -> IpProtocol {
         match self {
             SmolPacket::V4(packet) => packet.next_header(),
-            SmolPacket::V6(packet) => match packet.next_header() {
-                IpProtocol::Tcp => IpProtocol::Tcp,
-                IpProtocol::Udp => IpProtocol::Udp,
-                IpProtocol::Icmp => IpProtocol::Icmp,
-                IpProtocol::Icmpv6 => IpProtocol::Icmpv6,
-                other => {
-                    log::debug!("TODO: Implement IPv6 next_header logic: {}", other);
-                    other
-                }
-            },
+            SmolPacket::V6(packet) => ipv6_upper_layer_or_end(packet).0,
+        }
+    }
+
+    /// Length of the IP header, including IPv6 extension headers.
+    pub fn header_len(&self) -> usize {
+        match self {
+            SmolPacket::V4(packet) => packet.header_len() as usize,
+            SmolPacket::V6(packet) => ipv6_upper_layer_or_end(packet).1,
         }
     }
 
+    /// The upper-layer payload. For IPv6, this starts after all extension headers.
     pub fn payload_mut(&mut self) -> &mut [u8] {
         match self {
             SmolPacket::V4(packet) => packet.payload_mut(),
-            SmolPacket::V6(packet) => packet.payload_mut(),
+            SmolPacket::V6(packet) => {
+                let offset = ipv6_upper_layer_or_end(packet).1 - IPV6_HEADER_LEN;
+                &mut packet.payload_mut()[offset..]
+            }
         }
     }
 
+    /// Whether this is an IPv6 packet whose extension header chain ends before the upper-layer
+    /// header, so that it cannot be processed.
+    pub fn has_truncated_extension_headers(&self) -> bool {
+        match self {
+            SmolPacket::V4(_) => false,
+            SmolPacket::V6(packet) => ipv6_upper_layer(packet).is_none(),
+        }
+    }
+
+    /// Remove all IPv6 extension headers, so that the upper-layer header directly follows the
+    /// IPv6 header. smoltcp only understands a leading Hop-by-Hop header.
+    /// Checksums are not affected by this, IPv4 packets and IPv6 packets with a truncated
+    /// extension header chain are returned unchanged.
+    pub fn without_extension_headers(self) -> SmolPacket {
+        let SmolPacket::V6(packet) = self else {
+            return self;
+        };
+        let Some((next_header, offset)) = ipv6_upper_layer(&packet) else {
+            return SmolPacket::V6(packet);
+        };
+        if offset == IPV6_HEADER_LEN {
+            return SmolPacket::V6(packet);
+        }
+        let total_len = IPV6_HEADER_LEN + packet.payload_len() as usize;
+        let mut buf = packet.into_inner();
+        buf.truncate(total_len);
+        buf.drain(IPV6_HEADER_LEN..offset);
+        let mut packet = Ipv6Packet::new_unchecked(buf);
+        packet.set_next_header(next_header);
+        packet.set_payload_len((total_len - offset) as u16);
+        SmolPacket::V6(packet)
+    }
+
     pub fn into_inner(self) -> Vec<u8> {
         match self {
             SmolPacket::V4(packet) => packet.into_inner(),
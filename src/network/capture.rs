@@ -0,0 +1,515 @@
+//! Packet capture of everything the network stack receives and sends, in pcapng format.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::messages::{SmolPacket, TunnelInfo};
+use anyhow::{Context, Result};
+use data_encoding::BASE64;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+/// Raw IPv4 or IPv6 packets without a link layer header.
+const LINKTYPE_RAW: u16 = 101;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+const EPB_FLAGS_INBOUND: u32 = 0b01;
+const EPB_FLAGS_OUTBOUND: u32 = 0b10;
+/// How many client addresses we remember the tunnel info of, see [CaptureTap::record_outbound].
+const MAX_ROUTES: usize = 4096;
+
+/// Whether a packet was received or sent by the network stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Append a pcapng block with the given body, adding the type and both length fields.
+fn push_block(out: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+    let total_len = (12 + body.len()) as u32;
+    out.extend_from_slice(&block_type.to_le_bytes());
+    out.extend_from_slice(&total_len.to_le_bytes());
+    out.extend_from_slice(body);
+    out.extend_from_slice(&total_len.to_le_bytes());
+}
+
+/// Append an option or packet data, padded to 32 bits.
+fn push_padded(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(data);
+    out.resize(out.len() + (4 - data.len() % 4) % 4, 0);
+}
+
+fn push_option(out: &mut Vec<u8>, code: u16, value: &[u8]) {
+    out.extend_from_slice(&code.to_le_bytes());
+    out.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    push_padded(out, value);
+}
+
+/// The section header and interface description that every capture file starts with.
+fn file_header() -> Vec<u8> {
+    let mut out = Vec::new();
+
+    let mut shb = Vec::new();
+    shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    shb.extend_from_slice(&1u16.to_le_bytes()); // major version
+    shb.extend_from_slice(&0u16.to_le_bytes()); // minor version
+    shb.extend_from_slice(&(-1i64).to_le_bytes()); // section length: unspecified
+    push_block(&mut out, BLOCK_SECTION_HEADER, &shb);
+
+    let mut idb = Vec::new();
+    idb.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    idb.extend_from_slice(&0u16.to_le_bytes()); // reserved
+    idb.extend_from_slice(&0u32.to_le_bytes()); // snaplen: unlimited
+    push_option(&mut idb, OPT_IF_NAME, b"mitmproxy");
+    push_option(&mut idb, OPT_END, &[]);
+    push_block(&mut out, BLOCK_INTERFACE_DESCRIPTION, &idb);
+
+    out
+}
+
+/// Describe where a packet came from or went to, e.g. the WireGuard peer or the process.
+fn comment(direction: Direction, tunnel_info: Option<&TunnelInfo>) -> String {
+    let direction = match direction {
+        Direction::Inbound => "inbound",
+        Direction::Outbound => "outbound",
+    };
+    match tunnel_info {
+        Some(TunnelInfo::WireGuard {
+            src_addr,
+            peer_public_key,
+            peer_index,
+            ..
+        }) => format!(
+            "{direction}, WireGuard peer {} ({}, index {})",
+            BASE64.encode(peer_public_key.as_bytes()),
+            src_addr,
+            peer_index
+        ),
+        Some(TunnelInfo::LocalRedirector {
+            pid, process_name, ..
+        }) => {
+            let pid = pid.map(|pid| pid.to_string());
+            format!(
+                "{direction}, process {} (pid {})",
+                process_name.as_deref().unwrap_or("unknown"),
+                pid.as_deref().unwrap_or("unknown")
+            )
+        }
+        Some(TunnelInfo::None) | None => direction.to_string(),
+    }
+}
+
+/// Encode a packet as an Enhanced Packet Block.
+fn packet_block(
+    direction: Direction,
+    packet: &SmolPacket,
+    tunnel_info: Option<&TunnelInfo>,
+    timestamp: SystemTime,
+) -> Vec<u8> {
+    let data = match packet {
+        SmolPacket::V4(packet) => packet.as_ref(),
+        SmolPacket::V6(packet) => packet.as_ref(),
+    };
+    // Timestamps are in microseconds, the default resolution.
+    let micros = timestamp
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+
+    let mut epb = Vec::with_capacity(data.len() + 128);
+    epb.extend_from_slice(&0u32.to_le_bytes()); // interface id
+    epb.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    epb.extend_from_slice(&(micros as u32).to_le_bytes());
+    epb.extend_from_slice(&(data.len() as u32).to_le_bytes()); // captured length
+    epb.extend_from_slice(&(data.len() as u32).to_le_bytes()); // original length
+    push_padded(&mut epb, data);
+    push_option(
+        &mut epb,
+        OPT_COMMENT,
+        comment(direction, tunnel_info).as_bytes(),
+    );
+    let flags = match direction {
+        Direction::Inbound => EPB_FLAGS_INBOUND,
+        Direction::Outbound => EPB_FLAGS_OUTBOUND,
+    };
+    push_option(&mut epb, OPT_EPB_FLAGS, &flags.to_le_bytes());
+    push_option(&mut epb, OPT_END, &[]);
+
+    let mut out = Vec::with_capacity(epb.len() + 12);
+    push_block(&mut out, BLOCK_ENHANCED_PACKET, &epb);
+    out
+}
+
+/// A series of capture files. Once a file exceeds the maximum size, we continue with the
+/// next one: `capture.pcapng`, `capture.1.pcapng`, `capture.2.pcapng`, ...
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    max_files: Option<usize>,
+    index: usize,
+    file: BufWriter<File>,
+    size: u64,
+    packets: usize,
+}
+
+impl RotatingFile {
+    fn file_path(&self, index: usize) -> PathBuf {
+        if index == 0 {
+            return self.path.clone();
+        }
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match self.path.extension() {
+            Some(ext) => format!("{}.{}.{}", stem, index, ext.to_string_lossy()),
+            None => format!("{}.{}", stem, index),
+        };
+        self.path.with_file_name(name)
+    }
+
+    fn create(path: &Path) -> Result<(BufWriter<File>, u64)> {
+        let mut file = BufWriter::new(
+            File::create(path)
+                .with_context(|| format!("Failed to create capture file {}", path.display()))?,
+        );
+        let header = file_header();
+        file.write_all(&header)?;
+        Ok((file, header.len() as u64))
+    }
+
+    fn write(&mut self, block: &[u8]) -> Result<()> {
+        // Every file gets at least one packet, even if it exceeds the size limit on its own.
+        if self.packets > 0
+            && self
+                .max_size
+                .is_some_and(|max_size| self.size + block.len() as u64 > max_size)
+        {
+            self.file.flush()?;
+            self.index += 1;
+            (self.file, self.size) = Self::create(&self.file_path(self.index))?;
+            self.packets = 0;
+            if let Some(max_files) = self.max_files {
+                if let Some(expired) = self.index.checked_sub(max_files) {
+                    fs::remove_file(self.file_path(expired)).ok();
+                }
+            }
+        }
+        self.file.write_all(block)?;
+        self.file.flush()?;
+        self.size += block.len() as u64;
+        self.packets += 1;
+        Ok(())
+    }
+}
+
+/// The most recent packets, kept in memory up to a total size.
+#[derive(Debug)]
+pub struct CaptureRing {
+    capacity: usize,
+    size: usize,
+    blocks: VecDeque<Vec<u8>>,
+}
+
+impl CaptureRing {
+    fn push(&mut self, block: Vec<u8>) {
+        self.size += block.len();
+        self.blocks.push_back(block);
+        while self.size > self.capacity {
+            let Some(evicted) = self.blocks.pop_front() else {
+                break;
+            };
+            self.size -= evicted.len();
+        }
+    }
+
+    /// A complete pcapng file with the packets currently in the ring.
+    pub fn to_pcapng(&self) -> Vec<u8> {
+        let mut out = file_header();
+        out.reserve(self.size);
+        for block in &self.blocks {
+            out.extend_from_slice(block);
+        }
+        out
+    }
+}
+
+/// Where captured packets go.
+#[derive(Debug)]
+pub enum Capture {
+    File(RotatingFile),
+    Ring(CaptureRing),
+}
+
+impl Capture {
+    /// Capture into a pcapng file, which is created (or truncated) right away.
+    /// If `max_size` is set, a new file is started whenever the current one would exceed
+    /// `max_size` bytes. If `max_files` is set as well, only the most recent files are kept.
+    pub fn file(path: PathBuf, max_size: Option<u64>, max_files: Option<usize>) -> Result<Self> {
+        let (file, size) = RotatingFile::create(&path)?;
+        Ok(Self::File(RotatingFile {
+            path,
+            max_size,
+            max_files,
+            index: 0,
+            file,
+            size,
+            packets: 0,
+        }))
+    }
+
+    /// Capture into memory, keeping the most recent packets up to `capacity` bytes.
+    pub fn ring(capacity: usize) -> Self {
+        Self::Ring(CaptureRing {
+            capacity,
+            size: 0,
+            blocks: VecDeque::new(),
+        })
+    }
+
+    fn record(&mut self, block: Vec<u8>) -> Result<()> {
+        match self {
+            Capture::File(file) => file.write(&block),
+            Capture::Ring(ring) => {
+                ring.push(block);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A switch to start and stop capturing at runtime, see [crate::network::NetworkConf::capture].
+/// The network stack records packets on the tap while it [is active](CaptureTap::is_active).
+#[derive(Debug, Clone, Default)]
+pub struct CaptureTap(Arc<Tap>);
+
+#[derive(Debug, Default)]
+struct Tap {
+    /// Whether a capture is running, so that the network stack only takes the lock if it is.
+    active: AtomicBool,
+    capture: Mutex<Option<Capture>>,
+    /// The tunnel info of the most recent packet from each client address.
+    routes: Mutex<HashMap<IpAddr, TunnelInfo>>,
+}
+
+impl CaptureTap {
+    /// Start capturing, replacing any capture that is currently running.
+    pub fn start(&self, capture: Capture) -> Option<Capture> {
+        let mut current = self.0.capture.lock().unwrap();
+        self.0.active.store(true, Ordering::Relaxed);
+        current.replace(capture)
+    }
+
+    /// Stop capturing and return the capture, e.g. to read the contents of a ring.
+    pub fn stop(&self) -> Option<Capture> {
+        let mut current = self.0.capture.lock().unwrap();
+        self.0.active.store(false, Ordering::Relaxed);
+        self.0.routes.lock().unwrap().clear();
+        current.take()
+    }
+
+    /// The contents of the ring that is currently being captured into, as a pcapng file.
+    pub fn ring_contents(&self) -> Option<Vec<u8>> {
+        match &*self.0.capture.lock().unwrap() {
+            Some(Capture::Ring(ring)) => Some(ring.to_pcapng()),
+            _ => None,
+        }
+    }
+
+    pub(super) fn is_active(&self) -> bool {
+        self.0.active.load(Ordering::Relaxed)
+    }
+
+    /// Record a packet from the clients. Its tunnel info is remembered, so that packets sent back
+    /// to the same address are attributed to the same peer or process.
+    pub(super) fn record_inbound(&self, packet: &SmolPacket, tunnel_info: &TunnelInfo) {
+        {
+            let mut routes = self.0.routes.lock().unwrap();
+            if routes.len() >= MAX_ROUTES && !routes.contains_key(&packet.src_ip()) {
+                routes.clear();
+            }
+            routes.insert(packet.src_ip(), tunnel_info.clone());
+        }
+        self.record(Direction::Inbound, packet, Some(tunnel_info));
+    }
+
+    /// Record a packet towards the clients, with the tunnel info of the most recent packet that
+    /// came from its destination address.
+    pub(super) fn record_outbound(&self, packet: &SmolPacket) {
+        let tunnel_info = self.0.routes.lock().unwrap().get(&packet.dst_ip()).cloned();
+        self.record(Direction::Outbound, packet, tunnel_info.as_ref());
+    }
+
+    /// Record a packet in the capture that is currently running, if any.
+    fn record(&self, direction: Direction, packet: &SmolPacket, tunnel_info: Option<&TunnelInfo>) {
+        let mut capture = self.0.capture.lock().unwrap();
+        let Some(c) = capture.as_mut() else {
+            return;
+        };
+        let block = packet_block(direction, packet, tunnel_info, SystemTime::now());
+        if let Err(e) = c.record(block) {
+            log::error!("Failed to write packet capture, stopping capture: {:?}", e);
+            self.0.active.store(false, Ordering::Relaxed);
+            *capture = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::wire::{IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr};
+
+    fn packet(payload_len: usize) -> SmolPacket {
+        let repr = Ipv4Repr {
+            src_addr: Ipv4Address::new(10, 0, 0, 1),
+            dst_addr: Ipv4Address::new(10, 0, 0, 42),
+            next_header: IpProtocol::Udp,
+            payload_len,
+            hop_limit: 64,
+        };
+        let mut buf = vec![0u8; 20 + payload_len];
+        repr.emit(
+            &mut Ipv4Packet::new_unchecked(&mut buf),
+            &Default::default(),
+        );
+        SmolPacket::V4(Ipv4Packet::new_unchecked(buf))
+    }
+
+    /// Split a pcapng file into (block type, block body) pairs, checking the length fields.
+    fn blocks(mut data: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut blocks = Vec::new();
+        while !data.is_empty() {
+            let block_type = u32::from_le_bytes(data[..4].try_into().unwrap());
+            let len = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(data[len - 4..len], data[4..8]);
+            blocks.push((block_type, data[8..len - 4].to_vec()));
+            data = &data[len..];
+        }
+        blocks
+    }
+
+    #[test]
+    fn packet_block_layout() {
+        let packet = packet(3);
+        let block = packet_block(
+            Direction::Inbound,
+            &packet,
+            Some(&TunnelInfo::LocalRedirector {
+                pid: Some(42),
+                process_name: Some("curl".to_string()),
+                remote_endpoint: None,
+            }),
+            SystemTime::UNIX_EPOCH + std::time::Duration::from_micros(0x1_0000_0002),
+        );
+        let [(BLOCK_ENHANCED_PACKET, body)] = &blocks(&block)[..] else {
+            panic!("expected a single enhanced packet block");
+        };
+        assert_eq!(body[4..8], 1u32.to_le_bytes());
+        assert_eq!(body[8..12], 2u32.to_le_bytes());
+        assert_eq!(body[12..16], 23u32.to_le_bytes());
+        assert_eq!(body[16..20], 23u32.to_le_bytes());
+        assert_eq!(&body[20..43], packet.clone().into_inner());
+        // packet data is padded to 24 bytes, followed by the options.
+        let comment = b"inbound, process curl (pid 42)";
+        assert_eq!(body[44..46], OPT_COMMENT.to_le_bytes());
+        assert_eq!(body[46..48], (comment.len() as u16).to_le_bytes());
+        assert_eq!(&body[48..48 + comment.len()], comment);
+        let flags = &body[48 + comment.len().next_multiple_of(4)..];
+        assert_eq!(
+            flags,
+            [2, 0, 4, 0, EPB_FLAGS_INBOUND as u8, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(comment(Direction::Outbound, None), "outbound");
+        assert_eq!(
+            comment(Direction::Inbound, Some(&TunnelInfo::None)),
+            "inbound"
+        );
+        assert_eq!(
+            comment(
+                Direction::Inbound,
+                Some(&TunnelInfo::WireGuard {
+                    src_addr: "192.0.2.1:51820".parse().unwrap(),
+                    dst_addr: "192.0.2.2:51820".parse().unwrap(),
+                    peer_public_key: [0u8; 32].into(),
+                    peer_index: 3,
+                })
+            ),
+            "inbound, WireGuard peer AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA= (192.0.2.1:51820, index 3)"
+        );
+    }
+
+    #[test]
+    fn ring() {
+        let tap = CaptureTap::default();
+        assert_eq!(tap.ring_contents(), None);
+        tap.record(Direction::Inbound, &packet(100), None);
+
+        let block_len =
+            packet_block(Direction::Outbound, &packet(100), None, SystemTime::now()).len();
+        tap.start(Capture::ring(block_len * 2));
+        for _ in 0..3 {
+            tap.record(Direction::Outbound, &packet(100), None);
+        }
+        let contents = tap.ring_contents().unwrap();
+        let types: Vec<u32> = blocks(&contents).into_iter().map(|(t, _)| t).collect();
+        assert_eq!(
+            types,
+            [
+                BLOCK_SECTION_HEADER,
+                BLOCK_INTERFACE_DESCRIPTION,
+                BLOCK_ENHANCED_PACKET,
+                BLOCK_ENHANCED_PACKET
+            ]
+        );
+
+        assert!(matches!(tap.stop(), Some(Capture::Ring(_))));
+        assert_eq!(tap.ring_contents(), None);
+    }
+
+    #[test]
+    fn file_rotation() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("mitmproxy-capture-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("capture.pcapng");
+        let header_len = file_header().len() as u64;
+        let block_len =
+            packet_block(Direction::Inbound, &packet(100), None, SystemTime::now()).len();
+
+        let tap = CaptureTap::default();
+        tap.start(Capture::file(
+            path.clone(),
+            Some(header_len + 2 * block_len as u64),
+            Some(2),
+        )?);
+        for _ in 0..5 {
+            tap.record(Direction::Inbound, &packet(100), None);
+        }
+        tap.stop();
+
+        // capture.pcapng has been rotated away, the last two files are kept.
+        assert!(!path.exists());
+        let first = fs::read(dir.join("capture.1.pcapng"))?;
+        let second = fs::read(dir.join("capture.2.pcapng"))?;
+        fs::remove_dir_all(&dir)?;
+        assert_eq!(blocks(&first).len(), 4);
+        assert_eq!(blocks(&second).len(), 3);
+        assert_eq!(first[..header_len as usize], file_header());
+        Ok(())
+    }
+}
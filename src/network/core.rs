@@ -1,31 +1,65 @@
-use std::cmp::min;
+use std::collections::HashMap;
 use std::fmt;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Arc;
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 
 use smoltcp::wire::IpProtocol;
-use tokio::sync::mpsc::{Permit, Sender};
+use tokio::sync::mpsc::Permit;
+use tokio::sync::Semaphore;
 
-use crate::messages::{NetworkCommand, NetworkEvent, SmolPacket, TransportCommand, TransportEvent};
-use crate::network::icmp::{handle_icmpv4_echo_request, handle_icmpv6_echo_request};
+use crate::messages::{
+    ConnectionId, NetworkEvent, Rejection, SmolPacket, TransportCommand, TransportEvent,
+    UnreachableCode,
+};
+use crate::network::capture::CaptureTap;
+use crate::network::fake_dns::{DnsAnswer, FakeDns};
+use crate::network::fragments::{
+    fragment_packet, FragmentReassembler, REASSEMBLY_BUDGET, REASSEMBLY_TIMEOUT,
+};
+use crate::network::icmp::{icmp_error, EchoRequest};
+use crate::network::packet_sender::PacketSender;
 
 use crate::network::tcp::TcpHandler;
 use crate::network::udp::{UdpHandler, UdpPacket};
+use crate::network::{NetworkConf, ProtocolAction};
+
+/// How many forwarded ICMP echo requests and DNS queries may wait for their reply at the same time.
+const MAX_PENDING_REPLIES: usize = 64;
 
 pub struct NetworkStack<'a> {
     tcp: TcpHandler<'a>,
     udp: UdpHandler,
-    net_tx: Sender<NetworkCommand>,
+    fragments: FragmentReassembler,
+    net_tx: PacketSender,
+    mtu: usize,
+    next_fragment_ident: u32,
+    forward_icmp: bool,
+    pending_replies: Arc<Semaphore>,
+    protocol_actions: HashMap<u8, ProtocolAction>,
+    fake_dns: Option<FakeDns>,
+    capture: Option<CaptureTap>,
 }
 
 impl NetworkStack<'_> {
-    pub fn new(net_tx: Sender<NetworkCommand>) -> Self {
+    pub fn new(net_tx: PacketSender, conf: &NetworkConf) -> Self {
         Self {
-            tcp: TcpHandler::new(net_tx.clone()),
+            tcp: TcpHandler::new(net_tx.clone(), conf),
             udp: UdpHandler::new(),
+            fragments: FragmentReassembler::new(REASSEMBLY_BUDGET, REASSEMBLY_TIMEOUT),
             net_tx,
+            mtu: conf.mtu(),
+            next_fragment_ident: 0,
+            forward_icmp: conf.forward_icmp,
+            pending_replies: Arc::new(Semaphore::new(MAX_PENDING_REPLIES)),
+            protocol_actions: conf.protocol_actions.clone(),
+            fake_dns: (!conf.fake_dns_servers.is_empty())
+                .then(|| FakeDns::new(conf.fake_dns_servers.clone())),
+            capture: conf.capture.clone(),
         }
     }
 
@@ -41,6 +75,10 @@ impl NetworkStack<'_> {
             } => (packet, tunnel_info),
         };
 
+        if let Some(tap) = self.capture.as_ref().filter(|tap| tap.is_active()) {
+            tap.record_inbound(&packet, &tunnel_info);
+        }
+
         if let SmolPacket::V4(p) = &packet {
             if !p.verify_checksum() {
                 log::warn!("Received invalid IP packet (checksum error).");
@@ -48,74 +86,215 @@ impl NetworkStack<'_> {
             }
         }
 
+        let Some(packet) = self.fragments.process(packet, Instant::now()) else {
+            return Ok(());
+        };
+        if packet.has_truncated_extension_headers() {
+            log::debug!("Received IPv6 packet with truncated extension headers.");
+            return Ok(());
+        }
+
+        let original_hostname = self
+            .fake_dns
+            .as_ref()
+            .and_then(|dns| dns.hostname(packet.dst_ip()));
+
         match packet.transport_protocol() {
-            IpProtocol::Tcp => self.tcp.receive_packet(packet, tunnel_info, permit),
+            // smoltcp and our ICMP handlers expect the upper-layer header right after the IP header.
+            IpProtocol::Tcp => self.tcp.receive_packet(
+                packet.without_extension_headers(),
+                tunnel_info,
+                original_hostname,
+                permit,
+            ),
             IpProtocol::Udp => {
                 match UdpPacket::try_from(packet) {
-                    Ok(packet) => self.udp.receive_data(packet, tunnel_info, permit),
+                    Ok(packet)
+                        if self
+                            .fake_dns
+                            .as_ref()
+                            .is_some_and(|dns| dns.is_query(&packet)) =>
+                    {
+                        self.receive_dns_query(packet)
+                    }
+                    Ok(packet) => {
+                        self.udp
+                            .receive_data(packet, tunnel_info, original_hostname, permit)
+                    }
                     Err(e) => log::debug!("Received invalid UDP packet: {}", e),
                 };
                 Ok(())
             }
-            IpProtocol::Icmp => self.receive_packet_icmp(packet),
-            IpProtocol::Icmpv6 => self.receive_packet_icmp(packet),
-            _ => {
-                log::debug!(
-                    "Received IP packet for unknown protocol: {}",
-                    packet.transport_protocol()
-                );
+            IpProtocol::Icmp | IpProtocol::Icmpv6 => {
+                self.receive_packet_icmp(packet.without_extension_headers())
+            }
+            protocol => {
+                match self
+                    .protocol_actions
+                    .get(&u8::from(protocol))
+                    .copied()
+                    .unwrap_or_default()
+                {
+                    ProtocolAction::Drop => {
+                        log::debug!("Received IP packet for unknown protocol: {}", protocol);
+                    }
+                    ProtocolAction::Forward => {
+                        permit.send(TransportEvent::RawPacket {
+                            packet,
+                            tunnel_info,
+                        });
+                    }
+                    ProtocolAction::Unreachable => {
+                        // RFC 1122: no ICMP errors for multicast or broadcast datagrams.
+                        let dst_ip = packet.dst_ip();
+                        let is_broadcast = match dst_ip {
+                            IpAddr::V4(ip) => ip.is_broadcast(),
+                            IpAddr::V6(_) => false,
+                        };
+                        if !dst_ip.is_multicast() && !is_broadcast {
+                            let rejection = Rejection::Unreachable(UnreachableCode::Protocol);
+                            if let Some(response) =
+                                icmp_error(&packet.without_extension_headers(), rejection)
+                            {
+                                self.send_packet(response);
+                            }
+                        }
+                    }
+                }
                 Ok(())
             }
         }
     }
 
+    fn receive_dns_query(&mut self, packet: UdpPacket) {
+        let Some(dns) = &mut self.fake_dns else {
+            return;
+        };
+        match dns.handle_query(packet) {
+            Some(DnsAnswer::Local(reply)) => self.send_packet(reply.into()),
+            Some(DnsAnswer::Upstream(query)) => {
+                self.spawn_reply(async move { query.resolve().await.map(SmolPacket::from) })
+            }
+            None => {}
+        }
+    }
+
     fn receive_packet_icmp(&mut self, packet: SmolPacket) -> Result<()> {
         // Some apps check network connectivity by sending ICMP pings. ICMP traffic is currently
         // swallowed by mitmproxy_rs, which makes them believe that there is no network connectivity.
-        // Generating fake ICMP replies as a simple workaround.
-
-        if let Ok(permit) = self.net_tx.try_reserve() {
-            // Generating and sending fake replies for ICMP echo requests. Ignoring all other ICMP types.
-            let response_packet = match packet {
-                SmolPacket::V4(packet) => handle_icmpv4_echo_request(packet),
-                SmolPacket::V6(packet) => handle_icmpv6_echo_request(packet),
-            };
-            if let Some(response_packet) = response_packet {
-                permit.send(NetworkCommand::SendPacket(response_packet));
-            }
-        } else {
-            log::debug!("Channel full, discarding ICMP packet.");
+        // Unless echo requests are forwarded, we generate fake replies as a simple workaround.
+        // All other ICMP types are ignored.
+        let Some(request) = EchoRequest::parse(packet) else {
+            return Ok(());
+        };
+        if !self.forward_icmp {
+            self.send_packet(request.reply(&request.data));
+            return Ok(());
         }
+
+        self.spawn_reply(async move {
+            match request.forward().await {
+                Ok(Some(data)) => Some(request.reply(&data)),
+                Ok(None) => {
+                    log::debug!("No echo reply from {}.", request.dst_addr);
+                    None
+                }
+                Err(e) => {
+                    log::warn!("Failed to forward ICMP echo request: {:#}", e);
+                    None
+                }
+            }
+        });
         Ok(())
     }
 
+    /// Compute a reply in the background and send it once it is ready.
+    fn spawn_reply(&mut self, reply: impl Future<Output = Option<SmolPacket>> + Send + 'static) {
+        let Ok(permit) = self.pending_replies.clone().try_acquire_owned() else {
+            log::debug!("Too many pending replies, discarding packet.");
+            return;
+        };
+        let net_tx = self.net_tx.clone();
+        let mtu = self.mtu;
+        let ident = self.next_fragment_ident();
+        tokio::spawn(async move {
+            if let Some(packet) = reply.await {
+                for fragment in fragment_packet(packet, mtu, ident) {
+                    if net_tx.send(fragment).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            drop(permit);
+        });
+    }
+
+    fn next_fragment_ident(&mut self) -> u32 {
+        let ident = self.next_fragment_ident;
+        self.next_fragment_ident = ident.wrapping_add(1);
+        ident
+    }
+
+    /// Send a packet that we generated ourselves, fragmenting it if it exceeds the link MTU.
+    fn send_packet(&mut self, packet: SmolPacket) {
+        let ident = self.next_fragment_ident();
+        for fragment in fragment_packet(packet, self.mtu, ident) {
+            if self.net_tx.try_send(fragment).is_err() {
+                log::debug!("Channel unavailable, discarding packet.");
+                return;
+            }
+        }
+    }
+
     pub fn handle_transport_command(&mut self, command: TransportCommand) {
-        if command.connection_id().is_tcp() {
+        if let TransportCommand::SendRawPacket(packet) = command {
+            if self.net_tx.try_send(packet).is_err() {
+                log::debug!("Channel unavailable, discarding raw packet.");
+            }
+            return;
+        }
+        let is_tcp = match &command {
+            TransportCommand::OpenConnection { udp, .. } => !udp,
+            other => other.connection_id().is_some_and(ConnectionId::is_tcp),
+        };
+        if is_tcp {
             self.tcp.handle_transport_command(command);
         } else if let Some(packet) = self.udp.handle_transport_command(command) {
-            if self
-                .net_tx
-                .try_send(NetworkCommand::SendPacket(SmolPacket::from(packet)))
-                .is_err()
-            {
-                log::debug!("Channel unavailable, discarding UDP packet.");
-            }
+            self.send_packet(packet);
         }
     }
 
     pub fn poll_delay(&mut self) -> Option<Duration> {
-        match (self.tcp.poll_delay(), self.udp.poll_delay()) {
-            (Some(a), Some(b)) => Some(min(a, b)),
-            (Some(a), None) => Some(a),
-            (None, Some(b)) => Some(b),
-            (None, None) => None,
-        }
+        [
+            self.tcp.poll_delay(),
+            self.udp.poll_delay(),
+            self.fragments.poll_delay(Instant::now()),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
     }
 
     pub fn poll(&mut self) -> Result<()> {
+        self.fragments.expire(Instant::now());
         self.udp.poll();
         self.tcp.poll()
     }
+
+    /// Take the events that have accumulated since the last call and are not a direct response
+    /// to a packet, e.g. `ConnectionClosed` events.
+    pub fn take_events(&mut self) -> Vec<TransportEvent> {
+        let mut events = self.tcp.take_events();
+        events.extend(self.udp.take_closed_connections());
+        events
+    }
+
+    /// Report all remaining connections as closed because the server is shutting down.
+    pub fn shutdown(&mut self) -> Vec<TransportEvent> {
+        let mut events = self.tcp.shutdown();
+        events.extend(self.udp.shutdown());
+        events
+    }
 }
 
 impl fmt::Debug for NetworkStack<'_> {
@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use hickory_resolver::proto::op::{Message, MessageType, OpCode, ResponseCode};
+use hickory_resolver::proto::rr::rdata::{A, AAAA};
+use hickory_resolver::proto::rr::{RData, Record, RecordType};
+
+use crate::dns::{DnsResolver, ResolveErrorKind};
+use crate::network::udp::UdpPacket;
+
+/// Synthetic IPv4 addresses are taken from 198.18.0.0/15, which is reserved for benchmarking
+/// (RFC 2544) and therefore unlikely to clash with real destinations.
+const FAKE_IPV4_BASE: Ipv4Addr = Ipv4Addr::new(198, 18, 0, 0);
+/// Synthetic IPv6 addresses are taken from a unique local /64.
+const FAKE_IPV6_BASE: Ipv6Addr = Ipv6Addr::new(0xfd00, 0x6d69, 0x746d, 0, 0, 0, 0, 0);
+/// How many addresses each pool hands out before reusing the oldest one.
+const POOL_SIZE: u32 = 1 << 17;
+/// TTL of our synthetic records. Clients should not cache them for long,
+/// as the address may be assigned to another hostname once the pool wraps around.
+const FAKE_TTL: u32 = 60;
+
+/// A range of synthetic addresses, each of which is assigned to one hostname.
+#[derive(Debug)]
+struct AddressPool {
+    base: IpAddr,
+    size: u32,
+    next: u32,
+    offsets: HashMap<String, u32>,
+    hostnames: HashMap<u32, String>,
+}
+
+impl AddressPool {
+    fn new(base: IpAddr, size: u32) -> Self {
+        Self {
+            base,
+            size,
+            // Skip the network address itself.
+            next: 1,
+            offsets: HashMap::new(),
+            hostnames: HashMap::new(),
+        }
+    }
+
+    /// Get the address assigned to `hostname`, assigning a new one if necessary.
+    /// Once the pool is exhausted, the oldest assignment is evicted.
+    fn assign(&mut self, hostname: &str) -> IpAddr {
+        if let Some(&offset) = self.offsets.get(hostname) {
+            return self.address(offset);
+        }
+        let offset = self.next;
+        self.next = if offset + 1 < self.size {
+            offset + 1
+        } else {
+            1
+        };
+        if let Some(evicted) = self.hostnames.insert(offset, hostname.to_string()) {
+            log::debug!("Fake DNS address pool exhausted, evicting {}.", evicted);
+            self.offsets.remove(&evicted);
+        }
+        self.offsets.insert(hostname.to_string(), offset);
+        self.address(offset)
+    }
+
+    fn hostname(&self, addr: IpAddr) -> Option<&str> {
+        let offset = match (self.base, addr) {
+            (IpAddr::V4(base), IpAddr::V4(addr)) => u32::from(addr).checked_sub(u32::from(base))?,
+            (IpAddr::V6(base), IpAddr::V6(addr)) => {
+                u32::try_from(u128::from(addr).checked_sub(u128::from(base))?).ok()?
+            }
+            _ => return None,
+        };
+        self.hostnames.get(&offset).map(String::as_str)
+    }
+
+    fn address(&self, offset: u32) -> IpAddr {
+        match self.base {
+            IpAddr::V4(base) => Ipv4Addr::from(u32::from(base) + offset).into(),
+            IpAddr::V6(base) => Ipv6Addr::from(u128::from(base) + u128::from(offset)).into(),
+        }
+    }
+}
+
+/// A DNS responder that answers A and AAAA queries with synthetic addresses and remembers which
+/// hostname each address was handed out for. This allows us to recover the hostname a client
+/// wanted to connect to even if the connection itself does not contain it.
+pub(super) struct FakeDns {
+    resolver_addrs: Vec<IpAddr>,
+    upstream: Option<Arc<DnsResolver>>,
+    v4: AddressPool,
+    v6: AddressPool,
+}
+
+pub(super) enum DnsAnswer {
+    /// We can answer the query right away.
+    Local(UdpPacket),
+    /// The query needs to be forwarded to the system resolver.
+    Upstream(UpstreamQuery),
+}
+
+impl FakeDns {
+    pub fn new(resolver_addrs: Vec<IpAddr>) -> Self {
+        let upstream = match DnsResolver::new(None, true) {
+            Ok(resolver) => Some(Arc::new(resolver)),
+            Err(e) => {
+                log::warn!(
+                    "Failed to read system DNS configuration, only A and AAAA queries will be answered: {}",
+                    e
+                );
+                None
+            }
+        };
+        Self {
+            resolver_addrs,
+            upstream,
+            v4: AddressPool::new(FAKE_IPV4_BASE.into(), POOL_SIZE),
+            v6: AddressPool::new(FAKE_IPV6_BASE.into(), POOL_SIZE),
+        }
+    }
+
+    /// Check if a packet is a DNS query directed at us.
+    pub fn is_query(&self, packet: &UdpPacket) -> bool {
+        packet.dst_addr.port() == 53 && self.resolver_addrs.contains(&packet.dst_addr.ip())
+    }
+
+    /// Get the hostname that a synthetic address has been handed out for.
+    pub fn hostname(&self, addr: IpAddr) -> Option<&str> {
+        match addr {
+            IpAddr::V4(_) => self.v4.hostname(addr),
+            IpAddr::V6(_) => self.v6.hostname(addr),
+        }
+    }
+
+    pub fn handle_query(&mut self, packet: UdpPacket) -> Option<DnsAnswer> {
+        let request = match Message::from_vec(&packet.payload) {
+            Ok(request) if request.message_type() == MessageType::Query => request,
+            Ok(_) => return None,
+            Err(e) => {
+                log::debug!("Received invalid DNS query: {}", e);
+                return None;
+            }
+        };
+        let mut response = Message::new();
+        response
+            .set_id(request.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(request.op_code())
+            .set_recursion_desired(request.recursion_desired())
+            .set_recursion_available(true)
+            .add_queries(request.queries().iter().cloned());
+        let mut reply = Reply {
+            src_addr: packet.dst_addr,
+            dst_addr: packet.src_addr,
+            max_payload: request.max_payload(),
+            response,
+        };
+
+        let query = match request.queries() {
+            [query] if request.op_code() == OpCode::Query => query,
+            [_] => return reply.error(ResponseCode::NotImp).map(DnsAnswer::Local),
+            _ => return reply.error(ResponseCode::FormErr).map(DnsAnswer::Local),
+        };
+        let pool = match query.query_type() {
+            RecordType::A => &mut self.v4,
+            RecordType::AAAA => &mut self.v6,
+            record_type => {
+                let Some(resolver) = self.upstream.clone() else {
+                    return reply.error(ResponseCode::ServFail).map(DnsAnswer::Local);
+                };
+                return Some(DnsAnswer::Upstream(UpstreamQuery {
+                    resolver,
+                    record_type,
+                    reply,
+                }));
+            }
+        };
+        let hostname = query.name().to_lowercase().to_ascii();
+        let rdata = match pool.assign(hostname.trim_end_matches('.')) {
+            IpAddr::V4(addr) => RData::A(A::from(addr)),
+            IpAddr::V6(addr) => RData::AAAA(AAAA::from(addr)),
+        };
+        reply
+            .response
+            .add_answer(Record::from_rdata(query.name().clone(), FAKE_TTL, rdata));
+        reply.encode().map(DnsAnswer::Local)
+    }
+}
+
+/// A query for a record type other than A or AAAA, which is resolved by the system resolver.
+pub(super) struct UpstreamQuery {
+    resolver: Arc<DnsResolver>,
+    record_type: RecordType,
+    reply: Reply,
+}
+
+impl UpstreamQuery {
+    pub async fn resolve(self) -> Option<UdpPacket> {
+        let UpstreamQuery {
+            resolver,
+            record_type,
+            mut reply,
+        } = self;
+        let name = reply.response.queries()[0].name().clone();
+        match resolver.lookup(name, record_type).await {
+            Ok(records) => {
+                reply.response.add_answers(records);
+                reply.encode()
+            }
+            Err(e) => match e.kind() {
+                ResolveErrorKind::NoRecordsFound { response_code, .. } => {
+                    reply.error(*response_code)
+                }
+                _ => {
+                    log::debug!("Failed to resolve {} query: {}", record_type, e);
+                    reply.error(ResponseCode::ServFail)
+                }
+            },
+        }
+    }
+}
+
+/// A DNS response under construction, together with where it needs to be sent.
+struct Reply {
+    src_addr: SocketAddr,
+    dst_addr: SocketAddr,
+    max_payload: u16,
+    response: Message,
+}
+
+impl Reply {
+    fn error(mut self, response_code: ResponseCode) -> Option<UdpPacket> {
+        self.response.set_response_code(response_code);
+        self.encode()
+    }
+
+    fn encode(mut self) -> Option<UdpPacket> {
+        let mut payload = self.response.to_vec();
+        if payload
+            .as_ref()
+            .is_ok_and(|p| p.len() > usize::from(self.max_payload))
+        {
+            // Let the client retry over TCP, which is passed on to Python as usual.
+            self.response.take_answers();
+            self.response.set_truncated(true);
+            payload = self.response.to_vec();
+        }
+        match payload {
+            Ok(payload) => Some(UdpPacket {
+                src_addr: self.src_addr,
+                dst_addr: self.dst_addr,
+                payload,
+            }),
+            Err(e) => {
+                log::error!("Failed to encode DNS response: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pool_assign() {
+        let mut pool = AddressPool::new(FAKE_IPV4_BASE.into(), POOL_SIZE);
+        let a = pool.assign("example.com");
+        let b = pool.assign("example.org");
+        assert_eq!(a, IpAddr::from([198, 18, 0, 1]));
+        assert_eq!(b, IpAddr::from([198, 18, 0, 2]));
+        assert_eq!(pool.assign("example.com"), a);
+        assert_eq!(pool.hostname(a), Some("example.com"));
+        assert_eq!(pool.hostname(b), Some("example.org"));
+        assert_eq!(pool.hostname(IpAddr::from([198, 18, 0, 3])), None);
+        assert_eq!(pool.hostname(IpAddr::from([10, 0, 0, 1])), None);
+
+        let mut pool = AddressPool::new(FAKE_IPV6_BASE.into(), POOL_SIZE);
+        let a = pool.assign("example.com");
+        assert_eq!(a, "fd00:6d69:746d::1".parse::<IpAddr>().unwrap());
+        assert_eq!(pool.hostname(a), Some("example.com"));
+        assert_eq!(pool.hostname("fd00::1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn pool_eviction() {
+        let mut pool = AddressPool::new(FAKE_IPV4_BASE.into(), 3);
+        let a = pool.assign("a.example");
+        let b = pool.assign("b.example");
+        let c = pool.assign("c.example");
+        assert_eq!(c, a);
+        assert_eq!(pool.hostname(a), Some("c.example"));
+        assert_eq!(pool.hostname(b), Some("b.example"));
+        assert_eq!(pool.assign("a.example"), b);
+        assert_eq!(pool.hostname(b), Some("a.example"));
+    }
+}
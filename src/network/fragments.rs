@@ -0,0 +1,632 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use smoltcp::wire::{
+    IpProtocol, Ipv4Packet, Ipv6ExtHeader, Ipv6FragmentHeader, Ipv6FragmentRepr, Ipv6Packet,
+    IPV4_MIN_MTU, IPV6_HEADER_LEN, IPV6_MIN_MTU,
+};
+
+use crate::messages::SmolPacket;
+use crate::network::MAX_PACKET_SIZE;
+
+/// Upper bound for the number of bytes buffered across all incomplete datagrams.
+pub const REASSEMBLY_BUDGET: usize = 4 * 1024 * 1024;
+/// Incomplete datagrams are discarded if they are not complete this long after the first fragment arrived.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FragmentKey {
+    src: IpAddr,
+    dst: IpAddr,
+    /// Only part of the key for IPv4, for IPv6 the protocol is not known before reassembly.
+    protocol: Option<IpProtocol>,
+    ident: u32,
+}
+
+/// What we need to know about a single fragment.
+struct FragmentInfo {
+    key: FragmentKey,
+    /// Offset of the fragment data in the reassembled payload.
+    offset: usize,
+    more_frags: bool,
+    /// Length of the headers that precede the fragment data.
+    /// For IPv6, this includes the fragment header itself.
+    data_start: usize,
+    /// The headers that are copied into the reassembled packet.
+    /// For IPv6, these are the fixed header and all extension headers before the fragment header.
+    header_len: usize,
+    /// For IPv6, the position of the next header field that points to the fragment header,
+    /// and the next header value of the fragment header itself.
+    next_header: Option<(usize, u8)>,
+}
+
+/// The first fragment of a datagram (the one with offset zero), which determines the headers.
+struct FirstFragment {
+    header: Vec<u8>,
+    next_header: Option<(usize, u8)>,
+}
+
+struct FragmentBuffer {
+    first: Option<FirstFragment>,
+    fragments: BTreeMap<usize, Vec<u8>>,
+    /// Length of the reassembled payload, known once the last fragment has arrived.
+    total_len: Option<usize>,
+    /// Number of bytes counted against the reassembly budget.
+    size: usize,
+    deadline: Instant,
+}
+
+impl FragmentBuffer {
+    fn new(deadline: Instant) -> Self {
+        Self {
+            first: None,
+            fragments: BTreeMap::new(),
+            total_len: None,
+            size: 0,
+            deadline,
+        }
+    }
+
+    /// Add fragment data at the given offset.
+    /// Exact duplicates are ignored, all other overlaps are rejected (RFC 5722).
+    fn insert(&mut self, offset: usize, data: &[u8], more_frags: bool) -> Result<(), &'static str> {
+        let end = offset + data.len();
+        if more_frags {
+            if self.total_len.is_some_and(|len| end > len) {
+                return Err("fragment extends beyond the last fragment");
+            }
+        } else {
+            if self.total_len.is_some_and(|len| len != end) {
+                return Err("conflicting last fragments");
+            }
+            if self
+                .fragments
+                .last_key_value()
+                .is_some_and(|(o, d)| o + d.len() > end)
+            {
+                return Err("fragment extends beyond the last fragment");
+            }
+        }
+
+        if self
+            .fragments
+            .get(&offset)
+            .is_some_and(|existing| existing.as_slice() == data)
+        {
+            return Ok(());
+        }
+        // Fragments don't overlap each other, so we only need to check the closest one.
+        if let Some((o, d)) = self.fragments.range(..end.max(offset + 1)).next_back() {
+            if o + d.len() > offset {
+                return Err("overlapping fragments");
+            }
+        }
+
+        if !more_frags {
+            self.total_len = Some(end);
+        }
+        self.size += data.len();
+        self.fragments.insert(offset, data.to_vec());
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        let (Some(total_len), Some(_)) = (self.total_len, &self.first) else {
+            return false;
+        };
+        let mut pos = 0;
+        for (offset, data) in &self.fragments {
+            if *offset != pos {
+                return false;
+            }
+            pos += data.len();
+        }
+        pos == total_len
+    }
+
+    fn assemble(self) -> Option<SmolPacket> {
+        let first = self.first?;
+        let mut buf = first.header;
+        for data in self.fragments.into_values() {
+            buf.extend(data);
+        }
+
+        match first.next_header {
+            None => {
+                let total_len = u16::try_from(buf.len()).ok()?;
+                let mut packet = Ipv4Packet::new_unchecked(buf);
+                packet.set_total_len(total_len);
+                packet.set_more_frags(false);
+                packet.set_frag_offset(0);
+                packet.fill_checksum();
+                Some(SmolPacket::V4(packet))
+            }
+            Some((pos, next_header)) => {
+                buf[pos] = next_header;
+                let payload_len = u16::try_from(buf.len() - IPV6_HEADER_LEN).ok()?;
+                let mut packet = Ipv6Packet::new_unchecked(buf);
+                packet.set_payload_len(payload_len);
+                Some(SmolPacket::V6(packet))
+            }
+        }
+    }
+}
+
+/// Reassembles fragmented IPv4 and IPv6 packets before they are dispatched to the protocol handlers.
+pub struct FragmentReassembler {
+    buffers: HashMap<FragmentKey, FragmentBuffer>,
+    /// Number of bytes currently buffered across all datagrams.
+    buffered: usize,
+    budget: usize,
+    timeout: Duration,
+}
+
+impl FragmentReassembler {
+    pub fn new(budget: usize, timeout: Duration) -> Self {
+        Self {
+            buffers: HashMap::new(),
+            buffered: 0,
+            budget,
+            timeout,
+        }
+    }
+
+    /// Feed a packet into the reassembler.
+    ///
+    /// Unfragmented packets are returned unchanged. Fragments are buffered, and the reassembled
+    /// packet is returned once the last missing fragment arrives.
+    pub fn process(&mut self, packet: SmolPacket, now: Instant) -> Option<SmolPacket> {
+        let info = match fragment_info(&packet) {
+            Ok(Some(info)) => info,
+            Ok(None) => return Some(packet),
+            Err(e) => {
+                log::debug!("Received invalid IP fragment: {}", e);
+                return None;
+            }
+        };
+        let len = packet_len(&packet);
+        let bytes = packet.into_inner();
+        let data = &bytes[info.data_start..len];
+
+        if self.buffered + data.len() > self.budget {
+            self.expire(now);
+            if self.buffered + data.len() > self.budget {
+                log::debug!("Fragment reassembly budget exceeded, dropping fragment.");
+                return None;
+            }
+        }
+
+        let timeout = self.timeout;
+        let buffer = self
+            .buffers
+            .entry(info.key)
+            .or_insert_with(|| FragmentBuffer::new(now + timeout));
+        let size_before = buffer.size;
+        if let Err(e) = buffer.insert(info.offset, data, info.more_frags) {
+            log::debug!("Discarding fragmented packet: {}", e);
+            let buffer = self.buffers.remove(&info.key).unwrap();
+            self.buffered -= buffer.size;
+            return None;
+        }
+        if info.offset == 0 && buffer.first.is_none() {
+            buffer.first = Some(FirstFragment {
+                header: bytes[..info.header_len].to_vec(),
+                next_header: info.next_header,
+            });
+            buffer.size += info.header_len;
+        }
+        self.buffered += buffer.size - size_before;
+
+        if !buffer.is_complete() {
+            return None;
+        }
+        let buffer = self.buffers.remove(&info.key).unwrap();
+        self.buffered -= buffer.size;
+        let packet = buffer.assemble();
+        if packet.is_none() {
+            log::debug!("Discarding fragmented packet: reassembled packet is too large");
+        }
+        packet
+    }
+
+    /// Discard all incomplete datagrams whose reassembly timeout has expired.
+    pub fn expire(&mut self, now: Instant) {
+        let buffered = &mut self.buffered;
+        self.buffers.retain(|_, buffer| {
+            let keep = buffer.deadline > now;
+            if !keep {
+                *buffered -= buffer.size;
+            }
+            keep
+        });
+    }
+
+    pub fn poll_delay(&self, now: Instant) -> Option<Duration> {
+        self.buffers
+            .values()
+            .map(|buffer| buffer.deadline.saturating_duration_since(now))
+            .min()
+    }
+}
+
+/// Determine whether a packet is a fragment. Returns `None` for regular packets.
+fn fragment_info(packet: &SmolPacket) -> Result<Option<FragmentInfo>, &'static str> {
+    let info = match packet {
+        SmolPacket::V4(packet) => {
+            if !packet.more_frags() && packet.frag_offset() == 0 {
+                return Ok(None);
+            }
+            let header_len = packet.header_len() as usize;
+            FragmentInfo {
+                key: FragmentKey {
+                    src: IpAddr::V4(packet.src_addr()),
+                    dst: IpAddr::V4(packet.dst_addr()),
+                    protocol: Some(packet.next_header()),
+                    ident: u32::from(packet.ident()),
+                },
+                offset: packet.frag_offset() as usize,
+                more_frags: packet.more_frags(),
+                data_start: header_len,
+                header_len,
+                next_header: None,
+            }
+        }
+        SmolPacket::V6(packet) => {
+            let bytes = &packet.as_ref()[..IPV6_HEADER_LEN + packet.payload_len() as usize];
+            // Skip over the unfragmentable part to find the fragment header (RFC 8200, Section 4.5).
+            let mut next_header = packet.next_header();
+            let mut next_header_pos = 6;
+            let mut pos = IPV6_HEADER_LEN;
+            while matches!(
+                next_header,
+                IpProtocol::HopByHop | IpProtocol::Ipv6Route | IpProtocol::Ipv6Opts
+            ) {
+                let ext = bytes
+                    .get(pos..)
+                    .and_then(|b| Ipv6ExtHeader::new_checked(b).ok())
+                    .ok_or("truncated extension header")?;
+                next_header = ext.next_header();
+                next_header_pos = pos;
+                pos += 8 + ext.header_len() as usize * 8;
+            }
+            if next_header != IpProtocol::Ipv6Frag {
+                return Ok(None);
+            }
+            let ext = bytes
+                .get(pos..)
+                .and_then(|b| Ipv6ExtHeader::new_checked(b).ok())
+                .ok_or("truncated fragment header")?;
+            let fragment = Ipv6FragmentHeader::new_checked(ext.payload())
+                .map_err(|_| "truncated fragment header")?;
+            FragmentInfo {
+                key: FragmentKey {
+                    src: IpAddr::V6(packet.src_addr()),
+                    dst: IpAddr::V6(packet.dst_addr()),
+                    protocol: None,
+                    ident: fragment.ident(),
+                },
+                offset: fragment.frag_offset() as usize * 8,
+                more_frags: fragment.more_frags(),
+                data_start: pos + 8,
+                header_len: pos,
+                next_header: Some((next_header_pos, u8::from(ext.next_header()))),
+            }
+        }
+    };
+
+    let data_len = packet_len(packet) - info.data_start;
+    if info.more_frags && (data_len == 0 || data_len % 8 != 0) {
+        return Err("fragment length is not a multiple of 8");
+    }
+    if info.offset + data_len > MAX_PACKET_SIZE {
+        return Err("fragment extends beyond the maximum packet size");
+    }
+    Ok(Some(info))
+}
+
+/// Split a packet that we generated ourselves into fragments that fit into `mtu`.
+/// Packets that already fit are returned unchanged.
+///
+/// For IPv6, the fragment header is inserted directly after the IPv6 header, so this must not be
+/// used for packets with Hop-by-Hop or Routing headers.
+pub fn fragment_packet(packet: SmolPacket, mtu: usize, ident: u32) -> Vec<SmolPacket> {
+    let len = packet_len(&packet);
+    if len <= mtu {
+        return vec![packet];
+    }
+    match packet {
+        SmolPacket::V4(packet) => {
+            let header_len = packet.header_len() as usize;
+            let bytes = packet.into_inner();
+            let (header, payload) = bytes[..len].split_at(header_len);
+            let chunk_size = (mtu.max(IPV4_MIN_MTU) - header_len) & !7;
+            payload
+                .chunks(chunk_size)
+                .enumerate()
+                .map(|(i, data)| {
+                    let offset = i * chunk_size;
+                    let mut fragment = Ipv4Packet::new_unchecked([header, data].concat());
+                    fragment.set_total_len((header_len + data.len()) as u16);
+                    fragment.set_ident(ident as u16);
+                    fragment.set_dont_frag(false);
+                    fragment.set_more_frags(offset + data.len() < payload.len());
+                    fragment.set_frag_offset(offset as u16);
+                    fragment.fill_checksum();
+                    SmolPacket::V4(fragment)
+                })
+                .collect()
+        }
+        SmolPacket::V6(packet) => {
+            let next_header = packet.next_header();
+            let bytes = packet.into_inner();
+            let (header, payload) = bytes[..len].split_at(IPV6_HEADER_LEN);
+            let chunk_size = (mtu.max(IPV6_MIN_MTU) - IPV6_HEADER_LEN - 8) & !7;
+            payload
+                .chunks(chunk_size)
+                .enumerate()
+                .map(|(i, data)| {
+                    let offset = i * chunk_size;
+                    let mut buf = [header, &[0u8; 8], data].concat();
+                    let mut ext = Ipv6ExtHeader::new_unchecked(&mut buf[IPV6_HEADER_LEN..]);
+                    ext.set_next_header(next_header);
+                    ext.set_header_len(0);
+                    Ipv6FragmentRepr {
+                        frag_offset: (offset / 8) as u16,
+                        more_frags: offset + data.len() < payload.len(),
+                        ident,
+                    }
+                    .emit(&mut Ipv6FragmentHeader::new_unchecked(ext.payload_mut()));
+                    let mut fragment = Ipv6Packet::new_unchecked(buf);
+                    fragment.set_next_header(IpProtocol::Ipv6Frag);
+                    fragment.set_payload_len((8 + data.len()) as u16);
+                    SmolPacket::V6(fragment)
+                })
+                .collect()
+        }
+    }
+}
+
+fn packet_len(packet: &SmolPacket) -> usize {
+    match packet {
+        SmolPacket::V4(packet) => packet.total_len() as usize,
+        SmolPacket::V6(packet) => IPV6_HEADER_LEN + packet.payload_len() as usize,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::udp::UdpPacket;
+
+    const PAYLOAD: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCD";
+
+    fn udp_packet(v6: bool) -> SmolPacket {
+        let (src_addr, dst_addr) = if v6 {
+            (
+                "[fd00::1]:1234".parse().unwrap(),
+                "[fd00::2]:53".parse().unwrap(),
+            )
+        } else {
+            (
+                "10.0.0.1:1234".parse().unwrap(),
+                "10.0.0.2:53".parse().unwrap(),
+            )
+        };
+        SmolPacket::from(UdpPacket {
+            src_addr,
+            dst_addr,
+            payload: PAYLOAD.to_vec(),
+        })
+    }
+
+    /// Cut the fragment at `offset..offset + len` of the IP payload out of `packet`.
+    fn fragment(packet: &SmolPacket, ident: u32, offset: usize, len: usize) -> SmolPacket {
+        match packet {
+            SmolPacket::V4(packet) => {
+                let header_len = packet.header_len() as usize;
+                let payload = &packet.as_ref()[header_len..packet.total_len() as usize];
+                let more_frags = offset + len < payload.len();
+                let mut buf = packet.as_ref()[..header_len].to_vec();
+                buf.extend(&payload[offset..offset + len]);
+                let mut fragment = Ipv4Packet::new_unchecked(buf);
+                fragment.set_total_len((header_len + len) as u16);
+                fragment.set_ident(ident as u16);
+                fragment.set_dont_frag(false);
+                fragment.set_more_frags(more_frags);
+                fragment.set_frag_offset(offset as u16);
+                fragment.fill_checksum();
+                SmolPacket::V4(fragment)
+            }
+            SmolPacket::V6(packet) => {
+                let payload = &packet.as_ref()[IPV6_HEADER_LEN..];
+                let more_frags = offset + len < payload.len();
+                let mut buf = packet.as_ref()[..IPV6_HEADER_LEN].to_vec();
+                buf.extend([0u8; 8]);
+                buf.extend(&payload[offset..offset + len]);
+                let mut ext = Ipv6ExtHeader::new_unchecked(&mut buf[IPV6_HEADER_LEN..]);
+                ext.set_next_header(packet.next_header());
+                ext.set_header_len(0);
+                Ipv6FragmentRepr {
+                    frag_offset: (offset / 8) as u16,
+                    more_frags,
+                    ident,
+                }
+                .emit(&mut Ipv6FragmentHeader::new_unchecked(ext.payload_mut()));
+                let mut fragment = Ipv6Packet::new_unchecked(buf);
+                fragment.set_next_header(IpProtocol::Ipv6Frag);
+                fragment.set_payload_len((8 + len) as u16);
+                SmolPacket::V6(fragment)
+            }
+        }
+    }
+
+    fn reassembler() -> FragmentReassembler {
+        FragmentReassembler::new(REASSEMBLY_BUDGET, REASSEMBLY_TIMEOUT)
+    }
+
+    fn assert_reassembled(packet: Option<SmolPacket>) {
+        let packet = UdpPacket::try_from(packet.expect("packet should be complete")).unwrap();
+        assert_eq!(packet.payload, PAYLOAD);
+    }
+
+    #[test]
+    fn unfragmented() {
+        let mut reassembler = reassembler();
+        let packet = udp_packet(false);
+        assert_reassembled(reassembler.process(packet, Instant::now()));
+        assert_eq!(reassembler.buffered, 0);
+    }
+
+    #[test]
+    fn out_of_order() {
+        for v6 in [false, true] {
+            let mut reassembler = reassembler();
+            let now = Instant::now();
+            let packet = udp_packet(v6);
+            assert!(reassembler
+                .process(fragment(&packet, 1, 32, 16), now)
+                .is_none());
+            assert!(reassembler
+                .process(fragment(&packet, 1, 0, 16), now)
+                .is_none());
+            assert_reassembled(reassembler.process(fragment(&packet, 1, 16, 16), now));
+            assert_eq!(reassembler.buffered, 0);
+        }
+    }
+
+    #[test]
+    fn interleaved_datagrams() {
+        let mut reassembler = reassembler();
+        let now = Instant::now();
+        let packet = udp_packet(false);
+        assert!(reassembler
+            .process(fragment(&packet, 1, 0, 24), now)
+            .is_none());
+        assert!(reassembler
+            .process(fragment(&packet, 2, 24, 24), now)
+            .is_none());
+        assert_reassembled(reassembler.process(fragment(&packet, 2, 0, 24), now));
+        assert_reassembled(reassembler.process(fragment(&packet, 1, 24, 24), now));
+    }
+
+    #[test]
+    fn duplicate_fragments() {
+        let mut reassembler = reassembler();
+        let now = Instant::now();
+        let packet = udp_packet(true);
+        for _ in 0..2 {
+            assert!(reassembler
+                .process(fragment(&packet, 1, 0, 16), now)
+                .is_none());
+        }
+        assert!(reassembler
+            .process(fragment(&packet, 1, 16, 16), now)
+            .is_none());
+        assert_reassembled(reassembler.process(fragment(&packet, 1, 32, 16), now));
+    }
+
+    #[test]
+    fn overlapping_fragments() {
+        for v6 in [false, true] {
+            let mut reassembler = reassembler();
+            let now = Instant::now();
+            let packet = udp_packet(v6);
+            assert!(reassembler
+                .process(fragment(&packet, 1, 0, 24), now)
+                .is_none());
+            assert!(reassembler
+                .process(fragment(&packet, 1, 16, 16), now)
+                .is_none());
+            // the whole datagram is discarded.
+            assert_eq!(reassembler.buffered, 0);
+            assert!(reassembler
+                .process(fragment(&packet, 1, 24, 24), now)
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn invalid_fragment_length() {
+        let mut reassembler = reassembler();
+        let packet = udp_packet(false);
+        assert!(reassembler
+            .process(fragment(&packet, 1, 0, 12), Instant::now())
+            .is_none());
+        assert_eq!(reassembler.buffered, 0);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut reassembler = reassembler();
+        let now = Instant::now();
+        let packet = udp_packet(false);
+        assert!(reassembler
+            .process(fragment(&packet, 1, 0, 24), now)
+            .is_none());
+        assert_eq!(reassembler.poll_delay(now), Some(REASSEMBLY_TIMEOUT));
+
+        let later = now + REASSEMBLY_TIMEOUT;
+        reassembler.expire(later);
+        assert_eq!(reassembler.buffered, 0);
+        assert_eq!(reassembler.poll_delay(later), None);
+        assert!(reassembler
+            .process(fragment(&packet, 1, 24, 24), later)
+            .is_none());
+    }
+
+    #[test]
+    fn fragment_and_reassemble() {
+        for v6 in [false, true] {
+            let mut reassembler = reassembler();
+            let now = Instant::now();
+            let packet = SmolPacket::from(UdpPacket {
+                src_addr: if v6 { "[fd00::1]:53" } else { "10.0.0.1:53" }
+                    .parse()
+                    .unwrap(),
+                dst_addr: if v6 {
+                    "[fd00::2]:1234"
+                } else {
+                    "10.0.0.2:1234"
+                }
+                .parse()
+                .unwrap(),
+                payload: vec![42; 4000],
+            });
+            let fragments = fragment_packet(packet, 1280, 7);
+            assert_eq!(fragments.len(), 4);
+
+            let mut reassembled = None;
+            for fragment in fragments.into_iter().rev() {
+                assert!(packet_len(&fragment) <= 1280);
+                assert!(reassembled.is_none());
+                reassembled = reassembler.process(fragment, now);
+            }
+            let packet = UdpPacket::try_from(reassembled.unwrap()).unwrap();
+            assert_eq!(packet.payload, vec![42; 4000]);
+        }
+    }
+
+    #[test]
+    fn fragment_small_packet() {
+        let packet = udp_packet(false);
+        let fragments = fragment_packet(packet.clone(), 1280, 7);
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fragments[0].clone().into_inner(), packet.into_inner());
+    }
+
+    #[test]
+    fn budget() {
+        let mut reassembler = FragmentReassembler::new(32, REASSEMBLY_TIMEOUT);
+        let now = Instant::now();
+        let packet = udp_packet(false);
+        assert!(reassembler
+            .process(fragment(&packet, 1, 24, 24), now)
+            .is_none());
+        // this would exceed the budget.
+        assert!(reassembler
+            .process(fragment(&packet, 2, 24, 24), now)
+            .is_none());
+        assert_eq!(reassembler.buffers.len(), 1);
+    }
+}
@@ -1,111 +1,320 @@
-use crate::messages::SmolPacket;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::time::{timeout_at, Instant};
+
+use crate::messages::{Rejection, SmolPacket, UnreachableCode};
+use crate::network::MAX_PACKET_SIZE;
 use smoltcp::phy::ChecksumCapabilities;
 use smoltcp::wire::{
-    Icmpv4Message, Icmpv4Packet, Icmpv4Repr, Icmpv6Message, Icmpv6Packet, Icmpv6Repr, IpProtocol,
-    Ipv4Packet, Ipv4Repr, Ipv6Packet, Ipv6Repr,
+    Icmpv4DstUnreachable, Icmpv4Message, Icmpv4Packet, Icmpv4Repr, Icmpv4TimeExceeded,
+    Icmpv6DstUnreachable, Icmpv6Message, Icmpv6Packet, Icmpv6ParamProblem, Icmpv6Repr,
+    Icmpv6TimeExceeded, IpProtocol, Ipv4Packet, Ipv4Repr, Ipv6Packet, Ipv6Repr, IPV6_MIN_MTU,
 };
 
-pub(super) fn handle_icmpv4_echo_request(
-    mut input_packet: Ipv4Packet<Vec<u8>>,
-) -> Option<SmolPacket> {
-    let src_addr = input_packet.src_addr();
-    let dst_addr = input_packet.dst_addr();
+/// How long we wait for the reply to a forwarded echo request.
+pub(super) const ECHO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An ICMP(v6) echo request received from a client.
+#[derive(Debug)]
+pub(super) struct EchoRequest {
+    pub src_addr: IpAddr,
+    pub dst_addr: IpAddr,
+    pub ident: u16,
+    pub seq_no: u16,
+    pub data: Vec<u8>,
+}
 
-    // Parsing ICMP Packet
-    let mut input_icmpv4_packet = match Icmpv4Packet::new_checked(input_packet.payload_mut()) {
-        Ok(p) => p,
-        Err(e) => {
-            log::debug!("Received invalid ICMPv4 packet: {}", e);
-            return None;
+impl EchoRequest {
+    /// Parse an echo request, ignoring all other ICMP types.
+    pub fn parse(packet: SmolPacket) -> Option<Self> {
+        match packet {
+            SmolPacket::V4(mut packet) => {
+                let src_addr = packet.src_addr().into();
+                let dst_addr = packet.dst_addr().into();
+                let icmp = match Icmpv4Packet::new_checked(&packet.payload_mut()[..]) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        log::debug!("Received invalid ICMPv4 packet: {}", e);
+                        return None;
+                    }
+                };
+                if icmp.msg_type() != Icmpv4Message::EchoRequest {
+                    log::debug!("Unsupported ICMPv4 packet of type: {}", icmp.msg_type());
+                    return None;
+                }
+                Some(Self {
+                    src_addr,
+                    dst_addr,
+                    ident: icmp.echo_ident(),
+                    seq_no: icmp.echo_seq_no(),
+                    data: icmp.data().to_vec(),
+                })
+            }
+            SmolPacket::V6(mut packet) => {
+                let src_addr = packet.src_addr().into();
+                let dst_addr = packet.dst_addr().into();
+                let icmp = match Icmpv6Packet::new_checked(&packet.payload_mut()[..]) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        log::debug!("Received invalid ICMPv6 packet: {}", e);
+                        return None;
+                    }
+                };
+                match icmp.msg_type() {
+                    Icmpv6Message::EchoRequest => (),
+                    Icmpv6Message::RouterSolicit => {
+                        // These happen in Linux local redirect mode, not investigated any further.
+                        log::debug!("Ignoring ICMPv6 router solicitation.");
+                        return None;
+                    }
+                    other => {
+                        log::debug!("Unsupported ICMPv6 packet of type: {other}");
+                        return None;
+                    }
+                }
+                Some(Self {
+                    src_addr,
+                    dst_addr,
+                    ident: icmp.echo_ident(),
+                    seq_no: icmp.echo_seq_no(),
+                    data: icmp.payload().to_vec(),
+                })
+            }
         }
-    };
+    }
 
-    // Checking that it is an ICMP Echo Request.
-    if input_icmpv4_packet.msg_type() != Icmpv4Message::EchoRequest {
-        log::debug!(
-            "Unsupported ICMPv4 packet of type: {}",
-            input_icmpv4_packet.msg_type()
-        );
-        return None;
+    /// Build an echo reply with the given data, directed back to the original source address.
+    pub fn reply(&self, data: &[u8]) -> SmolPacket {
+        match (self.src_addr, self.dst_addr) {
+            (IpAddr::V4(src_addr), IpAddr::V4(dst_addr)) => {
+                let icmp_repr = Icmpv4Repr::EchoReply {
+                    ident: self.ident,
+                    seq_no: self.seq_no,
+                    data,
+                };
+                let ip_repr = Ipv4Repr {
+                    src_addr: dst_addr,
+                    dst_addr: src_addr,
+                    next_header: IpProtocol::Icmp,
+                    payload_len: icmp_repr.buffer_len(),
+                    hop_limit: 255,
+                };
+                let buf = vec![0u8; ip_repr.buffer_len() + icmp_repr.buffer_len()];
+                let mut output_ipv4_packet = Ipv4Packet::new_unchecked(buf);
+                ip_repr.emit(&mut output_ipv4_packet, &ChecksumCapabilities::default());
+                icmp_repr.emit(
+                    &mut Icmpv4Packet::new_unchecked(output_ipv4_packet.payload_mut()),
+                    &ChecksumCapabilities::default(),
+                );
+                SmolPacket::from(output_ipv4_packet)
+            }
+            (IpAddr::V6(src_addr), IpAddr::V6(dst_addr)) => {
+                let icmp_repr = Icmpv6Repr::EchoReply {
+                    ident: self.ident,
+                    seq_no: self.seq_no,
+                    data,
+                };
+                let ip_repr = Ipv6Repr {
+                    src_addr: dst_addr,
+                    dst_addr: src_addr,
+                    next_header: IpProtocol::Icmpv6,
+                    payload_len: icmp_repr.buffer_len(),
+                    hop_limit: 255,
+                };
+                let buf = vec![0u8; ip_repr.buffer_len() + icmp_repr.buffer_len()];
+                let mut output_ipv6_packet = Ipv6Packet::new_unchecked(buf);
+                ip_repr.emit(&mut output_ipv6_packet);
+                icmp_repr.emit(
+                    &dst_addr,
+                    &src_addr,
+                    &mut Icmpv6Packet::new_unchecked(output_ipv6_packet.payload_mut()),
+                    &ChecksumCapabilities::default(),
+                );
+                SmolPacket::from(output_ipv6_packet)
+            }
+            _ => unreachable!("source and destination have the same address family"),
+        }
     }
 
-    // Creating fake response packet.
-    let icmp_repr = Icmpv4Repr::EchoReply {
-        ident: input_icmpv4_packet.echo_ident(),
-        seq_no: input_icmpv4_packet.echo_seq_no(),
-        data: input_icmpv4_packet.data_mut(),
-    };
-    let ip_repr = Ipv4Repr {
-        // Directing fake reply back to the original source address.
-        src_addr: dst_addr,
-        dst_addr: src_addr,
-        next_header: IpProtocol::Icmp,
-        payload_len: icmp_repr.buffer_len(),
-        hop_limit: 255,
-    };
-    let buf = vec![0u8; ip_repr.buffer_len() + icmp_repr.buffer_len()];
-    let mut output_ipv4_packet = Ipv4Packet::new_unchecked(buf);
-    ip_repr.emit(&mut output_ipv4_packet, &ChecksumCapabilities::default());
-    let mut output_ip_packet = SmolPacket::from(output_ipv4_packet);
-    icmp_repr.emit(
-        &mut Icmpv4Packet::new_unchecked(output_ip_packet.payload_mut()),
-        &ChecksumCapabilities::default(),
-    );
-    Some(output_ip_packet)
-}
+    /// Send this request to its real destination using an unprivileged ICMP socket and return
+    /// the data of the reply, or `None` if no reply arrives within [ECHO_TIMEOUT].
+    pub async fn forward(&self) -> Result<Option<Vec<u8>>> {
+        let deadline = Instant::now() + ECHO_TIMEOUT;
+        let socket = match self.dst_addr {
+            IpAddr::V4(_) => Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4)),
+            IpAddr::V6(_) => Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::ICMPV6)),
+        }
+        .context("Failed to create ICMP socket")?;
+        let socket: std::net::UdpSocket = socket.into();
+        socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(socket)?;
 
-pub(super) fn handle_icmpv6_echo_request(
-    mut input_packet: Ipv6Packet<Vec<u8>>,
-) -> Option<SmolPacket> {
-    let src_addr = input_packet.src_addr();
-    let dst_addr = input_packet.dst_addr();
+        // Linux replaces the identifier with the socket's own and computes the ICMPv6 checksum.
+        let mut request = vec![0u8; 8 + self.data.len()];
+        match self.dst_addr {
+            IpAddr::V4(_) => {
+                let mut icmp = Icmpv4Packet::new_unchecked(&mut request);
+                icmp.set_msg_type(Icmpv4Message::EchoRequest);
+                icmp.set_echo_ident(self.ident);
+                icmp.set_echo_seq_no(self.seq_no);
+                icmp.data_mut().copy_from_slice(&self.data);
+                icmp.fill_checksum();
+            }
+            IpAddr::V6(_) => {
+                let mut icmp = Icmpv6Packet::new_unchecked(&mut request);
+                icmp.set_msg_type(Icmpv6Message::EchoRequest);
+                icmp.set_echo_ident(self.ident);
+                icmp.set_echo_seq_no(self.seq_no);
+                icmp.payload_mut().copy_from_slice(&self.data);
+            }
+        }
+        socket
+            .send_to(&request, SocketAddr::new(self.dst_addr, 0))
+            .await
+            .with_context(|| format!("Failed to send echo request to {}", self.dst_addr))?;
 
-    // Parsing ICMP Packet
-    let mut input_icmpv6_packet = match Icmpv6Packet::new_checked(input_packet.payload_mut()) {
-        Ok(p) => p,
-        Err(e) => {
-            log::debug!("Received invalid ICMPv6 packet: {}", e);
-            return None;
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+        loop {
+            let Ok(received) = timeout_at(deadline, socket.recv(&mut buf)).await else {
+                return Ok(None);
+            };
+            let mut reply = &buf[..received?];
+            match self.dst_addr {
+                IpAddr::V4(_) => {
+                    // macOS includes the IPv4 header, echo replies start with a zero type byte.
+                    if reply.first().is_some_and(|b| b >> 4 == 4) {
+                        let Ok(ip) = Ipv4Packet::new_checked(reply) else {
+                            continue;
+                        };
+                        reply = &reply[ip.header_len() as usize..];
+                    }
+                    let Ok(icmp) = Icmpv4Packet::new_checked(reply) else {
+                        continue;
+                    };
+                    if icmp.msg_type() == Icmpv4Message::EchoReply
+                        && icmp.echo_seq_no() == self.seq_no
+                    {
+                        return Ok(Some(icmp.data().to_vec()));
+                    }
+                }
+                IpAddr::V6(_) => {
+                    let Ok(icmp) = Icmpv6Packet::new_checked(reply) else {
+                        continue;
+                    };
+                    if icmp.msg_type() == Icmpv6Message::EchoReply
+                        && icmp.echo_seq_no() == self.seq_no
+                    {
+                        return Ok(Some(icmp.payload().to_vec()));
+                    }
+                }
+            }
         }
-    };
+    }
+}
 
-    match input_icmpv6_packet.msg_type() {
-        Icmpv6Message::EchoRequest => (),
-        Icmpv6Message::RouterSolicit => {
-            // These happen in Linux local redirect mode, not investigated any further.
-            log::debug!("Ignoring ICMPv6 router solicitation.");
-            return None;
+/// Build the ICMP(v6) error message for `rejection` in response to `packet`,
+/// or `None` for [Rejection::Reset].
+/// As much of the original packet is quoted as the respective RFCs recommend.
+pub(super) fn icmp_error(packet: &SmolPacket, rejection: Rejection) -> Option<SmolPacket> {
+    match packet {
+        SmolPacket::V4(packet) => {
+            let input_packet = Ipv4Packet::new_unchecked(packet.as_ref());
+            let header = Ipv4Repr::parse(&input_packet, &ChecksumCapabilities::ignored()).ok()?;
+            // RFC 792: internet header + 64 bits of original data datagram
+            let payload = input_packet.payload();
+            let data = &payload[..payload.len().min(8)];
+            let icmp_repr = match rejection {
+                Rejection::Reset => return None,
+                Rejection::Unreachable(code) => Icmpv4Repr::DstUnreachable {
+                    reason: match code {
+                        UnreachableCode::Network => Icmpv4DstUnreachable::NetUnreachable,
+                        UnreachableCode::Host => Icmpv4DstUnreachable::HostUnreachable,
+                        UnreachableCode::Protocol => Icmpv4DstUnreachable::ProtoUnreachable,
+                        UnreachableCode::Port => Icmpv4DstUnreachable::PortUnreachable,
+                        UnreachableCode::Prohibited => Icmpv4DstUnreachable::CommProhibited,
+                    },
+                    header,
+                    data,
+                },
+                Rejection::TimeExceeded => Icmpv4Repr::TimeExceeded {
+                    reason: Icmpv4TimeExceeded::TtlExpired,
+                    header,
+                    data,
+                },
+            };
+            let ip_repr = Ipv4Repr {
+                src_addr: header.dst_addr,
+                dst_addr: header.src_addr,
+                next_header: IpProtocol::Icmp,
+                payload_len: icmp_repr.buffer_len(),
+                hop_limit: 255,
+            };
+            let buf = vec![0u8; ip_repr.buffer_len() + icmp_repr.buffer_len()];
+            let mut output_ipv4_packet = Ipv4Packet::new_unchecked(buf);
+            ip_repr.emit(&mut output_ipv4_packet, &ChecksumCapabilities::default());
+            icmp_repr.emit(
+                &mut Icmpv4Packet::new_unchecked(output_ipv4_packet.payload_mut()),
+                &ChecksumCapabilities::default(),
+            );
+            Some(SmolPacket::from(output_ipv4_packet))
         }
-        other => {
-            log::debug!("Unsupported ICMPv6 packet of type: {other}");
-            return None;
+        SmolPacket::V6(packet) => {
+            let input_packet = Ipv6Packet::new_unchecked(packet.as_ref());
+            let header = Ipv6Repr::parse(&input_packet).ok()?;
+            // RFC 4443: as much of invoking packet as possible without exceeding the minimum MTU
+            let payload = input_packet.payload();
+            let max_len = IPV6_MIN_MTU - 2 * header.buffer_len() - 8;
+            let data = &payload[..payload.len().min(max_len)];
+            let dst_unreachable = |reason| Icmpv6Repr::DstUnreachable {
+                reason,
+                header,
+                data,
+            };
+            let icmp_repr = match rejection {
+                Rejection::Reset => return None,
+                Rejection::Unreachable(code) => match code {
+                    UnreachableCode::Network => dst_unreachable(Icmpv6DstUnreachable::NoRoute),
+                    UnreachableCode::Host => dst_unreachable(Icmpv6DstUnreachable::AddrUnreachable),
+                    // ICMPv6 reports unknown protocols as a problem with the next header field,
+                    // which we expect to be in the fixed header.
+                    UnreachableCode::Protocol => Icmpv6Repr::ParamProblem {
+                        reason: Icmpv6ParamProblem::UnrecognizedNxtHdr,
+                        pointer: 6,
+                        header,
+                        data,
+                    },
+                    UnreachableCode::Port => dst_unreachable(Icmpv6DstUnreachable::PortUnreachable),
+                    UnreachableCode::Prohibited => {
+                        dst_unreachable(Icmpv6DstUnreachable::AdminProhibit)
+                    }
+                },
+                Rejection::TimeExceeded => Icmpv6Repr::TimeExceeded {
+                    reason: Icmpv6TimeExceeded::HopLimitExceeded,
+                    header,
+                    data,
+                },
+            };
+            let ip_repr = Ipv6Repr {
+                src_addr: header.dst_addr,
+                dst_addr: header.src_addr,
+                next_header: IpProtocol::Icmpv6,
+                payload_len: icmp_repr.buffer_len(),
+                hop_limit: 255,
+            };
+            let buf = vec![0u8; ip_repr.buffer_len() + icmp_repr.buffer_len()];
+            let mut output_ipv6_packet = Ipv6Packet::new_unchecked(buf);
+            ip_repr.emit(&mut output_ipv6_packet);
+            icmp_repr.emit(
+                &header.dst_addr,
+                &header.src_addr,
+                &mut Icmpv6Packet::new_unchecked(output_ipv6_packet.payload_mut()),
+                &ChecksumCapabilities::default(),
+            );
+            Some(SmolPacket::from(output_ipv6_packet))
         }
     }
-
-    // Creating fake response packet.
-    let icmp_repr = Icmpv6Repr::EchoReply {
-        ident: input_icmpv6_packet.echo_ident(),
-        seq_no: input_icmpv6_packet.echo_seq_no(),
-        data: input_icmpv6_packet.payload_mut(),
-    };
-    let ip_repr = Ipv6Repr {
-        // Directing fake reply back to the original source address.
-        src_addr: dst_addr,
-        dst_addr: src_addr,
-        next_header: IpProtocol::Icmp,
-        payload_len: icmp_repr.buffer_len(),
-        hop_limit: 255,
-    };
-    let buf = vec![0u8; ip_repr.buffer_len() + icmp_repr.buffer_len()];
-    let mut output_ipv6_packet = Ipv6Packet::new_unchecked(buf);
-    ip_repr.emit(&mut output_ipv6_packet);
-    let mut output_ip_packet = SmolPacket::from(output_ipv6_packet);
-    icmp_repr.emit(
-        // Directing fake reply back to the original source address.
-        &dst_addr,
-        &src_addr,
-        &mut Icmpv6Packet::new_unchecked(output_ip_packet.payload_mut()),
-        &ChecksumCapabilities::default(),
-    );
-    Some(output_ip_packet)
 }
@@ -4,11 +4,82 @@ pub use task::NetworkTask;
 
 mod virtual_device;
 
+pub mod capture;
 mod core;
+mod fake_dns;
+mod fragments;
 mod icmp;
+mod packet_sender;
+mod peek;
+pub mod shaping;
+mod splice;
 mod tcp;
 #[cfg(test)]
 mod tests;
 pub(crate) mod udp;
 
+pub use peek::ClientHello;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use tokio::sync::watch;
+
 pub const MAX_PACKET_SIZE: usize = 65535;
+/// The link MTU we assume if none has been configured, matching WireGuard's default.
+pub const DEFAULT_MTU: usize = 1420;
+
+/// Configuration for the virtual network stack.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkConf {
+    /// If set, the TCP handshake is held back when a new connection comes in. Python needs to
+    /// accept or reject the connection with [crate::messages::TransportCommand::AcceptConnection] or
+    /// [crate::messages::TransportCommand::RejectConnection] first. Reading from or writing to
+    /// the connection accepts it implicitly. Connections that are still pending after this
+    /// timeout are rejected with a RST.
+    pub tcp_accept_timeout: Option<std::time::Duration>,
+    /// If set, new TCP connections are only reported once the client has sent a complete TLS
+    /// ClientHello or something else, or after this timeout. This makes the ClientHello
+    /// available with [crate::messages::TransportEvent::ConnectionEstablished] without consuming
+    /// any data. Has no effect if `tcp_accept_timeout` is set, as the client cannot send any data
+    /// before the connection is accepted. UDP connections always report the ClientHello from
+    /// a QUIC Initial in their first datagram.
+    pub tcp_peek_timeout: Option<std::time::Duration>,
+    /// The MTU of the link towards the clients, [DEFAULT_MTU] if unset. TCP segments are sized
+    /// accordingly, larger packets that we send are fragmented.
+    pub mtu: Option<usize>,
+    /// Forward ICMP echo requests to their real destination instead of answering them ourselves.
+    /// This requires unprivileged ICMP sockets, see `net.ipv4.ping_group_range` on Linux.
+    pub forward_icmp: bool,
+    /// What to do with packets of IP protocols other than TCP, UDP and ICMP,
+    /// keyed by protocol number. Protocols without an entry are dropped.
+    pub protocol_actions: HashMap<u8, ProtocolAction>,
+    /// Answer DNS queries to these addresses ourselves. A and AAAA queries get synthetic
+    /// addresses, which let us report the hostname for connections to them as
+    /// `original_hostname`. All other queries are forwarded to the system resolver.
+    pub fake_dns_servers: Vec<IpAddr>,
+    /// If set, packets between the packet source and the network stack pass through a traffic
+    /// shaper that simulates the network conditions given here. Changes apply immediately.
+    /// Packets bypass the shaper while no network conditions are configured.
+    pub shaping: Option<watch::Receiver<shaping::ShapingConf>>,
+    /// If set, all packets that the network stack receives and sends are recorded
+    /// while a capture is running on the tap.
+    pub capture: Option<capture::CaptureTap>,
+}
+
+/// How packets of an IP protocol that we do not handle ourselves are treated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProtocolAction {
+    #[default]
+    Drop,
+    /// Pass the whole IP packet to Python as [crate::messages::TransportEvent::RawPacket].
+    Forward,
+    /// Answer with an ICMP protocol unreachable message (ICMPv6: unrecognized next header).
+    Unreachable,
+}
+
+impl NetworkConf {
+    pub fn mtu(&self) -> usize {
+        self.mtu.unwrap_or(DEFAULT_MTU)
+    }
+}
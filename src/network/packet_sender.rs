@@ -0,0 +1,154 @@
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::sync::mpsc::{Permit, Sender};
+
+use crate::messages::{NetworkCommand, SmolPacket};
+use crate::network::capture::CaptureTap;
+use crate::network::shaping::TrafficShaper;
+
+/// Sends the packets of the network stack to the packet source, or to the traffic shaper while
+/// it is active. Packets are recorded on the capture tap once they have been accepted.
+///
+/// Sending fails with [TrySendError::Full] if either the channel or the queue of the traffic
+/// shaper is full, so that smoltcp holds back packets until there is room again.
+#[derive(Debug, Clone)]
+pub struct PacketSender {
+    tx: Sender<NetworkCommand>,
+    shaper: Option<TrafficShaper>,
+    capture: Option<CaptureTap>,
+}
+
+impl PacketSender {
+    pub fn new(
+        tx: Sender<NetworkCommand>,
+        shaper: Option<TrafficShaper>,
+        capture: Option<CaptureTap>,
+    ) -> Self {
+        Self {
+            tx,
+            shaper,
+            capture,
+        }
+    }
+
+    /// Reserve capacity for a packet that has yet to be built.
+    pub fn try_reserve(&self) -> Option<PacketPermit<'_>> {
+        if self.shaper_full() {
+            return None;
+        }
+        Some(PacketPermit {
+            permit: self.tx.try_reserve().ok()?,
+            sender: self,
+        })
+    }
+
+    pub fn try_send(&self, packet: SmolPacket) -> Result<(), TrySendError<NetworkCommand>> {
+        let Some(permit) = self.try_reserve() else {
+            return Err(if self.tx.is_closed() {
+                TrySendError::Closed(NetworkCommand::SendPacket(packet))
+            } else {
+                TrySendError::Full(NetworkCommand::SendPacket(packet))
+            });
+        };
+        permit.send(packet);
+        Ok(())
+    }
+
+    /// Send a packet, waiting for room in the channel or the queue of the traffic shaper.
+    pub async fn send(&self, packet: SmolPacket) -> Result<(), SendError<NetworkCommand>> {
+        if let Some(shaper) = &self.shaper {
+            shaper.downlink_ready().await;
+        }
+        let Ok(permit) = self.tx.reserve().await else {
+            return Err(SendError(NetworkCommand::SendPacket(packet)));
+        };
+        PacketPermit {
+            permit,
+            sender: self,
+        }
+        .send(packet);
+        Ok(())
+    }
+
+    fn shaper_full(&self) -> bool {
+        self.shaper
+            .as_ref()
+            .is_some_and(TrafficShaper::downlink_full)
+    }
+
+    /// Hand a packet to the traffic shaper, which returns it if it is not shaped.
+    fn shape(&self, packet: SmolPacket) -> Option<SmolPacket> {
+        match &self.shaper {
+            Some(shaper) => shaper.downlink(packet),
+            None => Some(packet),
+        }
+    }
+}
+
+pub struct PacketPermit<'a> {
+    permit: Permit<'a, NetworkCommand>,
+    sender: &'a PacketSender,
+}
+
+impl PacketPermit<'_> {
+    pub fn send(self, packet: SmolPacket) {
+        if let Some(tap) = self.sender.capture.as_ref().filter(|tap| tap.is_active()) {
+            tap.record_outbound(&packet);
+        }
+        if let Some(packet) = self.sender.shape(packet) {
+            self.permit.send(NetworkCommand::SendPacket(packet));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::shaping::{LinkConditions, ShapingConf, ShapingProfile};
+    use smoltcp::wire::{IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr};
+    use std::time::Duration;
+    use tokio::sync::mpsc::channel;
+
+    fn packet() -> SmolPacket {
+        let repr = Ipv4Repr {
+            src_addr: Ipv4Address::new(10, 0, 0, 42),
+            dst_addr: Ipv4Address::new(10, 0, 0, 1),
+            next_header: IpProtocol::Udp,
+            payload_len: 8,
+            hop_limit: 64,
+        };
+        let mut buf = vec![0u8; 28];
+        repr.emit(
+            &mut Ipv4Packet::new_unchecked(&mut buf),
+            &Default::default(),
+        );
+        SmolPacket::V4(Ipv4Packet::new_unchecked(buf))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_shaper_queue() {
+        let (tx, _rx) = channel(1);
+        let shaper = TrafficShaper::new(ShapingConf {
+            default: ShapingProfile {
+                downlink: LinkConditions {
+                    latency: Duration::from_millis(100),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let sender = PacketSender::new(tx, Some(shaper), None);
+
+        // Shaped packets do not take up channel capacity, but the shaper's queue is limited.
+        let mut sent = 0;
+        while sender.try_send(packet()).is_ok() {
+            sent += 1;
+        }
+        assert_eq!(sent, 1000);
+        assert!(matches!(
+            sender.try_send(packet()),
+            Err(TrySendError::Full(NetworkCommand::SendPacket(_)))
+        ));
+        assert!(sender.try_reserve().is_none());
+    }
+}
@@ -0,0 +1,74 @@
+//! Inspection of the first bytes of a connection to learn about the TLS handshake without
+//! consuming any data.
+
+mod quic;
+mod tls;
+
+pub(super) use quic::peek_quic_initial;
+pub(super) use tls::{peek_tls, Peek};
+
+/// Details of the TLS ClientHello that a client sent at the start of a connection.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientHello {
+    /// The host name from the `server_name` extension.
+    pub sni: Option<String>,
+    /// The protocols from the `application_layer_protocol_negotiation` extension.
+    pub alpn_protocols: Vec<Vec<u8>>,
+    /// The versions from the `supported_versions` extension, or the legacy version field
+    /// if the extension is absent. GREASE values are omitted.
+    pub tls_versions: Vec<u16>,
+}
+
+/// A cursor over a byte slice with the primitives of the TLS presentation language.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+
+    /// A vector with a one-byte length prefix.
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()?;
+        self.take(len as usize)
+    }
+
+    /// A vector with a two-byte length prefix.
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()?;
+        self.take(len as usize)
+    }
+
+    /// A QUIC variable-length integer (RFC 9000, Section 16).
+    fn varint(&mut self) -> Option<u64> {
+        let first = *self.0.first()?;
+        let bytes = self.take(1 << (first >> 6))?;
+        let mut value = u64::from(first & 0x3f);
+        for b in &bytes[1..] {
+            value = (value << 8) | u64::from(*b);
+        }
+        Some(value)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
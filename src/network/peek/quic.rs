@@ -0,0 +1,265 @@
+use ring::aead::quic::{HeaderProtectionKey, AES_128};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM};
+use ring::hkdf;
+
+use super::tls::parse_client_hello;
+use super::{ClientHello, Reader};
+
+const QUIC_VERSION_1: u32 = 1;
+/// RFC 9001, Section 5.2
+const INITIAL_SALT_V1: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+
+const FRAME_PADDING: u64 = 0x00;
+const FRAME_PING: u64 = 0x01;
+const FRAME_ACK: u64 = 0x02;
+const FRAME_ACK_ECN: u64 = 0x03;
+const FRAME_CRYPTO: u64 = 0x06;
+
+/// Look for a QUIC v1 Initial packet at the start of a UDP datagram and extract the ClientHello
+/// from its CRYPTO frames. If the ClientHello continues in the next packet, we parse as much of it
+/// as we have.
+pub fn peek_quic_initial(datagram: &[u8]) -> Option<ClientHello> {
+    let (header, payload) = split_initial(datagram)?;
+    let frames = decrypt_initial(header, payload)?;
+    let crypto_stream = reassemble_crypto_frames(&frames)?;
+
+    let mut message = Reader(&crypto_stream);
+    if message.u8()? != 1 {
+        return None;
+    }
+    let len = message.u24()?;
+    parse_client_hello(&message.0[..len.min(message.0.len())])
+}
+
+/// Split an Initial packet into its header and the protected payload, which starts
+/// with the packet number.
+fn split_initial(datagram: &[u8]) -> Option<(&[u8], &[u8])> {
+    let mut r = Reader(datagram);
+    let first = r.u8()?;
+    // Long header, packet type Initial
+    if first & 0x80 == 0 || (first >> 4) & 0x03 != 0 {
+        return None;
+    }
+    let version = u32::from_be_bytes(r.take(4)?.try_into().unwrap());
+    if version != QUIC_VERSION_1 {
+        return None;
+    }
+    let dcid = r.vec8()?;
+    if dcid.len() > 20 {
+        return None;
+    }
+    r.vec8()?; // source connection id
+    let token_len = r.varint()?;
+    r.take(usize::try_from(token_len).ok()?)?;
+    let len = usize::try_from(r.varint()?).ok()?;
+    let header_len = datagram.len() - r.0.len();
+    let payload = r.take(len)?;
+    Some((&datagram[..header_len], payload))
+}
+
+/// Remove header and packet protection (RFC 9001, Section 5).
+fn decrypt_initial(header: &[u8], payload: &[u8]) -> Option<Vec<u8>> {
+    // header: first byte, version, then the Destination Connection ID.
+    let dcid = &header[6..6 + header[5] as usize];
+    let keys = InitialKeys::client(dcid);
+
+    // The sample starts 4 bytes into the packet number field, whatever its actual length.
+    let mask = HeaderProtectionKey::new(&AES_128, &keys.hp)
+        .ok()?
+        .new_mask(payload.get(4..20)?)
+        .ok()?;
+
+    let mut unprotected_header = header.to_vec();
+    unprotected_header[0] ^= mask[0] & 0x0f;
+    let pn_len = (unprotected_header[0] & 0x03) as usize + 1;
+    let mut packet_number = 0u64;
+    for (b, m) in payload[..pn_len].iter().zip(&mask[1..]) {
+        unprotected_header.push(b ^ m);
+        packet_number = (packet_number << 8) | u64::from(b ^ m);
+    }
+
+    let mut nonce = keys.iv;
+    for (n, p) in nonce[4..].iter_mut().zip(packet_number.to_be_bytes()) {
+        *n ^= p;
+    }
+    let key = LessSafeKey::new(UnboundKey::new(&AES_128_GCM, &keys.key).ok()?);
+    let mut in_out = payload[pn_len..].to_vec();
+    let len = key
+        .open_in_place(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(&unprotected_header),
+            &mut in_out,
+        )
+        .ok()?
+        .len();
+    in_out.truncate(len);
+    Some(in_out)
+}
+
+/// The keys that protect a client's Initial packets (RFC 9001, Section 5.2).
+struct InitialKeys {
+    key: [u8; 16],
+    iv: [u8; 12],
+    hp: [u8; 16],
+}
+
+impl InitialKeys {
+    fn client(dcid: &[u8]) -> Self {
+        let initial_secret = hkdf::Salt::new(hkdf::HKDF_SHA256, &INITIAL_SALT_V1).extract(dcid);
+        let client_secret: [u8; 32] = hkdf_expand_label(&initial_secret, "client in");
+        let client_secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &client_secret);
+        Self {
+            key: hkdf_expand_label(&client_secret, "quic key"),
+            iv: hkdf_expand_label(&client_secret, "quic iv"),
+            hp: hkdf_expand_label(&client_secret, "quic hp"),
+        }
+    }
+}
+
+/// HKDF-Expand-Label from TLS 1.3 (RFC 8446, Section 7.1) with an empty context.
+fn hkdf_expand_label<const N: usize>(secret: &hkdf::Prk, label: &str) -> [u8; N] {
+    struct Len(usize);
+    impl hkdf::KeyType for Len {
+        fn len(&self) -> usize {
+            self.0
+        }
+    }
+
+    let length = (N as u16).to_be_bytes();
+    let label_len = [(6 + label.len()) as u8];
+    let info = [&length[..], &label_len, b"tls13 ", label.as_bytes(), &[0]];
+    let mut out = [0u8; N];
+    secret
+        .expand(&info, Len(N))
+        .and_then(|okm| okm.fill(&mut out))
+        .expect("output length is at most 255 times the hash length");
+    out
+}
+
+/// Collect the contiguous start of the CRYPTO stream. Clients may split the ClientHello into
+/// several CRYPTO frames and send them in any order.
+fn reassemble_crypto_frames(frames: &[u8]) -> Option<Vec<u8>> {
+    let mut chunks = Vec::new();
+    let mut r = Reader(frames);
+    while !r.is_empty() {
+        match r.varint()? {
+            FRAME_PADDING | FRAME_PING => (),
+            frame_type @ (FRAME_ACK | FRAME_ACK_ECN) => {
+                r.varint()?; // largest acknowledged
+                r.varint()?; // ack delay
+                let range_count = r.varint()?;
+                r.varint()?; // first ack range
+                for _ in 0..range_count {
+                    r.varint()?; // gap
+                    r.varint()?; // ack range length
+                }
+                if frame_type == FRAME_ACK_ECN {
+                    for _ in 0..3 {
+                        r.varint()?;
+                    }
+                }
+            }
+            FRAME_CRYPTO => {
+                let offset = usize::try_from(r.varint()?).ok()?;
+                let len = usize::try_from(r.varint()?).ok()?;
+                chunks.push((offset, r.take(len)?));
+            }
+            // Nothing else is allowed in a client's Initial packet that we care about.
+            _ => break,
+        }
+    }
+
+    chunks.sort_by_key(|(offset, _)| *offset);
+    let mut stream = Vec::new();
+    for (offset, data) in chunks {
+        if offset > stream.len() {
+            break;
+        }
+        if let Some(new) = data.get(stream.len() - offset..) {
+            stream.extend_from_slice(new);
+        }
+    }
+    Some(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use data_encoding::HEXLOWER;
+
+    const INITIAL: &[u8] = include_bytes!("testdata/quic_initial.bin");
+
+    fn hex(s: &str) -> Vec<u8> {
+        HEXLOWER.decode(s.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn initial_keys() {
+        // RFC 9001, Appendix A.1
+        let keys = InitialKeys::client(&hex("8394c8f03e515708"));
+        assert_eq!(keys.key.to_vec(), hex("1f369613dd76d5467730efcbe3b1a22d"));
+        assert_eq!(keys.iv.to_vec(), hex("fa044b2f42a3fd3b46fb255c"));
+        assert_eq!(keys.hp.to_vec(), hex("9f50449e04a0e810283a1e9933adedd2"));
+
+        // RFC 9001, Appendix A.2
+        let mask = HeaderProtectionKey::new(&AES_128, &keys.hp)
+            .unwrap()
+            .new_mask(&hex("d1b1c98dd7689fb8ec11d242b123dc9b"))
+            .unwrap();
+        assert_eq!(mask.to_vec(), hex("437b9aec36"));
+    }
+
+    #[test]
+    fn quic_initial() {
+        assert_eq!(
+            peek_quic_initial(INITIAL),
+            Some(ClientHello {
+                sni: Some("www.mitmproxy.org".to_string()),
+                alpn_protocols: vec![b"h3".to_vec()],
+                tls_versions: vec![0x0304],
+            })
+        );
+    }
+
+    #[test]
+    fn quic_initial_tampered() {
+        let mut packet = INITIAL.to_vec();
+        packet[100] ^= 1;
+        assert_eq!(peek_quic_initial(&packet), None);
+    }
+
+    #[test]
+    fn quic_initial_truncated() {
+        assert_eq!(peek_quic_initial(&INITIAL[..INITIAL.len() - 1]), None);
+        assert_eq!(peek_quic_initial(&INITIAL[..30]), None);
+    }
+
+    #[test]
+    fn not_quic() {
+        assert_eq!(peek_quic_initial(b""), None);
+        assert_eq!(peek_quic_initial(b"\x00\x2a\x01\x00\x00\x01"), None);
+        // a DNS query with the high bit set in its ID
+        assert_eq!(
+            peek_quic_initial(b"\xc0\x2a\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00"),
+            None
+        );
+    }
+
+    #[test]
+    fn crypto_frames_out_of_order() {
+        let frames = [
+            &[0x06, 0x03, 0x03][..],
+            b"def",
+            &[0x01, 0x00, 0x06, 0x00, 0x03],
+            b"abc",
+            &[0x06, 0x08, 0x01],
+            b"x",
+        ]
+        .concat();
+        assert_eq!(reassemble_crypto_frames(&frames), Some(b"abcdef".to_vec()));
+    }
+}
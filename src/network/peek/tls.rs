@@ -0,0 +1,196 @@
+use super::{ClientHello, Reader};
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 1;
+
+const EXTENSION_SERVER_NAME: u16 = 0;
+const EXTENSION_ALPN: u16 = 16;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 43;
+
+/// The outcome of peeking at the start of a TCP stream.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Peek {
+    /// The stream starts with a complete ClientHello.
+    Complete(ClientHello),
+    /// The stream may start with a ClientHello, but we need more data to tell.
+    Incomplete,
+    /// The stream does not start with a ClientHello.
+    NotTls,
+}
+
+/// Look for a TLS ClientHello at the start of a TCP stream.
+/// The ClientHello may be split across several TLS records.
+pub fn peek_tls(data: &[u8]) -> Peek {
+    let mut records = Reader(data);
+    let mut handshake = Vec::new();
+    loop {
+        let mut message = Reader(&handshake);
+        match message.u8() {
+            Some(HANDSHAKE_TYPE_CLIENT_HELLO) => (),
+            Some(_) => return Peek::NotTls,
+            None => (),
+        }
+        if let Some(len) = message.u24() {
+            if let Some(body) = message.take(len) {
+                return match parse_client_hello(body) {
+                    Some(hello) => Peek::Complete(hello),
+                    None => Peek::NotTls,
+                };
+            }
+        }
+
+        let header = records.0.get(..5).unwrap_or(records.0);
+        match header {
+            [] => return Peek::Incomplete,
+            [CONTENT_TYPE_HANDSHAKE] | [CONTENT_TYPE_HANDSHAKE, 3, ..] => (),
+            _ => return Peek::NotTls,
+        }
+        if header.len() < 5 {
+            return Peek::Incomplete;
+        }
+        records.take(3);
+        let Some(fragment) = records.vec16() else {
+            return Peek::Incomplete;
+        };
+        handshake.extend_from_slice(fragment);
+    }
+}
+
+/// Parse the body of a ClientHello handshake message (RFC 8446, Section 4.1.2).
+///
+/// A truncated list of extensions is not an error: we return whatever we have found so far,
+/// which is the best we can do for ClientHellos that span multiple QUIC packets.
+pub(super) fn parse_client_hello(body: &[u8]) -> Option<ClientHello> {
+    let mut r = Reader(body);
+    let legacy_version = r.u16()?;
+    r.take(32)?; // random
+    r.vec8()?; // legacy_session_id
+    r.vec16()?; // cipher_suites
+    r.vec8()?; // legacy_compression_methods
+
+    let mut hello = ClientHello::default();
+    let mut supported_versions = None;
+    let mut extensions = Reader(r.vec16().unwrap_or(r.0));
+    while let (Some(extension_type), Some(data)) = (extensions.u16(), extensions.vec16()) {
+        match extension_type {
+            EXTENSION_SERVER_NAME => hello.sni = parse_server_name(data),
+            EXTENSION_ALPN => hello.alpn_protocols = parse_alpn(data),
+            EXTENSION_SUPPORTED_VERSIONS => supported_versions = parse_supported_versions(data),
+            _ => (),
+        }
+    }
+    hello.tls_versions = supported_versions.unwrap_or_else(|| vec![legacy_version]);
+    Some(hello)
+}
+
+fn parse_server_name(data: &[u8]) -> Option<String> {
+    let mut names = Reader(Reader(data).vec16()?);
+    while !names.is_empty() {
+        let name_type = names.u8()?;
+        let name = names.vec16()?;
+        if name_type == 0 {
+            return String::from_utf8(name.to_vec()).ok();
+        }
+    }
+    None
+}
+
+fn parse_alpn(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut protocols = Vec::new();
+    if let Some(list) = Reader(data).vec16() {
+        let mut list = Reader(list);
+        while let Some(protocol) = list.vec8() {
+            protocols.push(protocol.to_vec());
+        }
+    }
+    protocols
+}
+
+fn parse_supported_versions(data: &[u8]) -> Option<Vec<u16>> {
+    let mut versions = Reader(Reader(data).vec8()?);
+    let mut result = Vec::new();
+    while let Some(version) = versions.u16() {
+        if !is_grease(version) {
+            result.push(version);
+        }
+    }
+    Some(result)
+}
+
+/// GREASE values (RFC 8701) are sent to keep servers tolerant of unknown values.
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_HELLO: &[u8] = include_bytes!("testdata/tls_client_hello.bin");
+    const CLIENT_HELLO_FRAGMENTED: &[u8] =
+        include_bytes!("testdata/tls_client_hello_fragmented.bin");
+    const CLIENT_HELLO_NO_SNI: &[u8] = include_bytes!("testdata/tls_client_hello_no_sni.bin");
+
+    fn expected() -> ClientHello {
+        ClientHello {
+            sni: Some("example.com".to_string()),
+            alpn_protocols: vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            tls_versions: vec![0x0304, 0x0303],
+        }
+    }
+
+    #[test]
+    fn client_hello() {
+        assert_eq!(peek_tls(CLIENT_HELLO), Peek::Complete(expected()));
+    }
+
+    #[test]
+    fn client_hello_fragmented_records() {
+        assert_eq!(
+            peek_tls(CLIENT_HELLO_FRAGMENTED),
+            Peek::Complete(expected())
+        );
+    }
+
+    #[test]
+    fn client_hello_incomplete() {
+        // A ClientHello that has only partially arrived, e.g. because it spans several TCP segments.
+        for fixture in [CLIENT_HELLO, CLIENT_HELLO_FRAGMENTED] {
+            for len in [0, 1, 3, 5, 6, 400, 405, fixture.len() - 1] {
+                assert_eq!(peek_tls(&fixture[..len]), Peek::Incomplete, "{len}");
+            }
+        }
+    }
+
+    #[test]
+    fn client_hello_no_sni() {
+        assert_eq!(
+            peek_tls(CLIENT_HELLO_NO_SNI),
+            Peek::Complete(ClientHello {
+                sni: None,
+                alpn_protocols: vec![],
+                tls_versions: vec![0x0303],
+            })
+        );
+    }
+
+    #[test]
+    fn not_tls() {
+        assert_eq!(peek_tls(b"GET / HTTP/1.1\r\n"), Peek::NotTls);
+        assert_eq!(peek_tls(b"\x16\x01"), Peek::NotTls);
+        assert_eq!(peek_tls(b"SSH-2.0-OpenSSH_9.6\r\n"), Peek::NotTls);
+        // a handshake message other than ClientHello
+        assert_eq!(
+            peek_tls(b"\x16\x03\x01\x00\x04\x02\x00\x00\x00"),
+            Peek::NotTls
+        );
+    }
+
+    #[test]
+    fn grease() {
+        assert!(is_grease(0x0a0a));
+        assert!(is_grease(0xfafa));
+        assert!(!is_grease(0x0304));
+        assert!(!is_grease(0x0a1a));
+    }
+}
@@ -0,0 +1,693 @@
+//! Simulation of network conditions such as limited bandwidth, latency and packet loss
+//! between the packet source and the network stack.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use smoltcp::wire::IpProtocol;
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::messages::{NetworkEvent, SmolPacket};
+
+/// How many packets may be queued in each direction. Once a queue is full, no more packets are
+/// taken in that direction until some have been released.
+const MAX_QUEUED_PACKETS: usize = 1000;
+
+/// Network conditions to simulate for packets in one direction.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LinkConditions {
+    /// Bandwidth in bytes per second. Packets queue up if it is exceeded.
+    pub bandwidth: Option<u64>,
+    /// Delay added to every packet.
+    pub latency: Duration,
+    /// The delay varies randomly by up to this much in either direction. Packets are not
+    /// reordered by this.
+    pub jitter: Duration,
+    /// Probability that a packet is dropped.
+    pub loss: f64,
+    /// Probability that a packet skips the latency, overtaking the packets before it.
+    pub reorder: f64,
+    /// Probability that a packet is sent twice.
+    pub duplicate: f64,
+}
+
+/// Network conditions for both directions.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShapingProfile {
+    /// Packets from the clients into the network stack.
+    pub uplink: LinkConditions,
+    /// Packets from the network stack to the clients.
+    pub downlink: LinkConditions,
+}
+
+/// Network conditions for the traffic to a specific destination or of a single connection.
+/// All fields that are set must match.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShapingRule {
+    /// The IP address of the remote end, i.e. where the clients send their packets to.
+    pub server_ip: Option<IpAddr>,
+    /// The TCP or UDP port of the remote end.
+    pub server_port: Option<u16>,
+    /// The client's address of a single TCP connection or UDP flow.
+    pub client_addr: Option<SocketAddr>,
+    pub profile: ShapingProfile,
+}
+
+/// Configuration for traffic shaping, see [crate::network::NetworkConf::shaping].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShapingConf {
+    /// Network conditions for all packets that do not match any rule.
+    pub default: ShapingProfile,
+    /// Rules for specific destinations or connections. The first matching rule applies.
+    /// Each rule has its own bandwidth budget.
+    pub rules: Vec<ShapingRule>,
+    /// Seed for all random decisions, which makes the simulation reproducible.
+    /// A random seed is used if this is unset.
+    pub seed: Option<u64>,
+}
+
+impl ShapingConf {
+    /// Whether packets pass through unchanged.
+    fn is_passthrough(&self) -> bool {
+        self.default == ShapingProfile::default()
+            && self
+                .rules
+                .iter()
+                .all(|rule| rule.profile == ShapingProfile::default())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Direction {
+    Uplink,
+    Downlink,
+}
+
+/// The state of a simulated link, i.e. one direction of the default profile or a rule.
+#[derive(Debug)]
+struct Link {
+    /// When the link has finished transmitting all queued packets.
+    idle_at: Instant,
+    /// When the most recent packet leaves the link. Later packets must not overtake it.
+    last_release: Instant,
+}
+
+/// Decides what happens to each packet.
+#[derive(Debug)]
+struct Shaper {
+    conf: ShapingConf,
+    rng: StdRng,
+    links: HashMap<(Option<usize>, Direction), Link>,
+}
+
+impl Shaper {
+    fn new(conf: ShapingConf) -> Self {
+        let rng = StdRng::seed_from_u64(conf.seed.unwrap_or_else(rand::random));
+        Self {
+            conf,
+            rng,
+            links: HashMap::new(),
+        }
+    }
+
+    /// Apply new settings to subsequent packets. The state of the links is kept, and so is the
+    /// random number generator unless a different seed is given.
+    fn set_conf(&mut self, conf: ShapingConf) {
+        if let Some(seed) = conf.seed.filter(|seed| Some(*seed) != self.conf.seed) {
+            self.rng = StdRng::seed_from_u64(seed);
+        }
+        self.conf = conf;
+    }
+
+    /// Pick the link for a packet and determine when the packet (and possibly a duplicate)
+    /// leaves it. An empty result means that the packet is lost.
+    fn schedule(
+        &mut self,
+        direction: Direction,
+        packet: &mut SmolPacket,
+        now: Instant,
+    ) -> Vec<Instant> {
+        let ports = ports(packet);
+        let src = (packet.src_ip(), ports.map(|(src, _)| src));
+        let dst = (packet.dst_ip(), ports.map(|(_, dst)| dst));
+        let (client, server) = match direction {
+            Direction::Uplink => (src, dst),
+            Direction::Downlink => (dst, src),
+        };
+        let rule = self.conf.rules.iter().position(|rule| {
+            rule.server_ip.map_or(true, |ip| ip == server.0)
+                && rule.server_port.map_or(true, |port| Some(port) == server.1)
+                && rule
+                    .client_addr
+                    .map_or(true, |addr| (addr.ip(), Some(addr.port())) == client)
+        });
+        let profile = match rule {
+            Some(i) => &self.conf.rules[i].profile,
+            None => &self.conf.default,
+        };
+        let conditions = match direction {
+            Direction::Uplink => &profile.uplink,
+            Direction::Downlink => &profile.downlink,
+        };
+        if *conditions == LinkConditions::default() {
+            return vec![now];
+        }
+
+        let link = self.links.entry((rule, direction)).or_insert(Link {
+            idle_at: now,
+            last_release: now,
+        });
+        if self.rng.random::<f64>() < conditions.loss {
+            return vec![];
+        }
+        let copies = if self.rng.random::<f64>() < conditions.duplicate {
+            2
+        } else {
+            1
+        };
+
+        let len = packet_len(packet) as f64;
+        let mut releases = Vec::with_capacity(copies);
+        for _ in 0..copies {
+            let mut sent = now;
+            if let Some(bandwidth) = conditions.bandwidth {
+                sent =
+                    link.idle_at.max(now) + Duration::from_secs_f64(len / bandwidth.max(1) as f64);
+                link.idle_at = sent;
+            }
+            if self.rng.random::<f64>() < conditions.reorder {
+                releases.push(sent);
+                continue;
+            }
+            let jitter = conditions.jitter.as_secs_f64() * self.rng.random_range(-1.0..=1.0);
+            let delay =
+                Duration::from_secs_f64((conditions.latency.as_secs_f64() + jitter).max(0.0));
+            let release = (sent + delay).max(link.last_release);
+            link.last_release = release;
+            releases.push(release);
+        }
+        releases
+    }
+}
+
+fn packet_len(packet: &SmolPacket) -> usize {
+    match packet {
+        SmolPacket::V4(packet) => packet.as_ref().len(),
+        SmolPacket::V6(packet) => packet.as_ref().len(),
+    }
+}
+
+/// The TCP or UDP ports of a packet, if it has any.
+fn ports(packet: &mut SmolPacket) -> Option<(u16, u16)> {
+    if let SmolPacket::V4(packet) = packet {
+        if packet.frag_offset() != 0 {
+            return None;
+        }
+    }
+    if !matches!(
+        packet.transport_protocol(),
+        IpProtocol::Tcp | IpProtocol::Udp
+    ) {
+        return None;
+    }
+    match packet.payload_mut() {
+        [a, b, c, d, ..] => Some((u16::from_be_bytes([*a, *b]), u16::from_be_bytes([*c, *d]))),
+        _ => None,
+    }
+}
+
+/// Packets waiting to leave a simulated link, ordered by release time.
+#[derive(Debug)]
+struct Queue<T> {
+    packets: BTreeMap<(Instant, u64), T>,
+    counter: u64,
+}
+
+impl<T> Queue<T> {
+    fn new() -> Self {
+        Self {
+            packets: BTreeMap::new(),
+            counter: 0,
+        }
+    }
+
+    fn push(&mut self, release: Instant, item: T) {
+        self.counter += 1;
+        self.packets.insert((release, self.counter), item);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Senders are expected to check this before pushing. A queue may still exceed the limit by a
+    /// few packets, e.g. if a packet is duplicated.
+    fn is_full(&self) -> bool {
+        self.packets.len() >= MAX_QUEUED_PACKETS
+    }
+
+    fn next_release(&self) -> Option<Instant> {
+        self.packets
+            .first_key_value()
+            .map(|((release, _), _)| *release)
+    }
+
+    fn pop_due(&mut self, now: Instant) -> Option<T> {
+        if self.next_release()? > now {
+            return None;
+        }
+        self.packets.pop_first().map(|(_, item)| item)
+    }
+}
+
+/// Traffic shaping between the packet source and the network stack, see
+/// [crate::network::NetworkConf::shaping]. The network task queues packets here instead of passing
+/// them on while network conditions are configured or shaped packets are still waiting.
+/// Otherwise, packets bypass the shaper.
+///
+/// Each queue holds at most [MAX_QUEUED_PACKETS] packets. The network task stops reading from the
+/// packet source while the uplink queue is full, and senders towards the clients check
+/// [TrafficShaper::downlink_full] or wait for [TrafficShaper::downlink_ready].
+#[derive(Debug, Clone)]
+pub(super) struct TrafficShaper(Arc<Shared>);
+
+#[derive(Debug)]
+struct Shared {
+    /// Whether packets need to be queued, so that we only take the lock if they do.
+    active: AtomicBool,
+    state: Mutex<State>,
+    /// Wakes up the network task if a packet has been queued from another task.
+    queued: Notify,
+    /// Wakes up senders that wait for room in the downlink queue.
+    released: Notify,
+}
+
+#[derive(Debug)]
+struct State {
+    shaper: Shaper,
+    uplink: Queue<NetworkEvent>,
+    downlink: Queue<SmolPacket>,
+}
+
+impl State {
+    fn is_active(&self) -> bool {
+        !self.shaper.conf.is_passthrough() || !self.uplink.is_empty() || !self.downlink.is_empty()
+    }
+}
+
+impl TrafficShaper {
+    pub fn new(conf: ShapingConf) -> Self {
+        let state = State {
+            shaper: Shaper::new(conf),
+            uplink: Queue::new(),
+            downlink: Queue::new(),
+        };
+        Self(Arc::new(Shared {
+            active: AtomicBool::new(state.is_active()),
+            state: Mutex::new(state),
+            queued: Notify::new(),
+            released: Notify::new(),
+        }))
+    }
+
+    /// Apply new settings, which take effect immediately for new packets.
+    /// Packets that are already queued keep their release time.
+    pub fn set_conf(&self, conf: ShapingConf) {
+        self.update(|state| state.shaper.set_conf(conf));
+    }
+
+    /// Queue a packet from the clients, or hand it back if traffic shaping is inactive.
+    /// Callers should check [TrafficShaper::uplink_full] first.
+    pub fn uplink(&self, event: NetworkEvent) -> Option<NetworkEvent> {
+        if !self.0.active.load(Ordering::Relaxed) {
+            return Some(event);
+        }
+        let NetworkEvent::ReceivePacket {
+            mut packet,
+            tunnel_info,
+        } = event;
+        self.update(|state| {
+            let now = Instant::now();
+            for release in state.shaper.schedule(Direction::Uplink, &mut packet, now) {
+                state.uplink.push(
+                    release,
+                    NetworkEvent::ReceivePacket {
+                        packet: packet.clone(),
+                        tunnel_info: tunnel_info.clone(),
+                    },
+                );
+            }
+        });
+        None
+    }
+
+    /// Queue a packet towards the clients, or hand it back if traffic shaping is inactive.
+    /// Callers should check [TrafficShaper::downlink_full] first.
+    pub fn downlink(&self, mut packet: SmolPacket) -> Option<SmolPacket> {
+        if !self.0.active.load(Ordering::Relaxed) {
+            return Some(packet);
+        }
+        self.update(|state| {
+            let now = Instant::now();
+            for release in state.shaper.schedule(Direction::Downlink, &mut packet, now) {
+                state.downlink.push(release, packet.clone());
+            }
+        });
+        self.0.queued.notify_one();
+        None
+    }
+
+    /// The next packet from the clients whose release time has come.
+    pub fn pop_uplink(&self, now: Instant) -> Option<NetworkEvent> {
+        self.update(|state| state.uplink.pop_due(now))
+    }
+
+    /// The next packet towards the clients whose release time has come.
+    pub fn pop_downlink(&self, now: Instant) -> Option<SmolPacket> {
+        let packet = self.update(|state| state.downlink.pop_due(now));
+        if packet.is_some() {
+            self.0.released.notify_waiters();
+        }
+        packet
+    }
+
+    /// Whether the queue of packets from the clients is full, so that no more should be taken in.
+    pub fn uplink_full(&self) -> bool {
+        self.0.active.load(Ordering::Relaxed) && self.0.state.lock().unwrap().uplink.is_full()
+    }
+
+    /// Whether the queue of packets towards the clients is full, so that senders have to wait.
+    pub fn downlink_full(&self) -> bool {
+        self.0.active.load(Ordering::Relaxed) && self.0.state.lock().unwrap().downlink.is_full()
+    }
+
+    /// Wait until there is room in the queue of packets towards the clients.
+    pub async fn downlink_ready(&self) {
+        loop {
+            let released = self.0.released.notified();
+            if !self.downlink_full() {
+                return;
+            }
+            released.await;
+        }
+    }
+
+    /// When the next packet is due in the directions that can currently make progress.
+    pub fn next_release(&self, uplink: bool, downlink: bool) -> Option<Instant> {
+        let state = self.0.state.lock().unwrap();
+        [
+            uplink.then(|| state.uplink.next_release()).flatten(),
+            downlink.then(|| state.downlink.next_release()).flatten(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// Wait until a packet towards the clients has been queued.
+    pub fn queued(&self) -> Notified<'_> {
+        self.0.queued.notified()
+    }
+
+    fn update<T>(&self, f: impl FnOnce(&mut State) -> T) -> T {
+        let mut state = self.0.state.lock().unwrap();
+        let result = f(&mut state);
+        self.0.active.store(state.is_active(), Ordering::Relaxed);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::TunnelInfo;
+    use smoltcp::wire::{Ipv4Address, Ipv4Packet, Ipv4Repr};
+
+    fn packet(len: usize) -> SmolPacket {
+        let repr = Ipv4Repr {
+            src_addr: Ipv4Address::new(10, 0, 0, 1),
+            dst_addr: Ipv4Address::new(10, 0, 0, 42),
+            next_header: IpProtocol::Udp,
+            payload_len: len - 20,
+            hop_limit: 64,
+        };
+        let mut buf = vec![0u8; len];
+        let mut ip = Ipv4Packet::new_unchecked(&mut buf);
+        repr.emit(&mut ip, &Default::default());
+        ip.payload_mut()[..4].copy_from_slice(&[0x04, 0xd2, 0x00, 0x35]);
+        SmolPacket::V4(Ipv4Packet::new_unchecked(buf))
+    }
+
+    fn shaper(uplink: LinkConditions) -> Shaper {
+        Shaper::new(ShapingConf {
+            default: ShapingProfile {
+                uplink,
+                ..Default::default()
+            },
+            seed: Some(42),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn passthrough() {
+        let mut shaper = Shaper::new(ShapingConf::default());
+        let now = Instant::now();
+        for direction in [Direction::Uplink, Direction::Downlink] {
+            assert_eq!(shaper.schedule(direction, &mut packet(100), now), [now]);
+        }
+    }
+
+    #[test]
+    fn bandwidth_and_latency() {
+        let mut shaper = shaper(LinkConditions {
+            bandwidth: Some(1000),
+            latency: Duration::from_millis(50),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let first = shaper.schedule(Direction::Uplink, &mut packet(100), now);
+        let second = shaper.schedule(Direction::Uplink, &mut packet(100), now);
+        assert_eq!(first, [now + Duration::from_millis(150)]);
+        assert_eq!(second, [now + Duration::from_millis(250)]);
+        // The other direction is not affected.
+        assert_eq!(
+            shaper.schedule(Direction::Downlink, &mut packet(100), now),
+            [now]
+        );
+    }
+
+    #[test]
+    fn jitter_keeps_order() {
+        let mut shaper = shaper(LinkConditions {
+            latency: Duration::from_millis(100),
+            jitter: Duration::from_millis(50),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let releases: Vec<Instant> = (0..100)
+            .flat_map(|i| {
+                let now = now + Duration::from_millis(i);
+                shaper.schedule(Direction::Uplink, &mut packet(100), now)
+            })
+            .collect();
+        assert!(releases.windows(2).all(|w| w[0] <= w[1]));
+        assert!(releases[0] >= now + Duration::from_millis(50));
+        assert!(releases[0] <= now + Duration::from_millis(150));
+    }
+
+    #[test]
+    fn loss_duplicate_reorder() {
+        let conditions = LinkConditions {
+            latency: Duration::from_millis(100),
+            loss: 0.2,
+            duplicate: 0.1,
+            reorder: 0.1,
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let run = |mut shaper: Shaper| -> Vec<Vec<Instant>> {
+            (0..1000)
+                .map(|_| shaper.schedule(Direction::Uplink, &mut packet(100), now))
+                .collect()
+        };
+        let results = run(shaper(conditions.clone()));
+        // The same seed yields the same decisions.
+        assert_eq!(results, run(shaper(conditions)));
+
+        let lost = results.iter().filter(|r| r.is_empty()).count();
+        let duplicated = results.iter().filter(|r| r.len() == 2).count();
+        let reordered = results.iter().flatten().filter(|r| **r == now).count();
+        assert!((150..250).contains(&lost), "{lost}");
+        assert!((50..130).contains(&duplicated), "{duplicated}");
+        assert!((50..130).contains(&reordered), "{reordered}");
+    }
+
+    #[test]
+    fn rules() {
+        let slow = ShapingProfile {
+            uplink: LinkConditions {
+                latency: Duration::from_millis(100),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let delayed = [now + Duration::from_millis(100)];
+        for (rule, matches) in [
+            (
+                ShapingRule {
+                    server_ip: Some("10.0.0.42".parse().unwrap()),
+                    ..Default::default()
+                },
+                true,
+            ),
+            (
+                ShapingRule {
+                    server_ip: Some("10.0.0.42".parse().unwrap()),
+                    server_port: Some(53),
+                    ..Default::default()
+                },
+                true,
+            ),
+            (
+                ShapingRule {
+                    server_port: Some(443),
+                    ..Default::default()
+                },
+                false,
+            ),
+            (
+                ShapingRule {
+                    client_addr: Some("10.0.0.1:1234".parse().unwrap()),
+                    ..Default::default()
+                },
+                true,
+            ),
+            (
+                ShapingRule {
+                    client_addr: Some("10.0.0.1:4321".parse().unwrap()),
+                    ..Default::default()
+                },
+                false,
+            ),
+        ] {
+            let mut shaper = Shaper::new(ShapingConf {
+                rules: vec![ShapingRule {
+                    profile: slow.clone(),
+                    ..rule.clone()
+                }],
+                ..Default::default()
+            });
+            let expected = if matches { &delayed[..] } else { &[now][..] };
+            assert_eq!(
+                shaper.schedule(Direction::Uplink, &mut packet(100), now),
+                expected,
+                "{rule:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn set_conf_keeps_link_state() {
+        let conditions = LinkConditions {
+            bandwidth: Some(1000),
+            ..Default::default()
+        };
+        let mut shaper = shaper(conditions.clone());
+        let now = Instant::now();
+        shaper.schedule(Direction::Uplink, &mut packet(100), now);
+        shaper.set_conf(ShapingConf {
+            default: ShapingProfile {
+                uplink: conditions,
+                ..Default::default()
+            },
+            seed: Some(42),
+            ..Default::default()
+        });
+        assert_eq!(
+            shaper.schedule(Direction::Uplink, &mut packet(100), now),
+            [now + Duration::from_millis(200)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn traffic_shaper() {
+        let shaper = TrafficShaper::new(ShapingConf::default());
+        let event = || NetworkEvent::ReceivePacket {
+            packet: packet(100),
+            tunnel_info: TunnelInfo::None,
+        };
+        // Without network conditions, packets bypass the shaper.
+        assert!(shaper.uplink(event()).is_some());
+        assert!(shaper.downlink(packet(100)).is_some());
+
+        shaper.set_conf(ShapingConf {
+            default: ShapingProfile {
+                uplink: LinkConditions {
+                    latency: Duration::from_millis(100),
+                    ..Default::default()
+                },
+                downlink: LinkConditions {
+                    loss: 1.0,
+                    ..Default::default()
+                },
+            },
+            seed: Some(42),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        assert!(shaper.uplink(event()).is_none());
+        // All packets towards the clients are lost.
+        assert!(shaper.downlink(packet(100)).is_none());
+        let release = now + Duration::from_millis(100);
+        assert_eq!(shaper.next_release(true, true), Some(release));
+        assert_eq!(shaper.next_release(false, true), None);
+        assert!(shaper.pop_uplink(now).is_none());
+
+        // Changes apply immediately, but packets keep queueing until the queued ones are released.
+        shaper.set_conf(ShapingConf::default());
+        assert!(shaper.downlink(packet(100)).is_none());
+        assert!(shaper.pop_downlink(now).is_some());
+        assert!(shaper.pop_uplink(release).is_some());
+        assert!(shaper.uplink(event()).is_some());
+        assert!(shaper.downlink(packet(100)).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_queue() {
+        let shaper = TrafficShaper::new(ShapingConf {
+            default: ShapingProfile {
+                downlink: LinkConditions {
+                    latency: Duration::from_millis(100),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        for _ in 0..MAX_QUEUED_PACKETS {
+            assert!(!shaper.downlink_full());
+            assert!(shaper.downlink(packet(100)).is_none());
+        }
+        assert!(shaper.downlink_full());
+        assert!(!shaper.uplink_full());
+
+        // Queued packets are kept, and senders wait until one of them has been released.
+        let ready = shaper.downlink_ready();
+        tokio::pin!(ready);
+        assert!(futures_util::poll!(ready.as_mut()).is_pending());
+        assert!(shaper.pop_downlink(Instant::now()).is_none());
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert!(shaper.pop_downlink(Instant::now()).is_some());
+        assert!(futures_util::poll!(ready.as_mut()).is_ready());
+        assert!(!shaper.downlink_full());
+    }
+}
@@ -0,0 +1,191 @@
+//! Relaying of connections to their real destination without involving Python,
+//! see [TransportCommand::SpliceConnection].
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+
+use crate::messages::{ConnectionId, SpliceStats, TransportCommand};
+
+/// How much we read from either side at once.
+const CHUNK_SIZE: usize = 65536;
+
+/// Our side of a connection in the network stack. It is driven with the same commands
+/// that Python would otherwise send.
+struct Client {
+    id: ConnectionId,
+    commands: UnboundedSender<TransportCommand>,
+}
+
+impl Client {
+    /// Read data from the client. An empty result means that the client has closed its end
+    /// (or the flow has expired for UDP).
+    async fn read(&self) -> Vec<u8> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .commands
+            .send(TransportCommand::ReadData(self.id, CHUNK_SIZE as u32, tx))
+            .is_err()
+        {
+            return Vec::new();
+        }
+        rx.await.unwrap_or_default()
+    }
+
+    /// Write data to the client and wait until there is buffer space again.
+    /// Returns `false` if the connection is gone.
+    async fn write(&self, data: Vec<u8>) -> bool {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .send(TransportCommand::WriteData(self.id, data))
+            .is_ok()
+            && self
+                .commands
+                .send(TransportCommand::DrainWriter(self.id, tx))
+                .is_ok()
+            && rx.await.is_ok()
+    }
+
+    fn send(&self, command: TransportCommand) {
+        self.commands.send(command).ok();
+    }
+}
+
+/// Relay a connection to `host:port` until both sides have been closed.
+/// If the upstream connection fails, the client connection is aborted.
+pub(super) async fn splice(
+    connection_id: ConnectionId,
+    host: String,
+    port: u16,
+    commands: UnboundedSender<TransportCommand>,
+) -> io::Result<SpliceStats> {
+    let client = Client {
+        id: connection_id,
+        commands,
+    };
+    let relay = async {
+        if connection_id.is_tcp() {
+            splice_tcp(&client, &host, port).await
+        } else {
+            splice_udp(&client, &host, port).await
+        }
+    };
+    let result = tokio::select! {
+        // Once the network stack is gone, reads return nothing, which must not look like
+        // a regular close.
+        biased;
+        _ = client.commands.closed() => Err(io::Error::other("Network stack has shut down.")),
+        result = relay => result,
+    };
+    match &result {
+        Ok(stats) => log::debug!(
+            "Spliced connection {} to {}:{} closed: {:?}",
+            connection_id,
+            host,
+            port,
+            stats
+        ),
+        Err(e) => {
+            log::debug!(
+                "Spliced connection {} to {}:{} failed: {}",
+                connection_id,
+                host,
+                port,
+                e
+            );
+            client.send(TransportCommand::AbortConnection(connection_id));
+        }
+    }
+    result
+}
+
+async fn splice_tcp(client: &Client, host: &str, port: u16) -> io::Result<SpliceStats> {
+    let (mut upstream_rx, mut upstream_tx) = TcpStream::connect((host, port)).await?.into_split();
+
+    let client_to_server = async {
+        let mut bytes = 0;
+        loop {
+            let data = client.read().await;
+            if data.is_empty() {
+                break;
+            }
+            bytes += data.len() as u64;
+            upstream_tx.write_all(&data).await?;
+        }
+        upstream_tx.shutdown().await?;
+        Ok::<_, io::Error>(bytes)
+    };
+    let server_to_client = async {
+        let mut bytes = 0;
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let n = upstream_rx.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            bytes += n as u64;
+            if !client.write(buf[..n].to_vec()).await {
+                break;
+            }
+        }
+        client.send(TransportCommand::CloseConnection(client.id, true));
+        Ok::<_, io::Error>(bytes)
+    };
+
+    let (bytes_from_client, bytes_from_server) =
+        tokio::try_join!(client_to_server, server_to_client)?;
+    Ok(SpliceStats {
+        bytes_from_client,
+        bytes_from_server,
+    })
+}
+
+async fn splice_udp(client: &Client, host: &str, port: u16) -> io::Result<SpliceStats> {
+    let addr = lookup_host((host, port)).await?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("No address for {host}."))
+    })?;
+    let bind_addr = match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(addr).await?;
+
+    let mut bytes_from_client = 0;
+    let mut bytes_from_server = 0;
+    let client_to_server = async {
+        loop {
+            let data = client.read().await;
+            if data.is_empty() {
+                break;
+            }
+            bytes_from_client += data.len() as u64;
+            socket.send(&data).await?;
+        }
+        Ok::<_, io::Error>(())
+    };
+    let server_to_client = async {
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let n = socket.recv(&mut buf).await?;
+            bytes_from_server += n as u64;
+            if !client.write(buf[..n].to_vec()).await {
+                break;
+            }
+        }
+        Ok::<_, io::Error>(())
+    };
+    // UDP has no notion of closing, so we relay until the client flow ends.
+    tokio::select! {
+        result = client_to_server => result?,
+        result = server_to_client => result?,
+    }
+    Ok(SpliceStats {
+        bytes_from_client,
+        bytes_from_server,
+    })
+}
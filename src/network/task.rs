@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::fmt;
 
 use anyhow::Result;
@@ -5,12 +6,18 @@ use anyhow::Result;
 use std::time::Duration;
 use tokio::sync::{
     mpsc,
-    mpsc::{Permit, Receiver, Sender, UnboundedReceiver},
+    mpsc::{Permit, Receiver, Sender, UnboundedReceiver, UnboundedSender},
+    watch,
 };
 use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Instant};
 
 use crate::messages::{NetworkCommand, NetworkEvent, TransportCommand, TransportEvent};
 use crate::network::core::NetworkStack;
+use crate::network::packet_sender::PacketSender;
+use crate::network::shaping::{ShapingConf, TrafficShaper};
+use crate::network::splice::splice;
+use crate::network::NetworkConf;
 use crate::shutdown;
 
 pub struct NetworkTask<'a> {
@@ -18,6 +25,12 @@ pub struct NetworkTask<'a> {
     net_rx: Receiver<NetworkEvent>,
     py_tx: Sender<TransportEvent>,
     py_rx: UnboundedReceiver<TransportCommand>,
+    /// Commands from the tasks that relay spliced connections.
+    splice_tx: UnboundedSender<TransportCommand>,
+    splice_rx: UnboundedReceiver<TransportCommand>,
+    /// Queues packets in both directions while traffic shaping is active.
+    shaper: Option<TrafficShaper>,
+    shaping_conf: Option<watch::Receiver<ShapingConf>>,
 
     shutdown: shutdown::Receiver,
     io: NetworkStack<'a>,
@@ -28,6 +41,7 @@ pub fn add_network_layer(
     transport_events_tx: Sender<TransportEvent>,
     transport_commands_rx: UnboundedReceiver<TransportCommand>,
     shutdown: shutdown::Receiver,
+    conf: NetworkConf,
 ) -> (
     JoinHandle<Result<()>>,
     Sender<NetworkEvent>,
@@ -43,6 +57,7 @@ pub fn add_network_layer(
         transport_events_tx,
         transport_commands_rx,
         shutdown,
+        &conf,
     );
     let h = tokio::spawn(Box::pin(async move { task.run().await }));
     (h, network_events_tx, network_commands_rx)
@@ -55,13 +70,26 @@ impl NetworkTask<'_> {
         py_tx: Sender<TransportEvent>,
         py_rx: UnboundedReceiver<TransportCommand>,
         shutdown: shutdown::Receiver,
+        conf: &NetworkConf,
     ) -> Self {
-        let io = NetworkStack::new(net_tx.clone());
+        let shaping_conf = conf.shaping.clone();
+        let shaper = shaping_conf
+            .as_ref()
+            .map(|conf| TrafficShaper::new(conf.borrow().clone()));
+        let io = NetworkStack::new(
+            PacketSender::new(net_tx.clone(), shaper.clone(), conf.capture.clone()),
+            conf,
+        );
+        let (splice_tx, splice_rx) = mpsc::unbounded_channel();
         Self {
             net_tx,
             net_rx,
             py_tx,
             py_rx,
+            splice_tx,
+            splice_rx,
+            shaper,
+            shaping_conf,
             shutdown,
             io,
         }
@@ -70,8 +98,20 @@ impl NetworkTask<'_> {
     pub async fn run(mut self) -> Result<()> {
         let mut py_tx_permit: Option<Permit<TransportEvent>> = None;
         let mut delay: Option<Duration> = None;
+        let mut pending_events: VecDeque<TransportEvent> = VecDeque::new();
 
         'task: loop {
+            // Notify Python land of closed (and previously held back) connections, as far as there is channel capacity.
+            while !pending_events.is_empty() {
+                let Some(permit) = py_tx_permit
+                    .take()
+                    .or_else(|| self.py_tx.try_reserve().ok())
+                else {
+                    break;
+                };
+                permit.send(pending_events.pop_front().unwrap());
+            }
+
             // On a high level, we do three things in our main loop:
             // 1. Wait for an event from either side and handle it, or wait until the next smoltcp timeout.
             // 2. `.poll()` the smoltcp interface until it's finished with everything for now.
@@ -87,24 +127,51 @@ impl NetworkTask<'_> {
 
             let py_tx_available = py_tx_permit.is_some();
             let net_tx_available = self.net_tx.capacity() > 0;
+            // stop taking in packets while the traffic shaper's queue for them is full
+            let uplink_full = uplink_queue_full(&self.shaper);
+            let downlink_full = downlink_queue_full(&self.shaper);
+            let shaper = self.shaper.as_ref();
+            let shaping_release =
+                shaper.and_then(|s| s.next_release(py_tx_available, net_tx_available));
+            let shaping_conf = self.shaping_conf.as_mut();
 
             tokio::select! {
                 // wait for graceful shutdown
                 _ = self.shutdown.recv() => break 'task,
                 // wait for timeouts when the device is idle
                 _ = async { tokio::time::sleep(delay.unwrap()).await }, if delay.is_some() => {},
+                // wait for shaped packets that are due...
+                _ = async { sleep_until(shaping_release.unwrap()).await }, if shaping_release.is_some() => {},
+                // ...or have been queued from elsewhere
+                _ = async { shaper.unwrap().queued().await }, if shaper.is_some() => {},
+                // apply new traffic shaping settings
+                Ok(conf) = async {
+                    let conf = shaping_conf.unwrap();
+                    conf.changed().await.map(|()| conf.borrow_and_update().clone())
+                }, if shaping_conf.is_some() => {
+                    if let Some(shaper) = &self.shaper {
+                        shaper.set_conf(conf);
+                    }
+                },
                 // wait for py_tx channel capacity...
                 Ok(permit) = self.py_tx.reserve(), if !py_tx_available => {
                     py_tx_permit = Some(permit);
                     continue 'task;
                 },
                 // ...or process incoming packets
-                Some(e) = self.net_rx.recv(), if py_tx_available => {
+                Some(e) = self.net_rx.recv(), if py_tx_available && !uplink_full => {
                     // handle pending network events until channel is full
-                    self.io.handle_network_event(e, py_tx_permit.take().unwrap())?;
+                    if let Some(e) = shape_uplink(&self.shaper, e) {
+                        self.io.handle_network_event(e, py_tx_permit.take().unwrap())?;
+                    }
                     while let Ok(p) = self.py_tx.try_reserve() {
+                        if uplink_queue_full(&self.shaper) {
+                            break;
+                        }
                         if let Ok(e) = self.net_rx.try_recv() {
-                            self.io.handle_network_event(e, p)?;
+                            if let Some(e) = shape_uplink(&self.shaper, e) {
+                                self.io.handle_network_event(e, p)?;
+                            }
                         } else {
                             break;
                         }
@@ -116,11 +183,22 @@ impl NetworkTask<'_> {
                     continue 'task;
                 },
                 // ...or process outgoing packets
-                Some(c) = self.py_rx.recv(), if net_tx_available => {
+                Some(c) = self.py_rx.recv(), if net_tx_available && !downlink_full => {
                     // handle pending transport commands until channel is full
-                    self.io.handle_transport_command(c);
-                    while self.net_tx.capacity() > 0 {
+                    handle_transport_command(&mut self.io, &self.splice_tx, c);
+                    while self.net_tx.capacity() > 0 && !downlink_queue_full(&self.shaper) {
                         if let Ok(c) = self.py_rx.try_recv() {
+                            handle_transport_command(&mut self.io, &self.splice_tx, c);
+                        } else {
+                            break;
+                        }
+                    }
+                },
+                // ...or data of spliced connections
+                Some(c) = self.splice_rx.recv(), if net_tx_available && !downlink_full => {
+                    self.io.handle_transport_command(c);
+                    while self.net_tx.capacity() > 0 && !downlink_queue_full(&self.shaper) {
+                        if let Ok(c) = self.splice_rx.try_recv() {
                             self.io.handle_transport_command(c);
                         } else {
                             break;
@@ -129,17 +207,86 @@ impl NetworkTask<'_> {
                 },
             }
 
+            // pass on shaped packets that are due, as far as there is channel capacity
+            if let Some(shaper) = &self.shaper {
+                let now = Instant::now();
+                while let Ok(permit) = self.net_tx.try_reserve() {
+                    let Some(packet) = shaper.pop_downlink(now) else {
+                        break;
+                    };
+                    permit.send(NetworkCommand::SendPacket(packet));
+                }
+                while let Some(permit) = py_tx_permit
+                    .take()
+                    .or_else(|| self.py_tx.try_reserve().ok())
+                {
+                    let Some(e) = shaper.pop_uplink(now) else {
+                        py_tx_permit = Some(permit);
+                        break;
+                    };
+                    self.io.handle_network_event(e, permit)?;
+                }
+            }
+
             self.io.poll()?;
+            pending_events.extend(self.io.take_events());
             delay = self.io.poll_delay();
         }
 
         // TODO: process remaining pending data after the shutdown request was received?
 
+        // best effort: tell Python land which connections are going away.
+        drop(py_tx_permit);
+        for event in pending_events.into_iter().chain(self.io.shutdown()) {
+            if self.py_tx.try_send(event).is_err() {
+                break;
+            }
+        }
+
         log::debug!("Virtual Network device task shutting down.");
         Ok(())
     }
 }
 
+/// Let the traffic shaper queue an incoming packet, unless it is inactive.
+fn shape_uplink(shaper: &Option<TrafficShaper>, event: NetworkEvent) -> Option<NetworkEvent> {
+    match shaper {
+        Some(shaper) => shaper.uplink(event),
+        None => Some(event),
+    }
+}
+
+fn uplink_queue_full(shaper: &Option<TrafficShaper>) -> bool {
+    shaper.as_ref().is_some_and(TrafficShaper::uplink_full)
+}
+
+fn downlink_queue_full(shaper: &Option<TrafficShaper>) -> bool {
+    shaper.as_ref().is_some_and(TrafficShaper::downlink_full)
+}
+
+/// Pass a command on to the network stack, unless it starts splicing a connection.
+fn handle_transport_command(
+    io: &mut NetworkStack,
+    splice_tx: &UnboundedSender<TransportCommand>,
+    command: TransportCommand,
+) {
+    if let TransportCommand::SpliceConnection {
+        connection_id,
+        host,
+        port,
+        tx,
+    } = command
+    {
+        let commands = splice_tx.clone();
+        tokio::spawn(async move {
+            tx.send(splice(connection_id, host, port, commands).await)
+                .ok();
+        });
+    } else {
+        io.handle_transport_command(command);
+    }
+}
+
 impl fmt::Debug for NetworkTask<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("NetworkTask").field("io", &self.io).finish()
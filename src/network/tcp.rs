@@ -1,29 +1,35 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
-use std::{cmp, fmt};
+use std::{cmp, fmt, io};
 
 use anyhow::Result;
 use pretty_hex::pretty_hex;
 use smoltcp::iface::{Config, SocketSet};
+use smoltcp::phy::ChecksumCapabilities;
 use smoltcp::socket::{tcp, Socket};
-use smoltcp::wire::{HardwareAddress, Ipv6Address};
+use smoltcp::wire::{HardwareAddress, IpProtocol, IpRepr, Ipv6Address, TcpControl, TcpRepr};
 use smoltcp::{
     iface::{Interface, SocketHandle},
     time::Instant,
     wire::{IpAddress, IpCidr, Ipv4Address, TcpPacket},
 };
 use std::time::Duration;
-use tokio::sync::{
-    mpsc::{Permit, Sender},
-    oneshot,
-};
+use tokio::sync::{mpsc::Permit, oneshot, watch};
 
 use crate::messages::{
-    ConnectionId, ConnectionIdGenerator, NetworkCommand, SmolPacket, TransportCommand,
+    CloseReason, ConnectionId, ConnectionIdGenerator, Rejection, SmolPacket, TransportCommand,
     TransportEvent, TunnelInfo,
 };
 
+use super::icmp::icmp_error;
+use super::packet_sender::PacketSender;
+use super::peek::{peek_tls, Peek};
 use super::virtual_device::VirtualDevice;
+use super::NetworkConf;
+
+/// How long we wait for the peer to close its end after it has acknowledged our FIN on a fully
+/// closed connection. Once this expires, the connection is aborted with a RST.
+const FIN_WAIT_2_TIMEOUT: smoltcp::time::Duration = smoltcp::time::Duration::from_secs(5);
 
 /// Associated data for a smoltcp socket.
 #[derive(Debug)]
@@ -34,11 +40,86 @@ struct SocketData {
     /// we want to send a FIN.
     send_buffer: VecDeque<u8>,
     write_eof: bool,
+    /// Set once the connection has been closed for both reading and writing.
+    /// Incoming data is discarded from here on.
+    full_close: bool,
+    /// Deadline after which a fully closed connection is aborted.
+    close_deadline: Option<Instant>,
     // Gets notified once there's data to be read.
     recv_waiter: Option<(u32, oneshot::Sender<Vec<u8>>)>,
     // Gets notified once there is enough space in the write buffer.
     drain_waiter: Vec<oneshot::Sender<()>>,
     addr_tuple: (SocketAddr, SocketAddr),
+    // Reported with the ConnectionClosed event.
+    opened: Instant,
+    bytes_received: u64,
+    bytes_sent: u64,
+    close_reason: Option<CloseReason>,
+    /// For connections that we open ourselves: notified once the connection is established.
+    connect_waiter: Option<oneshot::Sender<io::Result<(ConnectionId, SocketAddr)>>>,
+    /// For connections that we open ourselves: dropped once the connection has been removed.
+    #[allow(dead_code)]
+    closed: Option<watch::Sender<()>>,
+    /// The `ConnectionEstablished` event while it is held back to peek at the ClientHello,
+    /// together with the deadline after which it is sent regardless.
+    established: Option<(TransportEvent, Instant)>,
+}
+
+impl SocketData {
+    fn new(handle: SocketHandle, addr_tuple: (SocketAddr, SocketAddr), opened: Instant) -> Self {
+        SocketData {
+            handle,
+            send_buffer: VecDeque::new(),
+            write_eof: false,
+            full_close: false,
+            close_deadline: None,
+            recv_waiter: None,
+            drain_waiter: Vec::new(),
+            addr_tuple,
+            opened,
+            bytes_received: 0,
+            bytes_sent: 0,
+            close_reason: None,
+            connect_waiter: None,
+            closed: None,
+            established: None,
+        }
+    }
+
+    fn closed_event(&self, connection_id: ConnectionId, reason: CloseReason) -> TransportEvent {
+        TransportEvent::ConnectionClosed {
+            connection_id,
+            src_addr: self.addr_tuple.0,
+            dst_addr: self.addr_tuple.1,
+            reason,
+            bytes_received: self.bytes_received,
+            bytes_sent: self.bytes_sent,
+            duration: (Instant::now() - self.opened).into(),
+        }
+    }
+}
+
+/// A connection whose handshake is held back until Python accepts or rejects it.
+#[derive(Debug)]
+struct PendingConnection {
+    syn: SmolPacket,
+    addr_tuple: (SocketAddr, SocketAddr),
+    opened: Instant,
+    deadline: Instant,
+}
+
+impl PendingConnection {
+    fn closed_event(&self, connection_id: ConnectionId, reason: CloseReason) -> TransportEvent {
+        TransportEvent::ConnectionClosed {
+            connection_id,
+            src_addr: self.addr_tuple.0,
+            dst_addr: self.addr_tuple.1,
+            reason,
+            bytes_received: 0,
+            bytes_sent: 0,
+            duration: (Instant::now() - self.opened).into(),
+        }
+    }
 }
 
 pub struct TcpHandler<'a> {
@@ -48,12 +129,17 @@ pub struct TcpHandler<'a> {
     sockets: SocketSet<'a>,
     socket_data: HashMap<ConnectionId, SocketData>,
     remove_conns: Vec<ConnectionId>,
-    active_connections: HashSet<(SocketAddr, SocketAddr)>,
+    active_connections: HashMap<(SocketAddr, SocketAddr), ConnectionId>,
+    events: Vec<TransportEvent>,
+    pending_connections: HashMap<ConnectionId, PendingConnection>,
+    accept_timeout: Option<smoltcp::time::Duration>,
+    peek_timeout: Option<smoltcp::time::Duration>,
+    next_ephemeral_port: u16,
 }
 
 impl TcpHandler<'_> {
-    pub fn new(net_tx: Sender<NetworkCommand>) -> Self {
-        let mut device = VirtualDevice::new(net_tx);
+    pub fn new(net_tx: PacketSender, conf: &NetworkConf) -> Self {
+        let mut device = VirtualDevice::new(net_tx, conf.mtu());
 
         let config = Config::new(HardwareAddress::Ip);
         let mut iface = Interface::new(config, &mut device, Instant::now());
@@ -82,9 +168,14 @@ impl TcpHandler<'_> {
             device,
             sockets: SocketSet::new(Vec::new()),
             socket_data: HashMap::new(),
-            active_connections: HashSet::new(),
+            active_connections: HashMap::new(),
             connection_id_generator: ConnectionIdGenerator::tcp(),
             remove_conns: Vec::new(),
+            events: Vec::new(),
+            pending_connections: HashMap::new(),
+            next_ephemeral_port: 0,
+            accept_timeout: conf.tcp_accept_timeout.map(smoltcp::time::Duration::from),
+            peek_timeout: conf.tcp_peek_timeout.map(smoltcp::time::Duration::from),
         }
     }
 
@@ -92,6 +183,7 @@ impl TcpHandler<'_> {
         &mut self,
         mut packet: SmolPacket,
         tunnel_info: TunnelInfo,
+        original_hostname: Option<&str>,
         permit: Permit<'_, TransportEvent>,
     ) -> Result<()> {
         let src_ip = packet.src_ip();
@@ -120,52 +212,157 @@ impl TcpHandler<'_> {
         let src_addr = SocketAddr::new(src_ip, tcp_packet.src_port());
         let dst_addr = SocketAddr::new(dst_ip, tcp_packet.dst_port());
 
+        if let Some(&connection_id) = self.active_connections.get(&(src_addr, dst_addr)) {
+            if self.pending_connections.contains_key(&connection_id) {
+                // The handshake is held back, so we only care about the client giving up.
+                // SYN retransmissions are dropped, we still have the original one.
+                if tcp_packet.rst() {
+                    let pending = self.pending_connections.remove(&connection_id).unwrap();
+                    self.active_connections.remove(&pending.addr_tuple);
+                    self.events
+                        .push(pending.closed_event(connection_id, CloseReason::Reset));
+                }
+                return Ok(());
+            }
+        }
+
+        if tcp_packet.rst() {
+            if let Some(data) = self
+                .active_connections
+                .get(&(src_addr, dst_addr))
+                .and_then(|id| self.socket_data.get_mut(id))
+            {
+                data.close_reason = Some(CloseReason::Reset);
+            }
+        }
+
         if tcp_packet.syn()
             && !tcp_packet.ack()
-            && !self.active_connections.contains(&(src_addr, dst_addr))
+            && !self.active_connections.contains_key(&(src_addr, dst_addr))
         {
-            let mut socket = tcp::Socket::new(
-                tcp::SocketBuffer::new(vec![0u8; 64 * 1024]),
-                tcp::SocketBuffer::new(vec![0u8; 64 * 1024]),
-            );
-
-            socket.listen(dst_addr)?;
-            socket.set_timeout(Some(smoltcp::time::Duration::from_secs(60)));
-            socket.set_keep_alive(Some(smoltcp::time::Duration::from_secs(28)));
-
-            let handle = self.sockets.add(socket);
-
             let connection_id = self.connection_id_generator.next_id();
+            let opened = Instant::now();
 
-            let data = SocketData {
-                handle,
-                send_buffer: VecDeque::new(),
-                write_eof: false,
-                recv_waiter: None,
-                drain_waiter: Vec::new(),
-                addr_tuple: (src_addr, dst_addr),
-            };
-            self.socket_data.insert(connection_id, data);
-            self.active_connections.insert((src_addr, dst_addr));
+            if self.accept_timeout.is_none() {
+                self.create_socket(connection_id, (src_addr, dst_addr), opened)?;
+            }
+            self.active_connections
+                .insert((src_addr, dst_addr), connection_id);
 
             let event = TransportEvent::ConnectionEstablished {
                 connection_id,
                 src_addr,
                 dst_addr,
                 tunnel_info,
+                original_hostname: original_hostname.map(str::to_string),
+                client_hello: None,
                 command_tx: None,
             };
-            permit.send(event);
+
+            if let (None, Some(timeout)) = (self.accept_timeout, self.peek_timeout) {
+                // We report the connection once we know whether it starts with a ClientHello.
+                let data = self.socket_data.get_mut(&connection_id).unwrap();
+                data.established = Some((event, opened + timeout));
+            } else {
+                permit.send(event);
+            }
+
+            if let Some(timeout) = self.accept_timeout {
+                // We keep the SYN and only feed it into smoltcp once Python accepts the connection.
+                self.pending_connections.insert(
+                    connection_id,
+                    PendingConnection {
+                        syn: packet,
+                        addr_tuple: (src_addr, dst_addr),
+                        opened,
+                        deadline: opened + timeout,
+                    },
+                );
+                return Ok(());
+            }
         }
 
         self.device.receive_packet(packet);
         Ok(())
     }
 
+    fn create_socket(
+        &mut self,
+        connection_id: ConnectionId,
+        addr_tuple: (SocketAddr, SocketAddr),
+        opened: Instant,
+    ) -> Result<()> {
+        let mut socket = new_socket();
+        socket.listen(addr_tuple.1)?;
+
+        let handle = self.sockets.add(socket);
+        self.socket_data
+            .insert(connection_id, SocketData::new(handle, addr_tuple, opened));
+        Ok(())
+    }
+
+    pub fn open_connection(
+        &mut self,
+        mut src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        tx: oneshot::Sender<io::Result<(ConnectionId, SocketAddr)>>,
+        closed: watch::Sender<()>,
+    ) {
+        if src_addr.port() == 0 {
+            let Some(port) = ephemeral_port(&mut self.next_ephemeral_port, |port| {
+                !self
+                    .active_connections
+                    .contains_key(&(dst_addr, SocketAddr::new(src_addr.ip(), port)))
+            }) else {
+                tx.send(Err(io::ErrorKind::AddrInUse.into())).ok();
+                return;
+            };
+            src_addr.set_port(port);
+        }
+        if self.active_connections.contains_key(&(dst_addr, src_addr)) {
+            tx.send(Err(io::ErrorKind::AddrInUse.into())).ok();
+            return;
+        }
+
+        let mut socket = new_socket();
+        if let Err(e) = socket.connect(self.iface.context(), dst_addr, src_addr) {
+            tx.send(Err(io::Error::new(io::ErrorKind::InvalidInput, e)))
+                .ok();
+            return;
+        }
+        let handle = self.sockets.add(socket);
+
+        let connection_id = self.connection_id_generator.next_id();
+        let mut data = SocketData::new(handle, (dst_addr, src_addr), Instant::now());
+        data.connect_waiter = Some(tx);
+        data.closed = Some(closed);
+        self.socket_data.insert(connection_id, data);
+        self.active_connections
+            .insert((dst_addr, src_addr), connection_id);
+    }
+
     pub fn poll_delay(&mut self) -> Option<Duration> {
-        self.iface
-            .poll_delay(Instant::now(), &self.sockets)
-            .map(Duration::from)
+        let now = Instant::now();
+        let iface_delay = self.iface.poll_delay(now, &self.sockets);
+        let close_delay = self
+            .socket_data
+            .values()
+            .flat_map(|data| [data.close_deadline, data.established.as_ref().map(|e| e.1)])
+            .flatten()
+            .chain(self.pending_connections.values().map(|p| p.deadline))
+            .min()
+            .map(|deadline| {
+                if deadline > now {
+                    deadline - now
+                } else {
+                    smoltcp::time::Duration::ZERO
+                }
+            });
+        match (iface_delay, close_delay) {
+            (Some(a), Some(b)) => Some(cmp::min(a, b)),
+            (a, b) => a.or(b),
+        }
+        .map(Duration::from)
     }
 
     pub fn handle_transport_command(&mut self, command: TransportCommand) {
@@ -176,10 +373,72 @@ impl TcpHandler<'_> {
             TransportCommand::CloseConnection(id, half_close) => {
                 self.close_connection(id, half_close)
             }
+            TransportCommand::AbortConnection(id) => self.abort_connection(id),
+            TransportCommand::AcceptConnection(id) => self.accept_connection(id),
+            TransportCommand::RejectConnection(id, rejection) => {
+                self.reject_connection(id, rejection)
+            }
+            TransportCommand::OpenConnection {
+                udp: _,
+                src_addr,
+                dst_addr,
+                tx,
+                closed,
+            } => self.open_connection(src_addr, dst_addr, tx, closed),
+            // Raw packets are handled by the network stack.
+            TransportCommand::SendRawPacket(_) => (),
+            // Splicing is handled by the network task.
+            TransportCommand::SpliceConnection { tx, .. } => {
+                tx.send(Err(io::ErrorKind::Unsupported.into())).ok();
+            }
         };
     }
 
+    pub fn accept_connection(&mut self, id: ConnectionId) {
+        let Some(pending) = self.pending_connections.remove(&id) else {
+            // connection is not deferred (anymore).
+            return;
+        };
+        match self.create_socket(id, pending.addr_tuple, pending.opened) {
+            Ok(()) => self.device.receive_packet(pending.syn),
+            Err(e) => {
+                log::error!("Failed to accept TCP connection: {}", e);
+                self.active_connections.remove(&pending.addr_tuple);
+                self.events
+                    .push(pending.closed_event(id, CloseReason::LocalClose));
+            }
+        }
+    }
+
+    pub fn reject_connection(&mut self, id: ConnectionId, rejection: Rejection) {
+        if let Some(pending) = self.pending_connections.remove(&id) {
+            self.refuse(id, pending, rejection, CloseReason::LocalClose);
+        } else {
+            // the handshake has already happened, so all we can do is a RST.
+            self.abort_connection(id);
+        }
+    }
+
+    fn refuse(
+        &mut self,
+        id: ConnectionId,
+        pending: PendingConnection,
+        rejection: Rejection,
+        reason: CloseReason,
+    ) {
+        let response = match rejection {
+            Rejection::Reset => reset_reply(pending.syn.clone()),
+            rejection => icmp_error(&pending.syn, rejection),
+        };
+        if let Some(response) = response {
+            self.device.send_packet(response);
+        }
+        self.active_connections.remove(&pending.addr_tuple);
+        self.events.push(pending.closed_event(id, reason));
+    }
+
     pub fn read_data(&mut self, id: ConnectionId, n: u32, tx: oneshot::Sender<Vec<u8>>) {
+        self.accept_connection(id);
         if let Some(data) = self.socket_data.get_mut(&id) {
             assert!(data.recv_waiter.is_none());
             data.recv_waiter = Some((n, tx));
@@ -190,6 +449,7 @@ impl TcpHandler<'_> {
     }
 
     pub fn write_data(&mut self, id: ConnectionId, buf: Vec<u8>) {
+        self.accept_connection(id);
         if let Some(data) = self.socket_data.get_mut(&id) {
             data.send_buffer.extend(buf);
         } else {
@@ -199,6 +459,7 @@ impl TcpHandler<'_> {
     }
 
     pub fn drain_writer(&mut self, id: ConnectionId, tx: oneshot::Sender<()>) {
+        self.accept_connection(id);
         if let Some(data) = self.socket_data.get_mut(&id) {
             data.drain_waiter.push(tx);
         } else {
@@ -207,23 +468,64 @@ impl TcpHandler<'_> {
         }
     }
 
-    pub fn close_connection(&mut self, id: ConnectionId, _half_close: bool) {
-        if let Some(data) = self.socket_data.get_mut(&id) {
-            // smoltcp does not have a good way to do a full close ("SHUT_RDWR"). We can't call
-            // .abort() here because that sends a RST instead of a FIN (and breaks
-            // retransmissions of the connection close packet). Alternatively, we could manually
-            // set a timer on .close() and then forcibly .abort() once the timer expires (see
-            // tcp-abort branch). This incurs a bit of unnecessary complexity, so we try something
-            // dumber here: We simply close our end and then hope that either the client sends a FIN
-            // or times out via the keepalive mechanism.
-
+    pub fn close_connection(&mut self, id: ConnectionId, half_close: bool) {
+        if let Some(pending) = self.pending_connections.remove(&id) {
+            self.refuse(id, pending, Rejection::Reset, CloseReason::LocalClose);
+        } else if let Some(data) = self.socket_data.get_mut(&id) {
+            // We always send a FIN once the send buffer has been flushed.
             data.write_eof = true;
+            data.close_reason.get_or_insert(CloseReason::LocalClose);
+
+            if !half_close {
+                // smoltcp does not have a good way to do a full close ("SHUT_RDWR"). We can't call
+                // .abort() here because that sends a RST instead of a FIN (and breaks
+                // retransmissions of the connection close packet). Instead, we stop reading and
+                // set a timer once our FIN has been acknowledged (see process_tcp). If the client
+                // hasn't closed its end by then, we abort the connection.
+                data.full_close = true;
+                if let Some((_, tx)) = data.recv_waiter.take() {
+                    tx.send(Vec::new()).ok();
+                }
+            }
+        } else {
+            // connection is already dead.
+        }
+    }
+
+    pub fn abort_connection(&mut self, id: ConnectionId) {
+        if let Some(pending) = self.pending_connections.remove(&id) {
+            self.refuse(id, pending, Rejection::Reset, CloseReason::LocalClose);
+        } else if let Some(data) = self.socket_data.get_mut(&id) {
+            // This immediately moves the socket to the CLOSED state, smoltcp sends a RST on the
+            // next poll.
+            self.sockets.get_mut::<tcp::Socket>(data.handle).abort();
+            data.close_reason.get_or_insert(CloseReason::LocalClose);
+            data.send_buffer.clear();
+            data.write_eof = false;
+            if let Some((_, tx)) = data.recv_waiter.take() {
+                tx.send(Vec::new()).ok();
+            }
+            // dropping the drain waiters signals that the connection is closed.
+            data.drain_waiter.clear();
         } else {
             // connection is already dead.
         }
     }
 
     pub fn poll(&mut self) -> Result<()> {
+        // reject deferred connections that Python hasn't decided on in time.
+        let now = Instant::now();
+        let expired: Vec<ConnectionId> = self
+            .pending_connections
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            let pending = self.pending_connections.remove(&id).unwrap();
+            self.refuse(id, pending, Rejection::Reset, CloseReason::Timeout);
+        }
+
         // poll virtual network device
         #[cfg(debug_assertions)]
         log::debug!("Polling virtual network device ...");
@@ -244,9 +546,74 @@ impl TcpHandler<'_> {
     }
 
     fn process_tcp(&mut self) -> Result<()> {
+        let now = Instant::now();
         for (connection_id, data) in self.socket_data.iter_mut() {
             let socket = self.sockets.get_mut::<tcp::Socket>(data.handle);
 
+            if socket.state() == tcp::State::CloseWait {
+                data.close_reason.get_or_insert(CloseReason::PeerClosed);
+            }
+
+            if let Some((_, deadline)) = &data.established {
+                // smoltcp refuses to peek before the handshake has completed.
+                let peek = socket
+                    .peek(socket.recv_queue())
+                    .map_or(Peek::Incomplete, peek_tls);
+                let peeked = match peek {
+                    Peek::Complete(client_hello) => Some(Some(client_hello)),
+                    Peek::NotTls => Some(None),
+                    Peek::Incomplete => {
+                        // give up if the ClientHello cannot arrive anymore or is taking too long.
+                        use tcp::State::*;
+                        let stalled = socket.recv_queue() == socket.recv_capacity()
+                            || matches!(
+                                socket.state(),
+                                CloseWait | LastAck | Closed | Closing | TimeWait
+                            );
+                        (stalled || now >= *deadline).then_some(None)
+                    }
+                };
+                if let Some(peeked) = peeked {
+                    let (mut event, _) = data.established.take().unwrap();
+                    if let TransportEvent::ConnectionEstablished { client_hello, .. } = &mut event {
+                        *client_hello = peeked;
+                    }
+                    self.events.push(event);
+                }
+            }
+
+            if socket.may_send() {
+                if let Some(tx) = data.connect_waiter.take() {
+                    if tx.send(Ok((*connection_id, data.addr_tuple.1))).is_err() {
+                        // nobody is waiting for this connection anymore.
+                        socket.abort();
+                        data.close_reason = Some(CloseReason::LocalClose);
+                    }
+                }
+            }
+
+            if data.full_close {
+                // nobody is going to read this anymore.
+                while socket.can_recv() {
+                    socket.recv(|buf| (buf.len(), ()))?;
+                }
+
+                // once our FIN is acknowledged, give the client some time to close its end.
+                if socket.state() == tcp::State::FinWait2 {
+                    let deadline = *data.close_deadline.get_or_insert(now + FIN_WAIT_2_TIMEOUT);
+                    if now >= deadline {
+                        log::debug!(
+                            "TCP connection {}: client did not close connection, aborting.",
+                            connection_id
+                        );
+                        socket.abort();
+                        data.close_reason = Some(CloseReason::Timeout);
+                    }
+                } else {
+                    data.close_deadline = None;
+                }
+            }
+
             // receive data over the socket
             if data.recv_waiter.is_some() {
                 if socket.can_recv() {
@@ -255,6 +622,7 @@ impl TcpHandler<'_> {
 
                     let mut buf = vec![0u8; cmp::min(bytes_available, n as usize)];
                     let bytes_read = socket.recv_slice(&mut buf)?;
+                    data.bytes_received += bytes_read as u64;
 
                     buf.truncate(bytes_read);
                     if tx.send(buf).is_err() {
@@ -281,6 +649,7 @@ impl TcpHandler<'_> {
                 let (a, b) = data.send_buffer.as_slices();
                 let sent = socket.send_slice(a)? + socket.send_slice(b)?;
                 data.send_buffer.drain(..sent);
+                data.bytes_sent += sent as u64;
             }
 
             // if necessary, drain write buffers:
@@ -304,25 +673,136 @@ impl TcpHandler<'_> {
                 data.addr_tuple,
             );
 
-            // if requested, close socket
-            if data.write_eof && data.send_buffer.is_empty() {
+            // if requested, close socket.
+            // We wait for the handshake to complete, smoltcp does not reliably send a FIN otherwise.
+            if data.write_eof
+                && data.send_buffer.is_empty()
+                && socket.state() != tcp::State::SynReceived
+            {
                 socket.close();
                 data.write_eof = false;
             }
 
-            // if socket is closed, mark connection for removal
-            if socket.state() == tcp::State::Closed {
+            // if socket is closed, mark connection for removal.
+            // Aborted sockets keep their remote endpoint until the RST has been sent.
+            if socket.state() == tcp::State::Closed && socket.remote_endpoint().is_none() {
                 self.remove_conns.push(*connection_id);
             }
         }
 
         for connection_id in self.remove_conns.drain(..) {
-            let data = self.socket_data.remove(&connection_id).unwrap();
+            let mut data = self.socket_data.remove(&connection_id).unwrap();
             self.sockets.remove(data.handle);
             self.active_connections.remove(&data.addr_tuple);
+            // if nobody closed the connection, smoltcp has given up on it.
+            let reason = data.close_reason.unwrap_or(CloseReason::Timeout);
+            if let Some(tx) = data.connect_waiter {
+                // the connection has never been established, so Python doesn't know about it.
+                let kind = match reason {
+                    CloseReason::Reset => io::ErrorKind::ConnectionRefused,
+                    _ => io::ErrorKind::TimedOut,
+                };
+                tx.send(Err(kind.into())).ok();
+            } else {
+                if let Some((event, _)) = data.established.take() {
+                    // the connection is gone before we were done peeking.
+                    self.events.push(event);
+                }
+                self.events.push(data.closed_event(connection_id, reason));
+            }
         }
         Ok(())
     }
+
+    /// Take the `ConnectionClosed` events for connections that have been removed,
+    /// and `ConnectionEstablished` events that have been held back until now.
+    pub fn take_events(&mut self) -> Vec<TransportEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Report all remaining connections as closed because the server is shutting down.
+    pub fn shutdown(&mut self) -> Vec<TransportEvent> {
+        self.socket_data
+            .iter()
+            // Python doesn't know about connections we have been peeking at.
+            .filter(|(_, data)| data.established.is_none())
+            .map(|(id, data)| data.closed_event(*id, CloseReason::Shutdown))
+            .chain(
+                self.pending_connections
+                    .iter()
+                    .map(|(id, pending)| pending.closed_event(*id, CloseReason::Shutdown)),
+            )
+            .collect()
+    }
+}
+
+fn new_socket() -> tcp::Socket<'static> {
+    let mut socket = tcp::Socket::new(
+        tcp::SocketBuffer::new(vec![0u8; 64 * 1024]),
+        tcp::SocketBuffer::new(vec![0u8; 64 * 1024]),
+    );
+    socket.set_timeout(Some(smoltcp::time::Duration::from_secs(60)));
+    socket.set_keep_alive(Some(smoltcp::time::Duration::from_secs(28)));
+    socket
+}
+
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+
+/// Pick the next port from the dynamic range (RFC 6335) for which `is_free` returns true.
+/// `next` keeps track of where the search continues on the next call.
+pub(super) fn ephemeral_port(next: &mut u16, is_free: impl Fn(u16) -> bool) -> Option<u16> {
+    for _ in EPHEMERAL_PORTS {
+        let port = (*next).max(*EPHEMERAL_PORTS.start());
+        *next = port.checked_add(1).unwrap_or(*EPHEMERAL_PORTS.start());
+        if is_free(port) {
+            return Some(port);
+        }
+    }
+    None
+}
+
+/// Build a RST in response to a TCP segment, as described in RFC 9293, Section 3.10.7.1.
+fn reset_reply(mut packet: SmolPacket) -> Option<SmolPacket> {
+    let src_addr = IpAddress::from(packet.src_ip());
+    let dst_addr = IpAddress::from(packet.dst_ip());
+    let tcp_packet = TcpPacket::new_checked(&*packet.payload_mut()).ok()?;
+    let segment = TcpRepr::parse(
+        &tcp_packet,
+        &src_addr,
+        &dst_addr,
+        &ChecksumCapabilities::ignored(),
+    )
+    .ok()?;
+    let tcp_repr = TcpRepr {
+        src_port: segment.dst_port,
+        dst_port: segment.src_port,
+        control: TcpControl::Rst,
+        seq_number: segment.ack_number.unwrap_or_default(),
+        ack_number: Some(segment.seq_number + segment.segment_len()),
+        window_len: 0,
+        window_scale: None,
+        max_seg_size: None,
+        sack_permitted: false,
+        sack_ranges: [None, None, None],
+        timestamp: None,
+        payload: &[],
+    };
+    let ip_repr = IpRepr::new(
+        dst_addr,
+        src_addr,
+        IpProtocol::Tcp,
+        tcp_repr.buffer_len(),
+        64,
+    );
+    let mut buf = vec![0u8; ip_repr.buffer_len()];
+    ip_repr.emit(&mut buf[..], &ChecksumCapabilities::default());
+    tcp_repr.emit(
+        &mut TcpPacket::new_unchecked(&mut buf[ip_repr.header_len()..]),
+        &dst_addr,
+        &src_addr,
+        &ChecksumCapabilities::default(),
+    );
+    SmolPacket::try_from(buf).ok()
 }
 
 impl fmt::Debug for TcpHandler<'_> {
@@ -1,16 +1,25 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
 
+use super::capture::{Capture, CaptureTap};
+use super::shaping::{LinkConditions, ShapingConf, ShapingProfile};
 use super::task::NetworkTask;
+use super::{NetworkConf, ProtocolAction};
 use crate::messages::{
-    NetworkCommand, NetworkEvent, SmolPacket, TransportCommand, TransportEvent, TunnelInfo,
+    CloseReason, ConnectionId, NetworkCommand, NetworkEvent, Rejection, SmolPacket, SpliceStats,
+    TransportCommand, TransportEvent, TunnelInfo, UnreachableCode,
 };
 use crate::shutdown;
 use anyhow::{anyhow, Result};
+use boringtun::x25519::PublicKey;
 use core::net::Ipv4Addr;
 use core::net::Ipv6Addr;
 use internet_packet::InternetPacket;
 use smoltcp::{phy::ChecksumCapabilities, wire::*};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::watch;
+use tokio::time::{timeout, Instant};
 use tokio::{
     sync::{
         mpsc::{channel, unbounded_channel, Receiver, Sender, UnboundedSender},
@@ -32,6 +41,10 @@ struct MockNetwork {
 
 impl MockNetwork {
     async fn init() -> Result<Self> {
+        Self::init_with_conf(NetworkConf::default()).await
+    }
+
+    async fn init_with_conf(conf: NetworkConf) -> Result<Self> {
         let (wg_to_smol_tx, wg_to_smol_rx) = channel(16);
         let (smol_to_wg_tx, smol_to_wg_rx) = channel(16);
 
@@ -46,6 +59,7 @@ impl MockNetwork {
             smol_to_py_tx,
             py_to_smol_rx,
             sd_watcher,
+            &conf,
         );
 
         let handle = tokio::spawn(task.run());
@@ -69,6 +83,8 @@ impl MockNetwork {
         let tunnel_info = TunnelInfo::WireGuard {
             src_addr: "192.168.86.134:12345".parse()?,
             dst_addr: "0.0.0.0:0".parse()?,
+            peer_public_key: PublicKey::from([0; 32]),
+            peer_index: 0,
         };
         let event = NetworkEvent::ReceivePacket {
             packet,
@@ -84,6 +100,24 @@ impl MockNetwork {
         packet
     }
 
+    /// Pull the next packet and parse it as an IPv4 TCP segment.
+    async fn pull_tcp_segment(&mut self) -> Result<(TcpControl, TcpSeqNumber, Vec<u8>)> {
+        let mut packet = match self.pull_smol_packet().await {
+            SmolPacket::V4(packet) => packet,
+            SmolPacket::V6(_) => return Err(anyhow!("Received unexpected IPv6 packet!")),
+        };
+        let src_addr = packet.src_addr();
+        let dst_addr = packet.dst_addr();
+        let repr = TcpRepr::parse(
+            &TcpPacket::new_unchecked(packet.payload_mut()),
+            &src_addr.into(),
+            &dst_addr.into(),
+            &ChecksumCapabilities::default(),
+        )
+        .map_err(|e| anyhow!("Invalid TCP packet: {}", e))?;
+        Ok((repr.control, repr.seq_number, repr.payload.to_vec()))
+    }
+
     async fn pull_packet(&mut self) -> InternetPacket {
         let packet = self.pull_smol_packet().await;
         packet.try_into().unwrap()
@@ -313,7 +347,7 @@ fn build_icmp6_echo_packet(
     let ip_repr = Ipv6Repr {
         src_addr,
         dst_addr,
-        next_header: IpProtocol::Icmp,
+        next_header: IpProtocol::Icmpv6,
         payload_len: icmp_repr.buffer_len(),
         hop_limit: 255,
     };
@@ -360,7 +394,10 @@ async fn udp_read_write(
         src_addr: recv_src_addr,
         dst_addr: recv_dst_addr,
         ..
-    } = event;
+    } = event
+    else {
+        panic!("expected ConnectionEstablished");
+    };
 
     assert_eq!(src_addr, recv_src_addr);
     assert_eq!(dst_addr, recv_dst_addr);
@@ -428,6 +465,228 @@ async fn ipv6_udp() -> Result<()> {
     .await
 }
 
+/// IPv6 extension header data consisting of a single PadN option.
+const PADN_OPTION: [u8; 6] = [1, 4, 0, 0, 0, 0];
+
+/// Insert an extension header of the given type directly after the IPv6 header.
+fn push_ipv6_extension_header(
+    packet: Ipv6Packet<Vec<u8>>,
+    header_type: IpProtocol,
+    data: &[u8],
+) -> Ipv6Packet<Vec<u8>> {
+    assert_eq!((data.len() + 2) % 8, 0);
+    let next_header = packet.next_header();
+    let payload_len = packet.payload_len() as usize + data.len() + 2;
+    let mut buf = packet.into_inner();
+    let header = [u8::from(next_header), ((data.len() + 2) / 8 - 1) as u8]
+        .into_iter()
+        .chain(data.iter().copied());
+    buf.splice(IPV6_HEADER_LEN..IPV6_HEADER_LEN, header);
+    let mut packet = Ipv6Packet::new_unchecked(buf);
+    packet.set_next_header(header_type);
+    packet.set_payload_len(payload_len as u16);
+    packet
+}
+
+#[tokio::test]
+async fn ipv6_udp_extension_headers() -> Result<()> {
+    init_logger();
+
+    let src_addr: Ipv6Addr = "ca:fe:ca:fe:ca:fe:00:01".parse()?;
+    let dst_addr: Ipv6Addr = "ca:fe:ca:fe:ca:fe:00:02".parse()?;
+    let data = "hello world!".as_bytes();
+
+    let packet = build_ipv6_udp_packet(src_addr, dst_addr, 1234, 31337, data);
+    let packet = push_ipv6_extension_header(packet, IpProtocol::Ipv6Opts, &PADN_OPTION);
+    // an experimental routing header without segments left.
+    let packet = push_ipv6_extension_header(packet, IpProtocol::Ipv6Route, &[254, 0, 0, 0, 0, 0]);
+    let packet = push_ipv6_extension_header(packet, IpProtocol::HopByHop, &PADN_OPTION);
+
+    let mut packet = SmolPacket::from(packet);
+    assert_eq!(packet.transport_protocol(), IpProtocol::Udp);
+    assert_eq!(packet.header_len(), IPV6_HEADER_LEN + 24);
+    assert_eq!(
+        smoltcp::wire::UdpPacket::new_checked(packet.payload_mut())?.dst_port(),
+        31337
+    );
+
+    udp_read_write(
+        packet,
+        SocketAddr::from((src_addr, 1234)),
+        SocketAddr::from((dst_addr, 31337)),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn tcp_ipv6_extension_headers() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init().await?;
+
+    let src_addr: Ipv6Addr = "ca:fe:ca:fe:ca:fe:00:01".parse()?;
+    let dst_addr: Ipv6Addr = "ca:fe:ca:fe:ca:fe:00:02".parse()?;
+    let seq = TcpSeqNumber(rand::random::<i32>());
+    let syn = build_ipv6_tcp_packet(
+        src_addr,
+        dst_addr,
+        1234,
+        31337,
+        TcpControl::Syn,
+        seq,
+        None,
+        &[],
+    );
+    let syn = push_ipv6_extension_header(syn, IpProtocol::Ipv6Opts, &PADN_OPTION);
+    let syn = push_ipv6_extension_header(syn, IpProtocol::HopByHop, &PADN_OPTION);
+    mock.push_smol_packet(syn.into()).await?;
+
+    let Some(TransportEvent::ConnectionEstablished { src_addr: peer, .. }) =
+        mock.pull_py_event().await
+    else {
+        panic!("expected ConnectionEstablished");
+    };
+    assert_eq!(peer, SocketAddr::from((src_addr, 1234)));
+
+    let mut synack = match mock.pull_smol_packet().await {
+        SmolPacket::V6(packet) => packet,
+        SmolPacket::V4(_) => return Err(anyhow!("Received unexpected IPv4 packet!")),
+    };
+    assert_eq!(synack.dst_addr(), src_addr);
+    let repr = TcpRepr::parse(
+        &TcpPacket::new_checked(&*synack.payload_mut())?,
+        &dst_addr.into(),
+        &src_addr.into(),
+        &ChecksumCapabilities::default(),
+    )
+    .map_err(|e| anyhow!("Invalid TCP packet: {}", e))?;
+    assert_eq!(repr.control, TcpControl::Syn);
+    assert_eq!(repr.ack_number, Some(seq + 1));
+
+    mock.stop().await
+}
+
+#[test]
+fn mld_report_with_router_alert() -> Result<()> {
+    // MLDv2 listener report for ff02::fb as sent by Linux,
+    // with a Hop-by-Hop header carrying a router alert option.
+    let packet = vec![
+        0x60, 0x00, 0x00, 0x00, 0x00, 0x24, 0x00, 0x01, 0xfe, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x02, 0x15, 0x5d, 0xff, 0xfe, 0x3c, 0x11, 0x7a, 0xff, 0x02, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x16, 0x3a, 0x00, 0x05, 0x02, 0x00,
+        0x00, 0x01, 0x00, 0x8f, 0x00, 0x2c, 0x51, 0x00, 0x00, 0x00, 0x01, 0x04, 0x00, 0x00, 0x00,
+        0xff, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xfb,
+    ];
+    let mut packet = SmolPacket::try_from(packet)?;
+    assert_eq!(packet.transport_protocol(), IpProtocol::Icmpv6);
+    assert_eq!(packet.header_len(), 48);
+    assert_eq!(packet.payload_mut().len(), 28);
+    assert_eq!(
+        Icmpv6Packet::new_checked(packet.payload_mut())?.msg_type(),
+        Icmpv6Message::MldReport
+    );
+
+    let SmolPacket::V6(packet) = packet.without_extension_headers() else {
+        unreachable!();
+    };
+    assert_eq!(packet.next_header(), IpProtocol::Icmpv6);
+    assert_eq!(packet.payload_len(), 28);
+    Ok(())
+}
+
+#[test]
+fn udp_with_destination_options() -> Result<()> {
+    // DNS query for example.com with a Destination Options header
+    // carrying a tunnel encapsulation limit and padding.
+    let packet = vec![
+        0x60, 0x00, 0x00, 0x00, 0x00, 0x2d, 0x3c, 0x40, 0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x53, 0x11, 0x00, 0x04, 0x01, 0x04,
+        0x01, 0x01, 0x00, 0xc3, 0x50, 0x00, 0x35, 0x00, 0x25, 0xf6, 0xbf, 0x1a, 0x2b, 0x01, 0x00,
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c,
+        0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x01, 0x00, 0x01,
+    ];
+    let mut packet = SmolPacket::try_from(packet)?;
+    assert_eq!(packet.transport_protocol(), IpProtocol::Udp);
+    assert_eq!(packet.header_len(), IPV6_HEADER_LEN + 8);
+    assert_eq!(UdpPacket::new_checked(packet.payload_mut())?.dst_port(), 53);
+
+    let SmolPacket::V6(mut packet) = packet.without_extension_headers() else {
+        unreachable!();
+    };
+    assert_eq!(packet.next_header(), IpProtocol::Udp);
+    let (src_addr, dst_addr) = (packet.src_addr(), packet.dst_addr());
+    let repr = UdpRepr::parse(
+        &UdpPacket::new_checked(&*packet.payload_mut())?,
+        &src_addr.into(),
+        &dst_addr.into(),
+        &ChecksumCapabilities::default(),
+    )
+    .map_err(|e| anyhow!("Invalid UDP packet: {}", e))?;
+    assert_eq!((repr.src_port, repr.dst_port), (50000, 53));
+    Ok(())
+}
+
+#[test]
+fn tcp_with_routing_header() -> Result<()> {
+    // TCP SYN with a Segment Routing header whose only segment has been reached.
+    let packet = vec![
+        0x60, 0x00, 0x00, 0x00, 0x00, 0x30, 0x2b, 0x40, 0xfd, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0xfd, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x06, 0x02, 0x04, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0xfd, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x02, 0x9c, 0x40, 0x01, 0xbb, 0x12, 0x34, 0x56, 0x78, 0x00, 0x00, 0x00,
+        0x00, 0x60, 0x02, 0xfd, 0x20, 0x9a, 0x81, 0x00, 0x00, 0x02, 0x04, 0x05, 0x8c,
+    ];
+    let mut packet = SmolPacket::try_from(packet)?;
+    assert_eq!(packet.transport_protocol(), IpProtocol::Tcp);
+    assert_eq!(packet.header_len(), IPV6_HEADER_LEN + 24);
+    assert_eq!(
+        TcpPacket::new_checked(packet.payload_mut())?.dst_port(),
+        443
+    );
+
+    let SmolPacket::V6(mut packet) = packet.without_extension_headers() else {
+        unreachable!();
+    };
+    assert_eq!(packet.next_header(), IpProtocol::Tcp);
+    assert_eq!(packet.payload_len(), 24);
+    let (src_addr, dst_addr) = (packet.src_addr(), packet.dst_addr());
+    let repr = TcpRepr::parse(
+        &TcpPacket::new_checked(&*packet.payload_mut())?,
+        &src_addr.into(),
+        &dst_addr.into(),
+        &ChecksumCapabilities::default(),
+    )
+    .map_err(|e| anyhow!("Invalid TCP packet: {}", e))?;
+    assert_eq!(repr.control, TcpControl::Syn);
+    assert_eq!(repr.max_seg_size, Some(1420));
+    Ok(())
+}
+
+#[test]
+fn truncated_extension_headers() -> Result<()> {
+    // a Hop-by-Hop header that claims to be 16 bytes long, followed by nothing.
+    let packet = vec![
+        0x60, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x40, 0xfd, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0xfd, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x11, 0x01, 0x01, 0x04, 0x00,
+        0x00, 0x00, 0x00,
+    ];
+    let mut packet = SmolPacket::try_from(packet)?;
+    assert!(packet.has_truncated_extension_headers());
+    assert_eq!(packet.transport_protocol(), IpProtocol::Ipv6NoNxt);
+    assert_eq!(packet.header_len(), IPV6_HEADER_LEN + 8);
+    assert!(packet.payload_mut().is_empty());
+
+    let SmolPacket::V6(packet) = packet.without_extension_headers() else {
+        unreachable!();
+    };
+    assert_eq!(packet.next_header(), IpProtocol::HopByHop);
+    assert_eq!(packet.payload_len(), 8);
+    Ok(())
+}
+
 #[tokio::test]
 async fn tcp_ipv4_connection() -> Result<()> {
     init_logger();
@@ -496,7 +755,10 @@ async fn tcp_ipv4_connection() -> Result<()> {
         src_addr: tcp_src_sock,
         dst_addr: tcp_dst_sock,
         ..
-    } = event;
+    } = event
+    else {
+        panic!("expected ConnectionEstablished");
+    };
     assert_eq!(IpAddress::Ipv4(src_addr), tcp_src_sock.ip().into());
     assert_eq!(IpAddress::Ipv4(dst_addr), tcp_dst_sock.ip().into());
 
@@ -673,7 +935,10 @@ async fn tcp_ipv6_connection() -> Result<()> {
         src_addr: tcp_src_sock,
         dst_addr: tcp_dst_sock,
         ..
-    } = event;
+    } = event
+    else {
+        panic!("expected ConnectionEstablished");
+    };
     assert_eq!(IpAddress::Ipv6(src_addr), tcp_src_sock.ip().into());
     assert_eq!(IpAddress::Ipv6(dst_addr), tcp_dst_sock.ip().into());
 
@@ -782,78 +1047,1302 @@ async fn tcp_ipv6_connection() -> Result<()> {
     mock.stop().await
 }
 
+/// Send a TCP segment from 10.0.0.1:1234 to 10.0.0.42:31337.
+async fn push_tcp_segment(
+    mock: &MockNetwork,
+    control: TcpControl,
+    seq: TcpSeqNumber,
+    ack: Option<TcpSeqNumber>,
+    payload: &[u8],
+) -> Result<()> {
+    let packet = build_ipv4_tcp_packet(
+        "10.0.0.1".parse()?,
+        "10.0.0.42".parse()?,
+        1234,
+        31337,
+        control,
+        seq,
+        ack,
+        payload,
+    );
+    mock.push_smol_packet(packet.into()).await
+}
+
+/// Establish a TCP connection from 10.0.0.1:1234 to 10.0.0.42:31337.
+/// Returns the connection id and the next sequence numbers of the client and the network stack.
+async fn tcp_handshake(
+    mock: &mut MockNetwork,
+) -> Result<(ConnectionId, TcpSeqNumber, TcpSeqNumber)> {
+    let seq = TcpSeqNumber(rand::random::<i32>());
+    push_tcp_segment(mock, TcpControl::Syn, seq, None, &[]).await?;
+
+    let Some(TransportEvent::ConnectionEstablished { connection_id, .. }) =
+        mock.pull_py_event().await
+    else {
+        panic!("expected ConnectionEstablished");
+    };
+
+    let (control, synack_seq, _) = mock.pull_tcp_segment().await?;
+    assert_eq!(control, TcpControl::Syn);
+
+    push_tcp_segment(mock, TcpControl::None, seq + 1, Some(synack_seq + 1), &[]).await?;
+    Ok((connection_id, seq + 1, synack_seq + 1))
+}
+
 #[tokio::test]
-async fn receive_icmp4_echo() -> Result<()> {
+async fn tcp_half_close() -> Result<()> {
     init_logger();
     let mut mock = MockNetwork::init().await?;
+    let (tcp_conn_id, mut seq, _) = tcp_handshake(&mut mock).await?;
 
-    let src_addr = "10.0.0.1".parse()?;
-    let dst_addr = "10.0.0.42".parse()?;
-    let data = "hello world!".as_bytes();
+    mock.push_py_command(TransportCommand::CloseConnection(tcp_conn_id, true))
+        .await?;
+    let (control, fin_seq, _) = mock.pull_tcp_segment().await?;
+    assert_eq!(control, TcpControl::Fin);
 
-    let icmp_echo_ip_packet = build_icmp4_echo_packet(src_addr, dst_addr, 42, 31337, data);
+    // we can still read after closing our end.
+    push_tcp_segment(&mock, TcpControl::None, seq, Some(fin_seq + 1), b"hello").await?;
+    seq += 5;
+    let (tx, rx) = oneshot::channel();
+    mock.push_py_command(TransportCommand::ReadData(tcp_conn_id, 4096, tx))
+        .await?;
+    assert_eq!(rx.await?, b"hello");
 
-    mock.push_smol_packet(icmp_echo_ip_packet.into()).await?;
+    // until the client closes its end as well.
+    push_tcp_segment(&mock, TcpControl::Fin, seq, Some(fin_seq + 1), &[]).await?;
+    let (tx, rx) = oneshot::channel();
+    mock.push_py_command(TransportCommand::ReadData(tcp_conn_id, 4096, tx))
+        .await?;
+    assert_eq!(rx.await?, b"");
 
-    let response = mock.pull_smol_packet().await;
+    mock.stop().await
+}
 
-    if let SmolPacket::V4(mut response) = response {
-        // Checking that source and destination addresses were flipped and data was the same.
-        assert_eq!(src_addr, response.dst_addr());
-        assert_eq!(dst_addr, response.src_addr());
+#[tokio::test]
+async fn tcp_full_close() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init().await?;
+    let (tcp_conn_id, seq, _) = tcp_handshake(&mut mock).await?;
 
-        let mut input_icmpv4_packet = match Icmpv4Packet::new_checked(response.payload_mut()) {
-            Ok(p) => p,
-            Err(e) => {
-                return Err(anyhow!("Invalid ICMPv4 packet emitted: {}", e.to_string()));
+    let (tx, rx) = oneshot::channel();
+    mock.push_py_command(TransportCommand::ReadData(tcp_conn_id, 4096, tx))
+        .await?;
+    mock.push_py_command(TransportCommand::CloseConnection(tcp_conn_id, false))
+        .await?;
+    // pending reads return immediately.
+    assert_eq!(rx.await?, b"");
+
+    let (control, fin_seq, _) = mock.pull_tcp_segment().await?;
+    assert_eq!(control, TcpControl::Fin);
+
+    // acknowledge the FIN, but keep our end open and send more data, which is discarded.
+    push_tcp_segment(&mock, TcpControl::None, seq, Some(fin_seq + 1), b"hello").await?;
+
+    // the connection is aborted once the timeout expires.
+    timeout(Duration::from_secs(10), async {
+        loop {
+            let (control, _, payload) = mock.pull_tcp_segment().await?;
+            assert!(payload.is_empty());
+            if control == TcpControl::Rst {
+                return Ok::<(), anyhow::Error>(());
             }
-        };
+        }
+    })
+    .await??;
 
-        assert_eq!(input_icmpv4_packet.msg_type(), Icmpv4Message::EchoReply);
-        assert_eq!(42, input_icmpv4_packet.echo_ident());
-        assert_eq!(31337, input_icmpv4_packet.echo_seq_no());
-        assert_eq!(data, input_icmpv4_packet.data_mut());
-    } else {
-        return Err(anyhow!("Wrong packet IP type emitted!"));
-    }
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn tcp_full_close_flushes_written_data() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init().await?;
+    let (tcp_conn_id, seq, ack) = tcp_handshake(&mut mock).await?;
+
+    // this is what dropping a `Stream` without closing it does.
+    mock.push_py_command(TransportCommand::WriteData(tcp_conn_id, b"hello".to_vec()))
+        .await?;
+    mock.push_py_command(TransportCommand::CloseConnection(tcp_conn_id, false))
+        .await?;
+
+    // data that was written before is still sent, followed by our FIN.
+    let mut received = Vec::new();
+    let fin_seq = loop {
+        let (control, segment_seq, payload) = mock.pull_tcp_segment().await?;
+        if segment_seq == ack + received.len() {
+            received.extend(payload);
+        }
+        if control == TcpControl::Fin {
+            break ack + received.len();
+        }
+    };
+    assert_eq!(received, b"hello");
+
+    // the connection ends normally once the client closes its end as well.
+    push_tcp_segment(&mock, TcpControl::Fin, seq, Some(fin_seq + 1), b"").await?;
+    let (control, _, payload) = mock.pull_tcp_segment().await?;
+    assert_eq!(control, TcpControl::None);
+    assert!(payload.is_empty());
 
     mock.stop().await
 }
 
 #[tokio::test]
-async fn receive_icmp6_echo() -> Result<()> {
+async fn tcp_abort() -> Result<()> {
     init_logger();
     let mut mock = MockNetwork::init().await?;
+    let (tcp_conn_id, _, _) = tcp_handshake(&mut mock).await?;
 
-    let src_addr: Ipv6Addr = "ca:fe:ca:fe:ca:fe:00:01".parse()?;
-    let dst_addr: Ipv6Addr = "ca:fe:ca:fe:ca:fe:00:02".parse()?;
-    let data = "hello world!".as_bytes();
+    let (tx, rx) = oneshot::channel();
+    mock.push_py_command(TransportCommand::ReadData(tcp_conn_id, 4096, tx))
+        .await?;
+    mock.push_py_command(TransportCommand::AbortConnection(tcp_conn_id))
+        .await?;
+    assert_eq!(rx.await?, b"");
 
-    let icmp_echo_ip_packet = build_icmp6_echo_packet(src_addr, dst_addr, 42, 31337, data);
+    let (control, _, _) = mock.pull_tcp_segment().await?;
+    assert_eq!(control, TcpControl::Rst);
 
-    mock.push_smol_packet(icmp_echo_ip_packet.into()).await?;
+    let Some(TransportEvent::ConnectionClosed {
+        connection_id,
+        reason,
+        ..
+    }) = mock.pull_py_event().await
+    else {
+        panic!("expected ConnectionClosed");
+    };
+    assert_eq!(connection_id, tcp_conn_id);
+    assert_eq!(reason, CloseReason::LocalClose);
 
-    let response = mock.pull_smol_packet().await;
+    mock.stop().await
+}
 
-    if let SmolPacket::V6(mut response) = response {
-        // Checking that source and destination addresses were flipped and data was the same.
-        assert_eq!(src_addr, response.dst_addr());
-        assert_eq!(dst_addr, response.src_addr());
+#[tokio::test]
+async fn tcp_connection_closed_by_reset() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init().await?;
+    let (tcp_conn_id, mut seq, ack) = tcp_handshake(&mut mock).await?;
 
-        let mut input_icmpv6_packet = match Icmpv6Packet::new_checked(response.payload_mut()) {
-            Ok(p) => p,
-            Err(e) => {
-                return Err(anyhow!("Invalid ICMPv6 packet emitted: {}", e.to_string()));
-            }
-        };
+    push_tcp_segment(&mock, TcpControl::None, seq, Some(ack), b"hello").await?;
+    seq += 5;
+    let (tx, rx) = oneshot::channel();
+    mock.push_py_command(TransportCommand::ReadData(tcp_conn_id, 4096, tx))
+        .await?;
+    assert_eq!(rx.await?, b"hello");
+    mock.push_py_command(TransportCommand::WriteData(tcp_conn_id, b"hi".to_vec()))
+        .await?;
+    let (_, _, payload) = mock.pull_tcp_segment().await?;
+    assert_eq!(payload, b"hi");
 
-        assert_eq!(input_icmpv6_packet.msg_type(), Icmpv6Message::EchoReply);
-        assert_eq!(42, input_icmpv6_packet.echo_ident());
-        assert_eq!(31337, input_icmpv6_packet.echo_seq_no());
-        assert_eq!(data, input_icmpv6_packet.payload_mut());
-    } else {
-        return Err(anyhow!("Wrong packet IP type emitted!"));
-    }
+    push_tcp_segment(&mock, TcpControl::Rst, seq, Some(ack), &[]).await?;
+
+    let Some(TransportEvent::ConnectionClosed {
+        connection_id,
+        reason,
+        bytes_received,
+        bytes_sent,
+        ..
+    }) = mock.pull_py_event().await
+    else {
+        panic!("expected ConnectionClosed");
+    };
+    assert_eq!(connection_id, tcp_conn_id);
+    assert_eq!(reason, CloseReason::Reset);
+    assert_eq!(bytes_received, 5);
+    assert_eq!(bytes_sent, 2);
+
+    mock.stop().await
+}
+
+/// Send a SYN from 10.0.0.1:1234 to 10.0.0.42:31337 and wait for the connection to be reported.
+async fn push_syn(mock: &mut MockNetwork) -> Result<(ConnectionId, TcpSeqNumber)> {
+    let seq = TcpSeqNumber(rand::random::<i32>());
+    push_tcp_segment(mock, TcpControl::Syn, seq, None, &[]).await?;
+
+    let Some(TransportEvent::ConnectionEstablished { connection_id, .. }) =
+        mock.pull_py_event().await
+    else {
+        panic!("expected ConnectionEstablished");
+    };
+    Ok((connection_id, seq))
+}
+
+async fn pull_closed_reason(mock: &mut MockNetwork) -> CloseReason {
+    let Some(TransportEvent::ConnectionClosed { reason, .. }) = mock.pull_py_event().await else {
+        panic!("expected ConnectionClosed");
+    };
+    reason
+}
+
+#[tokio::test]
+async fn tcp_deferred_accept() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init_with_conf(NetworkConf {
+        tcp_accept_timeout: Some(Duration::from_secs(10)),
+        ..Default::default()
+    })
+    .await?;
+    let (tcp_conn_id, seq) = push_syn(&mut mock).await?;
+
+    // the handshake is held back, retransmitted SYNs are ignored.
+    push_tcp_segment(&mock, TcpControl::Syn, seq, None, &[]).await?;
+    assert!(timeout(Duration::from_millis(100), mock.pull_smol_packet())
+        .await
+        .is_err());
+
+    mock.push_py_command(TransportCommand::AcceptConnection(tcp_conn_id))
+        .await?;
+    let (control, synack_seq, _) = mock.pull_tcp_segment().await?;
+    assert_eq!(control, TcpControl::Syn);
+
+    push_tcp_segment(
+        &mock,
+        TcpControl::None,
+        seq + 1,
+        Some(synack_seq + 1),
+        b"hello",
+    )
+    .await?;
+    let (tx, rx) = oneshot::channel();
+    mock.push_py_command(TransportCommand::ReadData(tcp_conn_id, 4096, tx))
+        .await?;
+    assert_eq!(rx.await?, b"hello");
+
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn tcp_deferred_reject() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init_with_conf(NetworkConf {
+        tcp_accept_timeout: Some(Duration::from_secs(10)),
+        ..Default::default()
+    })
+    .await?;
+
+    let (tcp_conn_id, seq) = push_syn(&mut mock).await?;
+    mock.push_py_command(TransportCommand::RejectConnection(
+        tcp_conn_id,
+        Rejection::Reset,
+    ))
+    .await?;
+    let mut packet = mock.pull_smol_packet().await;
+    let (src_ip, dst_ip) = (packet.src_ip(), packet.dst_ip());
+    let repr = TcpRepr::parse(
+        &TcpPacket::new_checked(&*packet.payload_mut())?,
+        &src_ip.into(),
+        &dst_ip.into(),
+        &ChecksumCapabilities::default(),
+    )
+    .map_err(|e| anyhow!("Invalid TCP packet: {}", e))?;
+    assert_eq!(repr.control, TcpControl::Rst);
+    assert_eq!(repr.ack_number, Some(seq + 1));
+    assert_eq!((repr.src_port, repr.dst_port), (31337, 1234));
+    assert_eq!(pull_closed_reason(&mut mock).await, CloseReason::LocalClose);
+
+    let (tcp_conn_id, _) = push_syn(&mut mock).await?;
+    mock.push_py_command(TransportCommand::RejectConnection(
+        tcp_conn_id,
+        Rejection::Unreachable(UnreachableCode::Host),
+    ))
+    .await?;
+    let mut packet = match mock.pull_smol_packet().await {
+        SmolPacket::V4(packet) => packet,
+        SmolPacket::V6(_) => return Err(anyhow!("Received unexpected IPv6 packet!")),
+    };
+    assert_eq!(packet.dst_addr(), "10.0.0.1".parse::<Ipv4Addr>()?);
+    let icmp = Icmpv4Packet::new_checked(packet.payload_mut())?;
+    assert!(icmp.verify_checksum());
+    assert_eq!(icmp.msg_type(), Icmpv4Message::DstUnreachable);
+    assert_eq!(icmp.msg_code(), 1);
+    assert_eq!(pull_closed_reason(&mut mock).await, CloseReason::LocalClose);
+
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn tcp_deferred_accept_timeout() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init_with_conf(NetworkConf {
+        tcp_accept_timeout: Some(Duration::from_millis(100)),
+        ..Default::default()
+    })
+    .await?;
+
+    push_syn(&mut mock).await?;
+    let (control, _, _) = mock.pull_tcp_segment().await?;
+    assert_eq!(control, TcpControl::Rst);
+    assert_eq!(pull_closed_reason(&mut mock).await, CloseReason::Timeout);
+
+    mock.stop().await
+}
+
+/// Open a connection from 10.0.0.42 with an ephemeral port to 10.0.0.1:1234.
+async fn push_open_connection(
+    mock: &MockNetwork,
+    udp: bool,
+) -> Result<(
+    oneshot::Receiver<std::io::Result<(ConnectionId, SocketAddr)>>,
+    shutdown::Receiver,
+)> {
+    let (tx, rx) = oneshot::channel();
+    let (closed_tx, closed_rx) = shutdown::channel();
+    mock.push_py_command(TransportCommand::OpenConnection {
+        udp,
+        src_addr: "10.0.0.42:0".parse()?,
+        dst_addr: "10.0.0.1:1234".parse()?,
+        tx,
+        closed: closed_tx,
+    })
+    .await?;
+    Ok((rx, closed_rx))
+}
+
+/// Pull the SYN of a connection we opened, returning its source port and sequence number.
+async fn pull_outbound_syn(mock: &mut MockNetwork) -> Result<(u16, TcpSeqNumber)> {
+    let mut packet = mock.pull_smol_packet().await;
+    let (src_ip, dst_ip) = (packet.src_ip(), packet.dst_ip());
+    assert_eq!(src_ip, "10.0.0.42".parse::<std::net::IpAddr>()?);
+    assert_eq!(dst_ip, "10.0.0.1".parse::<std::net::IpAddr>()?);
+    let repr = TcpRepr::parse(
+        &TcpPacket::new_checked(&*packet.payload_mut())?,
+        &src_ip.into(),
+        &dst_ip.into(),
+        &ChecksumCapabilities::default(),
+    )
+    .map_err(|e| anyhow!("Invalid TCP packet: {}", e))?;
+    assert_eq!(repr.control, TcpControl::Syn);
+    assert_eq!(repr.ack_number, None);
+    assert_eq!(repr.dst_port, 1234);
+    Ok((repr.src_port, repr.seq_number))
+}
+
+#[tokio::test]
+async fn tcp_open_connection() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init().await?;
+
+    let (rx, mut closed) = push_open_connection(&mock, false).await?;
+    let (port, syn_seq) = pull_outbound_syn(&mut mock).await?;
+    assert!(port >= 49152);
+
+    let seq = TcpSeqNumber(rand::random::<i32>());
+    let synack = build_ipv4_tcp_packet(
+        "10.0.0.1".parse()?,
+        "10.0.0.42".parse()?,
+        1234,
+        port,
+        TcpControl::Syn,
+        seq,
+        Some(syn_seq + 1),
+        &[],
+    );
+    mock.push_smol_packet(synack.into()).await?;
+
+    let (connection_id, local_addr) = rx.await??;
+    assert!(connection_id.is_tcp());
+    assert_eq!(local_addr, SocketAddr::new("10.0.0.42".parse()?, port));
+
+    let (control, _, _) = mock.pull_tcp_segment().await?;
+    assert_eq!(control, TcpControl::None);
+
+    mock.push_py_command(TransportCommand::WriteData(
+        connection_id,
+        b"hello".to_vec(),
+    ))
+    .await?;
+    let (_, _, payload) = mock.pull_tcp_segment().await?;
+    assert_eq!(payload, b"hello");
+
+    mock.push_py_command(TransportCommand::AbortConnection(connection_id))
+        .await?;
+    let (control, _, _) = mock.pull_tcp_segment().await?;
+    assert_eq!(control, TcpControl::Rst);
+    let Some(TransportEvent::ConnectionClosed {
+        connection_id: closed_id,
+        src_addr,
+        reason,
+        bytes_sent,
+        ..
+    }) = mock.pull_py_event().await
+    else {
+        panic!("expected ConnectionClosed");
+    };
+    assert_eq!(closed_id, connection_id);
+    assert_eq!(src_addr, "10.0.0.1:1234".parse()?);
+    assert_eq!(reason, CloseReason::LocalClose);
+    assert_eq!(bytes_sent, 5);
+    timeout(Duration::from_secs(1), closed.recv()).await?;
+
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn tcp_open_connection_refused() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init().await?;
+
+    let (rx, _closed) = push_open_connection(&mock, false).await?;
+    let (port, syn_seq) = pull_outbound_syn(&mut mock).await?;
+
+    let rst = build_ipv4_tcp_packet(
+        "10.0.0.1".parse()?,
+        "10.0.0.42".parse()?,
+        1234,
+        port,
+        TcpControl::Rst,
+        TcpSeqNumber(0),
+        Some(syn_seq + 1),
+        &[],
+    );
+    mock.push_smol_packet(rst.into()).await?;
+
+    let err = rx.await?.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn udp_open_connection() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init().await?;
+
+    let (rx, _closed) = push_open_connection(&mock, true).await?;
+    let (connection_id, local_addr) = rx.await??;
+    assert!(!connection_id.is_tcp());
+    assert_eq!(local_addr.ip(), "10.0.0.42".parse::<std::net::IpAddr>()?);
+    assert_ne!(local_addr.port(), 0);
+
+    mock.push_py_command(TransportCommand::WriteData(connection_id, b"ping".to_vec()))
+        .await?;
+    let packet = mock.pull_packet().await;
+    assert_eq!(packet.src(), local_addr);
+    assert_eq!(packet.dst(), "10.0.0.1:1234".parse()?);
+    assert_eq!(packet.payload(), b"ping");
+
+    let reply = build_ipv4_udp_packet(
+        "10.0.0.1".parse()?,
+        "10.0.0.42".parse()?,
+        1234,
+        local_addr.port(),
+        b"pong",
+    );
+    mock.push_smol_packet(reply.into()).await?;
+    let (tx, rx) = oneshot::channel();
+    mock.push_py_command(TransportCommand::ReadData(connection_id, 0, tx))
+        .await?;
+    assert_eq!(rx.await?, b"pong");
+
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn udp_reject() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init().await?;
+
+    let packet = build_ipv4_udp_packet(
+        "10.0.0.1".parse()?,
+        "10.0.0.42".parse()?,
+        1234,
+        31337,
+        b"hello world!",
+    );
+    mock.push_smol_packet(packet.into()).await?;
+    let Some(TransportEvent::ConnectionEstablished { connection_id, .. }) =
+        mock.pull_py_event().await
+    else {
+        panic!("expected ConnectionEstablished");
+    };
+    mock.push_py_command(TransportCommand::RejectConnection(
+        connection_id,
+        Rejection::Unreachable(UnreachableCode::Port),
+    ))
+    .await?;
+
+    let SmolPacket::V4(mut packet) = mock.pull_smol_packet().await else {
+        return Err(anyhow!("Wrong packet IP type emitted!"));
+    };
+    assert_eq!(packet.src_addr(), "10.0.0.42".parse::<Ipv4Addr>()?);
+    assert_eq!(packet.dst_addr(), "10.0.0.1".parse::<Ipv4Addr>()?);
+    let icmp = Icmpv4Packet::new_checked(&packet.payload_mut()[..])?;
+    assert!(icmp.verify_checksum());
+    assert_eq!(icmp.msg_type(), Icmpv4Message::DstUnreachable);
+    assert_eq!(icmp.msg_code(), 3);
+    // The quoted datagram identifies the flow.
+    // For IPv4, only the UDP header is quoted.
+    let quoted = UdpPacket::new_unchecked(&icmp.data()[20..]);
+    assert_eq!((quoted.src_port(), quoted.dst_port()), (1234, 31337));
+    assert_eq!(pull_closed_reason(&mut mock).await, CloseReason::LocalClose);
+
+    let packet = build_ipv6_udp_packet(
+        "ca:fe:ca:fe:ca:fe:00:01".parse()?,
+        "ca:fe:ca:fe:ca:fe:00:02".parse()?,
+        1234,
+        31337,
+        b"hello world!",
+    );
+    mock.push_smol_packet(packet.into()).await?;
+    let Some(TransportEvent::ConnectionEstablished { connection_id, .. }) =
+        mock.pull_py_event().await
+    else {
+        panic!("expected ConnectionEstablished");
+    };
+    mock.push_py_command(TransportCommand::RejectConnection(
+        connection_id,
+        Rejection::TimeExceeded,
+    ))
+    .await?;
+
+    let SmolPacket::V6(mut packet) = mock.pull_smol_packet().await else {
+        return Err(anyhow!("Wrong packet IP type emitted!"));
+    };
+    assert_eq!(packet.next_header(), IpProtocol::Icmpv6);
+    let icmp = Icmpv6Packet::new_checked(&packet.payload_mut()[..])?;
+    assert_eq!(icmp.msg_type(), Icmpv6Message::TimeExceeded);
+    assert_eq!(icmp.msg_code(), 0);
+    let quoted = Ipv6Packet::new_checked(icmp.payload())?;
+    let quoted = UdpPacket::new_checked(quoted.payload())?;
+    assert_eq!((quoted.src_port(), quoted.dst_port()), (1234, 31337));
+    assert_eq!(quoted.payload(), b"hello world!");
+    assert_eq!(pull_closed_reason(&mut mock).await, CloseReason::LocalClose);
+
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn receive_icmp4_echo() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init().await?;
+
+    let src_addr = "10.0.0.1".parse()?;
+    let dst_addr = "10.0.0.42".parse()?;
+    let data = "hello world!".as_bytes();
+
+    let icmp_echo_ip_packet = build_icmp4_echo_packet(src_addr, dst_addr, 42, 31337, data);
+
+    mock.push_smol_packet(icmp_echo_ip_packet.into()).await?;
+
+    let response = mock.pull_smol_packet().await;
+
+    if let SmolPacket::V4(mut response) = response {
+        // Checking that source and destination addresses were flipped and data was the same.
+        assert_eq!(src_addr, response.dst_addr());
+        assert_eq!(dst_addr, response.src_addr());
+
+        let mut input_icmpv4_packet = match Icmpv4Packet::new_checked(response.payload_mut()) {
+            Ok(p) => p,
+            Err(e) => {
+                return Err(anyhow!("Invalid ICMPv4 packet emitted: {}", e.to_string()));
+            }
+        };
+
+        assert_eq!(input_icmpv4_packet.msg_type(), Icmpv4Message::EchoReply);
+        assert_eq!(42, input_icmpv4_packet.echo_ident());
+        assert_eq!(31337, input_icmpv4_packet.echo_seq_no());
+        assert_eq!(data, input_icmpv4_packet.data_mut());
+    } else {
+        return Err(anyhow!("Wrong packet IP type emitted!"));
+    }
+
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn receive_icmp6_echo() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init().await?;
+
+    let src_addr: Ipv6Addr = "ca:fe:ca:fe:ca:fe:00:01".parse()?;
+    let dst_addr: Ipv6Addr = "ca:fe:ca:fe:ca:fe:00:02".parse()?;
+    let data = "hello world!".as_bytes();
+
+    let icmp_echo_ip_packet = build_icmp6_echo_packet(src_addr, dst_addr, 42, 31337, data);
+
+    mock.push_smol_packet(icmp_echo_ip_packet.into()).await?;
+
+    let response = mock.pull_smol_packet().await;
+
+    if let SmolPacket::V6(mut response) = response {
+        // Checking that source and destination addresses were flipped and data was the same.
+        assert_eq!(src_addr, response.dst_addr());
+        assert_eq!(dst_addr, response.src_addr());
+
+        let mut input_icmpv6_packet = match Icmpv6Packet::new_checked(response.payload_mut()) {
+            Ok(p) => p,
+            Err(e) => {
+                return Err(anyhow!("Invalid ICMPv6 packet emitted: {}", e.to_string()));
+            }
+        };
+
+        assert_eq!(input_icmpv6_packet.msg_type(), Icmpv6Message::EchoReply);
+        assert_eq!(42, input_icmpv6_packet.echo_ident());
+        assert_eq!(31337, input_icmpv6_packet.echo_seq_no());
+        assert_eq!(data, input_icmpv6_packet.payload_mut());
+    } else {
+        return Err(anyhow!("Wrong packet IP type emitted!"));
+    }
+
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn receive_icmp6_echo_extension_headers() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init().await?;
+
+    let src_addr: Ipv6Addr = "ca:fe:ca:fe:ca:fe:00:01".parse()?;
+    let dst_addr: Ipv6Addr = "ca:fe:ca:fe:ca:fe:00:02".parse()?;
+    let packet = build_icmp6_echo_packet(src_addr, dst_addr, 42, 31337, b"hello world!");
+    let packet = push_ipv6_extension_header(packet, IpProtocol::Ipv6Opts, &PADN_OPTION);
+    mock.push_smol_packet(packet.into()).await?;
+
+    let SmolPacket::V6(mut response) = mock.pull_smol_packet().await else {
+        return Err(anyhow!("Wrong packet IP type emitted!"));
+    };
+    assert_eq!(response.dst_addr(), src_addr);
+    let icmp = Icmpv6Packet::new_checked(response.payload_mut())?;
+    assert_eq!(icmp.msg_type(), Icmpv6Message::EchoReply);
+    assert_eq!(icmp.echo_seq_no(), 31337);
+
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn tcp_mss_follows_mtu() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init_with_conf(NetworkConf {
+        mtu: Some(1280),
+        ..Default::default()
+    })
+    .await?;
+    push_syn(&mut mock).await?;
+
+    let SmolPacket::V4(mut packet) = mock.pull_smol_packet().await else {
+        return Err(anyhow!("Wrong packet IP type emitted!"));
+    };
+    let src_addr = packet.src_addr();
+    let dst_addr = packet.dst_addr();
+    let tcp = TcpPacket::new_checked(&packet.payload_mut()[..])?;
+    let repr = TcpRepr::parse(
+        &tcp,
+        &src_addr.into(),
+        &dst_addr.into(),
+        &ChecksumCapabilities::default(),
+    )?;
+    assert_eq!(repr.control, TcpControl::Syn);
+    assert_eq!(repr.max_seg_size, Some(1280 - 20 - 20));
+
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn udp_reply_fragmented() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init_with_conf(NetworkConf {
+        mtu: Some(1280),
+        ..Default::default()
+    })
+    .await?;
+
+    let packet = build_ipv4_udp_packet(
+        "10.0.0.1".parse()?,
+        "10.0.0.42".parse()?,
+        1234,
+        31337,
+        b"hello world!",
+    );
+    mock.push_smol_packet(packet.into()).await?;
+    let Some(TransportEvent::ConnectionEstablished { connection_id, .. }) =
+        mock.pull_py_event().await
+    else {
+        panic!("expected ConnectionEstablished");
+    };
+
+    let data = vec![42u8; 3000];
+    mock.push_py_command(TransportCommand::WriteData(connection_id, data.clone()))
+        .await?;
+
+    let mut received = vec![];
+    loop {
+        let SmolPacket::V4(mut packet) = mock.pull_smol_packet().await else {
+            return Err(anyhow!("Wrong packet IP type emitted!"));
+        };
+        assert!(packet.total_len() as usize <= 1280);
+        assert!(packet.verify_checksum());
+        assert_eq!(packet.frag_offset() as usize, received.len());
+        received.extend_from_slice(packet.payload_mut());
+        if !packet.more_frags() {
+            break;
+        }
+    }
+    // UDP header + payload
+    assert_eq!(received.len(), 8 + data.len());
+    assert_eq!(&received[8..], &data[..]);
+
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn forward_icmp4_echo() -> Result<()> {
+    init_logger();
+    if socket2::Socket::new(
+        socket2::Domain::IPV4,
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::ICMPV4),
+    )
+    .is_err()
+    {
+        log::warn!("Unprivileged ICMP sockets are not available, skipping test.");
+        return Ok(());
+    }
+    let mut mock = MockNetwork::init_with_conf(NetworkConf {
+        forward_icmp: true,
+        ..Default::default()
+    })
+    .await?;
+
+    let src_addr: Ipv4Addr = "10.0.0.1".parse()?;
+    let dst_addr = Ipv4Addr::LOCALHOST;
+    let data = b"hello world!";
+    let packet = build_icmp4_echo_packet(src_addr, dst_addr, 42, 31337, data);
+    mock.push_smol_packet(packet.into()).await?;
+
+    let SmolPacket::V4(mut response) =
+        timeout(Duration::from_secs(5), mock.pull_smol_packet()).await?
+    else {
+        return Err(anyhow!("Wrong packet IP type emitted!"));
+    };
+    assert_eq!(response.src_addr(), dst_addr);
+    assert_eq!(response.dst_addr(), src_addr);
+    let icmp = Icmpv4Packet::new_checked(&response.payload_mut()[..])?;
+    assert!(icmp.verify_checksum());
+    assert_eq!(icmp.msg_type(), Icmpv4Message::EchoReply);
+    assert_eq!(icmp.echo_ident(), 42);
+    assert_eq!(icmp.echo_seq_no(), 31337);
+    assert_eq!(icmp.data(), data);
+
+    mock.stop().await
+}
+
+fn build_ip_packet(
+    src_addr: IpAddress,
+    dst_addr: IpAddress,
+    protocol: u8,
+    payload: &[u8],
+) -> SmolPacket {
+    let ip_repr = IpRepr::new(
+        src_addr,
+        dst_addr,
+        IpProtocol::from(protocol),
+        payload.len(),
+        64,
+    );
+    let mut buf = vec![0u8; ip_repr.buffer_len()];
+    ip_repr.emit(&mut buf, &ChecksumCapabilities::default());
+    buf[ip_repr.header_len()..].copy_from_slice(payload);
+    SmolPacket::try_from(buf).unwrap()
+}
+
+#[tokio::test]
+async fn raw_protocol_actions() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init_with_conf(NetworkConf {
+        protocol_actions: HashMap::from([
+            (47, ProtocolAction::Forward),
+            (132, ProtocolAction::Unreachable),
+        ]),
+        ..Default::default()
+    })
+    .await?;
+    let v4_client = IpAddress::v4(10, 0, 0, 1);
+    let v4_server = IpAddress::v4(10, 0, 0, 42);
+
+    // Protocols without an entry are dropped.
+    mock.push_smol_packet(build_ip_packet(v4_client, v4_server, 50, b"esp"))
+        .await?;
+
+    let gre = build_ip_packet(v4_client, v4_server, 47, b"gre");
+    mock.push_smol_packet(gre.clone()).await?;
+    let Some(TransportEvent::RawPacket { packet, .. }) = mock.pull_py_event().await else {
+        panic!("expected RawPacket");
+    };
+    assert_eq!(packet.into_inner(), gre.clone().into_inner());
+
+    mock.push_py_command(TransportCommand::SendRawPacket(gre.clone()))
+        .await?;
+    assert_eq!(mock.pull_smol_packet().await.into_inner(), gre.into_inner());
+
+    mock.push_smol_packet(build_ip_packet(v4_client, v4_server, 132, b"sctp"))
+        .await?;
+    let SmolPacket::V4(mut packet) = mock.pull_smol_packet().await else {
+        return Err(anyhow!("Wrong packet IP type emitted!"));
+    };
+    assert_eq!(packet.dst_addr(), "10.0.0.1".parse::<Ipv4Addr>()?);
+    let icmp = Icmpv4Packet::new_checked(&packet.payload_mut()[..])?;
+    assert_eq!(icmp.msg_type(), Icmpv4Message::DstUnreachable);
+    assert_eq!(icmp.msg_code(), 2);
+
+    let v6_client = IpAddress::v6(0xca, 0xfe, 0xca, 0xfe, 0xca, 0xfe, 0, 1);
+    let v6_server = IpAddress::v6(0xca, 0xfe, 0xca, 0xfe, 0xca, 0xfe, 0, 2);
+    mock.push_smol_packet(build_ip_packet(v6_client, v6_server, 132, b"sctp"))
+        .await?;
+    let SmolPacket::V6(mut packet) = mock.pull_smol_packet().await else {
+        return Err(anyhow!("Wrong packet IP type emitted!"));
+    };
+    let icmp = Icmpv6Packet::new_checked(&packet.payload_mut()[..])?;
+    assert_eq!(icmp.msg_type(), Icmpv6Message::ParamProblem);
+    assert_eq!(icmp.msg_code(), 1);
+
+    // No ICMP errors for multicast destinations.
+    mock.push_smol_packet(build_ip_packet(
+        v4_client,
+        IpAddress::v4(224, 0, 0, 22),
+        132,
+        b"sctp",
+    ))
+    .await?;
+    assert!(timeout(Duration::from_millis(100), mock.pull_smol_packet())
+        .await
+        .is_err());
+
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn fake_dns() -> Result<()> {
+    use hickory_resolver::proto::op::{Message, Query, ResponseCode};
+    use hickory_resolver::proto::rr::{Name, RData, RecordType};
+
+    init_logger();
+    let mut mock = MockNetwork::init_with_conf(NetworkConf {
+        fake_dns_servers: vec!["10.0.0.53".parse()?],
+        ..Default::default()
+    })
+    .await?;
+
+    let mut query = Message::new();
+    query
+        .set_id(42)
+        .set_recursion_desired(true)
+        .add_query(Query::query(
+            Name::from_ascii("Example.COM.")?,
+            RecordType::A,
+        ));
+    let packet = build_ipv4_udp_packet(
+        "10.0.0.1".parse()?,
+        "10.0.0.53".parse()?,
+        5353,
+        53,
+        &query.to_vec()?,
+    );
+    mock.push_smol_packet(packet.into()).await?;
+
+    let SmolPacket::V4(mut packet) = mock.pull_smol_packet().await else {
+        return Err(anyhow!("Wrong packet IP type emitted!"));
+    };
+    assert_eq!(packet.src_addr(), Ipv4Addr::new(10, 0, 0, 53));
+    assert_eq!(packet.dst_addr(), Ipv4Addr::new(10, 0, 0, 1));
+    let udp = UdpPacket::new_checked(&packet.payload_mut()[..])?;
+    assert_eq!((udp.src_port(), udp.dst_port()), (53, 5353));
+    let response = Message::from_vec(udp.payload())?;
+    assert_eq!(response.id(), 42);
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(response.queries(), query.queries());
+    let [answer] = response.answers() else {
+        panic!("expected a single answer");
+    };
+    let Some(RData::A(fake_ip)) = answer.data() else {
+        panic!("expected an A record");
+    };
+    assert_eq!(fake_ip.0, Ipv4Addr::new(198, 18, 0, 1));
+
+    let packet = build_ipv4_udp_packet("10.0.0.1".parse()?, fake_ip.0, 1234, 443, b"hello");
+    mock.push_smol_packet(packet.into()).await?;
+    let Some(TransportEvent::ConnectionEstablished {
+        dst_addr,
+        original_hostname,
+        ..
+    }) = mock.pull_py_event().await
+    else {
+        panic!("expected ConnectionEstablished");
+    };
+    assert_eq!(dst_addr, "198.18.0.1:443".parse()?);
+    assert_eq!(original_hostname.as_deref(), Some("example.com"));
+
+    let packet = build_ipv4_udp_packet(
+        "10.0.0.1".parse()?,
+        "198.18.0.2".parse()?,
+        1234,
+        443,
+        b"hello",
+    );
+    mock.push_smol_packet(packet.into()).await?;
+    let Some(TransportEvent::ConnectionEstablished {
+        original_hostname, ..
+    }) = mock.pull_py_event().await
+    else {
+        panic!("expected ConnectionEstablished");
+    };
+    assert_eq!(original_hostname, None);
+
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn tcp_peek_client_hello() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init_with_conf(NetworkConf {
+        tcp_peek_timeout: Some(Duration::from_secs(10)),
+        ..Default::default()
+    })
+    .await?;
+    let client_hello = include_bytes!("peek/testdata/tls_client_hello_fragmented.bin");
+
+    let seq = TcpSeqNumber(rand::random::<i32>());
+    push_tcp_segment(&mock, TcpControl::Syn, seq, None, &[]).await?;
+    let (control, synack_seq, _) = mock.pull_tcp_segment().await?;
+    assert_eq!(control, TcpControl::Syn);
+    let ack = Some(synack_seq + 1);
+
+    // The ClientHello arrives in two segments, the connection is only reported after the second one.
+    let (first, second) = client_hello.split_at(700);
+    push_tcp_segment(&mock, TcpControl::None, seq + 1, ack, first).await?;
+    mock.pull_tcp_segment().await?;
+    assert!(timeout(Duration::from_millis(100), mock.pull_py_event())
+        .await
+        .is_err());
+    push_tcp_segment(&mock, TcpControl::Psh, seq + 1 + first.len(), ack, second).await?;
+
+    let Some(TransportEvent::ConnectionEstablished {
+        connection_id,
+        client_hello: Some(peeked),
+        ..
+    }) = mock.pull_py_event().await
+    else {
+        panic!("expected ConnectionEstablished with ClientHello");
+    };
+    assert_eq!(peeked.sni.as_deref(), Some("example.com"));
+    assert_eq!(
+        peeked.alpn_protocols,
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    );
+
+    // Peeking does not consume any data.
+    let (tx, rx) = oneshot::channel();
+    mock.push_py_command(TransportCommand::ReadData(
+        connection_id,
+        client_hello.len() as u32,
+        tx,
+    ))
+    .await?;
+    assert_eq!(rx.await?, client_hello);
+
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn tcp_peek_not_tls() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init_with_conf(NetworkConf {
+        tcp_peek_timeout: Some(Duration::from_secs(10)),
+        ..Default::default()
+    })
+    .await?;
+
+    let seq = TcpSeqNumber(rand::random::<i32>());
+    push_tcp_segment(&mock, TcpControl::Syn, seq, None, &[]).await?;
+    let (_, synack_seq, _) = mock.pull_tcp_segment().await?;
+    push_tcp_segment(
+        &mock,
+        TcpControl::Psh,
+        seq + 1,
+        Some(synack_seq + 1),
+        b"GET / HTTP/1.1\r\n",
+    )
+    .await?;
+
+    let Some(TransportEvent::ConnectionEstablished { client_hello, .. }) =
+        mock.pull_py_event().await
+    else {
+        panic!("expected ConnectionEstablished");
+    };
+    assert_eq!(client_hello, None);
+
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn tcp_peek_timeout() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init_with_conf(NetworkConf {
+        tcp_peek_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    })
+    .await?;
+
+    // The client waits for the server to speak first.
+    let seq = TcpSeqNumber(rand::random::<i32>());
+    push_tcp_segment(&mock, TcpControl::Syn, seq, None, &[]).await?;
+    let (_, synack_seq, _) = mock.pull_tcp_segment().await?;
+    push_tcp_segment(&mock, TcpControl::None, seq + 1, Some(synack_seq + 1), &[]).await?;
+
+    let Some(TransportEvent::ConnectionEstablished { client_hello, .. }) =
+        timeout(Duration::from_secs(5), mock.pull_py_event()).await?
+    else {
+        panic!("expected ConnectionEstablished");
+    };
+    assert_eq!(client_hello, None);
+
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn udp_peek_quic_initial() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init().await?;
+
+    let packet = build_ipv4_udp_packet(
+        "10.0.0.1".parse()?,
+        "10.0.0.42".parse()?,
+        1234,
+        443,
+        include_bytes!("peek/testdata/quic_initial.bin"),
+    );
+    mock.push_smol_packet(packet.into()).await?;
+    let Some(TransportEvent::ConnectionEstablished {
+        client_hello: Some(client_hello),
+        ..
+    }) = mock.pull_py_event().await
+    else {
+        panic!("expected ConnectionEstablished with ClientHello");
+    };
+    assert_eq!(client_hello.sni.as_deref(), Some("www.mitmproxy.org"));
+    assert_eq!(client_hello.alpn_protocols, vec![b"h3".to_vec()]);
+
+    mock.stop().await
+}
+
+/// Pull TCP segments until one with a payload or the given control flag arrives.
+async fn pull_tcp_data(
+    mock: &mut MockNetwork,
+    control: TcpControl,
+) -> Result<(TcpControl, TcpSeqNumber, Vec<u8>)> {
+    loop {
+        let segment = mock.pull_tcp_segment().await?;
+        if segment.0 == control || !segment.2.is_empty() {
+            return Ok(segment);
+        }
+    }
+}
+
+#[tokio::test]
+async fn tcp_splice() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init().await?;
+    let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let (tcp_conn_id, seq, ack) = tcp_handshake(&mut mock).await?;
+
+    let (tx, rx) = oneshot::channel();
+    mock.push_py_command(TransportCommand::SpliceConnection {
+        connection_id: tcp_conn_id,
+        host: "127.0.0.1".to_string(),
+        port: upstream.local_addr()?.port(),
+        tx,
+    })
+    .await?;
+    let (mut server, _) = upstream.accept().await?;
+
+    push_tcp_segment(&mock, TcpControl::Psh, seq, Some(ack), b"hello").await?;
+    let mut buf = [0u8; 5];
+    server.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    server.write_all(b"hi!").await?;
+    let (_, data_seq, payload) = pull_tcp_data(&mut mock, TcpControl::None).await?;
+    assert_eq!(data_seq, ack);
+    assert_eq!(payload, b"hi!");
+
+    // The client closes its end, which is passed on upstream, and the server follows.
+    push_tcp_segment(&mock, TcpControl::Fin, seq + 5, Some(ack + 3), &[]).await?;
+    assert_eq!(server.read(&mut buf).await?, 0);
+    drop(server);
+    let (control, fin_seq, _) = pull_tcp_data(&mut mock, TcpControl::Fin).await?;
+    assert_eq!(control, TcpControl::Fin);
+    push_tcp_segment(&mock, TcpControl::None, seq + 6, Some(fin_seq + 1), &[]).await?;
+
+    let stats = timeout(Duration::from_secs(5), rx).await???;
+    assert_eq!(
+        stats,
+        SpliceStats {
+            bytes_from_client: 5,
+            bytes_from_server: 3,
+        }
+    );
+
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn tcp_splice_upstream_refused() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init().await?;
+    let port = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await?
+        .local_addr()?
+        .port();
+    let (tcp_conn_id, _, _) = tcp_handshake(&mut mock).await?;
+
+    let (tx, rx) = oneshot::channel();
+    mock.push_py_command(TransportCommand::SpliceConnection {
+        connection_id: tcp_conn_id,
+        host: "127.0.0.1".to_string(),
+        port,
+        tx,
+    })
+    .await?;
+    assert_eq!(
+        rx.await?.unwrap_err().kind(),
+        std::io::ErrorKind::ConnectionRefused
+    );
+    let (control, _, _) = pull_tcp_data(&mut mock, TcpControl::Rst).await?;
+    assert_eq!(control, TcpControl::Rst);
+
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn udp_splice() -> Result<()> {
+    init_logger();
+    let mut mock = MockNetwork::init().await?;
+    let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+
+    let packet = build_ipv4_udp_packet(
+        "10.0.0.1".parse()?,
+        "10.0.0.42".parse()?,
+        1234,
+        31337,
+        b"hello",
+    );
+    mock.push_smol_packet(packet.clone().into()).await?;
+    let Some(TransportEvent::ConnectionEstablished { connection_id, .. }) =
+        mock.pull_py_event().await
+    else {
+        panic!("expected ConnectionEstablished");
+    };
+
+    let (tx, rx) = oneshot::channel();
+    mock.push_py_command(TransportCommand::SpliceConnection {
+        connection_id,
+        host: "127.0.0.1".to_string(),
+        port: upstream.local_addr()?.port(),
+        tx,
+    })
+    .await?;
+
+    // The first datagram has been buffered for the splice task.
+    let mut buf = [0u8; 64];
+    let (n, addr) = upstream.recv_from(&mut buf).await?;
+    assert_eq!(&buf[..n], b"hello");
+    upstream.send_to(b"hi!", addr).await?;
+    let response = mock.pull_packet().await;
+    assert_eq!(response.payload(), b"hi!");
+    assert_eq!(response.dst(), "10.0.0.1:1234".parse()?);
+
+    mock.push_smol_packet(packet.into()).await?;
+    let (n, _) = upstream.recv_from(&mut buf).await?;
+    assert_eq!(&buf[..n], b"hello");
+
+    // The relay ends once the network stack is gone.
+    mock.stop().await?;
+    assert!(rx.await?.is_err());
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn traffic_shaping() -> Result<()> {
+    init_logger();
+    let (shaping_tx, shaping_rx) = watch::channel(ShapingConf::default());
+    let mut mock = MockNetwork::init_with_conf(NetworkConf {
+        shaping: Some(shaping_rx),
+        ..Default::default()
+    })
+    .await?;
+    let ping = || {
+        build_icmp4_echo_packet(
+            "10.0.0.1".parse().unwrap(),
+            "10.0.0.42".parse().unwrap(),
+            42,
+            1,
+            b"ping",
+        )
+    };
+
+    shaping_tx.send_replace(ShapingConf {
+        default: ShapingProfile {
+            uplink: LinkConditions {
+                latency: Duration::from_millis(100),
+                ..Default::default()
+            },
+            downlink: LinkConditions {
+                latency: Duration::from_millis(50),
+                ..Default::default()
+            },
+        },
+        ..Default::default()
+    });
+    tokio::task::yield_now().await;
+    let start = Instant::now();
+    mock.push_smol_packet(ping().into()).await?;
+    mock.pull_smol_packet().await;
+    assert_eq!(start.elapsed(), Duration::from_millis(150));
+
+    // Without network conditions, packets bypass the shaper.
+    shaping_tx.send_replace(ShapingConf::default());
+    tokio::task::yield_now().await;
+    let start = Instant::now();
+    mock.push_smol_packet(ping().into()).await?;
+    mock.pull_smol_packet().await;
+    assert_eq!(start.elapsed(), Duration::ZERO);
+
+    mock.stop().await
+}
+
+#[tokio::test]
+async fn packet_capture() -> Result<()> {
+    init_logger();
+    let tap = CaptureTap::default();
+    let mut mock = MockNetwork::init_with_conf(NetworkConf {
+        capture: Some(tap.clone()),
+        ..Default::default()
+    })
+    .await?;
+    let ping = || {
+        build_icmp4_echo_packet(
+            "10.0.0.1".parse().unwrap(),
+            "10.0.0.42".parse().unwrap(),
+            42,
+            1,
+            b"ping",
+        )
+    };
+
+    // Nothing is recorded while no capture is running.
+    mock.push_smol_packet(ping().into()).await?;
+    mock.pull_smol_packet().await;
+
+    tap.start(Capture::ring(1 << 20));
+    mock.push_smol_packet(ping().into()).await?;
+    mock.pull_smol_packet().await;
+    let Some(Capture::Ring(ring)) = tap.stop() else {
+        panic!("expected a ring capture");
+    };
+    let contents = ring.to_pcapng();
+    let count = |s: &[u8]| contents.windows(s.len()).filter(|w| *w == s).count();
+    assert_eq!(count(b"inbound, WireGuard peer"), 1);
+    // The reply is attributed to the peer that the request came from.
+    assert_eq!(count(b"outbound, WireGuard peer"), 1);
 
     mock.stop().await
 }
@@ -1,15 +1,21 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::io;
 use std::net::SocketAddr;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use lru_time_cache::LruCache;
 use tokio::sync::mpsc::Permit;
-use tokio::sync::oneshot;
+use tokio::sync::{oneshot, watch};
+
+use super::icmp::icmp_error;
+use super::peek::peek_quic_initial;
+use super::tcp::ephemeral_port;
 
 use crate::messages::{
-    ConnectionId, ConnectionIdGenerator, SmolPacket, TransportCommand, TransportEvent, TunnelInfo,
+    CloseReason, ConnectionId, ConnectionIdGenerator, Rejection, SmolPacket, TransportCommand,
+    TransportEvent, TunnelInfo,
 };
-use internet_packet::InternetPacket;
+use anyhow::anyhow;
 use smoltcp::phy::ChecksumCapabilities;
 
 use smoltcp::wire::{IpProtocol, IpRepr, Ipv4Packet, Ipv4Repr, Ipv6Packet, Ipv6Repr, UdpRepr};
@@ -58,13 +64,65 @@ impl ConnectionState {
 }
 
 pub const UDP_TIMEOUT: Duration = Duration::from_secs(60);
+/// How much of the most recent datagram of a flow we keep around to quote in ICMP errors.
+const QUOTED_PAYLOAD_LEN: usize = 64;
 
 type FourTuple = (SocketAddr, SocketAddr);
 
+/// Counters for the ConnectionClosed event of a UDP flow.
+struct FlowStats {
+    addrs: FourTuple,
+    opened: Instant,
+    bytes_received: u64,
+    bytes_sent: u64,
+    /// The start of the most recent datagram that we received.
+    last_payload: Vec<u8>,
+    /// Dropped once the flow is closed, for flows opened with `open_connection`.
+    #[allow(dead_code)]
+    closed: Option<watch::Sender<()>>,
+}
+
+impl FlowStats {
+    fn new(addrs: FourTuple) -> Self {
+        Self {
+            addrs,
+            opened: Instant::now(),
+            bytes_received: 0,
+            bytes_sent: 0,
+            last_payload: Vec::new(),
+            closed: None,
+        }
+    }
+
+    fn record_received(&mut self, payload: &[u8]) {
+        self.bytes_received += payload.len() as u64;
+        self.last_payload.clear();
+        self.last_payload
+            .extend_from_slice(&payload[..payload.len().min(QUOTED_PAYLOAD_LEN)]);
+    }
+
+    fn closed_event(&self, connection_id: ConnectionId, reason: CloseReason) -> TransportEvent {
+        TransportEvent::ConnectionClosed {
+            connection_id,
+            src_addr: self.addrs.0,
+            dst_addr: self.addrs.1,
+            reason,
+            bytes_received: self.bytes_received,
+            bytes_sent: self.bytes_sent,
+            duration: self.opened.elapsed(),
+        }
+    }
+}
+
 pub struct UdpHandler {
     connection_id_generator: ConnectionIdGenerator,
     id_lookup: LruCache<FourTuple, ConnectionId>,
     connections: LruCache<ConnectionId, (ConnectionState, FourTuple)>,
+    /// Flows that have not been reported as closed yet.
+    /// Unlike `connections`, expired entries are only removed in `poll`.
+    open_flows: HashMap<ConnectionId, FlowStats>,
+    closed_connections: Vec<TransportEvent>,
+    next_ephemeral_port: u16,
 }
 
 impl UdpHandler {
@@ -75,30 +133,95 @@ impl UdpHandler {
             connections: LruCache::with_expiry_duration(UDP_TIMEOUT),
             id_lookup: LruCache::with_expiry_duration(UDP_TIMEOUT),
             connection_id_generator: ConnectionIdGenerator::udp(),
+            open_flows: HashMap::new(),
+            closed_connections: Vec::new(),
+            next_ephemeral_port: 0,
         }
     }
 
     pub(crate) fn handle_transport_command(
         &mut self,
         command: TransportCommand,
-    ) -> Option<UdpPacket> {
+    ) -> Option<SmolPacket> {
         match command {
             TransportCommand::ReadData(id, _, tx) => {
                 self.read_data(id, tx);
                 None
             }
-            TransportCommand::WriteData(id, data) => self.write_data(id, data),
+            TransportCommand::WriteData(id, data) => {
+                self.write_data(id, data).map(SmolPacket::from)
+            }
             TransportCommand::DrainWriter(id, tx) => {
                 self.drain_writer(id, tx);
                 None
             }
-            TransportCommand::CloseConnection(id, _) => {
+            TransportCommand::CloseConnection(id, _) | TransportCommand::AbortConnection(id) => {
                 self.close_connection(id);
                 None
             }
+            TransportCommand::RejectConnection(id, rejection) => {
+                self.reject_connection(id, rejection)
+            }
+            // UDP flows have no handshake to defer.
+            TransportCommand::AcceptConnection(_) => None,
+            // Raw packets are handled by the network stack.
+            TransportCommand::SendRawPacket(_) => None,
+            // Splicing is handled by the network task.
+            TransportCommand::SpliceConnection { tx, .. } => {
+                tx.send(Err(io::ErrorKind::Unsupported.into())).ok();
+                None
+            }
+            TransportCommand::OpenConnection {
+                udp,
+                src_addr,
+                dst_addr,
+                tx,
+                closed,
+            } => {
+                if udp {
+                    self.open_connection(src_addr, dst_addr, tx, closed);
+                } else {
+                    tx.send(Err(io::ErrorKind::Unsupported.into())).ok();
+                }
+                None
+            }
         }
     }
 
+    pub fn open_connection(
+        &mut self,
+        mut src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        tx: oneshot::Sender<io::Result<(ConnectionId, SocketAddr)>>,
+        closed: watch::Sender<()>,
+    ) {
+        if src_addr.port() == 0 {
+            let id_lookup = &self.id_lookup;
+            let Some(port) = ephemeral_port(&mut self.next_ephemeral_port, |port| {
+                !id_lookup.contains_key(&(dst_addr, SocketAddr::new(src_addr.ip(), port)))
+            }) else {
+                tx.send(Err(io::ErrorKind::AddrInUse.into())).ok();
+                return;
+            };
+            src_addr.set_port(port);
+        }
+        // From the tunnel's perspective, the remote peer is the source of the flow.
+        let addrs = (dst_addr, src_addr);
+        if self.id_lookup.contains_key(&addrs) {
+            tx.send(Err(io::ErrorKind::AddrInUse.into())).ok();
+            return;
+        }
+
+        let connection_id = self.connection_id_generator.next_id();
+        self.id_lookup.insert(addrs, connection_id);
+        self.connections
+            .insert(connection_id, (ConnectionState::default(), addrs));
+        let mut stats = FlowStats::new(addrs);
+        stats.closed = Some(closed);
+        self.open_flows.insert(connection_id, stats);
+        tx.send(Ok((connection_id, src_addr))).ok();
+    }
+
     pub fn read_data(&mut self, id: ConnectionId, tx: oneshot::Sender<Vec<u8>>) {
         if let Some((state, _)) = self.connections.get_mut(&id) {
             state.add_reader(tx);
@@ -113,6 +236,9 @@ impl UdpHandler {
         if state.closed {
             return None;
         }
+        if let Some(stats) = self.open_flows.get_mut(&id) {
+            stats.bytes_sent += data.len() as u64;
+        }
 
         Some(UdpPacket {
             src_addr: addrs.1,
@@ -129,12 +255,34 @@ impl UdpHandler {
         if let Some((state, _)) = self.connections.get_mut(&id) {
             state.close();
         }
+        // The flow stays around until it expires so that we keep ignoring its packets,
+        // but from Python's perspective it is closed now.
+        if let Some(stats) = self.open_flows.remove(&id) {
+            self.closed_connections
+                .push(stats.closed_event(id, CloseReason::LocalClose));
+        }
+    }
+
+    /// Close a flow and build an ICMP error message for it that quotes the most recent datagram.
+    pub fn reject_connection(
+        &mut self,
+        id: ConnectionId,
+        rejection: Rejection,
+    ) -> Option<SmolPacket> {
+        let quoted = self.open_flows.get_mut(&id).map(|stats| UdpPacket {
+            src_addr: stats.addrs.0,
+            dst_addr: stats.addrs.1,
+            payload: std::mem::take(&mut stats.last_payload),
+        });
+        self.close_connection(id);
+        icmp_error(&SmolPacket::from(quoted?), rejection)
     }
 
     pub(crate) fn receive_data(
         &mut self,
         packet: UdpPacket,
         tunnel_info: TunnelInfo,
+        original_hostname: Option<&str>,
         permit: Permit<'_, TransportEvent>,
     ) {
         let potential_cid = self
@@ -145,12 +293,19 @@ impl UdpHandler {
 
         match self.connections.get_mut(&potential_cid) {
             Some((state, _)) => {
+                if let Some(stats) = self.open_flows.get_mut(&potential_cid) {
+                    stats.record_received(&packet.payload);
+                }
                 state.add_packet(packet.payload);
             }
             None => {
+                let connection_id = self.connection_id_generator.next_id();
+                let client_hello = peek_quic_initial(&packet.payload);
+                let mut stats = FlowStats::new((packet.src_addr, packet.dst_addr));
+                stats.record_received(&packet.payload);
+                self.open_flows.insert(connection_id, stats);
                 let mut state = ConnectionState::default();
                 state.add_packet(packet.payload);
-                let connection_id = self.connection_id_generator.next_id();
                 self.id_lookup
                     .insert((packet.src_addr, packet.dst_addr), connection_id);
                 self.connections
@@ -160,6 +315,8 @@ impl UdpHandler {
                     src_addr: packet.src_addr,
                     dst_addr: packet.dst_addr,
                     tunnel_info,
+                    original_hostname: original_hostname.map(str::to_string),
+                    client_hello,
                     command_tx: None,
                 });
             }
@@ -178,6 +335,29 @@ impl UdpHandler {
         // Creating an iterator removes expired entries.
         self.connections.iter();
         self.id_lookup.iter();
+
+        let connections = &self.connections;
+        let closed_connections = &mut self.closed_connections;
+        self.open_flows.retain(|id, stats| {
+            let open = connections.contains_key(id);
+            if !open {
+                closed_connections.push(stats.closed_event(*id, CloseReason::Timeout));
+            }
+            open
+        });
+    }
+
+    /// Take the `ConnectionClosed` events for flows that have been closed or have expired.
+    pub fn take_closed_connections(&mut self) -> Vec<TransportEvent> {
+        std::mem::take(&mut self.closed_connections)
+    }
+
+    /// Report all remaining flows as closed because the server is shutting down.
+    pub fn shutdown(&mut self) -> Vec<TransportEvent> {
+        self.open_flows
+            .drain()
+            .map(|(id, stats)| stats.closed_event(id, CloseReason::Shutdown))
+            .collect()
     }
 }
 
@@ -187,13 +367,17 @@ pub(crate) struct UdpPacket {
     pub payload: Vec<u8>,
 }
 impl TryFrom<SmolPacket> for UdpPacket {
-    type Error = internet_packet::ParseError;
+    type Error = anyhow::Error;
 
-    fn try_from(value: SmolPacket) -> Result<Self, Self::Error> {
-        let packet: InternetPacket = value.try_into()?;
+    fn try_from(mut value: SmolPacket) -> Result<Self, Self::Error> {
+        if value.transport_protocol() != IpProtocol::Udp {
+            return Err(anyhow!("Not a UDP packet."));
+        }
+        let (src_ip, dst_ip) = (value.src_ip(), value.dst_ip());
+        let packet = smoltcp::wire::UdpPacket::new_checked(&*value.payload_mut())?;
         Ok(UdpPacket {
-            src_addr: packet.src(),
-            dst_addr: packet.dst(),
+            src_addr: SocketAddr::new(src_ip, packet.src_port()),
+            dst_addr: SocketAddr::new(dst_ip, packet.dst_port()),
             payload: packet.payload().to_vec(),
         })
     }
@@ -260,6 +444,7 @@ impl From<UdpPacket> for SmolPacket {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::network::NetworkConf;
     use crate::packet_sources::udp::UdpConf;
     use crate::packet_sources::{PacketSourceConf, PacketSourceTask};
     use crate::shutdown;
@@ -315,17 +500,17 @@ mod tests {
         let (commands_tx, commands_rx) = tokio::sync::mpsc::unbounded_channel();
         let (events_tx, mut events_rx) = tokio::sync::mpsc::channel(1);
         let (shutdown_tx, shutdown_rx) = shutdown::channel();
-        let (task, addr) = UdpConf {
+        let (task, addrs) = UdpConf {
             host: "127.0.0.1".to_string(),
             port: 0,
         }
-        .build(events_tx, commands_rx, shutdown_rx)
+        .build(events_tx, commands_rx, shutdown_rx, NetworkConf::default())
         .await?;
 
         let handle = tokio::spawn(task.run());
 
         let client = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await?;
-        client.connect(addr).await?;
+        client.connect(addrs[0]).await?;
         client.send(b"Hello World!").await?;
 
         let TransportEvent::ConnectionEstablished {
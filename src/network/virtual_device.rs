@@ -1,31 +1,39 @@
 use std::collections::VecDeque;
 
+use crate::messages::SmolPacket;
+use crate::network::packet_sender::{PacketPermit, PacketSender};
 use smoltcp::{
     phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken},
     time::Instant,
 };
-use tokio::sync::mpsc::{Permit, Sender};
-
-use crate::messages::{NetworkCommand, SmolPacket};
 
 /// A virtual smoltcp device into which we manually feed packets using
 /// [VirtualDevice::receive_packet] and which send outgoing packets to a channel.
 pub struct VirtualDevice {
     rx_buffer: VecDeque<Vec<u8>>,
-    tx_channel: Sender<NetworkCommand>,
+    tx_channel: PacketSender,
+    mtu: usize,
 }
 
 impl VirtualDevice {
-    pub fn new(tx_channel: Sender<NetworkCommand>) -> Self {
+    pub fn new(tx_channel: PacketSender, mtu: usize) -> Self {
         VirtualDevice {
             rx_buffer: VecDeque::new(),
             tx_channel,
+            mtu,
         }
     }
 
     pub fn receive_packet(&mut self, packet: SmolPacket) {
         self.rx_buffer.push_back(packet.into_inner());
     }
+
+    /// Send a packet that has not been generated by smoltcp, e.g. a RST for a rejected connection.
+    pub fn send_packet(&mut self, packet: SmolPacket) {
+        if self.tx_channel.try_send(packet).is_err() {
+            log::debug!("Channel full, discarding packet.");
+        }
+    }
 }
 
 impl Device for VirtualDevice {
@@ -43,7 +51,7 @@ impl Device for VirtualDevice {
             return None;
         }
 
-        if let Ok(permit) = self.tx_channel.try_reserve() {
+        if let Some(permit) = self.tx_channel.try_reserve() {
             if let Some(buffer) = self.rx_buffer.pop_front() {
                 let rx = Self::RxToken { buffer };
                 let tx = VirtualTxToken { permit };
@@ -55,22 +63,21 @@ impl Device for VirtualDevice {
     }
 
     fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
-        match self.tx_channel.try_reserve() {
-            Ok(permit) => Some(VirtualTxToken { permit }),
-            Err(_) => None,
-        }
+        self.tx_channel
+            .try_reserve()
+            .map(|permit| VirtualTxToken { permit })
     }
 
     fn capabilities(&self) -> DeviceCapabilities {
         let mut capabilities = DeviceCapabilities::default();
         capabilities.medium = Medium::Ip;
-        capabilities.max_transmission_unit = 1420;
+        capabilities.max_transmission_unit = self.mtu;
         capabilities
     }
 }
 
 pub struct VirtualTxToken<'a> {
-    permit: Permit<'a, NetworkCommand>,
+    permit: PacketPermit<'a>,
 }
 
 impl TxToken for VirtualTxToken<'_> {
@@ -83,7 +90,7 @@ impl TxToken for VirtualTxToken<'_> {
 
         match SmolPacket::try_from(buffer) {
             Ok(packet) => {
-                self.permit.send(NetworkCommand::SendPacket(packet));
+                self.permit.send(packet);
             }
             Err(err) => {
                 log::error!("Failed to parse packet from smol: {:?}", err)
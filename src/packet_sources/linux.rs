@@ -14,6 +14,7 @@ use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
 use crate::intercept_conf::InterceptConf;
 use crate::messages::{TransportCommand, TransportEvent};
+use crate::network::NetworkConf;
 use crate::packet_sources::{forward_packets, PacketSourceConf, PacketSourceTask};
 use crate::shutdown;
 use tempfile::{tempdir, TempDir};
@@ -165,6 +166,7 @@ impl PacketSourceConf for LinuxConf {
         transport_events_tx: Sender<TransportEvent>,
         transport_commands_rx: UnboundedReceiver<TransportCommand>,
         shutdown: shutdown::Receiver,
+        network: NetworkConf,
     ) -> Result<(Self::Task, Self::Data)> {
         let datagram_dir = tempdir().context("failed to create temp dir")?;
 
@@ -186,6 +188,7 @@ impl PacketSourceConf for LinuxConf {
                 transport_commands_rx,
                 conf_rx,
                 shutdown,
+                network,
             },
             conf_tx,
         ))
@@ -199,6 +202,7 @@ pub struct LinuxTask {
     transport_commands_rx: UnboundedReceiver<TransportCommand>,
     conf_rx: UnboundedReceiver<InterceptConf>,
     shutdown: shutdown::Receiver,
+    network: NetworkConf,
 }
 
 impl PacketSourceTask for LinuxTask {
@@ -209,6 +213,7 @@ impl PacketSourceTask for LinuxTask {
             self.transport_commands_rx,
             self.conf_rx,
             self.shutdown,
+            self.network,
         )
         .await?;
         drop(self.datagram_dir);
@@ -23,6 +23,7 @@ use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{UnixListener, UnixStream};
 
 use crate::network::udp::ConnectionState;
+use crate::network::NetworkConf;
 use tokio::process::Command;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
@@ -80,6 +81,7 @@ impl PacketSourceConf for MacosConf {
         transport_events_tx: Sender<TransportEvent>,
         _transport_commands_rx: UnboundedReceiver<TransportCommand>,
         shutdown: shutdown::Receiver,
+        _network: NetworkConf,
     ) -> Result<(Self::Task, Self::Data)> {
         let listener_addr = format!("/tmp/mitmproxy-{}", std::process::id());
         let listener = UnixListener::bind(&listener_addr)?;
@@ -263,6 +265,8 @@ impl ConnectionTask {
                             src_addr: local_address,
                             dst_addr,
                             tunnel_info,
+                            original_hostname: None,
+                            client_hello: None,
                             command_tx: Some(command_tx),
                         }).await?;
                     } else if remote_address != dst_addr {
@@ -296,6 +300,18 @@ impl ConnectionTask {
                                 break;
                             }
                         }
+                        TransportCommand::AbortConnection(_) | TransportCommand::RejectConnection(_, _) => {
+                            state.close();
+                            break;
+                        }
+                        TransportCommand::AcceptConnection(_) => (),
+                        TransportCommand::OpenConnection { tx, .. } => {
+                            tx.send(Err(std::io::ErrorKind::Unsupported.into())).ok();
+                        }
+                        TransportCommand::SendRawPacket(_) => (),
+                        TransportCommand::SpliceConnection { tx, .. } => {
+                            tx.send(Err(std::io::ErrorKind::Unsupported.into())).ok();
+                        }
                     }
                 }
             }
@@ -327,6 +343,8 @@ impl ConnectionTask {
                 src_addr,
                 dst_addr,
                 tunnel_info,
+                original_hostname: None,
+                client_hello: None,
                 command_tx: Some(command_tx),
             })
             .await?;
@@ -372,6 +390,19 @@ impl ConnectionTask {
                                 break;
                             }
                         }
+                        TransportCommand::AbortConnection(_) | TransportCommand::RejectConnection(_, _) => {
+                            // The redirector can't send a RST, so we close without flushing.
+                            break;
+                        }
+                        // The redirector has already completed the handshake.
+                        TransportCommand::AcceptConnection(_) => (),
+                        TransportCommand::OpenConnection { tx, .. } => {
+                            tx.send(Err(std::io::ErrorKind::Unsupported.into())).ok();
+                        }
+                        TransportCommand::SendRawPacket(_) => (),
+                        TransportCommand::SpliceConnection { tx, .. } => {
+                            tx.send(Err(std::io::ErrorKind::Unsupported.into())).ok();
+                        }
                     }
                 },
             }
@@ -3,7 +3,7 @@ use crate::ipc::PacketWithMeta;
 use crate::messages::{
     NetworkCommand, NetworkEvent, SmolPacket, TransportCommand, TransportEvent, TunnelInfo,
 };
-use crate::network::add_network_layer;
+use crate::network::{add_network_layer, NetworkConf};
 use crate::{ipc, shutdown, MAX_PACKET_SIZE};
 use anyhow::{anyhow, Context, Result};
 use prost::bytes::Bytes;
@@ -23,6 +23,7 @@ pub mod udp;
 #[cfg(windows)]
 pub mod windows;
 pub mod wireguard;
+pub mod wireguard_config;
 
 pub trait PacketSourceConf {
     type Task: PacketSourceTask + Send + 'static;
@@ -35,6 +36,7 @@ pub trait PacketSourceConf {
         transport_events_tx: mpsc::Sender<TransportEvent>,
         transport_commands_rx: mpsc::UnboundedReceiver<TransportCommand>,
         shutdown: shutdown::Receiver,
+        network: NetworkConf,
     ) -> impl Future<Output = Result<(Self::Task, Self::Data)>> + Send;
 }
 
@@ -52,10 +54,15 @@ async fn forward_packets<T: AsyncRead + AsyncWrite + Unpin>(
     transport_commands_rx: UnboundedReceiver<TransportCommand>,
     mut conf_rx: UnboundedReceiver<InterceptConf>,
     shutdown: shutdown::Receiver,
+    network: NetworkConf,
 ) -> Result<()> {
     let mut buf = Vec::with_capacity(IPC_BUF_SIZE);
-    let (mut network_task_handle, net_tx, mut net_rx) =
-        add_network_layer(transport_events_tx, transport_commands_rx, shutdown);
+    let (mut network_task_handle, net_tx, mut net_rx) = add_network_layer(
+        transport_events_tx,
+        transport_commands_rx,
+        shutdown,
+        network,
+    );
 
     loop {
         buf.clear();
@@ -1,7 +1,7 @@
 use crate::messages::{
     NetworkCommand, NetworkEvent, SmolPacket, TransportCommand, TransportEvent, TunnelInfo,
 };
-use crate::network::{add_network_layer, MAX_PACKET_SIZE};
+use crate::network::{add_network_layer, NetworkConf, MAX_PACKET_SIZE};
 use crate::packet_sources::{PacketSourceConf, PacketSourceTask};
 use crate::shutdown;
 use anyhow::{Context, Result};
@@ -28,11 +28,16 @@ impl PacketSourceConf for TunConf {
         transport_events_tx: Sender<TransportEvent>,
         transport_commands_rx: UnboundedReceiver<TransportCommand>,
         shutdown: shutdown::Receiver,
+        network: NetworkConf,
     ) -> Result<(Self::Task, Self::Data)> {
-        let (device, tun_name) = create_tun_device(self.tun_name)?;
+        let (device, tun_name) = create_tun_device(self.tun_name, Some(network.mtu()))?;
 
-        let (network_task_handle, net_tx, net_rx) =
-            add_network_layer(transport_events_tx, transport_commands_rx, shutdown);
+        let (network_task_handle, net_tx, net_rx) = add_network_layer(
+            transport_events_tx,
+            transport_commands_rx,
+            shutdown,
+            network,
+        );
 
         Ok((
             TunTask {
@@ -46,9 +51,15 @@ impl PacketSourceConf for TunConf {
     }
 }
 
-pub fn create_tun_device(tun_name: Option<String>) -> Result<(tun::AsyncDevice, String)> {
+/// Create a TUN device with the given MTU. Without one, the device takes packets of up to
+/// [MAX_PACKET_SIZE], which suits devices that only pass packets on, like the one of the
+/// local redirector. TUN interfaces use the MTU of their network stack.
+pub fn create_tun_device(
+    tun_name: Option<String>,
+    mtu: Option<usize>,
+) -> Result<(tun::AsyncDevice, String)> {
     let mut config = tun::Configuration::default();
-    config.mtu(MAX_PACKET_SIZE as u16);
+    config.mtu(mtu.unwrap_or(MAX_PACKET_SIZE) as u16);
     // Setting a local address and a destination is required on Linux.
     config.address("169.254.0.1");
     // config.netmask("0.0.0.0");
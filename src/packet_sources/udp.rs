@@ -1,14 +1,20 @@
-use std::net::{Ipv4Addr, SocketAddr};
-use std::str::FromStr;
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::poll_fn;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::Poll;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 
 use crate::messages::{TransportCommand, TransportEvent, TunnelInfo};
 use crate::network::udp::{UdpHandler, UdpPacket};
-use crate::network::MAX_PACKET_SIZE;
+use crate::network::{NetworkConf, MAX_PACKET_SIZE};
 use crate::packet_sources::{PacketSourceConf, PacketSourceTask};
 use crate::shutdown;
 use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::ReadBuf;
 use tokio::net::UdpSocket;
 use tokio::sync::mpsc::{Permit, Sender, UnboundedReceiver};
 
@@ -23,6 +29,142 @@ pub fn remote_host_closed_conn<T>(_res: &Result<T, std::io::Error>) -> bool {
     false
 }
 
+/// One or more UDP sockets that listen on the same port.
+///
+/// If no host is specified, we bind separate sockets for IPv4 and IPv6. IPV6_V6ONLY is set on all
+/// IPv6 sockets, so traffic from a peer always arrives on the socket for its address family.
+/// By picking the socket by address family when sending, replies leave from the socket that
+/// received the peer's traffic.
+pub struct UdpSockets {
+    sockets: Vec<(UdpSocket, SocketAddr)>,
+    /// The socket that is polled first on the next receive, so that a busy socket
+    /// cannot starve the others.
+    next: AtomicUsize,
+}
+
+impl UdpSockets {
+    pub fn bind(host: &str, port: u16) -> Result<Self> {
+        if !host.is_empty() {
+            let ip = host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .map_err(|_| anyhow!("Invalid listen address specified: {}", host))?;
+            let socket = bind_udp_socket(SocketAddr::new(ip, port))?;
+            return Self::from_sockets(vec![socket]);
+        }
+
+        let v4 = bind_udp_socket(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port))?;
+        // if the port was chosen by the OS, use the same one for IPv6.
+        let port = v4.local_addr()?.port();
+        match bind_udp_socket(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port)) {
+            Ok(v6) => Self::from_sockets(vec![v4, v6]),
+            Err(e)
+                if e.downcast_ref::<io::Error>()
+                    .is_some_and(|e| e.kind() == io::ErrorKind::AddrInUse) =>
+            {
+                Err(e)
+            }
+            Err(e) => {
+                log::warn!("Failed to listen on IPv6, using IPv4 only: {:#}", e);
+                Self::from_sockets(vec![v4])
+            }
+        }
+    }
+
+    fn from_sockets(sockets: Vec<UdpSocket>) -> Result<Self> {
+        let sockets = sockets
+            .into_iter()
+            .map(|socket| {
+                let local_addr = socket.local_addr()?;
+                Ok((socket, local_addr))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            sockets,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// The local addresses of all sockets.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.sockets.iter().map(|(_, addr)| *addr).collect()
+    }
+
+    /// Receive a datagram on any of the sockets.
+    /// Returns the number of bytes read, the source address, and the local address of the socket.
+    ///
+    /// This method is cancel safe.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+        poll_fn(|cx| {
+            let start = self.next.load(Ordering::Relaxed);
+            for i in 0..self.sockets.len() {
+                let idx = (start + i) % self.sockets.len();
+                let (socket, local_addr) = &self.sockets[idx];
+                let mut read_buf = ReadBuf::new(buf);
+                if let Poll::Ready(r) = socket.poll_recv_from(cx, &mut read_buf) {
+                    self.next
+                        .store((idx + 1) % self.sockets.len(), Ordering::Relaxed);
+                    return Poll::Ready(r.map(|src| (read_buf.filled().len(), src, *local_addr)));
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Send a datagram from the socket for `target`'s address family.
+    ///
+    /// This method is cancel safe.
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.socket_for(target).send_to(buf, target).await
+    }
+
+    fn socket_for(&self, target: SocketAddr) -> &UdpSocket {
+        let (socket, _) = self
+            .sockets
+            .iter()
+            .find(|(_, local_addr)| local_addr.is_ipv4() == target.is_ipv4())
+            .unwrap_or(&self.sockets[0]);
+        socket
+    }
+}
+
+impl fmt::Display for UdpSockets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let addrs = self
+            .sockets
+            .iter()
+            .map(|(_, addr)| addr.to_string())
+            .collect::<Vec<String>>();
+        write!(f, "{}", addrs.join(" and "))
+    }
+}
+
+fn bind_udp_socket(addr: SocketAddr) -> Result<UdpSocket> {
+    let domain = if addr.is_ipv4() {
+        Domain::IPV4
+    } else {
+        Domain::IPV6
+    };
+    let sock2 = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+
+    // Ensure that IPv6 sockets listen on IPv6 only
+    if addr.is_ipv6() {
+        sock2
+            .set_only_v6(true)
+            .context("Failed to set IPV6_V6ONLY flag")?;
+    }
+
+    sock2
+        .bind(&addr.into())
+        .with_context(|| format!("Failed to bind UDP socket to {}", addr))?;
+
+    let std_sock: std::net::UdpSocket = sock2.into();
+    std_sock.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(std_sock)?)
+}
+
 pub struct UdpConf {
     pub host: String,
     pub port: u16,
@@ -30,7 +172,7 @@ pub struct UdpConf {
 
 impl PacketSourceConf for UdpConf {
     type Task = UdpTask;
-    type Data = SocketAddr;
+    type Data = Vec<SocketAddr>;
 
     fn name(&self) -> &'static str {
         "UDP server"
@@ -41,52 +183,28 @@ impl PacketSourceConf for UdpConf {
         transport_events_tx: Sender<TransportEvent>,
         transport_commands_rx: UnboundedReceiver<TransportCommand>,
         shutdown: shutdown::Receiver,
+        _network: NetworkConf,
     ) -> Result<(Self::Task, Self::Data)> {
-        let addr = format!("{}:{}", self.host, self.port);
-        let sock_addr = SocketAddr::from_str(&addr).context("Invalid listen address specified")?;
-
-        let domain = if sock_addr.is_ipv4() {
-            Domain::IPV4
-        } else {
-            Domain::IPV6
-        };
-        let sock2 = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
-
-        // Ensure that IPv6 sockets listen on IPv6 only
-        if sock_addr.is_ipv6() {
-            sock2
-                .set_only_v6(true)
-                .context("Failed to set IPV6_V6ONLY flag")?;
-        }
-
-        sock2
-            .bind(&sock_addr.into())
-            .context(format!("Failed to bind UDP socket to {}", addr))?;
+        let sockets = UdpSockets::bind(&self.host, self.port)?;
+        let local_addrs = sockets.local_addrs();
 
-        let std_sock: std::net::UdpSocket = sock2.into();
-        std_sock.set_nonblocking(true)?;
-        let socket = UdpSocket::from_std(std_sock)?;
-        let local_addr = socket.local_addr()?;
-
-        log::debug!("UDP server listening on {} ...", local_addr);
+        log::debug!("UDP server listening on {} ...", sockets);
 
         Ok((
             UdpTask {
-                socket,
-                local_addr,
+                sockets,
                 handler: UdpHandler::new(),
                 transport_events_tx,
                 transport_commands_rx,
                 shutdown,
             },
-            local_addr,
+            local_addrs,
         ))
     }
 }
 
 pub struct UdpTask {
-    socket: UdpSocket,
-    local_addr: SocketAddr,
+    sockets: UdpSockets,
 
     handler: UdpHandler,
 
@@ -105,35 +223,54 @@ impl PacketSourceTask for UdpTask {
         let mut packet_dst = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
 
         let mut permit: Option<Permit<TransportEvent>> = None;
+        let mut closed_connections: VecDeque<TransportEvent> = VecDeque::new();
 
         loop {
+            // notify Python land of closed connections, as far as there is channel capacity.
+            closed_connections.extend(self.handler.take_closed_connections());
+            while !closed_connections.is_empty() {
+                let Some(p) = permit
+                    .take()
+                    .or_else(|| transport_events_tx.try_reserve().ok())
+                else {
+                    break;
+                };
+                p.send(closed_connections.pop_front().unwrap());
+            }
+
             let py_tx_available = permit.is_some();
+            let delay = self.handler.poll_delay();
 
             tokio::select! {
                 // wait for graceful shutdown
                 _ = self.shutdown.recv() => break,
+                // expire idle flows
+                _ = async { tokio::time::sleep(delay.unwrap()).await }, if delay.is_some() => {
+                    self.handler.poll();
+                },
                 // wait for transport_events_tx channel capacity...
                 Ok(p) = transport_events_tx.reserve(), if !py_tx_available => {
                     permit = Some(p);
                 },
                 // ... or process incoming packets
-                r = self.socket.recv_from(udp_buf.as_mut_slice()), if py_tx_available => {
+                r = self.sockets.recv_from(udp_buf.as_mut_slice()), if py_tx_available => {
                     if remote_host_closed_conn(&r) {
                         continue;
                     }
-                    let (len, src_addr) = r.context("UDP recv() failed")?;
+                    let (len, src_addr, dst_addr) = r.context("UDP recv() failed")?;
                     self.handler.receive_data(
                         UdpPacket {
                             src_addr,
-                            dst_addr: self.local_addr,
+                            dst_addr,
                             payload: udp_buf[..len].to_vec(),
                         },
                         TunnelInfo::None {},
+                        None,
                         permit.take().unwrap()
                     );
                 },
                 // send_to is cancel safe, so we can use that for backpressure.
-                r = self.socket.send_to(&packet_payload, packet_dst), if packet_needs_sending => {
+                r = self.sockets.send_to(&packet_payload, packet_dst), if packet_needs_sending => {
                     let sent = r.context("UDP send_to() failed")?;
                     if sent != packet_payload.len() {
                         log::debug!("socket.send_to: {} of {} bytes sent.", sent, packet_payload.len());
@@ -141,7 +278,9 @@ impl PacketSourceTask for UdpTask {
                     packet_needs_sending = false;
                 },
                 Some(command) = self.transport_commands_rx.recv(), if !packet_needs_sending => {
-                    if let Some(UdpPacket { payload, dst_addr, .. }) = self.handler.handle_transport_command(command) {
+                    // ICMP errors for rejected flows cannot be sent through a regular socket and are dropped here.
+                    let packet = self.handler.handle_transport_command(command).map(UdpPacket::try_from);
+                    if let Some(Ok(UdpPacket { payload, dst_addr, .. })) = packet {
                         packet_payload = payload;
                         packet_dst = dst_addr;
                         packet_needs_sending = true;
@@ -149,7 +288,95 @@ impl PacketSourceTask for UdpTask {
                 }
             }
         }
+        // best effort: tell Python land which connections are going away.
+        drop(permit);
+        for event in closed_connections
+            .into_iter()
+            .chain(self.handler.take_closed_connections())
+            .chain(self.handler.shutdown())
+        {
+            if transport_events_tx.try_send(event).is_err() {
+                break;
+            }
+        }
+
         log::debug!("UDP server task shutting down.");
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn bind_dual_stack() -> Result<()> {
+        let sockets = UdpSockets::bind("", 0)?;
+        let [v4, v6] = sockets.local_addrs()[..] else {
+            panic!("expected two sockets: {}", sockets);
+        };
+        assert!(v4.is_ipv4() && v6.is_ipv6());
+        assert_eq!(v4.port(), v6.port());
+
+        for (client_addr, server_addr) in [
+            (
+                "127.0.0.1:0",
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), v4.port()),
+            ),
+            (
+                "[::1]:0",
+                SocketAddr::new(Ipv6Addr::LOCALHOST.into(), v6.port()),
+            ),
+        ] {
+            let client = UdpSocket::bind(client_addr).await?;
+            client.send_to(b"ping", server_addr).await?;
+
+            let mut buf = [0u8; 16];
+            let (len, src_addr, local_addr) = sockets.recv_from(&mut buf).await?;
+            assert_eq!(&buf[..len], b"ping");
+            assert_eq!(src_addr, client.local_addr()?);
+            assert_eq!(local_addr.is_ipv4(), server_addr.is_ipv4());
+
+            sockets.send_to(b"pong", src_addr).await?;
+            let (len, reply_addr) = client.recv_from(&mut buf).await?;
+            assert_eq!(&buf[..len], b"pong");
+            assert_eq!(reply_addr, server_addr);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn recv_alternates_between_sockets() -> Result<()> {
+        let sockets = UdpSockets::from_sockets(vec![
+            bind_udp_socket("127.0.0.1:0".parse()?)?,
+            bind_udp_socket("127.0.0.1:0".parse()?)?,
+        ])?;
+        let [busy, quiet] = sockets.local_addrs()[..] else {
+            unreachable!()
+        };
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        for _ in 0..10 {
+            client.send_to(b"busy", busy).await?;
+        }
+        client.send_to(b"quiet", quiet).await?;
+
+        let mut buf = [0u8; 16];
+        let mut local_addrs = Vec::new();
+        for _ in 0..4 {
+            let (_, _, local_addr) = sockets.recv_from(&mut buf).await?;
+            local_addrs.push(local_addr);
+        }
+        assert_eq!(local_addrs, [busy, quiet, busy, busy]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bind_single_host() -> Result<()> {
+        for host in ["127.0.0.1", "::1", "[::1]"] {
+            let sockets = UdpSockets::bind(host, 0)?;
+            assert_eq!(sockets.local_addrs().len(), 1);
+        }
+        assert!(UdpSockets::bind("localhost", 0).is_err());
+        Ok(())
+    }
+}
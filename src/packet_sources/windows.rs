@@ -14,6 +14,7 @@ use windows::Win32::UI::WindowsAndMessaging::{SW_HIDE, SW_SHOWNORMAL};
 
 use crate::intercept_conf::InterceptConf;
 use crate::messages::{TransportCommand, TransportEvent};
+use crate::network::NetworkConf;
 use crate::packet_sources::{forward_packets, PacketSourceConf, PacketSourceTask, IPC_BUF_SIZE};
 use crate::shutdown;
 
@@ -34,6 +35,7 @@ impl PacketSourceConf for WindowsConf {
         transport_events_tx: Sender<TransportEvent>,
         transport_commands_rx: UnboundedReceiver<TransportCommand>,
         shutdown: shutdown::Receiver,
+        network: NetworkConf,
     ) -> Result<(Self::Task, Self::Data)> {
         let pipe_name = format!(
             r"\\.\pipe\mitmproxy-transparent-proxy-{}",
@@ -101,6 +103,7 @@ impl PacketSourceConf for WindowsConf {
                 transport_commands_rx,
                 conf_rx,
                 shutdown,
+                network,
             },
             conf_tx,
         ))
@@ -113,6 +116,7 @@ pub struct WindowsTask {
     transport_commands_rx: UnboundedReceiver<TransportCommand>,
     conf_rx: UnboundedReceiver<InterceptConf>,
     shutdown: shutdown::Receiver,
+    network: NetworkConf,
 }
 
 impl PacketSourceTask for WindowsTask {
@@ -127,6 +131,7 @@ impl PacketSourceTask for WindowsTask {
             self.transport_commands_rx,
             self.conf_rx,
             self.shutdown,
+            self.network,
         )
         .await
     }
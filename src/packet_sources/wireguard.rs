@@ -1,50 +1,237 @@
 use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::messages::{
     NetworkCommand, NetworkEvent, SmolPacket, TransportCommand, TransportEvent, TunnelInfo,
 };
-use crate::network::{add_network_layer, MAX_PACKET_SIZE};
+use crate::network::{add_network_layer, NetworkConf, MAX_PACKET_SIZE};
 use crate::packet_sources::{PacketSourceConf, PacketSourceTask};
 use anyhow::{anyhow, Context, Result};
 use boringtun::noise::{
-    errors::WireGuardError, handshake::parse_handshake_anon, Packet, Tunn, TunnResult,
+    errors::WireGuardError, handshake::parse_handshake_anon, rate_limiter::RateLimiter, Packet,
+    Tunn, TunnResult,
 };
 use boringtun::x25519::{PublicKey, StaticSecret};
+use data_encoding::BASE64;
+use lru_time_cache::LruCache;
 use pretty_hex::pretty_hex;
-use smoltcp::wire::{Ipv4Packet, Ipv6Packet};
-use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::{
-    net::UdpSocket,
-    sync::{
-        mpsc::{Receiver, Sender},
-        Mutex,
-    },
+use smoltcp::wire::{IpAddress, IpCidr, Ipv4Packet, Ipv6Packet};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{
+    mpsc::{Receiver, Sender},
+    oneshot, Mutex,
 };
+use tokio::time::MissedTickBehavior;
 
-use crate::packet_sources::udp::remote_host_closed_conn;
+use crate::packet_sources::udp::{remote_host_closed_conn, UdpSockets};
 use crate::shutdown;
 
 // WireGuard headers are 60 bytes for IPv4 and 80 bytes for IPv6
 const WG_HEADER_SIZE: usize = 80;
 
+// boringtun expects its timers to be updated every 250ms (see boringtun's device implementation).
+const TIMER_INTERVAL: Duration = Duration::from_millis(250);
+
+// Number of handshake messages per second above which we consider ourselves under load and reply
+// with cookies instead of processing handshakes (see section 5.3 of the WireGuard paper).
+const HANDSHAKE_RATE_LIMIT: u64 = 200;
+
+// Limits for handshakes from unknown peers, so that a flood of handshakes can't exhaust memory
+// or overwhelm the enrollment handler.
+const MAX_PENDING_ENROLLMENTS: usize = 64;
+const MAX_ENROLLMENT_REQUESTS_PER_SECOND: u32 = 10;
+const ENROLLMENT_TIMEOUT: Duration = Duration::from_secs(10);
+const REJECTED_PEER_TIMEOUT: Duration = Duration::from_secs(60);
+
+// boringtun reserves the lower 8 bits of a session index, the rest identifies the peer.
+const MAX_PEER_INDEX: u32 = 0x00ff_ffff;
+
 /// A WireGuard peer. We keep track of the tunnel state and the peer address.
 pub struct WireGuardPeer {
     tunnel: Tunn,
     endpoint: Option<SocketAddr>,
+    allowed_ips: Vec<IpCidr>,
+    public_key: PublicKey,
+    index: u32,
+    counters: PeerCounters,
+}
+
+#[derive(Debug, Default)]
+struct PeerCounters {
+    rx_bytes: u64,
+    rx_packets: u64,
+    tx_bytes: u64,
+    tx_packets: u64,
+    decapsulation_errors: u64,
+    dropped_packets: u64,
+}
+
+impl PeerCounters {
+    fn rx(&mut self, len: usize) {
+        self.rx_packets += 1;
+        self.rx_bytes += len as u64;
+    }
+
+    fn tx(&mut self, len: usize) {
+        self.tx_packets += 1;
+        self.tx_bytes += len as u64;
+    }
+}
+
+/// Statistics for a WireGuard peer, similar to what `wg show` displays.
+/// Byte and packet counts refer to WireGuard datagrams, including handshakes and keepalives.
+#[derive(Debug, Clone)]
+pub struct WireGuardPeerStats {
+    pub public_key: PublicKey,
+    pub endpoint: Option<SocketAddr>,
+    /// Time of the last successful handshake, `None` if there is no active session.
+    pub last_handshake: Option<SystemTime>,
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    /// Incoming datagrams that could not be decapsulated, e.g. because there was no current session.
+    pub decapsulation_errors: u64,
+    /// Decrypted packets that were dropped because the network stack was not keeping up.
+    pub dropped_packets: u64,
+}
+
+/// Server-wide statistics, which also cover handshakes from unknown peers and packets that could
+/// not be routed to any peer.
+#[derive(Debug, Clone, Default)]
+pub struct WireGuardHandshakeStats {
+    /// Handshake initiations that passed the rate limiter.
+    pub handshakes: u64,
+    /// Handshake initiations that passed the rate limiter in the last full second.
+    pub handshakes_per_second: u64,
+    /// Cookie replies sent instead of processing a handshake because we were under load.
+    pub cookie_replies: u64,
+    /// Handshake messages that were dropped because of an invalid MAC.
+    pub invalid_handshakes: u64,
+    /// Outgoing packets that were dropped because no peer's allowed IPs cover their destination.
+    pub unroutable_packets: u64,
+}
+
+impl WireGuardPeer {
+    fn stats(&self) -> WireGuardPeerStats {
+        WireGuardPeerStats {
+            public_key: self.public_key,
+            endpoint: self.endpoint,
+            last_handshake: self
+                .tunnel
+                .time_since_last_handshake()
+                .and_then(|elapsed| SystemTime::now().checked_sub(elapsed)),
+            rx_bytes: self.counters.rx_bytes,
+            rx_packets: self.counters.rx_packets,
+            tx_bytes: self.counters.tx_bytes,
+            tx_packets: self.counters.tx_packets,
+            decapsulation_errors: self.counters.decapsulation_errors,
+            dropped_packets: self.counters.dropped_packets,
+        }
+    }
+
+    fn is_allowed(&self, ip: IpAddr) -> bool {
+        let ip = IpAddress::from(ip);
+        self.allowed_ips.iter().any(|cidr| cidr.contains_addr(&ip))
+    }
+
+    fn tunnel_info(&self, src_addr: SocketAddr, dst_addr: SocketAddr) -> TunnelInfo {
+        TunnelInfo::WireGuard {
+            src_addr,
+            dst_addr,
+            peer_public_key: self.public_key,
+            peer_index: self.index,
+        }
+    }
+}
+
+/// Configuration for an individual WireGuard peer.
+#[derive(Debug, Clone)]
+pub struct WireGuardPeerConf {
+    pub public_key: PublicKey,
+    /// The tunnel addresses this peer may use. Packets from other source addresses are dropped,
+    /// and outgoing packets are routed to the peer with the most specific matching range.
+    pub allowed_ips: Vec<IpCidr>,
+    /// Optional symmetric key that is mixed into the handshake for post-quantum resistance.
+    pub preshared_key: Option<[u8; 32]>,
+}
+
+impl From<PublicKey> for WireGuardPeerConf {
+    /// Configure a peer that is allowed to use any tunnel address.
+    fn from(public_key: PublicKey) -> Self {
+        Self {
+            public_key,
+            allowed_ips: vec![
+                IpCidr::new(IpAddress::v4(0, 0, 0, 0), 0),
+                IpCidr::new(IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 0), 0),
+            ],
+            preshared_key: None,
+        }
+    }
+}
+
+impl WireGuardPeerConf {
+    /// Configure a peer that is allowed to use the given address ranges, e.g. `10.0.0.2/32`.
+    pub fn with_allowed_ips<S: AsRef<str>>(
+        public_key: PublicKey,
+        allowed_ips: impl IntoIterator<Item = S>,
+    ) -> Result<Self> {
+        let allowed_ips = allowed_ips
+            .into_iter()
+            .map(|cidr| {
+                let cidr = cidr.as_ref().trim();
+                IpCidr::from_str(cidr).map_err(|_| anyhow!("Invalid address range: {}", cidr))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            public_key,
+            allowed_ips,
+            preshared_key: None,
+        })
+    }
+}
+
+/// Commands that can be sent to a running WireGuard server.
+#[derive(Debug)]
+pub enum WireGuardCommand {
+    /// Add a peer. Fails if a peer with the same public key already exists.
+    AddPeer(WireGuardPeerConf, oneshot::Sender<Result<()>>),
+    /// Remove a peer and discard its sessions. Fails if there is no such peer.
+    RemovePeer(PublicKey, oneshot::Sender<Result<()>>),
+    ListPeers(oneshot::Sender<Vec<PublicKey>>),
+    GetStats(oneshot::Sender<Vec<WireGuardPeerStats>>),
+    GetHandshakeStats(oneshot::Sender<WireGuardHandshakeStats>),
+    /// Reject an [`EnrollmentRequest`]. Further handshakes from this peer are dropped for a while.
+    /// To accept a request instead, send [`WireGuardCommand::AddPeer`].
+    RejectEnrollment(PublicKey),
+}
+
+/// A valid handshake from a peer that is not configured, see [`WireGuardConf::enrollment`].
+#[derive(Debug)]
+pub struct EnrollmentRequest {
+    pub public_key: PublicKey,
+    pub src_addr: SocketAddr,
 }
 
 pub struct WireGuardConf {
     pub host: String,
     pub port: u16,
     pub private_key: StaticSecret,
-    pub peer_public_keys: Vec<PublicKey>,
+    pub peers: Vec<WireGuardPeerConf>,
+    /// Interval in seconds for sending keepalive packets to peers, or `None` to disable them.
+    pub persistent_keepalive: Option<u16>,
+    /// If set, handshakes from unknown peers are not dropped, but forwarded here for approval.
+    /// Accepted peers are added with [`WireGuardCommand::AddPeer`], which also completes the
+    /// pending handshake. Requests are dropped if this channel is full.
+    pub enrollment: Option<Sender<EnrollmentRequest>>,
 }
 
 impl PacketSourceConf for WireGuardConf {
     type Task = WireGuardTask;
-    type Data = SocketAddr;
+    type Data = (Vec<SocketAddr>, UnboundedSender<WireGuardCommand>);
 
     fn name(&self) -> &'static str {
         "WireGuard server"
@@ -55,113 +242,138 @@ impl PacketSourceConf for WireGuardConf {
         transport_events_tx: Sender<TransportEvent>,
         transport_commands_rx: UnboundedReceiver<TransportCommand>,
         shutdown: shutdown::Receiver,
+        network: NetworkConf,
     ) -> Result<(Self::Task, Self::Data)> {
-        let (network_task_handle, net_tx, net_rx) =
-            add_network_layer(transport_events_tx, transport_commands_rx, shutdown);
-
-        // initialize WireGuard server
-        let mut peers_by_idx = HashMap::new();
-        let mut peers_by_key = HashMap::new();
-        for public_key in self.peer_public_keys {
-            let index = peers_by_idx.len() as u32;
-
-            let tunnel = Tunn::new(
-                self.private_key.clone(),
-                public_key,
-                None,
-                Some(25),
-                index,
-                None,
-            )
-            .map_err(|error| anyhow!(error))?;
-
-            let peer = Arc::new(Mutex::new(WireGuardPeer {
-                tunnel,
-                endpoint: None,
-            }));
-
-            peers_by_idx.insert(index, peer.clone());
-            peers_by_key.insert(public_key, peer);
-        }
+        let (network_task_handle, net_tx, net_rx) = add_network_layer(
+            transport_events_tx,
+            transport_commands_rx,
+            shutdown,
+            network,
+        );
 
         // bind to UDP socket(s)
-        let socket_addrs = if self.host.is_empty() {
-            vec![
-                SocketAddr::new("0.0.0.0".parse().unwrap(), self.port),
-                SocketAddr::new("::".parse().unwrap(), self.port),
-            ]
-        } else {
-            vec![SocketAddr::new(self.host.parse()?, self.port)]
-        };
-
-        let socket = UdpSocket::bind(socket_addrs.as_slice()).await?;
-        let local_addr = socket.local_addr()?;
+        let sockets = UdpSockets::bind(&self.host, self.port)?;
+        let local_addrs = sockets.local_addrs();
 
         log::debug!(
             "WireGuard server listening for UDP connections on {} ...",
-            socket_addrs
-                .iter()
-                .map(|addr| addr.to_string())
-                .collect::<Vec<String>>()
-                .join(" and ")
+            sockets
         );
 
         let public_key = PublicKey::from(&self.private_key);
+        let (command_tx, command_rx) = unbounded_channel();
 
-        Ok((
-            WireGuardTask {
-                socket,
-                private_key: self.private_key,
-                public_key,
+        let mut task = WireGuardTask {
+            sockets,
+            private_key: self.private_key,
+            public_key,
+            persistent_keepalive: self.persistent_keepalive,
+
+            rate_limiter: RateLimiter::new(&public_key, HANDSHAKE_RATE_LIMIT),
+            rate_limiter_reset: Instant::now(),
+            handshake_stats: WireGuardHandshakeStats::default(),
+            handshakes_in_window: 0,
+
+            enrollment_tx: self.enrollment,
+            pending_enrollments: LruCache::with_expiry_duration_and_capacity(
+                ENROLLMENT_TIMEOUT,
+                MAX_PENDING_ENROLLMENTS,
+            ),
+            rejected_peers: LruCache::with_expiry_duration_and_capacity(
+                REJECTED_PEER_TIMEOUT,
+                1024,
+            ),
+            enrollment_window: (Instant::now(), 0),
 
-                peers_by_idx,
-                peers_by_key,
-                peers_by_ip: HashMap::new(),
-                wg_buf: vec![0u8; MAX_PACKET_SIZE],
+            peers_by_idx: HashMap::new(),
+            peers_by_key: HashMap::new(),
+            peers_by_ip: HashMap::new(),
+            routes: Vec::new(),
+            next_peer_idx: 0,
+            wg_buf: vec![0u8; MAX_PACKET_SIZE],
+            timer_interval: TIMER_INTERVAL,
 
-                net_tx,
-                net_rx,
-                network_task_handle,
-            },
-            local_addr,
-        ))
+            command_rx,
+            net_tx,
+            net_rx,
+            network_task_handle,
+        };
+
+        // initialize WireGuard peers
+        for peer in self.peers {
+            task.add_peer(peer)?;
+        }
+
+        Ok((task, (local_addrs, command_tx)))
     }
 }
 
 pub struct WireGuardTask {
-    socket: UdpSocket,
+    sockets: UdpSockets,
     private_key: StaticSecret,
     public_key: PublicKey,
+    persistent_keepalive: Option<u16>,
+
+    /// Checks handshakes of all peers before they are processed any further. Tunnels use their
+    /// own internal rate limiter instead of this one, so that each handshake is only counted once.
+    rate_limiter: RateLimiter,
+    rate_limiter_reset: Instant,
+    handshake_stats: WireGuardHandshakeStats,
+    handshakes_in_window: u64,
+
+    enrollment_tx: Option<Sender<EnrollmentRequest>>,
+    /// Handshakes from unknown peers that wait for approval: `(datagram, src_addr, local_addr)`.
+    pending_enrollments: LruCache<[u8; 32], (Vec<u8>, SocketAddr, SocketAddr)>,
+    rejected_peers: LruCache<[u8; 32], ()>,
+    /// Start of the current rate limiting window and the number of requests in it.
+    enrollment_window: (Instant, u32),
 
     peers_by_idx: HashMap<u32, Arc<Mutex<WireGuardPeer>>>,
     peers_by_key: HashMap<PublicKey, Arc<Mutex<WireGuardPeer>>>,
+    /// Tunnel addresses that peers have recently sent packets from.
     peers_by_ip: HashMap<IpAddr, Arc<Mutex<WireGuardPeer>>>,
+    /// Allowed IP ranges of all peers, ordered from most to least specific.
+    routes: Vec<(IpCidr, Arc<Mutex<WireGuardPeer>>)>,
+    next_peer_idx: u32,
 
+    command_rx: UnboundedReceiver<WireGuardCommand>,
     net_tx: Sender<NetworkEvent>,
     net_rx: Receiver<NetworkCommand>,
 
     wg_buf: Vec<u8>,
+    timer_interval: Duration,
     network_task_handle: tokio::task::JoinHandle<Result<()>>,
 }
 
 impl PacketSourceTask for WireGuardTask {
     async fn run(mut self) -> Result<()> {
-        if self.peers_by_idx.is_empty() {
-            return Err(anyhow!("No WireGuard peers were configured."));
+        if self.peers_by_idx.is_empty() && self.enrollment_tx.is_none() {
+            log::warn!("No WireGuard peers were configured.");
         }
 
         let mut udp_buf = vec![0; MAX_PACKET_SIZE];
 
+        let mut timers = tokio::time::interval(self.timer_interval);
+        timers.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
         loop {
             tokio::select! {
                 exit = &mut self.network_task_handle => break exit.context("network task panic")?.context("network task error")?,
+                // drive WireGuard timers for keepalives, rekeying and handshake retries
+                _ = timers.tick() => {
+                    self.update_timers().await?;
+                },
+                // wait for peer updates
+                Some(command) = self.command_rx.recv() => {
+                    self.handle_command(command).await?;
+                },
                 // wait for WireGuard packets incoming on the UDP socket
-                r = self.socket.recv_from(udp_buf.as_mut_slice()) => {
+                r = self.sockets.recv_from(udp_buf.as_mut_slice()) => {
                     if remote_host_closed_conn(&r) {
                         continue;
                     }
-                    let (len, src_orig) = r.context("UDP recv() failed")?;
-                    self.process_incoming_datagram(&udp_buf[..len], src_orig).await?;
+                    let (len, src_orig, local_addr) = r.context("UDP recv() failed")?;
+                    self.process_incoming_datagram(&udp_buf[..len], src_orig, local_addr).await?;
                 },
                 // wait for outgoing IP packets
                 Some(e) = self.net_rx.recv() => {
@@ -189,7 +401,193 @@ impl PacketSourceTask for WireGuardTask {
 }
 
 impl WireGuardTask {
-    fn find_peer_for_datagram(&self, data: &[u8]) -> Option<Arc<Mutex<WireGuardPeer>>> {
+    fn add_peer(&mut self, conf: WireGuardPeerConf) -> Result<()> {
+        let WireGuardPeerConf {
+            public_key,
+            allowed_ips,
+            preshared_key,
+        } = conf;
+        if self.peers_by_key.contains_key(&public_key) {
+            return Err(anyhow!("WireGuard peer already exists."));
+        }
+
+        // Once the index wraps around, we skip indices that are still in use.
+        if self.peers_by_idx.len() > MAX_PEER_INDEX as usize {
+            return Err(anyhow!("Too many WireGuard peers."));
+        }
+        let index = loop {
+            let index = self.next_peer_idx;
+            self.next_peer_idx = (self.next_peer_idx + 1) & MAX_PEER_INDEX;
+            if !self.peers_by_idx.contains_key(&index) {
+                break index;
+            }
+        };
+
+        let tunnel = Tunn::new(
+            self.private_key.clone(),
+            public_key,
+            preshared_key,
+            self.persistent_keepalive,
+            index,
+            None,
+        )
+        .map_err(|error| anyhow!(error))?;
+
+        let peer = Arc::new(Mutex::new(WireGuardPeer {
+            tunnel,
+            endpoint: None,
+            allowed_ips: allowed_ips.clone(),
+            public_key,
+            index,
+            counters: PeerCounters::default(),
+        }));
+
+        self.routes
+            .extend(allowed_ips.into_iter().map(|cidr| (cidr, peer.clone())));
+        self.routes
+            .sort_by_key(|(cidr, _)| std::cmp::Reverse(cidr.prefix_len()));
+        self.peers_by_idx.insert(index, peer.clone());
+        self.peers_by_key.insert(public_key, peer);
+        Ok(())
+    }
+
+    fn remove_peer(&mut self, public_key: &PublicKey) -> Result<()> {
+        let Some(peer) = self.peers_by_key.remove(public_key) else {
+            return Err(anyhow!("WireGuard peer does not exist."));
+        };
+        // Dropping the last reference to the tunnel discards all of its sessions.
+        self.peers_by_idx.retain(|_, p| !Arc::ptr_eq(p, &peer));
+        self.peers_by_ip.retain(|_, p| !Arc::ptr_eq(p, &peer));
+        self.routes.retain(|(_, p)| !Arc::ptr_eq(p, &peer));
+        Ok(())
+    }
+
+    /// Find the peer for a tunnel address by longest prefix match on the peers' allowed IPs.
+    ///
+    /// If multiple peers have equally specific matches, we pick the peer that has last sent packets
+    /// from this address. If there is no such peer, the destination is ambiguous and we return
+    /// `None` instead of possibly leaking traffic to a different peer.
+    fn route(&self, ip: IpAddr) -> Option<&Arc<Mutex<WireGuardPeer>>> {
+        let addr = IpAddress::from(ip);
+        let mut matches = self
+            .routes
+            .iter()
+            .filter(|(cidr, _)| cidr.contains_addr(&addr));
+        let (best, peer) = matches.next()?;
+        let mut candidates = matches
+            .take_while(|(cidr, _)| cidr.prefix_len() == best.prefix_len())
+            .peekable();
+        if candidates.peek().is_none() {
+            return Some(peer);
+        }
+        let learned = self.peers_by_ip.get(&ip)?;
+        std::iter::once(peer)
+            .chain(candidates.map(|(_, p)| p))
+            .find(|p| Arc::ptr_eq(p, learned))
+    }
+
+    async fn handle_command(&mut self, command: WireGuardCommand) -> Result<()> {
+        match command {
+            WireGuardCommand::AddPeer(peer, tx) => {
+                let public_key = peer.public_key;
+                let result = self.add_peer(peer);
+                let added = result.is_ok();
+                tx.send(result).ok();
+                if !added {
+                    return Ok(());
+                }
+                if let Some((data, src_addr, local_addr)) =
+                    self.pending_enrollments.remove(public_key.as_bytes())
+                {
+                    // complete the handshake that triggered the enrollment. It has already
+                    // passed the rate limiter and been counted when it first arrived.
+                    self.rejected_peers.remove(public_key.as_bytes());
+                    self.process_verified_datagram(&data, src_addr, local_addr)
+                        .await?;
+                }
+            }
+            WireGuardCommand::RemovePeer(public_key, tx) => {
+                tx.send(self.remove_peer(&public_key)).ok();
+            }
+            WireGuardCommand::ListPeers(tx) => {
+                tx.send(self.peers_by_key.keys().copied().collect()).ok();
+            }
+            WireGuardCommand::GetStats(tx) => {
+                let mut stats = Vec::with_capacity(self.peers_by_key.len());
+                for peer in self.peers_by_key.values() {
+                    stats.push(peer.lock().await.stats());
+                }
+                tx.send(stats).ok();
+            }
+            WireGuardCommand::GetHandshakeStats(tx) => {
+                tx.send(self.handshake_stats.clone()).ok();
+            }
+            WireGuardCommand::RejectEnrollment(public_key) => {
+                log::info!(
+                    "Rejected WireGuard peer {}.",
+                    BASE64.encode(public_key.as_bytes())
+                );
+                self.pending_enrollments.remove(public_key.as_bytes());
+                self.rejected_peers.insert(public_key.to_bytes(), ());
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask for approval of an unknown peer, subject to rate limiting.
+    fn request_enrollment(
+        &mut self,
+        public_key: PublicKey,
+        data: &[u8],
+        src_addr: SocketAddr,
+        local_addr: SocketAddr,
+    ) {
+        let Some(enrollment_tx) = &self.enrollment_tx else {
+            log::error!("Received WireGuard packet from unknown peer.");
+            return;
+        };
+        let key = public_key.to_bytes();
+        if self.rejected_peers.contains_key(&key) {
+            log::debug!("Dropping handshake from rejected WireGuard peer.");
+            return;
+        }
+        if let Some(pending) = self.pending_enrollments.get_mut(&key) {
+            // the peer has retried its handshake, keep the latest one.
+            *pending = (data.to_vec(), src_addr, local_addr);
+            return;
+        }
+
+        let (window_start, requests) = &mut self.enrollment_window;
+        if window_start.elapsed() >= Duration::from_secs(1) {
+            *window_start = Instant::now();
+            *requests = 0;
+        }
+        if *requests >= MAX_ENROLLMENT_REQUESTS_PER_SECOND
+            || self.pending_enrollments.len() >= MAX_PENDING_ENROLLMENTS
+        {
+            log::warn!("Too many handshakes from unknown WireGuard peers, dropping handshake.");
+            return;
+        }
+
+        let request = EnrollmentRequest {
+            public_key,
+            src_addr,
+        };
+        if enrollment_tx.try_send(request).is_err() {
+            log::warn!("Dropping handshake from unknown WireGuard peer, enrollment queue is full.");
+            return;
+        }
+        *requests += 1;
+        self.pending_enrollments
+            .insert(key, (data.to_vec(), src_addr, local_addr));
+    }
+
+    fn find_peer_for_datagram(
+        &mut self,
+        data: &[u8],
+        sender_addr: SocketAddr,
+        local_addr: SocketAddr,
+    ) -> Option<Arc<Mutex<WireGuardPeer>>> {
         let packet = match Tunn::parse_incoming_packet(data) {
             Ok(p) => p,
             Err(error) => {
@@ -214,7 +612,11 @@ impl WireGuardTask {
                 };
 
                 let peer_public_key = PublicKey::from(handshake.peer_static_public);
-                self.peers_by_key.get(&peer_public_key)
+                let peer = self.peers_by_key.get(&peer_public_key).cloned();
+                if peer.is_none() {
+                    self.request_enrollment(peer_public_key, data, sender_addr, local_addr);
+                }
+                return peer;
             }
             Packet::HandshakeResponse(p) => self.peers_by_idx.get(&(p.receiver_idx >> 8)),
             Packet::PacketCookieReply(p) => self.peers_by_idx.get(&(p.receiver_idx >> 8)),
@@ -229,13 +631,98 @@ impl WireGuardTask {
         }
     }
 
+    /// update the timers of all peers and send out keepalives or handshakes where necessary.
+    async fn update_timers(&mut self) -> Result<()> {
+        if self.rate_limiter_reset.elapsed() >= Duration::from_secs(1) {
+            self.rate_limiter.reset_count();
+            self.rate_limiter_reset = Instant::now();
+            self.handshake_stats.handshakes_per_second = self.handshakes_in_window;
+            self.handshakes_in_window = 0;
+        }
+
+        for peer in self.peers_by_idx.values() {
+            let mut peer = peer.lock().await;
+            match peer.tunnel.update_timers(&mut self.wg_buf) {
+                TunnResult::Done => {}
+                TunnResult::Err(WireGuardError::ConnectionExpired) => {
+                    log::debug!("WG::update_timers: WireGuard session expired.");
+                }
+                TunnResult::Err(error) => {
+                    log::error!("WG::update_timers: Err: {:?}", error);
+                }
+                TunnResult::WriteToNetwork(buf) => {
+                    let Some(dst_addr) = peer.endpoint else {
+                        // peer has never contacted us, so we don't know where to send this.
+                        continue;
+                    };
+                    peer.counters.tx(buf.len());
+                    drop(peer);
+
+                    log::trace!("WG::update_timers: WriteToNetwork, dst_addr: {}", dst_addr);
+                    self.sockets.send_to(buf, dst_addr).await?;
+                }
+                TunnResult::WriteToTunnelV4(_, _) | TunnResult::WriteToTunnelV6(_, _) => {
+                    log::warn!("WG::update_timers: WriteToTunnel: unexpected event");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check handshake messages against the rate limiter before doing any expensive work.
+    /// When under load, this sends a cookie reply instead. Returns `false` if the datagram
+    /// should not be processed any further.
+    async fn check_rate_limit(&mut self, data: &[u8], sender_addr: SocketAddr) -> Result<bool> {
+        match self
+            .rate_limiter
+            .verify_packet(Some(sender_addr.ip()), data, &mut self.wg_buf)
+        {
+            Ok(Packet::HandshakeInit(_)) => {
+                self.handshake_stats.handshakes += 1;
+                self.handshakes_in_window += 1;
+                Ok(true)
+            }
+            Ok(_) => Ok(true),
+            Err(TunnResult::WriteToNetwork(cookie)) => {
+                log::debug!("WireGuard server is under load, sending cookie reply.");
+                self.handshake_stats.cookie_replies += 1;
+                self.sockets.send_to(cookie, sender_addr).await?;
+                Ok(false)
+            }
+            Err(TunnResult::Err(WireGuardError::InvalidMac)) => {
+                log::debug!("Dropping WireGuard handshake with invalid MAC.");
+                self.handshake_stats.invalid_handshakes += 1;
+                Ok(false)
+            }
+            Err(error) => {
+                log::debug!("Dropping invalid WireGuard packet: {:?}", error);
+                Ok(false)
+            }
+        }
+    }
+
     /// process WireGuard datagrams and forward the decrypted packets.
     async fn process_incoming_datagram(
         &mut self,
         data: &[u8],
         sender_addr: SocketAddr,
+        local_addr: SocketAddr,
+    ) -> Result<()> {
+        if !self.check_rate_limit(data, sender_addr).await? {
+            return Ok(());
+        }
+        self.process_verified_datagram(data, sender_addr, local_addr)
+            .await
+    }
+
+    /// process WireGuard datagrams that have already passed the rate limiter.
+    async fn process_verified_datagram(
+        &mut self,
+        data: &[u8],
+        sender_addr: SocketAddr,
+        local_addr: SocketAddr,
     ) -> Result<()> {
-        let peer = match self.find_peer_for_datagram(data) {
+        let peer = match self.find_peer_for_datagram(data, sender_addr, local_addr) {
             Some(p) => p,
             None => return Ok(()),
         };
@@ -243,20 +730,19 @@ impl WireGuardTask {
         let mut result = {
             let mut peer = peer.lock().await;
             peer.endpoint = Some(sender_addr);
+            peer.counters.rx(data.len());
             peer.tunnel
                 .decapsulate(Some(sender_addr.ip()), data, &mut self.wg_buf)
         };
 
         while let TunnResult::WriteToNetwork(b) = result {
             log::trace!("WG::process_incoming_datagram: WriteToNetwork");
-            self.socket.send_to(b, sender_addr).await?;
+            self.sockets.send_to(b, sender_addr).await?;
 
             // check if there are more things to be handled
-            result = peer
-                .lock()
-                .await
-                .tunnel
-                .decapsulate(None, &[0; 0], &mut self.wg_buf);
+            let mut peer = peer.lock().await;
+            peer.counters.tx(b.len());
+            result = peer.tunnel.decapsulate(None, &[0; 0], &mut self.wg_buf);
         }
 
         match result {
@@ -264,6 +750,7 @@ impl WireGuardTask {
                 log::trace!("WG::process_incoming_datagram: Done");
             }
             TunnResult::Err(error) => {
+                peer.lock().await.counters.decapsulation_errors += 1;
                 if matches!(error, WireGuardError::NoCurrentSession) {
                     log::info!(
                         "No current session for incoming WireGuard packet: \
@@ -286,17 +773,28 @@ impl WireGuardTask {
                             pretty_hex(&buf),
                         );
 
-                        self.peers_by_ip.insert(packet.src_addr().into(), peer);
+                        let tunnel_info = {
+                            let p = peer.lock().await;
+                            if !p.is_allowed(packet.src_addr().into()) {
+                                log::warn!(
+                                    "Dropping incoming packet, source address {} is not in the peer's AllowedIPs.",
+                                    packet.src_addr()
+                                );
+                                return Ok(());
+                            }
+                            p.tunnel_info(sender_addr, local_addr)
+                        };
+
+                        self.peers_by_ip
+                            .insert(packet.src_addr().into(), peer.clone());
                         let event = NetworkEvent::ReceivePacket {
                             packet: SmolPacket::from(packet),
-                            tunnel_info: TunnelInfo::WireGuard {
-                                src_addr: sender_addr,
-                                dst_addr: self.socket.local_addr()?,
-                            },
+                            tunnel_info,
                         };
 
                         if self.net_tx.try_send(event).is_err() {
-                            log::warn!("Dropping incoming packet, TCP channel is full.")
+                            log::warn!("Dropping incoming packet, TCP channel is full.");
+                            peer.lock().await.counters.dropped_packets += 1;
                         };
                     }
                     Err(error) => {
@@ -317,17 +815,28 @@ impl WireGuardTask {
                             pretty_hex(&buf),
                         );
 
-                        self.peers_by_ip.insert(packet.src_addr().into(), peer);
+                        let tunnel_info = {
+                            let p = peer.lock().await;
+                            if !p.is_allowed(packet.src_addr().into()) {
+                                log::warn!(
+                                    "Dropping incoming packet, source address {} is not in the peer's AllowedIPs.",
+                                    packet.src_addr()
+                                );
+                                return Ok(());
+                            }
+                            p.tunnel_info(sender_addr, local_addr)
+                        };
+
+                        self.peers_by_ip
+                            .insert(packet.src_addr().into(), peer.clone());
                         let event = NetworkEvent::ReceivePacket {
                             packet: SmolPacket::from(packet),
-                            tunnel_info: TunnelInfo::WireGuard {
-                                src_addr: sender_addr,
-                                dst_addr: self.socket.local_addr()?,
-                            },
+                            tunnel_info,
                         };
 
                         if self.net_tx.try_send(event).is_err() {
-                            log::warn!("Dropping incoming packet, TCP channel is full.")
+                            log::warn!("Dropping incoming packet, TCP channel is full.");
+                            peer.lock().await.counters.dropped_packets += 1;
                         };
                     }
                     Err(error) => {
@@ -342,17 +851,15 @@ impl WireGuardTask {
 
     /// process packets and send the encrypted WireGuard datagrams to the peer.
     async fn process_outgoing_packet(&mut self, packet: SmolPacket) -> Result<()> {
-        let peer = self
-            .peers_by_ip
-            .get(&packet.dst_ip())
-            .or_else(|| {
-                log::warn!(
-                    "No peer found for IP {}, falling back to first peer.",
-                    packet.dst_ip()
-                );
-                self.peers_by_idx.values().next()
-            })
-            .unwrap();
+        let Some(peer) = self.route(packet.dst_ip()).cloned() else {
+            self.handshake_stats.unroutable_packets += 1;
+            log::warn!(
+                "No peer found for IP {}, dropping packet ({} unroutable packets in total).",
+                packet.dst_ip(),
+                self.handshake_stats.unroutable_packets,
+            );
+            return Ok(());
+        };
 
         let src_ip = packet.src_ip();
         let dst_ip = packet.dst_ip();
@@ -380,7 +887,14 @@ impl WireGuardTask {
                 log::error!("WG::process_outgoing_packet: Err: {:?}", error);
             }
             TunnResult::WriteToNetwork(buf) => {
-                let dst_addr = peer.endpoint.unwrap();
+                let Some(dst_addr) = peer.endpoint else {
+                    log::warn!(
+                        "Unable to send packet to {}, WireGuard peer has not connected yet.",
+                        dst_ip
+                    );
+                    return Ok(());
+                };
+                peer.counters.tx(buf.len());
                 drop(peer);
 
                 log::trace!(
@@ -393,7 +907,7 @@ impl WireGuardTask {
                     pretty_hex(&buf),
                 );
 
-                self.socket.send_to(buf, dst_addr).await?;
+                self.sockets.send_to(buf, dst_addr).await?;
             }
             // IPv4 packet
             TunnResult::WriteToTunnelV4(_, _) => {
@@ -408,3 +922,770 @@ impl WireGuardTask {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::udp::UdpPacket;
+    use crate::shutdown;
+    use anyhow::bail;
+    use mock_instant::MockClock;
+    use tokio::net::UdpSocket;
+    use tokio::sync::{mpsc, watch};
+    use tokio::task::JoinHandle;
+    use tokio::time::timeout;
+
+    const SERVER_KEY: [u8; 32] = [1; 32];
+    const CLIENT_KEY: [u8; 32] = [2; 32];
+
+    fn client_public_key() -> PublicKey {
+        PublicKey::from(&StaticSecret::from(CLIENT_KEY))
+    }
+
+    fn conf() -> WireGuardConf {
+        WireGuardConf {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            private_key: StaticSecret::from(SERVER_KEY),
+            peers: vec![client_public_key().into()],
+            persistent_keepalive: None,
+            enrollment: None,
+        }
+    }
+
+    struct TestServer {
+        addr: SocketAddr,
+        command_tx: UnboundedSender<WireGuardCommand>,
+        // also keeps the transport channels open while the server is running.
+        commands_tx: mpsc::UnboundedSender<TransportCommand>,
+        events_rx: Receiver<TransportEvent>,
+        shutdown_tx: watch::Sender<()>,
+        handle: JoinHandle<Result<()>>,
+    }
+
+    impl TestServer {
+        async fn start(conf: WireGuardConf) -> Result<Self> {
+            let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+            let (events_tx, events_rx) = mpsc::channel(16);
+            let (shutdown_tx, shutdown_rx) = shutdown::channel();
+
+            let (mut task, (addrs, command_tx)) = conf
+                .build(events_tx, commands_rx, shutdown_rx, NetworkConf::default())
+                .await?;
+            // tick a lot faster than usual so that tests do not need to wait for long.
+            task.timer_interval = Duration::from_millis(50);
+
+            Ok(Self {
+                addr: addrs[0],
+                command_tx,
+                commands_tx,
+                events_rx,
+                shutdown_tx,
+                handle: tokio::spawn(task.run()),
+            })
+        }
+
+        async fn add_peer(&self, peer: WireGuardPeerConf) -> Result<()> {
+            let (tx, rx) = oneshot::channel();
+            self.command_tx.send(WireGuardCommand::AddPeer(peer, tx))?;
+            rx.await?
+        }
+
+        async fn remove_peer(&self, public_key: PublicKey) -> Result<()> {
+            let (tx, rx) = oneshot::channel();
+            self.command_tx
+                .send(WireGuardCommand::RemovePeer(public_key, tx))?;
+            rx.await?
+        }
+
+        async fn peers(&self) -> Result<Vec<PublicKey>> {
+            let (tx, rx) = oneshot::channel();
+            self.command_tx.send(WireGuardCommand::ListPeers(tx))?;
+            Ok(rx.await?)
+        }
+
+        async fn stats(&self) -> Result<Vec<WireGuardPeerStats>> {
+            let (tx, rx) = oneshot::channel();
+            self.command_tx.send(WireGuardCommand::GetStats(tx))?;
+            Ok(rx.await?)
+        }
+
+        async fn handshake_stats(&self) -> Result<WireGuardHandshakeStats> {
+            let (tx, rx) = oneshot::channel();
+            self.command_tx
+                .send(WireGuardCommand::GetHandshakeStats(tx))?;
+            Ok(rx.await?)
+        }
+
+        async fn stop(self) -> Result<()> {
+            self.shutdown_tx.send(())?;
+            self.handle.await?
+        }
+    }
+
+    struct TestClient {
+        tunnel: Tunn,
+        socket: UdpSocket,
+        buf: Vec<u8>,
+    }
+
+    impl TestClient {
+        async fn connect(server_addr: SocketAddr) -> Result<Self> {
+            Self::connect_with_keys(server_addr, CLIENT_KEY, SERVER_KEY, None).await
+        }
+
+        async fn connect_with_keys(
+            server_addr: SocketAddr,
+            client_key: [u8; 32],
+            server_key: [u8; 32],
+            preshared_key: Option<[u8; 32]>,
+        ) -> Result<Self> {
+            let tunnel = Tunn::new(
+                StaticSecret::from(client_key),
+                PublicKey::from(&StaticSecret::from(server_key)),
+                preshared_key,
+                None,
+                0,
+                None,
+            )
+            .map_err(|error| anyhow!(error))?;
+            let socket = UdpSocket::bind("127.0.0.1:0").await?;
+            socket.connect(server_addr).await?;
+            Ok(Self {
+                tunnel,
+                socket,
+                buf: vec![0u8; MAX_PACKET_SIZE],
+            })
+        }
+
+        /// Receive the next datagram from the server, or `None` if nothing arrives in time.
+        async fn recv(&self, wait: Duration) -> Result<Option<Vec<u8>>> {
+            let mut recv_buf = vec![0u8; MAX_PACKET_SIZE];
+            match timeout(wait, self.socket.recv(&mut recv_buf)).await {
+                Ok(len) => Ok(Some(recv_buf[..len?].to_vec())),
+                Err(_) => Ok(None),
+            }
+        }
+
+        /// Process a datagram from the server and send out any replies.
+        async fn process(&mut self, datagram: &[u8]) -> Result<()> {
+            let mut result = self.tunnel.decapsulate(None, datagram, &mut self.buf);
+            while let TunnResult::WriteToNetwork(b) = result {
+                self.socket.send(b).await?;
+                result = self.tunnel.decapsulate(None, &[0; 0], &mut self.buf);
+            }
+            match result {
+                TunnResult::Done => Ok(()),
+                other => bail!("unexpected decapsulation result: {:?}", other),
+            }
+        }
+
+        async fn send(&mut self, packet: SmolPacket) -> Result<()> {
+            match self.tunnel.encapsulate(&packet.into_inner(), &mut self.buf) {
+                TunnResult::WriteToNetwork(b) => self.socket.send(b).await?,
+                other => bail!("unexpected encapsulation result: {:?}", other),
+            };
+            Ok(())
+        }
+
+        async fn initiate_handshake(&mut self) -> Result<()> {
+            match self
+                .tunnel
+                .format_handshake_initiation(&mut self.buf, false)
+            {
+                TunnResult::WriteToNetwork(b) => self.socket.send(b).await?,
+                other => bail!("unexpected handshake result: {:?}", other),
+            };
+            Ok(())
+        }
+
+        async fn handshake(&mut self) -> Result<()> {
+            self.initiate_handshake().await?;
+            let Some(response) = self.recv(Duration::from_secs(1)).await? else {
+                bail!("no handshake response");
+            };
+            // this also confirms the session with a keepalive.
+            self.process(&response).await
+        }
+    }
+
+    /// Advance tokio's clock, which drives our timer ticks, and boringtun's clock together.
+    async fn advance(duration: Duration) {
+        MockClock::advance(duration);
+        tokio::time::advance(duration).await;
+    }
+
+    /// Send a packet from the network stack to the client, which the client never answers.
+    fn send_to_client(server: &TestServer) -> Result<()> {
+        server
+            .commands_tx
+            .send(TransportCommand::SendRawPacket(SmolPacket::from(
+                UdpPacket {
+                    src_addr: "10.0.0.42:53".parse()?,
+                    dst_addr: "10.0.0.1:1234".parse()?,
+                    payload: b"hello".to_vec(),
+                },
+            )))?;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn persistent_keepalive() -> Result<()> {
+        let server = TestServer::start(WireGuardConf {
+            persistent_keepalive: Some(1),
+            ..conf()
+        })
+        .await?;
+        let mut client = TestClient::connect(server.addr).await?;
+        client.handshake().await?;
+        assert!(client.recv(Duration::from_millis(100)).await?.is_none());
+
+        advance(Duration::from_secs(1)).await;
+        let Some(keepalive) = client.recv(Duration::from_secs(1)).await? else {
+            bail!("no keepalive received");
+        };
+        assert!(matches!(
+            Tunn::parse_incoming_packet(&keepalive),
+            Ok(Packet::PacketData(_))
+        ));
+        client.process(&keepalive).await?;
+
+        server.stop().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_keepalive_without_endpoint() -> Result<()> {
+        let server = TestServer::start(WireGuardConf {
+            persistent_keepalive: Some(1),
+            ..conf()
+        })
+        .await?;
+        let client = TestClient::connect(server.addr).await?;
+
+        // the timers fire, but the server does not know where the peer is.
+        advance(Duration::from_secs(5)).await;
+        assert!(client.recv(Duration::from_secs(1)).await?.is_none());
+
+        server.stop().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_keepalive_if_disabled() -> Result<()> {
+        let server = TestServer::start(conf()).await?;
+        let mut client = TestClient::connect(server.addr).await?;
+        client.handshake().await?;
+
+        advance(Duration::from_secs(5)).await;
+        assert!(client.recv(Duration::from_secs(1)).await?.is_none());
+
+        server.stop().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handshake_if_peer_does_not_respond() -> Result<()> {
+        let server = TestServer::start(conf()).await?;
+        let mut client = TestClient::connect(server.addr).await?;
+        client.handshake().await?;
+
+        send_to_client(&server)?;
+        let Some(data) = client.recv(Duration::from_secs(1)).await? else {
+            bail!("no data received");
+        };
+        assert!(matches!(
+            Tunn::parse_incoming_packet(&data),
+            Ok(Packet::PacketData(_))
+        ));
+
+        // after sending data without hearing back for 15 seconds (KEEPALIVE_TIMEOUT + REKEY_TIMEOUT),
+        // the server initiates a new handshake, and retries it every 5 seconds (REKEY_TIMEOUT).
+        for _ in 0..2 {
+            advance(Duration::from_secs(16)).await;
+            let Some(init) = client.recv(Duration::from_secs(1)).await? else {
+                bail!("no handshake initiation received");
+            };
+            assert!(matches!(
+                Tunn::parse_incoming_packet(&init),
+                Ok(Packet::HandshakeInit(_))
+            ));
+        }
+
+        server.stop().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_session_is_dropped() -> Result<()> {
+        let server = TestServer::start(conf()).await?;
+        let mut client = TestClient::connect(server.addr).await?;
+        client.handshake().await?;
+        assert!(server.stats().await?[0].last_handshake.is_some());
+
+        // sessions are discarded after three times REJECT_AFTER_TIME without a new handshake.
+        advance(Duration::from_secs(3 * 180 + 1)).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let [stats] = &server.stats().await?[..] else {
+            bail!("expected exactly one peer");
+        };
+        assert!(stats.last_handshake.is_none());
+
+        // the client still has its session, but the server can no longer decrypt its packets.
+        client
+            .send(SmolPacket::from(UdpPacket {
+                src_addr: "10.0.0.1:1234".parse()?,
+                dst_addr: "10.0.0.42:53".parse()?,
+                payload: b"hello".to_vec(),
+            }))
+            .await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(server.stats().await?[0].decapsulation_errors, 1);
+
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn add_and_remove_peers() -> Result<()> {
+        let server = TestServer::start(WireGuardConf {
+            peers: vec![],
+            ..conf()
+        })
+        .await?;
+        assert!(server.peers().await?.is_empty());
+
+        // unknown peers do not get a handshake response.
+        let mut client = TestClient::connect(server.addr).await?;
+        assert!(client.handshake().await.is_err());
+
+        server.add_peer(client_public_key().into()).await?;
+        assert_eq!(server.peers().await?, vec![client_public_key()]);
+        let mut client = TestClient::connect(server.addr).await?;
+        client.handshake().await?;
+
+        // adding a peer twice is rejected, but does not affect the existing peer.
+        assert!(server.add_peer(client_public_key().into()).await.is_err());
+        assert_eq!(server.peers().await?, vec![client_public_key()]);
+
+        server.remove_peer(client_public_key()).await?;
+        assert!(server.peers().await?.is_empty());
+        let mut client = TestClient::connect(server.addr).await?;
+        assert!(client.handshake().await.is_err());
+
+        // removing an unknown peer is rejected.
+        assert!(server.remove_peer(client_public_key()).await.is_err());
+
+        server.stop().await
+    }
+
+    fn peer_conf(key: u8, allowed_ips: &[&str]) -> WireGuardPeerConf {
+        let public_key = PublicKey::from(&StaticSecret::from([key; 32]));
+        WireGuardPeerConf::with_allowed_ips(public_key, allowed_ips).unwrap()
+    }
+
+    #[test]
+    fn parse_allowed_ips() {
+        let conf = peer_conf(3, &["10.0.0.2/32", " fd00::/64"]);
+        assert_eq!(conf.allowed_ips.len(), 2);
+        assert_eq!(conf.allowed_ips[1].prefix_len(), 64);
+
+        let public_key = PublicKey::from(&StaticSecret::from([3; 32]));
+        assert!(WireGuardPeerConf::with_allowed_ips(public_key, ["10.0.0.2"]).is_err());
+        assert!(WireGuardPeerConf::with_allowed_ips(public_key, ["10.0.0.0/33"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn route_by_allowed_ips() -> Result<()> {
+        let (_commands_tx, commands_rx) = mpsc::unbounded_channel();
+        let (events_tx, _events_rx) = mpsc::channel(1);
+        let (_shutdown_tx, shutdown_rx) = shutdown::channel();
+        let (mut task, _) = WireGuardConf {
+            peers: vec![
+                peer_conf(3, &["10.0.0.0/24"]),
+                peer_conf(4, &["10.0.0.2/32"]),
+                peer_conf(5, &["0.0.0.0/0"]),
+                peer_conf(6, &["0.0.0.0/0"]),
+            ],
+            ..conf()
+        }
+        .build(events_tx, commands_rx, shutdown_rx, NetworkConf::default())
+        .await?;
+
+        let peer = |task: &WireGuardTask, key: u8| {
+            task.peers_by_key[&PublicKey::from(&StaticSecret::from([key; 32]))].clone()
+        };
+        let routes_to = |task: &WireGuardTask, ip: &str, key: u8| {
+            task.route(ip.parse().unwrap())
+                .is_some_and(|p| Arc::ptr_eq(p, &peer(task, key)))
+        };
+
+        // longest prefix wins.
+        assert!(routes_to(&task, "10.0.0.2", 4));
+        assert!(routes_to(&task, "10.0.0.3", 3));
+        // no peer is allowed to use IPv6.
+        assert!(task.route("fe80::1".parse()?).is_none());
+        // peers 5 and 6 are ambiguous...
+        assert!(task.route("192.168.0.1".parse()?).is_none());
+        // ...unless one of them has sent packets from that address.
+        let learned = peer(&task, 6);
+        task.peers_by_ip
+            .insert("192.168.0.1".parse()?, learned.clone());
+        assert!(routes_to(&task, "192.168.0.1", 6));
+        // learned addresses do not override more specific ranges.
+        task.peers_by_ip.insert("10.0.0.2".parse()?, learned);
+        assert!(routes_to(&task, "10.0.0.2", 4));
+
+        task.remove_peer(&PublicKey::from(&StaticSecret::from([6; 32])))?;
+        assert!(routes_to(&task, "192.168.0.1", 5));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn peer_index_wraps_around() -> Result<()> {
+        let (_commands_tx, commands_rx) = mpsc::unbounded_channel();
+        let (events_tx, _events_rx) = mpsc::channel(1);
+        let (_shutdown_tx, shutdown_rx) = shutdown::channel();
+        let (mut task, _) = WireGuardConf {
+            peers: vec![peer_conf(3, &["10.0.0.1/32"])],
+            ..conf()
+        }
+        .build(events_tx, commands_rx, shutdown_rx, NetworkConf::default())
+        .await?;
+
+        task.next_peer_idx = MAX_PEER_INDEX;
+        task.add_peer(peer_conf(4, &["10.0.0.2/32"]))?;
+        task.add_peer(peer_conf(5, &["10.0.0.3/32"]))?;
+
+        let index = |key: u8| {
+            let public_key = PublicKey::from(&StaticSecret::from([key; 32]));
+            task.peers_by_key[&public_key].try_lock().unwrap().index
+        };
+        assert_eq!(index(3), 0);
+        assert_eq!(index(4), MAX_PEER_INDEX);
+        // index 0 is still taken by the first peer.
+        assert_eq!(index(5), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drop_packets_outside_allowed_ips() -> Result<()> {
+        let mut server = TestServer::start(WireGuardConf {
+            peers: vec![WireGuardPeerConf::with_allowed_ips(
+                client_public_key(),
+                ["10.0.0.1/32"],
+            )?],
+            ..conf()
+        })
+        .await?;
+        let mut client = TestClient::connect(server.addr).await?;
+        client.handshake().await?;
+
+        let packet = |src_addr: &str| -> Result<SmolPacket> {
+            Ok(SmolPacket::from(UdpPacket {
+                src_addr: src_addr.parse()?,
+                dst_addr: "10.0.0.42:53".parse()?,
+                payload: b"hello".to_vec(),
+            }))
+        };
+
+        client.send(packet("10.0.0.2:1234")?).await?;
+        assert!(timeout(Duration::from_millis(500), server.events_rx.recv())
+            .await
+            .is_err());
+
+        client.send(packet("10.0.0.1:1234")?).await?;
+        assert!(matches!(
+            timeout(Duration::from_secs(1), server.events_rx.recv()).await,
+            Ok(Some(TransportEvent::ConnectionEstablished { .. }))
+        ));
+
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn count_unroutable_packets() -> Result<()> {
+        let server = TestServer::start(WireGuardConf {
+            peers: vec![WireGuardPeerConf::with_allowed_ips(
+                client_public_key(),
+                ["10.0.0.0/24"],
+            )?],
+            ..conf()
+        })
+        .await?;
+        let mut client = TestClient::connect(server.addr).await?;
+        client.handshake().await?;
+
+        let send = |dst_addr: &str| -> Result<()> {
+            server
+                .commands_tx
+                .send(TransportCommand::SendRawPacket(SmolPacket::from(
+                    UdpPacket {
+                        src_addr: "10.0.0.42:53".parse()?,
+                        dst_addr: dst_addr.parse()?,
+                        payload: b"hello".to_vec(),
+                    },
+                )))?;
+            Ok(())
+        };
+
+        send("192.168.0.1:1234")?;
+        send("10.0.1.1:1234")?;
+        // packets are processed in order, so the routable one arrives after the others were dropped.
+        send("10.0.0.1:1234")?;
+        assert!(client.recv(Duration::from_secs(1)).await?.is_some());
+
+        assert_eq!(server.handshake_stats().await?.unroutable_packets, 2);
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn preshared_key_and_peer_identity() -> Result<()> {
+        let mut server = TestServer::start(WireGuardConf {
+            peers: vec![WireGuardPeerConf {
+                preshared_key: Some([7; 32]),
+                ..client_public_key().into()
+            }],
+            ..conf()
+        })
+        .await?;
+
+        let mut client =
+            TestClient::connect_with_keys(server.addr, CLIENT_KEY, SERVER_KEY, Some([8; 32]))
+                .await?;
+        assert!(client.handshake().await.is_err());
+
+        let mut client =
+            TestClient::connect_with_keys(server.addr, CLIENT_KEY, SERVER_KEY, Some([7; 32]))
+                .await?;
+        client.handshake().await?;
+        client
+            .send(SmolPacket::from(UdpPacket {
+                src_addr: "10.0.0.1:1234".parse()?,
+                dst_addr: "10.0.0.42:53".parse()?,
+                payload: b"hello".to_vec(),
+            }))
+            .await?;
+
+        let Ok(Some(TransportEvent::ConnectionEstablished { tunnel_info, .. })) =
+            timeout(Duration::from_secs(1), server.events_rx.recv()).await
+        else {
+            bail!("no connection established");
+        };
+        let TunnelInfo::WireGuard {
+            src_addr,
+            peer_public_key,
+            peer_index,
+            ..
+        } = tunnel_info
+        else {
+            bail!("unexpected tunnel info: {:?}", tunnel_info);
+        };
+        assert_eq!(src_addr, client.socket.local_addr()?);
+        assert_eq!(peer_public_key, client_public_key());
+        assert_eq!(peer_index, 0);
+
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn peer_stats() -> Result<()> {
+        let server = TestServer::start(conf()).await?;
+
+        let stats = server.stats().await?;
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].public_key, client_public_key());
+        assert_eq!(stats[0].endpoint, None);
+        assert_eq!(stats[0].rx_packets, 0);
+
+        let mut client = TestClient::connect(server.addr).await?;
+        client.handshake().await?;
+        // a data packet for our session index that cannot be decrypted.
+        let mut bogus = vec![4, 0, 0, 0, 0, 0, 0, 0];
+        bogus.extend([0; 8 + 16]);
+        client.socket.send(&bogus).await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let [stats] = &server.stats().await?[..] else {
+            bail!("expected exactly one peer");
+        };
+        assert_eq!(stats.endpoint, Some(client.socket.local_addr()?));
+        assert!(stats.last_handshake.is_some());
+        // handshake initiation, keepalive, bogus packet.
+        assert_eq!(stats.rx_packets, 3);
+        assert_eq!(stats.rx_bytes, 148 + 32 + bogus.len() as u64);
+        // handshake response.
+        assert_eq!(stats.tx_packets, 1);
+        assert_eq!(stats.tx_bytes, 92);
+        assert_eq!(stats.decapsulation_errors, 1);
+        assert_eq!(stats.dropped_packets, 0);
+
+        server.stop().await
+    }
+
+    fn enrollment_conf() -> (WireGuardConf, Receiver<EnrollmentRequest>) {
+        let (tx, rx) = mpsc::channel(32);
+        let conf = WireGuardConf {
+            peers: vec![],
+            enrollment: Some(tx),
+            ..conf()
+        };
+        (conf, rx)
+    }
+
+    #[tokio::test]
+    async fn enrollment_accept() -> Result<()> {
+        let (conf, mut enrollment_rx) = enrollment_conf();
+        let server = TestServer::start(conf).await?;
+        let mut client = TestClient::connect(server.addr).await?;
+        client.initiate_handshake().await?;
+
+        let Some(request) = timeout(Duration::from_secs(1), enrollment_rx.recv()).await? else {
+            bail!("no enrollment request");
+        };
+        assert_eq!(request.public_key, client_public_key());
+        assert_eq!(request.src_addr, client.socket.local_addr()?);
+        // the handshake is held back until the peer is approved.
+        assert!(client.recv(Duration::from_millis(200)).await?.is_none());
+
+        server.add_peer(request.public_key.into()).await?;
+        let Some(response) = client.recv(Duration::from_secs(1)).await? else {
+            bail!("no handshake response");
+        };
+        client.process(&response).await?;
+        assert_eq!(server.peers().await?, vec![client_public_key()]);
+
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn enrollment_reject() -> Result<()> {
+        let (conf, mut enrollment_rx) = enrollment_conf();
+        let server = TestServer::start(conf).await?;
+        let mut client = TestClient::connect(server.addr).await?;
+        client.initiate_handshake().await?;
+
+        let Some(request) = timeout(Duration::from_secs(1), enrollment_rx.recv()).await? else {
+            bail!("no enrollment request");
+        };
+        server
+            .command_tx
+            .send(WireGuardCommand::RejectEnrollment(request.public_key))?;
+        assert!(client.recv(Duration::from_millis(200)).await?.is_none());
+        assert!(server.peers().await?.is_empty());
+
+        // rejected peers are not asked about again.
+        assert!(client.handshake().await.is_err());
+        assert!(enrollment_rx.try_recv().is_err());
+
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn enrollment_rate_limit() -> Result<()> {
+        let (conf, mut enrollment_rx) = enrollment_conf();
+        let server = TestServer::start(conf).await?;
+
+        for i in 0..2 * MAX_ENROLLMENT_REQUESTS_PER_SECOND as u8 {
+            let mut client =
+                TestClient::connect_with_keys(server.addr, [100 + i; 32], SERVER_KEY, None).await?;
+            client.initiate_handshake().await?;
+        }
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let mut requests = 0;
+        while enrollment_rx.try_recv().is_ok() {
+            requests += 1;
+        }
+        assert_eq!(requests, MAX_ENROLLMENT_REQUESTS_PER_SECOND);
+
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn handshakes_are_counted_once() -> Result<()> {
+        let (enrollment, mut enrollment_rx) = enrollment_conf();
+        let server = TestServer::start(WireGuardConf {
+            peers: vec![client_public_key().into()],
+            ..enrollment
+        })
+        .await?;
+        let mut client = TestClient::connect(server.addr).await?;
+        let init = match client
+            .tunnel
+            .format_handshake_initiation(&mut client.buf, false)
+        {
+            TunnResult::WriteToNetwork(b) => b.to_vec(),
+            other => bail!("unexpected handshake result: {:?}", other),
+        };
+        // more than half of the limit, but less than the limit.
+        let count = HANDSHAKE_RATE_LIMIT * 3 / 4;
+        for _ in 0..count {
+            client.socket.send(&init).await?;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let stats = server.handshake_stats().await?;
+        assert_eq!(stats.handshakes, count);
+        assert_eq!(stats.cookie_replies, 0);
+
+        // the handshake that triggered an enrollment is not counted again once it is approved.
+        let mut enrolled =
+            TestClient::connect_with_keys(server.addr, [3; 32], SERVER_KEY, None).await?;
+        enrolled.initiate_handshake().await?;
+        let Some(request) = timeout(Duration::from_secs(1), enrollment_rx.recv()).await? else {
+            bail!("no enrollment request");
+        };
+        server.add_peer(request.public_key.into()).await?;
+        if enrolled.recv(Duration::from_secs(1)).await?.is_none() {
+            bail!("no handshake response");
+        }
+
+        let stats = server.handshake_stats().await?;
+        assert_eq!(stats.handshakes, count + 1);
+        assert_eq!(stats.cookie_replies, 0);
+
+        server.stop().await
+    }
+
+    #[tokio::test]
+    async fn handshake_flood() -> Result<()> {
+        let mut server = TestServer::start(conf()).await?;
+        let mut client = TestClient::connect(server.addr).await?;
+        client.handshake().await?;
+
+        // replay a valid handshake initiation from an unknown peer as fast as we can.
+        let mut flooder =
+            TestClient::connect_with_keys(server.addr, [3; 32], SERVER_KEY, None).await?;
+        let init = match flooder
+            .tunnel
+            .format_handshake_initiation(&mut flooder.buf, false)
+        {
+            TunnResult::WriteToNetwork(b) => b.to_vec(),
+            other => bail!("unexpected handshake result: {:?}", other),
+        };
+        let flood = tokio::spawn(async move {
+            while flooder.socket.send(&init).await.is_ok() {
+                tokio::task::yield_now().await;
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // data packets from our peer keep flowing.
+        for port in 1000..1020 {
+            client
+                .send(SmolPacket::from(UdpPacket {
+                    src_addr: format!("10.0.0.1:{}", port).parse()?,
+                    dst_addr: "10.0.0.42:53".parse()?,
+                    payload: b"hello".to_vec(),
+                }))
+                .await?;
+            assert!(matches!(
+                timeout(Duration::from_secs(1), server.events_rx.recv()).await,
+                Ok(Some(TransportEvent::ConnectionEstablished { .. }))
+            ));
+        }
+
+        flood.abort();
+        let stats = server.handshake_stats().await?;
+        assert!(stats.cookie_replies > 0);
+        assert!(stats.handshakes > 0);
+
+        server.stop().await
+    }
+}
@@ -0,0 +1,602 @@
+//! Reading and writing WireGuard configuration files in the INI format that is used by
+//! `wg setconf` and `wg-quick`.
+
+use std::fmt::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use boringtun::x25519::{PublicKey, StaticSecret};
+use data_encoding::BASE64;
+use smoltcp::wire::{IpAddress, IpCidr};
+
+use crate::packet_sources::wireguard::{WireGuardConf, WireGuardPeerConf};
+
+/// The default WireGuard port.
+pub const DEFAULT_PORT: u16 = 51820;
+
+/// The DNS server that clients are configured with by default.
+/// This address is not assigned to any client.
+pub const DEFAULT_DNS: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 53));
+
+/// Configuration for a WireGuard client that connects to our server.
+pub struct ClientConf {
+    pub private_key: StaticSecret,
+    /// The tunnel addresses of the client.
+    pub address: Vec<IpCidr>,
+    pub dns: Vec<IpAddr>,
+    pub server_public_key: PublicKey,
+    pub preshared_key: Option<[u8; 32]>,
+    /// The server endpoint as `host:port`.
+    pub endpoint: String,
+    pub persistent_keepalive: Option<u16>,
+}
+
+impl ClientConf {
+    /// Create a client configuration with the tunnel addresses assigned to `client_index`
+    /// (see [`client_address`]) that routes all traffic through the tunnel.
+    pub fn new(
+        private_key: StaticSecret,
+        server_public_key: PublicKey,
+        endpoint: String,
+        client_index: u32,
+    ) -> Result<Self> {
+        Ok(Self {
+            private_key,
+            address: client_address(client_index)?,
+            dns: vec![DEFAULT_DNS],
+            server_public_key,
+            preshared_key: None,
+            endpoint,
+            persistent_keepalive: None,
+        })
+    }
+
+    /// The server-side configuration for this client.
+    pub fn peer_conf(&self) -> WireGuardPeerConf {
+        WireGuardPeerConf {
+            public_key: PublicKey::from(&self.private_key),
+            allowed_ips: self.address.clone(),
+            preshared_key: self.preshared_key,
+        }
+    }
+
+    /// Parse a wg-quick client configuration with exactly one peer.
+    pub fn from_config(config: &str) -> Result<Self> {
+        let mut interface = None;
+        let mut peer = None;
+        for section in parse_sections(config)? {
+            match section.name.as_str() {
+                "interface" if interface.is_none() => interface = Some(section),
+                "peer" if peer.is_none() => peer = Some(section),
+                _ => bail!(
+                    "Line {}: Unexpected [{}] section in client configuration.",
+                    section.line,
+                    section.name
+                ),
+            }
+        }
+        let interface = interface.context("Missing [Interface] section.")?;
+        let peer = peer.context("Missing [Peer] section.")?;
+
+        let mut private_key = None;
+        let mut address = vec![];
+        let mut dns = vec![];
+        for (line, key, value) in &interface.entries {
+            match key.to_ascii_lowercase().as_str() {
+                "privatekey" => private_key = Some(decode_key(value).context(line_ctx(*line))?),
+                "address" => address.extend(parse_cidrs(value).context(line_ctx(*line))?),
+                "dns" => {
+                    for ip in split_list(value) {
+                        dns.push(
+                            IpAddr::from_str(ip).map_err(|_| {
+                                anyhow!("Line {}: Invalid DNS server: {}", line, ip)
+                            })?,
+                        );
+                    }
+                }
+                k if is_wg_quick_key(k) => (),
+                _ => bail!("Line {}: Unknown [Interface] key: {}", line, key),
+            }
+        }
+
+        let mut server_public_key = None;
+        let mut preshared_key = None;
+        let mut endpoint = None;
+        let mut persistent_keepalive = None;
+        for (line, key, value) in &peer.entries {
+            match key.to_ascii_lowercase().as_str() {
+                "publickey" => {
+                    server_public_key = Some(decode_key(value).context(line_ctx(*line))?)
+                }
+                "presharedkey" => preshared_key = Some(decode_key(value).context(line_ctx(*line))?),
+                "allowedips" => {
+                    parse_cidrs(value).context(line_ctx(*line))?;
+                }
+                "endpoint" => endpoint = Some(value.clone()),
+                "persistentkeepalive" => {
+                    persistent_keepalive = parse_keepalive(value).context(line_ctx(*line))?;
+                }
+                _ => bail!("Line {}: Unknown [Peer] key: {}", line, key),
+            }
+        }
+
+        Ok(Self {
+            private_key: StaticSecret::from(private_key.context("Missing PrivateKey.")?),
+            address,
+            dns,
+            server_public_key: PublicKey::from(
+                server_public_key.context("Missing PublicKey for peer.")?,
+            ),
+            preshared_key,
+            endpoint: endpoint.context("Missing Endpoint for peer.")?,
+            persistent_keepalive,
+        })
+    }
+
+    /// Render this configuration in the wg-quick format, e.g. for display as a QR code.
+    pub fn to_config(&self) -> String {
+        let mut config = String::new();
+        writeln!(config, "[Interface]").unwrap();
+        writeln!(
+            config,
+            "PrivateKey = {}",
+            BASE64.encode(&self.private_key.to_bytes())
+        )
+        .unwrap();
+        if !self.address.is_empty() {
+            writeln!(config, "Address = {}", join(&self.address)).unwrap();
+        }
+        if !self.dns.is_empty() {
+            writeln!(config, "DNS = {}", join(&self.dns)).unwrap();
+        }
+        writeln!(config).unwrap();
+        writeln!(config, "[Peer]").unwrap();
+        writeln!(
+            config,
+            "PublicKey = {}",
+            BASE64.encode(self.server_public_key.as_bytes())
+        )
+        .unwrap();
+        if let Some(psk) = &self.preshared_key {
+            writeln!(config, "PresharedKey = {}", BASE64.encode(psk)).unwrap();
+        }
+        writeln!(config, "AllowedIPs = 0.0.0.0/0, ::/0").unwrap();
+        writeln!(config, "Endpoint = {}", self.endpoint).unwrap();
+        if let Some(keepalive) = self.persistent_keepalive {
+            writeln!(config, "PersistentKeepalive = {}", keepalive).unwrap();
+        }
+        config
+    }
+}
+
+impl WireGuardConf {
+    /// Parse a server configuration in the `wg setconf`/wg-quick format.
+    ///
+    /// wg-quick specific keys such as `Address` or `PostUp` are ignored. Peers must all use the same
+    /// `PersistentKeepalive` value, and `Endpoint` is ignored because we only learn about endpoints
+    /// from incoming packets.
+    pub fn from_config(host: String, config: &str) -> Result<Self> {
+        let mut private_key = None;
+        let mut port = DEFAULT_PORT;
+        let mut peers = vec![];
+        let mut persistent_keepalive = None;
+        let mut seen_interface = false;
+
+        for section in parse_sections(config)? {
+            match section.name.as_str() {
+                "interface" if !seen_interface => {
+                    seen_interface = true;
+                    for (line, key, value) in &section.entries {
+                        match key.to_ascii_lowercase().as_str() {
+                            "privatekey" => {
+                                private_key = Some(decode_key(value).context(line_ctx(*line))?)
+                            }
+                            "listenport" => {
+                                port = value.parse().map_err(|_| {
+                                    anyhow!("Line {}: Invalid ListenPort: {}", line, value)
+                                })?
+                            }
+                            k if is_wg_quick_key(k) => (),
+                            _ => bail!("Line {}: Unknown [Interface] key: {}", line, key),
+                        }
+                    }
+                }
+                "peer" => {
+                    let mut public_key = None;
+                    let mut preshared_key = None;
+                    let mut allowed_ips = vec![];
+                    let mut keepalive = None;
+                    for (line, key, value) in &section.entries {
+                        match key.to_ascii_lowercase().as_str() {
+                            "publickey" => {
+                                public_key = Some(decode_key(value).context(line_ctx(*line))?)
+                            }
+                            "presharedkey" => {
+                                preshared_key = Some(decode_key(value).context(line_ctx(*line))?)
+                            }
+                            "allowedips" => {
+                                allowed_ips.extend(parse_cidrs(value).context(line_ctx(*line))?)
+                            }
+                            "persistentkeepalive" => {
+                                keepalive = parse_keepalive(value).context(line_ctx(*line))?
+                            }
+                            "endpoint" => (),
+                            _ => bail!("Line {}: Unknown [Peer] key: {}", line, key),
+                        }
+                    }
+                    if peers.is_empty() {
+                        persistent_keepalive = keepalive;
+                    } else if keepalive != persistent_keepalive {
+                        bail!(
+                            "Line {}: PersistentKeepalive must be the same for all peers.",
+                            section.line
+                        );
+                    }
+                    peers.push(WireGuardPeerConf {
+                        public_key: PublicKey::from(public_key.with_context(|| {
+                            format!("Line {}: Missing PublicKey for peer.", section.line)
+                        })?),
+                        allowed_ips,
+                        preshared_key,
+                    });
+                }
+                _ => bail!(
+                    "Line {}: Unexpected [{}] section in server configuration.",
+                    section.line,
+                    section.name
+                ),
+            }
+        }
+
+        Ok(Self {
+            host,
+            port,
+            private_key: StaticSecret::from(private_key.context("Missing PrivateKey.")?),
+            peers,
+            persistent_keepalive,
+            enrollment: None,
+        })
+    }
+
+    /// Render this configuration in the `wg setconf` format.
+    pub fn to_config(&self) -> String {
+        let mut config = String::new();
+        writeln!(config, "[Interface]").unwrap();
+        writeln!(
+            config,
+            "PrivateKey = {}",
+            BASE64.encode(&self.private_key.to_bytes())
+        )
+        .unwrap();
+        writeln!(config, "ListenPort = {}", self.port).unwrap();
+        for peer in &self.peers {
+            writeln!(config).unwrap();
+            writeln!(config, "[Peer]").unwrap();
+            writeln!(
+                config,
+                "PublicKey = {}",
+                BASE64.encode(peer.public_key.as_bytes())
+            )
+            .unwrap();
+            if let Some(psk) = &peer.preshared_key {
+                writeln!(config, "PresharedKey = {}", BASE64.encode(psk)).unwrap();
+            }
+            if !peer.allowed_ips.is_empty() {
+                writeln!(config, "AllowedIPs = {}", join(&peer.allowed_ips)).unwrap();
+            }
+            if let Some(keepalive) = self.persistent_keepalive {
+                writeln!(config, "PersistentKeepalive = {}", keepalive).unwrap();
+            }
+        }
+        config
+    }
+}
+
+/// Assign tunnel addresses to the client with the given index.
+///
+/// Clients get consecutive addresses from `10.0.0.1` and `fd00::1` onwards,
+/// skipping [`DEFAULT_DNS`].
+pub fn client_address(client_index: u32) -> Result<Vec<IpCidr>> {
+    if client_index >= 0x00ff_fffd {
+        bail!("Client index out of range: {}", client_index);
+    }
+    let mut host = client_index + 1;
+    if host >= 53 {
+        host += 1;
+    }
+    let v4 = Ipv4Addr::from(u32::from(Ipv4Addr::new(10, 0, 0, 0)) | host);
+    let v6 = Ipv6Addr::from(u128::from(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0)) | host as u128);
+    Ok(vec![
+        IpCidr::new(IpAddress::from(v4), 32),
+        IpCidr::new(IpAddress::from(v6), 128),
+    ])
+}
+
+struct Section {
+    /// lowercase section name, e.g. `interface`.
+    name: String,
+    line: usize,
+    /// `(line, key, value)`
+    entries: Vec<(usize, String, String)>,
+}
+
+fn parse_sections(config: &str) -> Result<Vec<Section>> {
+    let mut sections: Vec<Section> = vec![];
+    for (i, line) in config.lines().enumerate() {
+        let line_no = i + 1;
+        let line = match line.find('#') {
+            Some(pos) => &line[..pos],
+            None => line,
+        }
+        .trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            sections.push(Section {
+                name: name.trim().to_ascii_lowercase(),
+                line: line_no,
+                entries: vec![],
+            });
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            bail!("Line {}: Expected `Key = Value`: {}", line_no, line);
+        };
+        let Some(section) = sections.last_mut() else {
+            bail!("Line {}: Key outside of a section: {}", line_no, key.trim());
+        };
+        section
+            .entries
+            .push((line_no, key.trim().to_string(), value.trim().to_string()));
+    }
+    Ok(sections)
+}
+
+/// Keys that are only meaningful to wg-quick and have no effect on the tunnel itself.
+fn is_wg_quick_key(key: &str) -> bool {
+    matches!(
+        key,
+        "address"
+            | "dns"
+            | "mtu"
+            | "table"
+            | "preup"
+            | "postup"
+            | "predown"
+            | "postdown"
+            | "saveconfig"
+            | "fwmark"
+    )
+}
+
+fn line_ctx(line: usize) -> String {
+    format!("Line {}", line)
+}
+
+fn decode_key(value: &str) -> Result<[u8; 32]> {
+    BASE64
+        .decode(value.as_bytes())
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .ok_or_else(|| anyhow!("Invalid key: {}", value))
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|s| !s.is_empty())
+}
+
+fn parse_cidrs(value: &str) -> Result<Vec<IpCidr>> {
+    split_list(value)
+        .map(|cidr| IpCidr::from_str(cidr).map_err(|_| anyhow!("Invalid address range: {}", cidr)))
+        .collect()
+}
+
+fn parse_keepalive(value: &str) -> Result<Option<u16>> {
+    if value == "off" {
+        return Ok(None);
+    }
+    match value.parse() {
+        Ok(0) => Ok(None),
+        Ok(seconds) => Ok(Some(seconds)),
+        Err(_) => Err(anyhow!("Invalid PersistentKeepalive: {}", value)),
+    }
+}
+
+fn join<T: std::fmt::Display>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER_KEY: &str = "qG8b7LI/s+ezngWpXqj5A7Nj988hbGL+eQ8ePki0iHk=";
+    const CLIENT_KEY: &str = "uO0Vh3cWrTHrxtOr2JxEhZ3wRjTKGrOQfbdPbPuxLUs=";
+    const PSK: &str = "Xc+6nXfdNbH/mvGa2bf7dPXwRMCYk1Ki3P1Ju/uwFgE=";
+
+    fn key(s: &str) -> [u8; 32] {
+        decode_key(s).unwrap()
+    }
+
+    #[test]
+    fn client_config_format() -> Result<()> {
+        let server_key = StaticSecret::from(key(SERVER_KEY));
+        let mut client = ClientConf::new(
+            StaticSecret::from(key(CLIENT_KEY)),
+            PublicKey::from(&server_key),
+            "192.168.0.10:51820".to_string(),
+            0,
+        )?;
+        client.preshared_key = Some(key(PSK));
+
+        let config = client.to_config();
+        assert_eq!(
+            config,
+            format!(
+                "[Interface]\n\
+                 PrivateKey = {CLIENT_KEY}\n\
+                 Address = 10.0.0.1/32, fd00::1/128\n\
+                 DNS = 10.0.0.53\n\
+                 \n\
+                 [Peer]\n\
+                 PublicKey = {}\n\
+                 PresharedKey = {PSK}\n\
+                 AllowedIPs = 0.0.0.0/0, ::/0\n\
+                 Endpoint = 192.168.0.10:51820\n",
+                BASE64.encode(PublicKey::from(&server_key).as_bytes()),
+            )
+        );
+
+        let parsed = ClientConf::from_config(&config)?;
+        assert_eq!(parsed.to_config(), config);
+        assert_eq!(parsed.private_key.to_bytes(), key(CLIENT_KEY));
+        assert_eq!(parsed.address, client.address);
+        assert_eq!(parsed.dns, client.dns);
+        assert_eq!(parsed.preshared_key, Some(key(PSK)));
+        assert_eq!(parsed.endpoint, "192.168.0.10:51820");
+        Ok(())
+    }
+
+    #[test]
+    fn server_config_round_trip() -> Result<()> {
+        let client = ClientConf::new(
+            StaticSecret::from(key(CLIENT_KEY)),
+            PublicKey::from(&StaticSecret::from(key(SERVER_KEY))),
+            "example.com:51820".to_string(),
+            1,
+        )?;
+        let conf = WireGuardConf {
+            host: "0.0.0.0".to_string(),
+            port: 1234,
+            private_key: StaticSecret::from(key(SERVER_KEY)),
+            peers: vec![
+                client.peer_conf(),
+                WireGuardPeerConf {
+                    preshared_key: Some(key(PSK)),
+                    ..PublicKey::from([3; 32]).into()
+                },
+            ],
+            persistent_keepalive: Some(25),
+            enrollment: None,
+        };
+
+        let config = conf.to_config();
+        assert_eq!(
+            config,
+            format!(
+                "[Interface]\n\
+                 PrivateKey = {SERVER_KEY}\n\
+                 ListenPort = 1234\n\
+                 \n\
+                 [Peer]\n\
+                 PublicKey = {}\n\
+                 AllowedIPs = 10.0.0.2/32, fd00::2/128\n\
+                 PersistentKeepalive = 25\n\
+                 \n\
+                 [Peer]\n\
+                 PublicKey = AwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwM=\n\
+                 PresharedKey = {PSK}\n\
+                 AllowedIPs = 0.0.0.0/0, ::/0\n\
+                 PersistentKeepalive = 25\n",
+                BASE64.encode(PublicKey::from(&client.private_key).as_bytes()),
+            )
+        );
+
+        let parsed = WireGuardConf::from_config("0.0.0.0".to_string(), &config)?;
+        assert_eq!(parsed.to_config(), config);
+        assert_eq!(parsed.port, 1234);
+        assert_eq!(parsed.peers.len(), 2);
+        assert_eq!(parsed.peers[0].allowed_ips, client.address);
+        assert_eq!(parsed.peers[1].preshared_key, Some(key(PSK)));
+        assert_eq!(parsed.persistent_keepalive, Some(25));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_wg_quick_config() -> Result<()> {
+        let conf = WireGuardConf::from_config(
+            "127.0.0.1".to_string(),
+            &format!(
+                "# managed by hand\n\
+                 [Interface]\n\
+                 Address = 10.0.0.100/24\n\
+                 privatekey={SERVER_KEY}\n\
+                 PostUp = iptables -A FORWARD -i %i -j ACCEPT\n\
+                 \n\
+                 [Peer] # laptop\n\
+                 PublicKey = {CLIENT_KEY}\n\
+                 AllowedIPs = 10.0.0.1/32,\n\
+                 AllowedIPs = fd00::1/128\n\
+                 Endpoint = 192.168.0.2:1234\n"
+            ),
+        )?;
+        assert_eq!(conf.port, DEFAULT_PORT);
+        assert_eq!(conf.peers.len(), 1);
+        assert_eq!(conf.peers[0].public_key, PublicKey::from(key(CLIENT_KEY)));
+        assert_eq!(conf.peers[0].allowed_ips.len(), 2);
+        assert_eq!(conf.persistent_keepalive, None);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = |config: &str| {
+            WireGuardConf::from_config("127.0.0.1".to_string(), config)
+                .err()
+                .map(|e| format!("{:#}", e))
+        };
+        assert_eq!(
+            err("PrivateKey = foo").as_deref(),
+            Some("Line 1: Key outside of a section: PrivateKey")
+        );
+        assert_eq!(
+            err("[Interface]\nPrivateKey = foo").as_deref(),
+            Some("Line 2: Invalid key: foo")
+        );
+        assert_eq!(
+            err("[Interface]\nListenPort = 1").as_deref(),
+            Some("Missing PrivateKey.")
+        );
+        assert_eq!(
+            err(&format!(
+                "[Interface]\nPrivateKey = {SERVER_KEY}\n[Peer]\nAllowedIPs = 10.0.0.1"
+            ))
+            .as_deref(),
+            Some("Line 4: Invalid address range: 10.0.0.1")
+        );
+        assert_eq!(
+            err(&format!(
+                "[Interface]\nPrivateKey = {SERVER_KEY}\n[Peer]\nFoo = bar"
+            ))
+            .as_deref(),
+            Some("Line 4: Unknown [Peer] key: Foo")
+        );
+        assert_eq!(
+            err(&format!(
+                "[Interface]\nPrivateKey = {SERVER_KEY}\n\
+                 [Peer]\nPublicKey = {CLIENT_KEY}\nPersistentKeepalive = 25\n\
+                 [Peer]\nPublicKey = {PSK}"
+            ))
+            .as_deref(),
+            Some("Line 6: PersistentKeepalive must be the same for all peers.")
+        );
+    }
+
+    #[test]
+    fn assign_client_addresses() -> Result<()> {
+        let v4 = |index| -> Result<String> { Ok(client_address(index)?[0].to_string()) };
+        assert_eq!(v4(0)?, "10.0.0.1/32");
+        assert_eq!(v4(51)?, "10.0.0.52/32");
+        assert_eq!(v4(52)?, "10.0.0.54/32");
+        assert_eq!(v4(300)?, "10.0.1.46/32");
+        assert_eq!(client_address(300)?[1].to_string(), "fd00::12e/128");
+        assert!(client_address(0x00ff_fffd).is_err());
+        Ok(())
+    }
+}